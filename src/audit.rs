@@ -0,0 +1,85 @@
+use serde::Serialize;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::fs::File;
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::sync::mpsc;
+
+/** A single accept loop decision, written to the audit file as a line of JSON. */
+#[derive(Serialize)]
+struct AuditEntry<'a> {
+    // seconds since the Unix epoch
+    time: u64,
+    ip: String,
+    port: u16,
+    // "connected" or "rejected"
+    outcome: &'a str,
+    // error code the connection was rejected with, absent for accepted connections
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<&'a str>,
+}
+
+/** Handle used to record connection attempts in the audit file. Entries are handed to a
+* dedicated task which appends them to the file, so recording never blocks the accept loop.
+*/
+pub struct AuditLog {
+    entries: mpsc::UnboundedSender<String>,
+}
+
+impl AuditLog {
+    /** Function which opens (or creates) the audit file at the given path for appending and
+     * spawns the task writing to it. Fails if the file cannot be opened.
+     */
+    pub fn open(path: &Path) -> std::io::Result<Self> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        let (entries, rx) = mpsc::unbounded_channel();
+        tokio::spawn(run_audit_writer(File::from_std(file), rx));
+        Ok(AuditLog { entries })
+    }
+
+    /** Function which records the outcome of a connection attempt from the given address,
+     * with the code it was rejected with (None if it was accepted).
+     */
+    pub fn record(&self, addr: SocketAddr, reason: Option<&str>) {
+        let entry = AuditEntry {
+            time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |elapsed| elapsed.as_secs()),
+            ip: addr.ip().to_string(),
+            port: addr.port(),
+            outcome: if reason.is_some() {
+                "rejected"
+            } else {
+                "connected"
+            },
+            reason,
+        };
+        // serializing plain integers and strings cannot fail
+        let line = format!("{}\n", serde_json::to_string(&entry).unwrap());
+        // the writer task only stops if a write fails, after which entries are discarded
+        let _ = self.entries.send(line);
+    }
+}
+
+/** Function run by the audit writer task. It appends entries to the file, buffering them
+* and flushing whenever no more entries are waiting, until writing fails.
+*/
+async fn run_audit_writer(file: File, mut entries: mpsc::UnboundedReceiver<String>) {
+    let mut file = BufWriter::new(file);
+    while let Some(line) = entries.recv().await {
+        if let Err(e) = file.write_all(line.as_bytes()).await {
+            log_eprintln!("Failed to write audit file: {}", e);
+            return;
+        }
+        if entries.is_empty()
+            && let Err(e) = file.flush().await
+        {
+            log_eprintln!("Failed to write audit file: {}", e);
+            return;
+        }
+    }
+}
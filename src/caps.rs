@@ -0,0 +1,98 @@
+use std::fmt;
+
+/** Optional protocol features a client can declare it understands during the handshake.
+* The server never sends a client the events of a capability it did not declare. Reliable is a
+* delivery mode rather than a feature, so it is only used by clients which declare it.
+*/
+#[derive(Clone, Copy)]
+pub enum Cap {
+    Nick,
+    Rooms,
+    // private messages (PRIVATE: lines)
+    Dm,
+    // history replay (HISTORY: lines)
+    History,
+    Json,
+    // REACT: lines
+    Reactions,
+    // EDIT: and DELETE: lines
+    Edits,
+    // LEAVE: and PRESENCE: lines
+    Presence,
+    // chat messages are resent until the client acknowledges them with ACK:RECV
+    Reliable,
+}
+
+// every capability, in the order they are advertised
+const ALL: [Cap; 9] = [
+    Cap::Nick,
+    Cap::Rooms,
+    Cap::Dm,
+    Cap::History,
+    Cap::Json,
+    Cap::Reactions,
+    Cap::Edits,
+    Cap::Presence,
+    Cap::Reliable,
+];
+
+impl Cap {
+    // Function which returns the name of the capability used in CAPS lines.
+    fn name(self) -> &'static str {
+        match self {
+            Cap::Nick => "nick",
+            Cap::Rooms => "rooms",
+            Cap::Dm => "dm",
+            Cap::History => "history",
+            Cap::Json => "json",
+            Cap::Reactions => "reactions",
+            Cap::Edits => "edits",
+            Cap::Presence => "presence",
+            Cap::Reliable => "reliable",
+        }
+    }
+}
+
+/** Set of capabilities, stored as a bitset with one bit per Cap. */
+#[derive(Clone, Copy)]
+pub struct Caps(u16);
+
+impl Caps {
+    // Function which returns the set of every capability, as advertised by the server.
+    pub fn all() -> Self {
+        Caps(ALL.iter().fold(0, |bits, cap| bits | 1 << *cap as u16))
+    }
+
+    // Function which returns the capabilities assumed for clients which declare none: every one but the reliable mode.
+    pub fn assumed() -> Self {
+        Caps(Caps::all().0 & !(1 << Cap::Reliable as u16))
+    }
+
+    /** Function which parses a comma-separated list of capability names (ex. `nick,dm`), as sent
+     * by a client. Unknown names are ignored, so clients can declare capabilities of newer servers.
+     */
+    pub fn parse(list: &str) -> Self {
+        let bits = ALL
+            .iter()
+            .filter(|cap| list.split(',').any(|name| name.trim() == cap.name()))
+            .fold(0, |bits, cap| bits | 1 << *cap as u16);
+        Caps(bits)
+    }
+
+    // Function which returns whether the set contains the given capability.
+    pub fn contains(self, cap: Cap) -> bool {
+        self.0 & 1 << cap as u16 != 0
+    }
+}
+
+impl fmt::Display for Caps {
+    // Formats the set as a comma-separated list of capability names, as sent by the server.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: Vec<&str> = ALL
+            .iter()
+            .filter(|cap| self.contains(**cap))
+            .map(|cap| cap.name())
+            .collect();
+        write!(f, "{}", names.join(","))
+    }
+}
@@ -0,0 +1,852 @@
+use crate::config::NickPolicy;
+use crate::connection::Removal;
+use crate::events::{Event, EventKind};
+use crate::handshake::parse_replay;
+use crate::message::Message;
+use crate::routing::forward_events;
+use crate::store::Record;
+use crate::targets::{Target, TargetError, Targets};
+use crate::{Connections, DEFAULT_ROOM, PrivateRoom, RECALL_SIZE, listener, logger, targets};
+use std::collections::{BTreeMap, HashSet};
+use tokio::time::Instant;
+
+// maximum length in characters of the tag a command can carry, for the client to correlate its replies
+const MAX_TAG_LEN: usize = 64;
+
+/** Function which returns whether a reply reports malformed input from the client (an unknown command,
+* or a command with invalid arguments), as opposed to a valid request which was refused.
+*/
+pub fn is_protocol_error(line: &str) -> bool {
+    line.starts_with("ERR:BAD_") || line.starts_with("ERR:UNKNOWN_COMMAND")
+}
+
+/** Function which splits the tag off a tagged command (ex. `[7]/nick alice`), returning the tag and the
+* command line, or None for any other line. A tag is up to MAX_TAG_LEN characters other than whitespace and
+* brackets, and is only recognised in front of a command, so chat messages starting with brackets are left
+* as they are, as is an escaped prefix (a chat message).
+*/
+pub fn split_tag<'a>(line: &'a str, prefix: &str) -> Option<(&'a str, &'a str)> {
+    let (tag, command) = line.strip_prefix('[')?.split_once(']')?;
+    let valid = !tag.is_empty()
+        && tag.chars().count() <= MAX_TAG_LEN
+        && !tag.contains(|c: char| c.is_whitespace() || c == '[');
+    let is_command = command.starts_with(prefix) && !command.starts_with(&prefix.repeat(2));
+    (valid && is_command).then_some((tag, command))
+}
+
+/** Implementation of command dispatch for the Connections struct.
+* Implements the function dispatching each command to its handler, and the handlers themselves.
+*/
+impl Connections {
+    /** Function which handles a command (a line starting with the command prefix, passed in without the prefix)
+     * sent by the client with the given c_id. Replies are written only to that client.
+     */
+    pub async fn handle_command(&mut self, c_id: u64, command: &str) {
+        self.last_activity.insert(c_id, Instant::now());
+        // split the command into its name and the (possibly empty) remainder of the line
+        let (name, args) = command.split_once(' ').unwrap_or((command, ""));
+        // counted under its own name, until the dispatch below finds it is not a command
+        *self.command_counts.entry(name.to_string()).or_default() += 1;
+        let disabled = self
+            .config
+            .disabled_features
+            .iter()
+            .any(|feature| feature.commands().contains(&name));
+        let reply = match name {
+            // a disabled feature's commands are rejected before any of their arguments are looked at
+            _ if disabled => "ERR:FEATURE_DISABLED\n".to_string(),
+            // without rooms every client stays in the default room, so broadcasts reach every client through the same room-aware path
+            "join" | "part" | "invite" if self.config.disable_rooms => {
+                "ERR:ROOMS_DISABLED\n".to_string()
+            }
+            "join" => return self.join_room(c_id, args.trim()),
+            "invite" => return self.invite(c_id, args.trim()),
+            "part" => self.part_room(c_id, args.trim()),
+            "topic" => return self.set_topic(c_id, args),
+            "recall" => return self.recall(c_id, args.trim()),
+            "admin" => self.authenticate_admin(c_id, args),
+            "spectate" => self.spectate(c_id),
+            "subscribe" => self.subscribe(c_id, args.trim()),
+            "events" => self.set_events(c_id, args.trim()),
+            "dnd" => self.set_dnd(c_id, args.trim()),
+            "seen" => self.seen(args.trim()),
+            "nick" => return self.change_nick(c_id, args.trim()),
+            "replay" => self.set_replay(c_id, args.trim()),
+            "roster" => self.roster(),
+            "rooms" => self.rooms(),
+            // echo the nonce straight back so the client can time the round trip
+            "ping" => format!("PONG:{}\n", args),
+            "edit" => return self.edit_message(c_id, args),
+            "delete" => return self.delete_message(c_id, args.trim()),
+            "react" => return self.react(c_id, args.trim()),
+            "multicast" => return self.multicast(c_id, args),
+            "msg" => return self.message_one(c_id, args),
+            "version" => format!("VERSION:{}\n", env!("CARGO_PKG_VERSION")),
+            "uptime" => format!("UPTIME:{}\n", self.started.elapsed().as_secs()),
+            "stats" => self.stats(),
+            // only tells the client to wipe its own display, server history and other clients are unaffected
+            "clear" => "CLEAR\n".to_string(),
+            "announce" => {
+                if !self.is_admin(c_id) {
+                    "ERR:FORBIDDEN\n".to_string()
+                } else {
+                    return self.announce(args);
+                }
+            }
+            "kickall" => {
+                if !self.is_admin(c_id) {
+                    "ERR:FORBIDDEN\n".to_string()
+                } else {
+                    return self.kick_all();
+                }
+            }
+            "kick" => {
+                if !self.is_admin(c_id) {
+                    "ERR:FORBIDDEN\n".to_string()
+                } else {
+                    return self.kick(c_id, args.trim());
+                }
+            }
+            "config" => {
+                if !self.is_admin(c_id) {
+                    "ERR:FORBIDDEN\n".to_string()
+                } else {
+                    return self.show_config(c_id);
+                }
+            }
+            "rebind" => {
+                if !self.is_admin(c_id) {
+                    "ERR:FORBIDDEN\n".to_string()
+                } else {
+                    self.rebind(args.trim()).await
+                }
+            }
+            _ => {
+                self.count_as_unknown(name);
+                "ERR:UNKNOWN_COMMAND\n".to_string()
+            }
+        };
+        self.send_to_client(c_id, &reply);
+    }
+
+    // Function which moves the count of a command found to be unknown from its name to "unknown", so /stats lists only real commands by name.
+    fn count_as_unknown(&mut self, name: &str) {
+        if let Some(count) = self.command_counts.get_mut(name) {
+            *count -= 1;
+            if *count == 0 {
+                self.command_counts.remove(name);
+            }
+        }
+        *self
+            .command_counts
+            .entry("unknown".to_string())
+            .or_default() += 1;
+    }
+
+    /** Function which grants admin rights to the client with the given c_id if the
+     * supplied password matches the configured admin password, returning the reply to send.
+     */
+    fn authenticate_admin(&mut self, c_id: u64, password: &str) -> String {
+        match (&self.config.admin_password, self.cons.get_mut(&c_id)) {
+            (Some(expected), Some(con)) if expected == password => {
+                con.is_admin = true;
+                "ACK:ADMIN\n".to_string()
+            }
+            _ => "ERR:FORBIDDEN\n".to_string(),
+        }
+    }
+
+    /** Function which marks the client with the given c_id as a read-only spectator, which
+     * keeps receiving broadcasts but can no longer send messages, returning the reply to send.
+     */
+    fn spectate(&mut self, c_id: u64) -> String {
+        if let Some(con) = self.cons.get_mut(&c_id) {
+            con.read_only = true;
+        }
+        "ACK:SPECTATE\n".to_string()
+    }
+
+    /** Function which subscribes the client with the given c_id to the server-wide stream of lifecycle
+     * events (`/subscribe events`), returning the reply to send. From then on the client is sent every
+     * event as `EVENT:{JSON}` (see Event), forwarded by a task of its own, and is no longer sent chat
+     * messages. Subscribing again has no further effect, and any other stream is rejected with
+     * `ERR:BAD_SUBSCRIPTION`.
+     */
+    fn subscribe(&mut self, c_id: u64, stream: &str) -> String {
+        if stream != "events" {
+            return "ERR:BAD_SUBSCRIPTION\n".to_string();
+        }
+        let Some(con) = self.cons.get_mut(&c_id) else {
+            return String::new();
+        };
+        if !con.subscribed {
+            con.subscribed = true;
+            tokio::spawn(forward_events(con.outbox.feed(), self.events.subscribe()));
+        }
+        "ACK:SUBSCRIBE events\n".to_string()
+    }
+
+    /** Function which chooses how the client with the given c_id is told of other clients connecting,
+     * disconnecting, joining or leaving its rooms and changing nickname (`/events {inline|structured}`),
+     * returning the reply to send. Inline clients are sent each change as a `SYSTEM:{TEXT}` line in order
+     * with the chat, while structured clients (the default) keep receiving control lines such as `LEAVE:`.
+     */
+    fn set_events(&mut self, c_id: u64, mode: &str) -> String {
+        let inline = match mode {
+            "inline" => true,
+            "structured" => false,
+            _ => return "ERR:BAD_EVENTS\n".to_string(),
+        };
+        if let Some(con) = self.cons.get_mut(&c_id) {
+            con.inline_events = inline;
+        }
+        format!("ACK:EVENTS {}\n", mode)
+    }
+
+    /** Function which turns do not disturb mode on or off for the client with the given c_id (`/dnd {on|off}`),
+     * returning the reply to send. While it is on, the client is not sent the chat messages broadcast to its
+     * rooms, but is still sent private messages, announcements and replies to its own commands.
+     */
+    fn set_dnd(&mut self, c_id: u64, value: &str) -> String {
+        let dnd = match value {
+            "on" => true,
+            "off" => false,
+            _ => return "ERR:BAD_DND\n".to_string(),
+        };
+        if let Some(con) = self.cons.get_mut(&c_id) {
+            con.dnd = dnd;
+        }
+        format!("ACK:DND {}\n", value)
+    }
+
+    /** Function which changes the nickname of the client with the given c_id (`/nick {NICK}`) and replies
+     * to it, telling inline clients in its rooms of the change when the nickname is a new one.
+     */
+    fn change_nick(&mut self, c_id: u64, nick: &str) {
+        let before = self.describe(c_id);
+        let unchanged = self
+            .cons
+            .get(&c_id)
+            .is_some_and(|con| con.nick.as_deref() == Some(nick));
+        let reply = self.set_nick(c_id, nick);
+        self.send_to_client(c_id, &reply);
+        if reply.starts_with("ACK:") && !unchanged {
+            let text = format!("{} is now known as {}", before, nick);
+            let rooms = self
+                .cons
+                .get(&c_id)
+                .map(|con| con.rooms.clone())
+                .unwrap_or_default();
+            let rooms: Vec<&str> = rooms.iter().map(String::as_str).collect();
+            self.send_presence(&rooms, c_id, None, &text);
+        }
+    }
+
+    /** Function which sets the nickname of the client with the given c_id, returning the reply
+     * to send. Nicknames must be between 1 and max_nick_len characters, may not contain
+     * whitespace, control characters or commas, may not consist only of digits (so they cannot be
+     * mistaken for client IDs), and may not already be in use by another client. On a server forbidding
+     * nicknames, every nickname is refused with `ERR:NICK_FORBIDDEN`, whether chosen with `/nick` or
+     * during the handshake.
+     */
+    pub fn set_nick(&mut self, c_id: u64, nick: &str) -> String {
+        if self.config.nick_policy == NickPolicy::Forbidden {
+            return "ERR:NICK_FORBIDDEN\n".to_string();
+        }
+        if nick.chars().count() > self.config.max_nick_len {
+            return "ERR:NICK_TOO_LONG\n".to_string();
+        }
+        if nick.is_empty()
+            || nick
+                .chars()
+                .any(|c| c.is_whitespace() || c.is_control() || c == ',')
+            || nick.chars().all(|c| c.is_ascii_digit())
+        {
+            return "ERR:BAD_NICK\n".to_string();
+        }
+        if self.nicks.get(nick).is_some_and(|id| *id != c_id) {
+            return "ERR:NICK_TAKEN\n".to_string();
+        }
+        if let Some(con) = self.cons.get_mut(&c_id) {
+            // the client's previous nickname (if any) is freed for others to use
+            if let Some(old) = con.nick.replace(nick.to_string()) {
+                self.nicks.remove(&old);
+            }
+            self.nicks.insert(nick.to_string(), c_id);
+        }
+        format!("ACK:NICK {}\n", nick)
+    }
+
+    /** Function which sets whether history replayed to the client with the given c_id (when it joins a
+     * room) includes its own messages (`/replay own={true|false}`), returning the reply to send.
+     */
+    fn set_replay(&mut self, c_id: u64, value: &str) -> String {
+        let Some(replay_own) = parse_replay(value) else {
+            return "ERR:BAD_REPLAY\n".to_string();
+        };
+        if let Some(con) = self.cons.get_mut(&c_id) {
+            con.replay_own = replay_own;
+        }
+        format!("ACK:REPLAY {}\n", value)
+    }
+
+    /** Function which builds the authoritative list of connected clients, letting a client
+     * resynchronise its view at any time. The reply is a single `ROSTER:` line with one
+     * space-separated `{id}:{room}:{nick}` entry per client (ordered by ID), where nick is
+     * empty for clients without a nickname.
+     */
+    fn roster(&self) -> String {
+        let entries: Vec<String> = self
+            .client_ids()
+            .into_iter()
+            .map(|c_id| {
+                let con = &self.cons[&c_id];
+                format!(
+                    "{}:{}:{}",
+                    c_id,
+                    con.room,
+                    con.nick.as_deref().unwrap_or("")
+                )
+            })
+            .collect();
+        format!("ROSTER:{}\n", entries.join(" "))
+    }
+
+    /** Function which returns the reply to `/rooms`: every room with clients in it, in name order, as
+     * `{ROOM}({MEMBERS},{public|private})`, followed by `:{TOPIC}` when the room has a topic, separated by `|`.
+     */
+    fn rooms(&self) -> String {
+        let mut members: BTreeMap<&str, usize> = BTreeMap::new();
+        for room in self.cons.values().flat_map(|con| con.rooms.iter()) {
+            *members.entry(room).or_default() += 1;
+        }
+        let entries: Vec<String> = members
+            .into_iter()
+            .map(|(room, count)| {
+                let privacy = if self.private_rooms.contains_key(room) {
+                    "private"
+                } else {
+                    "public"
+                };
+                match self.topics.get(room) {
+                    Some(topic) => format!("{}({},{}):{}", room, count, privacy, topic),
+                    None => format!("{}({},{})", room, count, privacy),
+                }
+            })
+            .collect();
+        format!("ROOMS:{}\n", entries.join("|"))
+    }
+
+    /** Function which reports when the client with the given ID (or nickname) was last active, returning
+     * `SEEN:{id} online` for a connected client, `SEEN:{id} {seconds_ago}` for a client which has
+     * since disconnected, and `ERR:NO_SUCH_CLIENT` for an ID which has never sent anything.
+     */
+    fn seen(&self, target: &str) -> String {
+        let Some(target) = self.resolve(target) else {
+            return "ERR:NO_SUCH_CLIENT\n".to_string();
+        };
+        if self.cons.contains_key(&target) {
+            return format!("SEEN:{} online\n", target);
+        }
+        match self.last_activity.get(&target) {
+            Some(last) => format!("SEEN:{} {}\n", target, last.elapsed().as_secs()),
+            None => "ERR:NO_SUCH_CLIENT\n".to_string(),
+        }
+    }
+
+    /** Function which moves the client with the given c_id into the specified room,
+     * acknowledges the join, and replays the history of the joined room. When clients may be in
+     * several rooms (max_rooms_per_client is set), the client instead joins the room while staying
+     * in the others, the joined room becoming its current room, and a client already in the maximum
+     * number of rooms is refused with `ERR:TOO_MANY_JOINS`. Joining an empty room with `--private`
+     * makes it a private room owned by the client, which other clients can only join once invited
+     * (`ERR:INVITE_REQUIRED`).
+     */
+    fn join_room(&mut self, c_id: u64, args: &str) {
+        // room names must be a single non-empty word, optionally followed by --private
+        let (room, private) = match args.split_whitespace().collect::<Vec<&str>>()[..] {
+            [room] => (room, false),
+            [room, "--private"] => (room, true),
+            _ => return self.send_to_client(c_id, "ERR:BAD_ROOM\n"),
+        };
+        // a client already in the maximum number of rooms can only rejoin one of them
+        let max_rooms = self.config.max_rooms_per_client;
+        let at_limit = self.cons.get(&c_id).is_some_and(|con| {
+            max_rooms.is_some_and(|max| !con.in_room(room) && con.rooms.len() >= max)
+        });
+        if at_limit {
+            return self.send_to_client(c_id, "ERR:TOO_MANY_JOINS\n");
+        }
+        if private {
+            // only a room nobody is in can be made private, and the default room never can
+            let occupied = self.cons.values().any(|con| con.in_room(room));
+            if occupied || room == DEFAULT_ROOM {
+                return self.send_to_client(c_id, "ERR:ROOM_EXISTS\n");
+            }
+            self.private_rooms.insert(
+                room.to_string(),
+                PrivateRoom {
+                    owner: c_id,
+                    invited: HashSet::new(),
+                },
+            );
+        } else if self
+            .private_rooms
+            .get(room)
+            .is_some_and(|private| private.owner != c_id && !private.invited.contains(&c_id))
+        {
+            return self.send_to_client(c_id, "ERR:INVITE_REQUIRED\n");
+        }
+        // the rooms the client leaves by moving (without max_rooms_per_client), and whether it was already in the room
+        let mut left = Vec::new();
+        let mut rejoined = false;
+        if let Some(con) = self.cons.get_mut(&c_id) {
+            rejoined = con.in_room(room);
+            match max_rooms {
+                // rejoining a room the client is already in only makes it the current room
+                Some(_) if rejoined => {}
+                Some(_) => con.rooms.push(room.to_string()),
+                None => {
+                    left = std::mem::replace(&mut con.rooms, vec![room.to_string()]);
+                    left.retain(|joined| joined != room);
+                }
+            }
+            con.room = room.to_string();
+        }
+        // moving rooms may have left a private room empty
+        self.prune_private_rooms();
+        self.events
+            .publish(Event::new(EventKind::Join, c_id, Some(room), None));
+        let who = self.describe(c_id);
+        for old in left.iter() {
+            let text = format!("{} left {}", who, old);
+            self.send_presence(&[old], c_id, None, &text);
+        }
+        if !rejoined {
+            let text = format!("{} joined {}", who, room);
+            self.send_presence(&[room], c_id, None, &text);
+        }
+        self.send_to_client(c_id, &format!("ACK:JOIN {}\n", room));
+        self.replay_history(c_id);
+        self.send_topic(c_id, room);
+    }
+
+    /** Function which sets the topic of one of the rooms the client with the given c_id is in
+     * (`/topic {ROOM} {TEXT}`), or clears it when no text is given, acknowledged with `ACK:TOPIC {ROOM}`.
+     * The other clients in the room receive `TOPIC:{ROOM} {TEXT}`, as does any client joining it later.
+     * Clients cannot set the topic of a room they are not in (`ERR:NOT_IN_ROOM`), and when topics are
+     * restricted to owners, only the owner of a private room (or an admin) can set its topic.
+     */
+    fn set_topic(&mut self, c_id: u64, args: &str) {
+        let (room, text) = args.trim().split_once(' ').unwrap_or((args.trim(), ""));
+        let text = text.trim();
+        if room.is_empty() {
+            return self.send_to_client(c_id, "ERR:BAD_ROOM\n");
+        }
+        if !self.cons.get(&c_id).is_some_and(|con| con.in_room(room)) {
+            return self.send_to_client(c_id, "ERR:NOT_IN_ROOM\n");
+        }
+        let is_owner = self
+            .private_rooms
+            .get(room)
+            .is_some_and(|private| private.owner == c_id);
+        if self.config.topic_owner_only && !is_owner && !self.is_admin(c_id) {
+            return self.send_to_client(c_id, "ERR:FORBIDDEN\n");
+        }
+        if text.is_empty() {
+            self.topics.remove(room);
+        } else {
+            self.topics.insert(room.to_string(), text.to_string());
+        }
+        let topic = Message::reply(&format!("TOPIC:{} {}", room, text));
+        self.send_to_room(room, c_id, &topic, None);
+        self.send_to_client(c_id, &format!("ACK:TOPIC {}\n", room));
+    }
+
+    /** Function which sends the client with the given c_id the last messages delivered to it (`/recall [N]`),
+     * the last N or every one kept (up to RECALL_SIZE), oldest first, as `RECALL:{CLIENT_ID} {TEXT}` lines
+     * followed by `ACK:RECALL {COUNT}`. Unlike history, these are only the messages this client received.
+     */
+    fn recall(&self, c_id: u64, args: &str) {
+        let count = match args {
+            "" => RECALL_SIZE,
+            count => match count.parse::<usize>() {
+                Ok(count) => count,
+                Err(_) => return self.send_to_client(c_id, "ERR:BAD_COUNT\n"),
+            },
+        };
+        let Some(con) = self.cons.get(&c_id) else {
+            return;
+        };
+        let lines: Vec<String> = con
+            .recall
+            .iter()
+            .skip(con.recall.len().saturating_sub(count))
+            .map(|(from, text)| format!("RECALL:{} {}\n", from, text))
+            .collect();
+        for line in lines.iter() {
+            self.send_to_client(c_id, line);
+        }
+        self.send_to_client(c_id, &format!("ACK:RECALL {}\n", lines.len()));
+    }
+
+    // Function which sends the client with the given c_id the topic of the given room, if it has one.
+    pub fn send_topic(&self, c_id: u64, room: &str) {
+        if let Some(topic) = self.topics.get(room) {
+            self.send_to_client(c_id, &format!("TOPIC:{} {}\n", room, topic));
+        }
+    }
+
+    /** Function which lets a client join one of the private rooms owned by the client with the given c_id
+     * (`/invite {ID} {ROOM}`), the invited client being told with `INVITE:{ROOM} from {OWNER_ID}` and the
+     * owner answered with `ACK:INVITE {ID} {ROOM}`. Only the owner of a private room can invite clients to it.
+     */
+    fn invite(&mut self, c_id: u64, args: &str) {
+        let (target, room) = args.split_once(' ').unwrap_or((args, ""));
+        let room = room.trim();
+        let is_owner = self
+            .private_rooms
+            .get(room)
+            .is_some_and(|private| private.owner == c_id);
+        if !is_owner {
+            return self.send_to_client(c_id, "ERR:FORBIDDEN\n");
+        }
+        let Some(target) = self
+            .resolve(target)
+            .filter(|target| self.cons.contains_key(target))
+        else {
+            return self.send_to_client(c_id, "ERR:NO_SUCH_CLIENT\n");
+        };
+        // the room is known to exist, as its owner was just checked
+        self.private_rooms
+            .get_mut(room)
+            .unwrap()
+            .invited
+            .insert(target);
+        self.send_to_client(target, &format!("INVITE:{} from {}\n", room, c_id));
+        self.send_to_client(c_id, &format!("ACK:INVITE {} {}\n", target, room));
+    }
+
+    /** Function which removes the client with the given c_id from one of the rooms it is in
+     * (`/part {ROOM}`), acknowledged with `ACK:PART {ROOM}`. If that was the client's current room,
+     * the room it joined most recently of those it is still in becomes its current room. A client
+     * cannot leave its only room (`ERR:LAST_ROOM`), nor a room it is not in (`ERR:NOT_JOINED`).
+     */
+    fn part_room(&mut self, c_id: u64, room: &str) -> String {
+        let Some(con) = self.cons.get_mut(&c_id) else {
+            return String::new();
+        };
+        if !con.in_room(room) {
+            return "ERR:NOT_JOINED\n".to_string();
+        }
+        if con.rooms.len() == 1 {
+            return "ERR:LAST_ROOM\n".to_string();
+        }
+        con.rooms.retain(|joined| joined != room);
+        if con.room == room {
+            // there is at least one room left, as the only room cannot be left
+            con.room = con.rooms.last().unwrap().clone();
+        }
+        self.prune_private_rooms();
+        self.events
+            .publish(Event::new(EventKind::Part, c_id, Some(room), None));
+        let text = format!("{} left {}", self.describe(c_id), room);
+        self.send_presence(&[room], c_id, None, &text);
+        format!("ACK:PART {}\n", room)
+    }
+
+    /** Function which returns the reply to `/stats`: the number of connected clients, of rooms
+     * with clients in them, of messages broadcast, the uptime in seconds, the overflow policy along with
+     * the number of lines it dropped and of clients it disconnected, and whether the message store is
+     * healthy.
+     */
+    fn stats(&self) -> String {
+        let rooms: HashSet<&str> = self
+            .cons
+            .values()
+            .flat_map(|con| con.rooms.iter().map(String::as_str))
+            .collect();
+        let mut stats = format!(
+            "STATS:clients={} rooms={} messages={} uptime={} overflow_policy={} dropped={} overflow_disconnects={} queued={} global_shed_total={} log_dropped={} persistence_healthy={}",
+            self.cons.len(),
+            rooms.len(),
+            self.next_seq - 1,
+            self.started.elapsed().as_secs(),
+            self.config.overflow_policy.name(),
+            self.dropped,
+            self.overflow_disconnects,
+            self.backlog.queued(),
+            self.backlog.shed(),
+            logger::dropped(),
+            self.persistence_healthy()
+        );
+        // commands appear in name order, only once they have been issued
+        let mut counts: Vec<_> = self.command_counts.iter().collect();
+        counts.sort();
+        let counts: Vec<String> = counts
+            .into_iter()
+            .map(|(name, count)| format!("{}:{}", name, count))
+            .collect();
+        stats.push_str(&format!(" commands={}", counts.join(",")));
+        stats.push('\n');
+        stats
+    }
+
+    /** Function which finds the history entry of the message with the given sequence number
+     * (given as the command argument), returning the name of its room and its position in the room's
+     * history. Fails with the reply to send if there is no such message or it was not sent by the
+     * client with the given c_id.
+     */
+    fn find_own_message(&self, c_id: u64, seq: &str) -> Result<(String, usize), &'static str> {
+        let seq: u64 = seq.parse().map_err(|_| "ERR:NO_SUCH_MESSAGE\n")?;
+        // sequence numbers are unique across rooms, so at most one room holds the message
+        for (room, history) in self.history.iter() {
+            if let Some(i) = history.iter().position(|entry| entry.seq == seq) {
+                if history[i].c_id != c_id {
+                    return Err("ERR:FORBIDDEN\n");
+                }
+                return Ok((room.clone(), i));
+            }
+        }
+        Err("ERR:NO_SUCH_MESSAGE\n")
+    }
+
+    /** Function which replaces the text of one of the client's own messages (`/edit {SEQ} {TEXT}`),
+     * both in its room's history and for the other clients in that room, which receive `EDIT:{SEQ} {TEXT}`.
+     * Only messages still in the history can be edited, and only by their sender.
+     */
+    fn edit_message(&mut self, c_id: u64, args: &str) {
+        let (seq, text) = args.split_once(' ').unwrap_or((args, ""));
+        let (room, i) = match self.find_own_message(c_id, seq) {
+            Ok(found) => found,
+            Err(reply) => return self.send_to_client(c_id, reply),
+        };
+        let entry = &mut self.history.get_mut(&room).unwrap()[i];
+        entry.text = text.to_string();
+        let seq = entry.seq;
+        self.persist(Record::Edit {
+            seq,
+            text: text.to_string(),
+        });
+        let edit = Message::Edit {
+            seq,
+            text: text.to_string(),
+        };
+        // clients which cannot apply edits see the new text as a chat message instead
+        let fallback = Message::Chat {
+            seq,
+            from: c_id,
+            sender: self.identity(c_id),
+            text: format!("edited message {}: {}", seq, text),
+            hmac: None,
+            numbered: false,
+        };
+        self.send_to_room(&room, c_id, &edit, Some(&fallback));
+        self.send_to_client(c_id, &format!("ACK:EDIT {}\n", seq));
+    }
+
+    /** Function which removes one of the client's own messages (`/delete {SEQ}`) from its room's
+     * history, telling the other clients in that room with `DELETE:{SEQ}`. Only messages still in the
+     * history can be deleted, and only by their sender.
+     */
+    fn delete_message(&mut self, c_id: u64, seq: &str) {
+        let (room, i) = match self.find_own_message(c_id, seq) {
+            Ok(found) => found,
+            Err(reply) => return self.send_to_client(c_id, reply),
+        };
+        let entry = self.history.get_mut(&room).unwrap().remove(i).unwrap();
+        self.persist(Record::Delete { seq: entry.seq });
+        self.send_to_room(&room, c_id, &Message::Delete { seq: entry.seq }, None);
+        self.send_to_client(c_id, &format!("ACK:DELETE {}\n", entry.seq));
+    }
+
+    /** Function which relays a reaction to a message in the client's room (`/react {SEQ} {EMOJI}`),
+     * sending `REACT:{SEQ} {CLIENT_ID} {EMOJI}` to the other clients in the room, which tally reactions
+     * themselves. The message must still be in the room's history, and each client's reactions are
+     * rate limited, with reactions beyond the limit rejected with `ERR:SLOW_DOWN`.
+     */
+    fn react(&mut self, c_id: u64, args: &str) {
+        let (seq, emoji) = args.split_once(' ').unwrap_or((args, ""));
+        let emoji = emoji.trim();
+        let Some(con) = self.cons.get_mut(&c_id) else {
+            return;
+        };
+        let reply = if emoji.is_empty() || emoji.contains(char::is_whitespace) {
+            "ERR:BAD_REACTION\n"
+        } else if !con.reactions.try_take() {
+            if let (Some(tarpit), Some(addr)) = (&self.tarpit, con.addr) {
+                tarpit.strike(addr.ip());
+            }
+            "ERR:SLOW_DOWN\n"
+        } else {
+            let room = con.room.clone();
+            let seq = seq.parse::<u64>().ok().filter(|seq| {
+                self.history
+                    .get(&room)
+                    .is_some_and(|history| history.iter().any(|entry| entry.seq == *seq))
+            });
+            match seq {
+                Some(seq) => {
+                    let react = Message::React {
+                        seq,
+                        from: c_id,
+                        emoji: emoji.to_string(),
+                    };
+                    // clients which cannot display reactions see them as a chat message instead
+                    let fallback = Message::Chat {
+                        seq,
+                        from: c_id,
+                        sender: self.identity(c_id),
+                        text: format!("reacted {} to message {}", emoji, seq),
+                        hmac: None,
+                        numbered: false,
+                    };
+                    self.send_to_room(&room, c_id, &react, Some(&fallback));
+                    return self.send_to_client(c_id, &format!("ACK:REACT {}\n", seq));
+                }
+                None => "ERR:NO_SUCH_MESSAGE\n",
+            }
+        };
+        self.send_to_client(c_id, reply);
+    }
+
+    /** Function which sends the client the effective configuration of the server, as one
+     * `CONFIG:{KEY} {VALUE}` line per setting, with secrets redacted.
+     */
+    fn show_config(&self, c_id: u64) {
+        for (key, value) in self.config.describe() {
+            self.send_to_client(c_id, &format!("CONFIG:{} {}\n", key, value));
+        }
+    }
+
+    /** Function which binds a listener to the given address and hands it to the accept loop,
+     * which stops listening on its previous address. Connected clients, rooms and history are
+     * unaffected. Returns the reply to send, `ERR:BIND_FAILED` if the address cannot be bound.
+     */
+    async fn rebind(&self, addr: &str) -> String {
+        match listener::bind(addr, self.config.reuse_port).await {
+            Ok(listener) => {
+                let reply = match listener.local_addr() {
+                    Ok(local_addr) => format!("ACK:REBIND {}\n", local_addr),
+                    Err(_) => format!("ACK:REBIND {}\n", addr),
+                };
+                // the accept loop lives as long as the server, so this only fails during shutdown
+                let _ = self.rebind.send(listener);
+                reply
+            }
+            Err(e) => {
+                log_eprintln!("Failed to bind {}: {}", addr, e);
+                "ERR:BIND_FAILED\n".to_string()
+            }
+        }
+    }
+
+    /** Function which sends a private message to a list of clients (`/multicast {ID},{ID} {TEXT}`),
+     * given by ID or nickname, which receive `PRIVATE:{CLIENT_ID} {TEXT}`. The list may also name every
+     * client in a room (`@room:{ROOM}`) or every connected client (`*`), and exclude clients (`!{ID}`),
+     * although only admins may use rooms and `*` (others receive `ERR:FORBIDDEN`), as these reach a room
+     * like a broadcast would without the checks broadcasts are subject to. The sender is answered with `ACK:MULTICAST {COUNT}`, where COUNT is the number of clients the message
+     * reached, unknown client IDs being skipped. A list naming an unknown nickname is rejected with
+     * `ERR:NO_SUCH_CLIENT`, and a malformed list with `ERR:BAD_TARGETS` or `ERR:BAD_TARGET`. The sender
+     * is skipped if it lists itself, unless self delivery is allowed.
+     */
+    fn multicast(&mut self, c_id: u64, args: &str) {
+        let (list, text) = args.split_once(' ').unwrap_or((args, ""));
+        let max = self.config.max_multicast_targets;
+        let reply = match (self.cons.get(&c_id), targets::parse(list, max)) {
+            (None, _) => return,
+            (Some(con), _) if con.read_only => "ERR:READ_ONLY\n".to_string(),
+            (Some(_), Err(e)) => e.reply().to_string(),
+            (Some(con), Ok(targets)) if !con.is_admin && targets.reaches_many() => {
+                "ERR:FORBIDDEN\n".to_string()
+            }
+            (Some(_), Ok(targets)) => match self.recipients(c_id, &targets) {
+                Ok(recipients) => self.send_private(c_id, recipients, text),
+                Err(e) => e.reply().to_string(),
+            },
+        };
+        self.send_to_client(c_id, &reply);
+    }
+
+    /** Function which sends a private message to a single client (`/msg {CLIENT_ID} {TEXT}`), named by its
+     * ID or nickname, acknowledged like a multicast. An unknown or disconnected client is answered with
+     * `ERR:NO_SUCH_CLIENT`.
+     */
+    fn message_one(&mut self, c_id: u64, args: &str) {
+        let (target, text) = args.split_once(' ').unwrap_or((args, ""));
+        let target = self
+            .resolve(target)
+            .filter(|target| self.cons.contains_key(target));
+        let reply = match (self.cons.get(&c_id), target) {
+            (None, _) => return,
+            (Some(con), _) if con.read_only => "ERR:READ_ONLY\n".to_string(),
+            (Some(_), None) => "ERR:NO_SUCH_CLIENT\n".to_string(),
+            (Some(_), Some(target)) => self.send_private(c_id, vec![target], text),
+        };
+        self.send_to_client(c_id, &reply);
+    }
+
+    /** Function which disconnects a single client (`/kick {CLIENT_ID}`), named by its ID or nickname, after
+     * sending it `SERVER:KICKED`, acknowledging the kick to the admin with `ACK:KICK {CLIENT_ID}`. The rest of
+     * the client's rooms see it leave as `kicked`. An unknown or disconnected client is answered with `ERR:NO_SUCH_CLIENT`.
+     */
+    fn kick(&mut self, c_id: u64, target: &str) {
+        let target = self
+            .resolve(target)
+            .filter(|target| self.cons.contains_key(target));
+        let Some(target) = target else {
+            return self.send_to_client(c_id, "ERR:NO_SUCH_CLIENT\n");
+        };
+        // acknowledged first, so an admin kicking itself is still told
+        self.send_to_client(c_id, &format!("ACK:KICK {}\n", target));
+        self.remove_client(target, Removal::Kicked);
+        log_println!("client_id {} kicked by client_id {}", target, c_id);
+    }
+
+    /** Function which resolves a parsed multicast target list from the client with the given c_id to the IDs
+     * of its recipients, in the order they are first named (the clients of a room, or every client, in ID
+     * order), each appearing once. Excluded clients, and the sender unless self delivery is allowed, are left
+     * out as the list is expanded, so a list reaching more than max_multicast_targets clients is rejected as
+     * soon as the limit is passed. Also fails if the list names (or excludes) an unknown nickname.
+     */
+    fn recipients(&self, c_id: u64, targets: &Targets) -> Result<Vec<u64>, TargetError> {
+        let mut named: HashSet<u64> = targets
+            .exclude
+            .iter()
+            .map(|client| self.resolve(client))
+            .collect::<Option<_>>()
+            .ok_or(TargetError::Unknown)?;
+        if !self.config.allow_self_delivery {
+            named.insert(c_id);
+        }
+        let max = self.config.max_multicast_targets;
+        let mut recipients: Vec<u64> = Vec::new();
+        for target in targets.include.iter() {
+            let ids = match target {
+                Target::Client(client) => vec![self.resolve(client).ok_or(TargetError::Unknown)?],
+                // event subscribers are only reached by naming them
+                Target::Room(room) => self
+                    .client_ids()
+                    .into_iter()
+                    .filter(|id| !self.cons[id].subscribed && self.cons[id].in_room(room))
+                    .collect(),
+                Target::Everyone => self
+                    .client_ids()
+                    .into_iter()
+                    .filter(|id| !self.cons[id].subscribed)
+                    .collect(),
+            };
+            for id in ids {
+                // excluded clients (and the sender) are in named already, so they are never added
+                if !named.insert(id) {
+                    continue;
+                }
+                if max.is_some_and(|max| recipients.len() == max) {
+                    return Err(TargetError::TooMany);
+                }
+                recipients.push(id);
+            }
+        }
+        Ok(recipients)
+    }
+}
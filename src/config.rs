@@ -0,0 +1,770 @@
+use crate::identity::IdentityFormat;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+/** What happens to a broadcast for a client whose queue stays full: the broadcast is dropped for
+* that client (the client being disconnected once it has lagged on several in a row), the oldest
+* line waiting in its queue is dropped to make room, or the client is disconnected at once.
+*/
+#[derive(Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OverflowPolicy {
+    DropNewest,
+    DropOldest,
+    Disconnect,
+}
+
+impl OverflowPolicy {
+    // Function which returns the name of the policy, as given to --overflow-policy.
+    pub fn name(&self) -> &'static str {
+        match self {
+            OverflowPolicy::DropNewest => "drop-newest",
+            OverflowPolicy::DropOldest => "drop-oldest",
+            OverflowPolicy::Disconnect => "disconnect",
+        }
+    }
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop-newest" => Ok(OverflowPolicy::DropNewest),
+            "drop-oldest" => Ok(OverflowPolicy::DropOldest),
+            "disconnect" => Ok(OverflowPolicy::Disconnect),
+            _ => Err(()),
+        }
+    }
+}
+
+/** How a newline embedded in the body of a message (such as one added by a message hook) is written to
+* clients receiving the plain line protocol, where it would otherwise end the line early: escaped as `\n`,
+* replaced with a space, or split into several lines of the same kind, one per line of the body.
+*/
+#[derive(Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum NewlinePolicy {
+    Escape,
+    Space,
+    Split,
+}
+
+impl FromStr for NewlinePolicy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "escape" => Ok(NewlinePolicy::Escape),
+            "space" => Ok(NewlinePolicy::Space),
+            "split" => Ok(NewlinePolicy::Split),
+            _ => Err(()),
+        }
+    }
+}
+
+/** Whether clients may, must or may not choose a nickname: by default nicknames are optional, a server
+* requiring them only broadcasts the chat messages of clients which have set one, while a server forbidding
+* them keeps every client anonymous, known only by its ID.
+*/
+#[derive(Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum NickPolicy {
+    Optional,
+    Required,
+    Forbidden,
+}
+
+/** A feature of the server which a deployment can disable entirely, along with the commands using it,
+* which are then answered with `ERR:FEATURE_DISABLED` rather than being processed.
+*/
+#[derive(Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Feature {
+    // private messages (/multicast)
+    Dm,
+    // moving between rooms (/join, /part and /invite)
+    Rooms,
+    Topics,
+    Reactions,
+    // editing and deleting messages
+    Edits,
+    Nick,
+    Recall,
+    Seen,
+}
+
+impl Feature {
+    // Function which returns the names of the commands using the feature.
+    pub fn commands(self) -> &'static [&'static str] {
+        match self {
+            Feature::Dm => &["multicast", "msg"],
+            Feature::Rooms => &["join", "part", "invite"],
+            Feature::Topics => &["topic"],
+            Feature::Reactions => &["react"],
+            Feature::Edits => &["edit", "delete"],
+            Feature::Nick => &["nick"],
+            Feature::Recall => &["recall"],
+            Feature::Seen => &["seen"],
+        }
+    }
+}
+
+impl FromStr for Feature {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "dm" => Ok(Feature::Dm),
+            "rooms" => Ok(Feature::Rooms),
+            "topics" => Ok(Feature::Topics),
+            "reactions" => Ok(Feature::Reactions),
+            "edits" => Ok(Feature::Edits),
+            "nick" => Ok(Feature::Nick),
+            "recall" => Ok(Feature::Recall),
+            "seen" => Ok(Feature::Seen),
+            _ => Err(()),
+        }
+    }
+}
+
+// settings whose values are never shown, such as by /config
+const SECRETS: [&str; 2] = ["admin_password", "hmac_key"];
+
+/** Struct holding the tunable settings of the server. A default instance matches the
+* behaviour of the server when no command line arguments are given. The settings can also
+* be loaded from a JSON config file, whose keys are the field names below (durations in seconds).
+*/
+#[derive(Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    // address the server listens on for new connections
+    pub bind: SocketAddr,
+    // whether the server falls back to a port chosen by the OS when the bind address's port is already in use
+    pub auto_port: bool,
+    // whether listeners are bound with SO_REUSEPORT, so another server process can listen on the same address
+    pub reuse_port: bool,
+    // additional addresses the server listens on, alongside the bind address
+    pub listen: Vec<SocketAddr>,
+    // maximum number of messages being processed (broadcast) at once across all connections
+    pub max_inflight: usize,
+    // password clients send with `/admin` to gain admin rights, admin commands are unavailable when unset
+    pub admin_password: Option<String>,
+    // key shared with clients to sign chat messages with HMAC-SHA256, messages are not signed when unset
+    pub hmac_key: Option<String>,
+    // whether every chat message from a client must carry a valid signature
+    pub require_signatures: bool,
+    // maximum number of simultaneously connected clients, unlimited when None
+    pub max_clients: Option<usize>,
+    // maximum number of recipients a single broadcast writes to concurrently
+    pub fanout_concurrency: usize,
+    // what happens to a broadcast for a recipient whose queue stays full
+    pub overflow_policy: OverflowPolicy,
+    // how newlines embedded in message bodies are written to clients receiving plain text lines
+    pub newline_policy: NewlinePolicy,
+    // maximum number of lines queued across every client before broadcasts shed lines, unlimited when None
+    pub max_buffered_lines: Option<usize>,
+    // whether broadcasts are fanned out one at a time, so every recipient sees messages in sequence number order
+    pub strict_order: bool,
+    // path of a Rhai script whose on_message function transforms or drops messages
+    pub script: Option<PathBuf>,
+    // line written to every client immediately after it connects, before the login acknowledgement
+    pub banner: Option<String>,
+    // maximum length of a line in bytes, a client exceeding it (even mid-line) is disconnected
+    pub max_line_bytes: Option<usize>,
+    // maximum length of a command line in bytes (0 for unlimited), the same as max_line_bytes when None
+    pub max_command_bytes: Option<usize>,
+    // time a client may take to finish a line once it has started it before it is disconnected, unlimited when None
+    #[serde(
+        deserialize_with = "deserialize_secs",
+        serialize_with = "serialize_secs"
+    )]
+    pub frame_timeout: Option<Duration>,
+    // time a client may send nothing before it is disconnected, never disconnected for idling when None
+    #[serde(
+        deserialize_with = "deserialize_secs",
+        serialize_with = "serialize_secs"
+    )]
+    pub idle_timeout: Option<Duration>,
+    // time a write to a client may make no progress before the client is removed as stalled, unlimited when None
+    #[serde(
+        deserialize_with = "deserialize_secs",
+        serialize_with = "serialize_secs"
+    )]
+    pub stall_timeout: Option<Duration>,
+    // time between presence snapshots sent to every client, no snapshots are sent when None
+    #[serde(
+        deserialize_with = "deserialize_secs",
+        serialize_with = "serialize_secs"
+    )]
+    pub presence_interval: Option<Duration>,
+    // how long before the idle disconnect the client is warned, no warning is sent when None
+    #[serde(
+        deserialize_with = "deserialize_secs",
+        serialize_with = "serialize_secs"
+    )]
+    pub idle_warning: Option<Duration>,
+    // maximum number of new connections accepted per second across all clients, unlimited when None
+    pub accept_rate: Option<u32>,
+    // maximum number of lines per second written to each client (other than high priority notices), unlimited when None
+    pub egress_rate: Option<u32>,
+    // delay imposed on connections and lines from addresses flagged for violating rate limits, nothing is delayed when None
+    #[serde(
+        rename = "tarpit_delay_ms",
+        deserialize_with = "deserialize_millis",
+        serialize_with = "serialize_millis"
+    )]
+    pub tarpit_delay: Option<Duration>,
+    // number of rate limit violations after which an address is flagged by the tarpit
+    pub tarpit_threshold: u32,
+    // time in milliseconds without a violation after which an address's strikes expire, unflagging it
+    pub tarpit_window_ms: u64,
+    // time in milliseconds a reliable client has to acknowledge a chat message before it is resent, doubling after each resend
+    pub ack_timeout_ms: u64,
+    // number of times an unacknowledged chat message is resent to a reliable client before it is given up on
+    pub ack_retries: u32,
+    // maximum number of entries in a multicast target list, and of clients it reaches, unlimited when None
+    pub max_multicast_targets: Option<usize>,
+    // maximum length of a nickname in characters
+    pub max_nick_len: usize,
+    // whether clients may, must or may not set a nickname (the latter two with --require-nick or --forbid-nick)
+    pub nick_policy: NickPolicy,
+    // template clients are labelled by in log lines and in the sender of chat and departure lines (ex. `{nick}@{ip}`)
+    pub identity_format: IdentityFormat,
+    // features disabled for this deployment, whose commands are rejected
+    pub disabled_features: Vec<Feature>,
+    // whether rooms are disabled, in which case every client stays in the default room, which is then a single global channel
+    pub disable_rooms: bool,
+    // maximum number of rooms a client can be in at once, each client is in a single room (joining moves it) when None
+    pub max_rooms_per_client: Option<usize>,
+    // whether only the owner of a private room (or an admin) can set a room's topic, rather than anyone in the room
+    pub topic_owner_only: bool,
+    // whether clients must complete a handshake (choosing their settings) before they are logged in
+    pub handshake: bool,
+    // whether any malformed input (such as an unknown command) disconnects the client, rather than only being answered with an error
+    pub strict: bool,
+    // time a client has to complete the handshake before it is disconnected, unlimited when None
+    #[serde(
+        deserialize_with = "deserialize_secs",
+        serialize_with = "serialize_secs"
+    )]
+    pub handshake_timeout: Option<Duration>,
+    // time a client has to start the handshake before it is taken to be a legacy client, legacy clients are not accepted when None
+    #[serde(
+        rename = "legacy_fallback_ms",
+        deserialize_with = "deserialize_millis",
+        serialize_with = "serialize_millis"
+    )]
+    pub legacy_fallback: Option<Duration>,
+    // prefix marking a line as a command rather than a chat message, commands are disabled when None
+    pub command_prefix: Option<String>,
+    // whether the login acknowledgement is extended with the server version and uptime
+    pub login_info: bool,
+    // maximum number of messages per second broadcast in each room across all its senders, unlimited when None
+    pub room_rate: Option<u32>,
+    // maximum number of messages retained in each room's history buffer, rooms keep no history when 0
+    pub history_size: usize,
+    // number of history lines replayed to a client at once, the rest following in chunks of this size
+    pub replay_chunk: usize,
+    // time in milliseconds between the chunks of a history replay, during which live messages to the client wait behind it
+    pub replay_pause_ms: u64,
+    // older name for message_store_file, whose records it shares, kept so existing setups restore their history
+    pub history_file: Option<PathBuf>,
+    // path of the file chat messages are stored in by the file message store, they are stored in memory when None
+    pub message_store_file: Option<PathBuf>,
+    // whether message acknowledgements omit the message's sequence number, for older clients
+    pub bare_ack: bool,
+    // whether a client's own messages (broadcast, or addressed to itself) are delivered back to it, for loopback testing
+    pub allow_self_delivery: bool,
+    // longest time a line written to a client may wait in its buffer before being flushed, flushed immediately when None
+    #[serde(
+        rename = "flush_interval_ms",
+        deserialize_with = "deserialize_millis",
+        serialize_with = "serialize_millis"
+    )]
+    pub flush_interval: Option<Duration>,
+    // path of the file every accepted and rejected connection is recorded in, nothing is recorded when None
+    pub audit_file: Option<PathBuf>,
+    // path of the file operational events are logged to as JSON lines, nothing is logged there when None
+    pub json_log: Option<PathBuf>,
+    // size in bytes past which the JSON log is rotated, it is rotated daily instead when None
+    pub json_log_max_bytes: Option<u64>,
+    // time a closing connection has to receive its remaining lines before it is dropped, unlimited when None
+    #[serde(
+        deserialize_with = "deserialize_secs",
+        serialize_with = "serialize_secs"
+    )]
+    pub disconnect_grace: Option<Duration>,
+    // whether the server shuts down once the last client has disconnected, for one-shot sessions
+    pub exit_when_empty: bool,
+    // time in milliseconds the server waits, once empty, for a client to connect before it shuts down
+    pub exit_grace_ms: u64,
+    // time a client which has closed its sending side keeps receiving lines, it is disconnected at once when None
+    #[serde(
+        deserialize_with = "deserialize_secs",
+        serialize_with = "serialize_secs"
+    )]
+    pub half_close: Option<Duration>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            bind: SocketAddr::from(([127, 0, 0, 1], 8888)),
+            auto_port: false,
+            reuse_port: false,
+            listen: Vec::new(),
+            max_inflight: 1024,
+            admin_password: None,
+            hmac_key: None,
+            require_signatures: false,
+            max_clients: None,
+            fanout_concurrency: 1,
+            overflow_policy: OverflowPolicy::DropNewest,
+            newline_policy: NewlinePolicy::Escape,
+            max_buffered_lines: None,
+            strict_order: false,
+            script: None,
+            banner: None,
+            max_line_bytes: Some(64 * 1024),
+            max_command_bytes: None,
+            frame_timeout: None,
+            idle_timeout: None,
+            stall_timeout: None,
+            presence_interval: None,
+            idle_warning: None,
+            accept_rate: None,
+            egress_rate: None,
+            tarpit_delay: None,
+            tarpit_threshold: 3,
+            tarpit_window_ms: 600_000,
+            ack_timeout_ms: 5000,
+            ack_retries: 3,
+            max_multicast_targets: None,
+            max_nick_len: 32,
+            nick_policy: NickPolicy::Optional,
+            identity_format: IdentityFormat::default(),
+            disabled_features: Vec::new(),
+            disable_rooms: false,
+            max_rooms_per_client: None,
+            topic_owner_only: false,
+            handshake: false,
+            strict: false,
+            handshake_timeout: None,
+            legacy_fallback: None,
+            command_prefix: Some("/".to_string()),
+            login_info: false,
+            room_rate: None,
+            history_size: 50,
+            replay_chunk: 64,
+            replay_pause_ms: 10,
+            history_file: None,
+            message_store_file: None,
+            bare_ack: false,
+            allow_self_delivery: false,
+            flush_interval: None,
+            audit_file: None,
+            json_log: None,
+            json_log_max_bytes: None,
+            disconnect_grace: Some(Duration::from_secs(5)),
+            exit_when_empty: false,
+            exit_grace_ms: 1000,
+            half_close: None,
+        }
+    }
+}
+
+impl ServerConfig {
+    /** Function which builds a ServerConfig from command line arguments (excluding the
+     * program name). Each option is given as a flag followed by its value, and any option
+     * that is not given keeps its default value, or its value in the config file given with
+     * `--config`. Flags always override the config file, wherever they appear.
+     */
+    pub fn from_args<I: IntoIterator<Item = String>>(args: I) -> Result<Self, String> {
+        let args: Vec<String> = args.into_iter().collect();
+        // the config file (if any) replaces the defaults before any other flag is applied
+        let mut config = match args.iter().position(|arg| arg == "--config") {
+            Some(i) => {
+                let path: PathBuf = parse_value("--config", args.get(i + 1).cloned())?;
+                ServerConfig::from_file(&path)?
+            }
+            None => ServerConfig::default(),
+        };
+        let mut args = args.into_iter();
+        while let Some(flag) = args.next() {
+            match flag.as_str() {
+                // already loaded above
+                "--config" => {
+                    args.next();
+                }
+                "--bind" => config.bind = parse_value(&flag, args.next())?,
+                "--reuse-port" => config.reuse_port = true,
+                "--auto-port" => config.auto_port = true,
+                "--listen" => config.listen.push(parse_value(&flag, args.next())?),
+                "--port" => config.bind.set_port(parse_value(&flag, args.next())?),
+                "--history-file" => config.history_file = Some(parse_value(&flag, args.next())?),
+                "--message-store-file" => {
+                    config.message_store_file = Some(parse_value(&flag, args.next())?)
+                }
+                "--history-size" => config.history_size = parse_value(&flag, args.next())?,
+                "--replay-chunk" => config.replay_chunk = parse_value(&flag, args.next())?,
+                "--replay-pause-ms" => config.replay_pause_ms = parse_value(&flag, args.next())?,
+                "--max-inflight" => config.max_inflight = parse_value(&flag, args.next())?,
+                "--admin-password" => {
+                    config.admin_password = Some(parse_value(&flag, args.next())?)
+                }
+                "--hmac-key" => config.hmac_key = Some(parse_value(&flag, args.next())?),
+                "--require-signatures" => config.require_signatures = true,
+                "--max-clients" => config.max_clients = Some(parse_value(&flag, args.next())?),
+                "--fanout-concurrency" => {
+                    config.fanout_concurrency = parse_value(&flag, args.next())?
+                }
+                "--overflow-policy" => config.overflow_policy = parse_value(&flag, args.next())?,
+                "--newline-policy" => config.newline_policy = parse_value(&flag, args.next())?,
+                "--max-buffered-lines" => {
+                    config.max_buffered_lines = Some(parse_value(&flag, args.next())?)
+                }
+                "--strict-order" => config.strict_order = true,
+                "--audit-file" => config.audit_file = Some(parse_value(&flag, args.next())?),
+                "--json-log" => config.json_log = Some(parse_value(&flag, args.next())?),
+                "--json-log-max-bytes" => {
+                    config.json_log_max_bytes = Some(parse_value(&flag, args.next())?)
+                }
+                "--script" => config.script = Some(parse_value(&flag, args.next())?),
+                "--banner" => {
+                    // an empty banner is the same as no banner
+                    let banner: String = parse_value(&flag, args.next())?;
+                    config.banner = (!banner.is_empty()).then_some(banner);
+                }
+                "--max-line-bytes" => {
+                    // a limit of 0 disables the check entirely
+                    let max: usize = parse_value(&flag, args.next())?;
+                    config.max_line_bytes = (max > 0).then_some(max);
+                }
+                "--max-command-bytes" => {
+                    config.max_command_bytes = Some(parse_value(&flag, args.next())?)
+                }
+                "--frame-timeout" => {
+                    config.frame_timeout =
+                        Some(Duration::from_secs(parse_value(&flag, args.next())?))
+                }
+                "--stall-timeout" => {
+                    config.stall_timeout =
+                        Some(Duration::from_secs(parse_value(&flag, args.next())?))
+                }
+                "--idle-timeout" => {
+                    config.idle_timeout =
+                        Some(Duration::from_secs(parse_value(&flag, args.next())?))
+                }
+                "--presence-interval" => {
+                    // an interval of 0 sends no snapshots
+                    let secs: u64 = parse_value(&flag, args.next())?;
+                    config.presence_interval = (secs > 0).then(|| Duration::from_secs(secs));
+                }
+                "--handshake" => config.handshake = true,
+                "--strict" => config.strict = true,
+                "--handshake-timeout" => {
+                    config.handshake_timeout =
+                        Some(Duration::from_secs(parse_value(&flag, args.next())?))
+                }
+                "--legacy-fallback-ms" => {
+                    config.legacy_fallback =
+                        Some(Duration::from_millis(parse_value(&flag, args.next())?))
+                }
+                "--command-prefix" => {
+                    // an empty prefix disables commands, so every line is a chat message
+                    let prefix: String = parse_value(&flag, args.next())?;
+                    config.command_prefix = (!prefix.is_empty()).then_some(prefix);
+                }
+                "--login-info" => config.login_info = true,
+                "--bare-ack" => config.bare_ack = true,
+                "--allow-self-delivery" => config.allow_self_delivery = true,
+                "--flush-interval-ms" => {
+                    // an interval of 0 flushes every line as soon as it is written
+                    let millis: u64 = parse_value(&flag, args.next())?;
+                    config.flush_interval = (millis > 0).then(|| Duration::from_millis(millis));
+                }
+                "--room-rate" => config.room_rate = Some(parse_value(&flag, args.next())?),
+                "--max-multicast-targets" => {
+                    config.max_multicast_targets = Some(parse_value(&flag, args.next())?)
+                }
+                "--max-nick-len" => config.max_nick_len = parse_value(&flag, args.next())?,
+                "--require-nick" | "--forbid-nick" => {
+                    let policy = match flag.as_str() {
+                        "--require-nick" => NickPolicy::Required,
+                        _ => NickPolicy::Forbidden,
+                    };
+                    if ![NickPolicy::Optional, policy].contains(&config.nick_policy) {
+                        return Err("--require-nick cannot be used with --forbid-nick".to_string());
+                    }
+                    config.nick_policy = policy;
+                }
+                "--ack-timeout-ms" => config.ack_timeout_ms = parse_value(&flag, args.next())?,
+                "--ack-retries" => config.ack_retries = parse_value(&flag, args.next())?,
+                "--identity-format" => {
+                    // the template's own error says what is wrong with it
+                    let format = args.next().ok_or("missing value for --identity-format")?;
+                    config.identity_format = format.parse()?;
+                }
+                "--disable-rooms" => config.disable_rooms = true,
+                "--disable-feature" => config
+                    .disabled_features
+                    .push(parse_value(&flag, args.next())?),
+                "--topic-owner-only" => config.topic_owner_only = true,
+                "--max-rooms-per-client" => {
+                    config.max_rooms_per_client = Some(parse_value(&flag, args.next())?)
+                }
+                "--accept-rate" => config.accept_rate = Some(parse_value(&flag, args.next())?),
+                "--egress-rate" => config.egress_rate = Some(parse_value(&flag, args.next())?),
+                "--tarpit-delay-ms" => {
+                    // a delay of 0 disables the tarpit
+                    let millis: u64 = parse_value(&flag, args.next())?;
+                    config.tarpit_delay = (millis > 0).then(|| Duration::from_millis(millis));
+                }
+                "--tarpit-threshold" => config.tarpit_threshold = parse_value(&flag, args.next())?,
+                "--tarpit-window-ms" => config.tarpit_window_ms = parse_value(&flag, args.next())?,
+                "--exit-when-empty" => config.exit_when_empty = true,
+                "--exit-grace-ms" => config.exit_grace_ms = parse_value(&flag, args.next())?,
+                "--disconnect-grace" => {
+                    config.disconnect_grace =
+                        Some(Duration::from_secs(parse_value(&flag, args.next())?))
+                }
+                "--half-close" => {
+                    config.half_close = Some(Duration::from_secs(parse_value(&flag, args.next())?))
+                }
+                "--idle-warning" => {
+                    config.idle_warning =
+                        Some(Duration::from_secs(parse_value(&flag, args.next())?))
+                }
+                _ => return Err(format!("unknown argument {}", flag)),
+            }
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    /** Function which checks that the settings are valid, both individually and in combination,
+     * so that an inconsistent configuration stops the server at startup with a descriptive error
+     * instead of behaving surprisingly at runtime.
+     */
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_inflight == 0 {
+            return Err("--max-inflight must be at least 1".to_string());
+        }
+        if self.fanout_concurrency == 0 {
+            return Err("--fanout-concurrency must be at least 1".to_string());
+        }
+        if self.accept_rate == Some(0) {
+            return Err("--accept-rate must be at least 1".to_string());
+        }
+        if self.egress_rate == Some(0) {
+            return Err("--egress-rate must be at least 1".to_string());
+        }
+        if self.room_rate == Some(0) {
+            return Err("--room-rate must be at least 1".to_string());
+        }
+        if self.max_rooms_per_client.is_some() && self.disable_rooms {
+            return Err("--max-rooms-per-client cannot be used with --disable-rooms".to_string());
+        }
+        if self.max_rooms_per_client == Some(0) {
+            return Err("--max-rooms-per-client must be at least 1".to_string());
+        }
+        // a server accepting no clients at all is never useful
+        if self.max_multicast_targets == Some(0) {
+            return Err("--max-multicast-targets must be at least 1".to_string());
+        }
+        if self.max_clients == Some(0) {
+            return Err("--max-clients must be at least 1".to_string());
+        }
+        // an empty password would be matched by a bare /admin
+        if self.admin_password.as_deref() == Some("") {
+            return Err("--admin-password must not be empty".to_string());
+        }
+        // admins authenticate with a command, so admin commands are unreachable without commands
+        if self.admin_password.is_some() && self.command_prefix.is_none() {
+            return Err(
+                "--admin-password requires commands to be enabled (see --command-prefix)"
+                    .to_string(),
+            );
+        }
+        if self.hmac_key.as_deref() == Some("") {
+            return Err("--hmac-key must not be empty".to_string());
+        }
+        if self.require_signatures && self.hmac_key.is_none() {
+            return Err("--require-signatures requires --hmac-key".to_string());
+        }
+        if self.max_buffered_lines == Some(0) {
+            return Err("--max-buffered-lines must be at least 1".to_string());
+        }
+        if self.max_command_bytes.is_some() && self.command_prefix.is_none() {
+            return Err("--max-command-bytes requires a command prefix".to_string());
+        }
+        if self.json_log_max_bytes.is_some() && self.json_log.is_none() {
+            return Err("--json-log-max-bytes requires --json-log".to_string());
+        }
+        if self.json_log_max_bytes == Some(0) {
+            return Err("--json-log-max-bytes must be at least 1".to_string());
+        }
+        if self.tarpit_threshold == 0 {
+            return Err("--tarpit-threshold must be at least 1".to_string());
+        }
+        if self.tarpit_window_ms == 0 {
+            return Err("--tarpit-window-ms must be at least 1".to_string());
+        }
+        // bounded so that the doubling wait between resends stays representable
+        if !(1..=3_600_000).contains(&self.ack_timeout_ms) {
+            return Err("--ack-timeout-ms must be between 1 and 3600000".to_string());
+        }
+        if self.ack_retries > 16 {
+            return Err("--ack-retries must be at most 16".to_string());
+        }
+        // both name the file of the one message store
+        if self.history_file.is_some() && self.message_store_file.is_some() {
+            return Err("--message-store-file cannot be used with --history-file".to_string());
+        }
+        if self.replay_chunk == 0 {
+            return Err("--replay-chunk must be at least 1".to_string());
+        }
+        if self.replay_pause_ms > 60_000 {
+            return Err("--replay-pause-ms must be at most 60000".to_string());
+        }
+        if self.legacy_fallback.is_some() && !self.handshake {
+            return Err("--legacy-fallback-ms requires --handshake".to_string());
+        }
+        if self.legacy_fallback == Some(Duration::ZERO) {
+            return Err("--legacy-fallback-ms must be at least 1".to_string());
+        }
+        if self.handshake_timeout.is_some() && !self.handshake {
+            return Err("--handshake-timeout requires --handshake".to_string());
+        }
+        if self.stall_timeout == Some(Duration::ZERO) {
+            return Err("--stall-timeout must be at least 1".to_string());
+        }
+        if self.frame_timeout == Some(Duration::ZERO) {
+            return Err("--frame-timeout must be at least 1".to_string());
+        }
+        // a closing connection needs some time to write its final notice
+        if self.disconnect_grace == Some(Duration::ZERO) {
+            return Err("--disconnect-grace must be at least 1".to_string());
+        }
+        if self.half_close == Some(Duration::ZERO) {
+            return Err("--half-close must be at least 1".to_string());
+        }
+        if self.handshake_timeout == Some(Duration::ZERO) {
+            return Err("--handshake-timeout must be at least 1".to_string());
+        }
+        match (self.idle_timeout, self.idle_warning) {
+            (Some(timeout), Some(warning)) if warning >= timeout => {
+                Err("--idle-warning must be less than --idle-timeout".to_string())
+            }
+            (None, Some(_)) => Err("--idle-warning requires --idle-timeout".to_string()),
+            _ => Ok(()),
+        }
+    }
+
+    /** Function which returns the maximum length of a command line, falling back to the chat line
+     * limit when no separate command limit is set. A command limit of 0 means unlimited.
+     */
+    pub fn command_line_limit(&self) -> Option<usize> {
+        match self.max_command_bytes {
+            None => self.max_line_bytes,
+            Some(max) => (max > 0).then_some(max),
+        }
+    }
+
+    /** Function which describes the settings as (key, value) pairs, with the keys used in the
+     * config file, sorted by key. Values are shown as in the config file (with strings unquoted),
+     * except that secrets are shown as `<redacted>` when they are set.
+     */
+    pub fn describe(&self) -> Vec<(String, String)> {
+        // serializing plain values cannot fail, and a struct always serializes as an object
+        let value = serde_json::to_value(self).unwrap();
+        let serde_json::Value::Object(fields) = value else {
+            return Vec::new();
+        };
+        let mut settings: Vec<(String, String)> = fields
+            .into_iter()
+            .map(|(key, value)| {
+                let value = match value {
+                    serde_json::Value::Null => "null".to_string(),
+                    _ if SECRETS.contains(&key.as_str()) => "<redacted>".to_string(),
+                    serde_json::Value::String(text) => text,
+                    value => value.to_string(),
+                };
+                (key, value)
+            })
+            .collect();
+        settings.sort();
+        settings
+    }
+
+    /** Function which loads a ServerConfig from the JSON config file at the given path, returning
+     * a descriptive error if the file cannot be read or contains unknown or malformed settings.
+     */
+    pub fn from_file(path: &Path) -> Result<Self, String> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("cannot read config file {}: {}", path.display(), e))?;
+        let mut config: ServerConfig = serde_json::from_str(&contents)
+            .map_err(|e| format!("invalid config file {}: {}", path.display(), e))?;
+        // empty values in the file have the same meaning as the corresponding empty flags
+        config.banner = config.banner.filter(|banner| !banner.is_empty());
+        config.max_line_bytes = config.max_line_bytes.filter(|&max| max > 0);
+        config.presence_interval = config
+            .presence_interval
+            .filter(|interval| !interval.is_zero());
+        config.tarpit_delay = config.tarpit_delay.filter(|delay| !delay.is_zero());
+        config.command_prefix = config.command_prefix.filter(|prefix| !prefix.is_empty());
+        Ok(config)
+    }
+}
+
+/** Function which serializes an optional Duration as a number of seconds, as in the config file. */
+fn serialize_secs<S: Serializer>(
+    value: &Option<Duration>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    value
+        .map(|duration| duration.as_secs())
+        .serialize(serializer)
+}
+
+/** Function which serializes an optional Duration as a number of milliseconds, as in the config file. */
+fn serialize_millis<S: Serializer>(
+    value: &Option<Duration>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    value
+        .map(|duration| duration.as_millis() as u64)
+        .serialize(serializer)
+}
+
+/** Function which deserializes an optional number of milliseconds from the config file as a
+* Duration, where 0 is the same as no value.
+*/
+fn deserialize_millis<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Duration>, D::Error> {
+    Ok(Option::<u64>::deserialize(deserializer)?
+        .filter(|&millis| millis > 0)
+        .map(Duration::from_millis))
+}
+
+/** Function which deserializes an optional number of seconds from the config file as a Duration. */
+fn deserialize_secs<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Duration>, D::Error> {
+    Ok(Option::<u64>::deserialize(deserializer)?.map(Duration::from_secs))
+}
+
+/** Function which parses the value following a flag, returning a descriptive error if
+* the value is missing or malformed.
+*/
+fn parse_value<T: FromStr>(flag: &str, value: Option<String>) -> Result<T, String> {
+    let value = value.ok_or_else(|| format!("missing value for {}", flag))?;
+    value
+        .parse()
+        .map_err(|_| format!("invalid value for {}: {}", flag, value))
+}
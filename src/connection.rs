@@ -0,0 +1,872 @@
+use crate::audit::AuditLog;
+use crate::caps::Cap;
+use crate::commands::split_tag;
+use crate::config::ServerConfig;
+use crate::events::{Event, EventKind};
+use crate::filter::{ConnectDecision, ConnectionFilter};
+use crate::handshake::{Handshake, read_handshake};
+use crate::hooks::{HookAction, MessageHook};
+use crate::jsonlog::{JsonLog, Level};
+use crate::message::Message;
+use crate::ratelimit::TokenBucket;
+use crate::reader::{LineReader, ReadLine};
+use crate::reliable::Unacked;
+use crate::routing::{broadcast, retransmit};
+use crate::signing::Unsigned;
+use crate::tarpit::Tarpit;
+use crate::transport::Transport;
+use crate::writer::{Stopped, spawn_writer};
+use crate::{Connection, Connections, DEFAULT_ROOM, SEEN_DEPARTED, describe, encode_for, text};
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::io::DuplexStream;
+use tokio::net::TcpListener;
+use tokio::sync::{Mutex, OwnedSemaphorePermit, Semaphore, TryAcquireError, mpsc, oneshot};
+use tokio::time::{Instant, sleep_until};
+
+// maximum number of reactions per second each client may send
+const REACTION_RATE: u32 = 5;
+// maximum number of clients removed while holding the connections lock once
+const REMOVAL_BATCH: usize = 256;
+// maximum number of buffered lines a connection handles in a row before yielding to other connections
+const READ_AHEAD_LINES: usize = 64;
+
+/** State shared by the accept loops of every listen address, along with everything they
+* hand to the connection tasks they spawn.
+*/
+pub struct AcceptState {
+    pub config: Arc<ServerConfig>,
+    pub connections: Arc<Mutex<Connections>>,
+    pub inflight: Arc<Semaphore>,
+    pub hook: Option<Arc<dyn MessageHook>>,
+    pub connection_filter: Arc<dyn ConnectionFilter>,
+    pub audit: Option<AuditLog>,
+    pub json_log: Option<JsonLog>,
+    pub tarpit: Option<Arc<Tarpit>>,
+    // global limit on the rate at which new connections are accepted, shared by all listen addresses
+    pub accept_limit: Option<std::sync::Mutex<TokenBucket>>,
+    pub removals: mpsc::UnboundedSender<(u64, Removal)>,
+    // ID assigned to the next client, so IDs are unique across all listen addresses
+    pub next_id: AtomicU64,
+    // one permit for each client the server may hold, None when the number of clients is unlimited
+    pub slots: Option<Arc<Semaphore>>,
+}
+
+impl AcceptState {
+    /** Function which assigns a newly accepted client its ID, used by every accept loop. IDs come from
+     * the shared counter alone, never from the client's address, so clients whose address has no meaningful
+     * port (such as in-memory clients, which have no address at all) still get unique IDs, and none is 0.
+     */
+    fn assign_id(&self) -> u64 {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /** Function which takes a place under the client limit for a newly accepted client, failing if the server
+     * already holds the maximum number of clients. The place is held from the moment the connection is
+     * accepted, through the handshake, until the client's record is removed (or its connection ends before
+     * it is logged in), so clients which are still logging in are counted as well.
+     */
+    fn take_slot(&self) -> Result<Option<OwnedSemaphorePermit>, TryAcquireError> {
+        self.slots
+            .as_ref()
+            .map(|slots| Arc::clone(slots).try_acquire_owned())
+            .transpose()
+    }
+}
+
+/** Function which accepts connections on the given listener until accepting fails, validating
+* each connection and spawning a connection task for it. A listener received from rebound replaces
+* the current one.
+*/
+pub async fn accept_loop(
+    state: Arc<AcceptState>,
+    mut listener: TcpListener,
+    mut rebound: mpsc::UnboundedReceiver<TcpListener>,
+) -> std::io::Result<Infallible> {
+    let config = &state.config;
+    log_println!("listening on port {}", listener.local_addr()?.port());
+    // loop to continually accept/connect to clients connecting to the listen address
+    loop {
+        // wait until a socket connects to port
+        let (mut stream, socket_addr) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    if let Some(log) = &state.json_log {
+                        log.log(Level::Error, "accept_error", None, None, Some(&e.to_string()));
+                    }
+                    return Err(e);
+                }
+            },
+            // swap to the new listener, dropping the old one stops accepting on the old address
+            // while connected clients (and all shared state) are unaffected
+            Some(new_listener) = rebound.recv() => {
+                listener = new_listener;
+                log_println!("listening on {}", listener.local_addr()?);
+                continue;
+            }
+        };
+        // identify the server with the configured banner before anything else is written
+        if let Some(banner) = &config.banner
+            && stream
+                .write_all(format!("{}\n", banner).as_bytes())
+                .await
+                .is_err()
+        {
+            continue;
+        }
+        // reject the connection if new connections are arriving faster than the accept rate allows
+        if let Some(bucket) = &state.accept_limit
+            && !bucket.lock().unwrap().try_take()
+        {
+            if let Some(tarpit) = &state.tarpit {
+                tarpit.strike(socket_addr.ip());
+            }
+            reject(stream, socket_addr, Rejection::SlowDown, &state).await;
+            continue;
+        }
+        // give the connection filter the chance to veto the connection before the client is logged in
+        if let ConnectDecision::Reject(reason) =
+            state.connection_filter.on_connect(socket_addr).await
+        {
+            reject(stream, socket_addr, Rejection::Filtered(reason), &state).await;
+            continue;
+        }
+        // reject the connection if the server already holds the maximum number of clients
+        let Ok(slot) = state.take_slot() else {
+            reject(stream, socket_addr, Rejection::ServerFull, &state).await;
+            continue;
+        };
+        // assign the client the next client id
+        let c_id = state.assign_id();
+        let identity = config.identity_format.render(c_id, None, Some(socket_addr));
+        log_println!("connected {} {}", socket_addr.ip(), identity);
+        if let Some(audit) = &state.audit {
+            audit.record(socket_addr, None);
+        }
+        if let Some(log) = &state.json_log {
+            log.log(Level::Info, "connect", Some(c_id), Some(socket_addr), None);
+        }
+        // a flagged address has its connection held up, in the connection's own task so other clients are not delayed
+        let delay = state
+            .tarpit
+            .as_ref()
+            .and_then(|tarpit| tarpit.delay_for(socket_addr.ip()));
+        spawn_connection(&state, stream, c_id, slot, delay);
+    }
+}
+
+/** Function which serves each in-memory stream received as a client, until the embedder drops the
+* sending end. In-memory clients have no address, so the checks made on network clients (the accept
+* rate, the connection filter, the audit log and the tarpit) do not apply to them, though they are
+* still sent the banner and held to the client limit.
+*/
+pub async fn accept_in_memory(
+    state: Arc<AcceptState>,
+    mut streams: mpsc::UnboundedReceiver<DuplexStream>,
+) {
+    while let Some(mut stream) = streams.recv().await {
+        if let Some(banner) = &state.config.banner
+            && stream
+                .write_all(format!("{}\n", banner).as_bytes())
+                .await
+                .is_err()
+        {
+            continue;
+        }
+        let Ok(slot) = state.take_slot() else {
+            let line = Rejection::ServerFull.message();
+            let _ = stream.write_all(line.as_bytes()).await;
+            continue;
+        };
+        let c_id = state.assign_id();
+        let identity = state.config.identity_format.render(c_id, None, None);
+        log_println!("connected in-memory {}", identity);
+        spawn_connection(&state, stream, c_id, slot, None);
+    }
+}
+
+/** Function which spawns the connection task serving the client with the given c_id, after the given delay (if any),
+* holding the client's place under the client limit (if any).
+*/
+fn spawn_connection(
+    state: &AcceptState,
+    stream: impl Transport,
+    c_id: u64,
+    slot: Option<OwnedSemaphorePermit>,
+    delay: Option<Duration>,
+) {
+    // clone connections via Arc so you are free to pass it in as an argument to handle_connection
+    let connections = Arc::clone(&state.connections);
+    let inflight = Arc::clone(&state.inflight);
+    let hook = state.hook.clone();
+    let config = Arc::clone(&state.config);
+    let removals = state.removals.clone();
+    // spawn a thread to asynchronously manage this socket/connection
+    tokio::spawn(async move {
+        if let Some(delay) = delay {
+            tokio::time::sleep(delay).await;
+        }
+        handle_connection(
+            stream,
+            connections,
+            inflight,
+            hook,
+            config,
+            removals,
+            c_id,
+            slot,
+        )
+        .await;
+    });
+}
+
+/** Reasons for which the accept loop can refuse a connection. Each reason carries a
+* suggested delay before retrying, so well-behaved clients know how long to back off.
+*/
+enum Rejection {
+    ServerFull,
+    SlowDown,
+    // rejected by the connection filter, with the reason it gave
+    Filtered(String),
+}
+
+impl Rejection {
+    // Function which returns the number of seconds a rejected client should wait before reconnecting.
+    fn retry_after_secs(&self) -> u64 {
+        match self {
+            Rejection::ServerFull => 5,
+            Rejection::SlowDown => 1,
+            Rejection::Filtered(_) => 60,
+        }
+    }
+
+    // Function which returns the error code the client is rejected with.
+    fn code(&self) -> &str {
+        match self {
+            Rejection::ServerFull => "SERVER_FULL",
+            Rejection::SlowDown => "SLOW_DOWN",
+            Rejection::Filtered(reason) => reason,
+        }
+    }
+
+    // Function which returns the error line sent to the rejected client, including the retry hint.
+    fn message(&self) -> String {
+        format!("ERR:{} retry={}\n", self.code(), self.retry_after_secs())
+    }
+}
+
+/** Reasons for which a connected client is removed. The reason is given to the other
+* clients in the removed client's room, and some reasons are also explained to the
+* removed client itself before its socket is closed.
+*/
+#[derive(Clone, Copy)]
+pub enum Removal {
+    // the client closed its connection (or it failed)
+    Quit,
+    IdleTimeout,
+    TooLong,
+    FrameTimeout,
+    // the client was skipped on too many consecutive broadcasts
+    Lagging,
+    // the client stopped reading, so writes to it made no progress for the stall timeout
+    Stalled,
+    // the client was disconnected by an admin's /kick
+    Kicked,
+    // every client was disconnected by /kickall
+    Reset,
+    // every client was disconnected as the server shuts down
+    Shutdown,
+    // the client sent a malformed command in strict mode, after being sent the error for it
+    ProtocolViolation,
+    // the client sent a line which is not valid UTF-8 in strict mode
+    InvalidUtf8,
+}
+
+impl Removal {
+    // Function which returns the reason given in the LEAVE line sent to the rest of the room.
+    fn reason(&self) -> &'static str {
+        match self {
+            Removal::Quit => "quit",
+            Removal::IdleTimeout => "idle_timeout",
+            Removal::TooLong => "too_long",
+            Removal::FrameTimeout => "frame_timeout",
+            Removal::Lagging => "lagging",
+            Removal::Stalled => "stalled",
+            Removal::Kicked => "kicked",
+            Removal::Reset => "reset",
+            Removal::Shutdown => "shutdown",
+            Removal::ProtocolViolation => "protocol_violation",
+            Removal::InvalidUtf8 => "invalid_utf8",
+        }
+    }
+
+    // Function which returns the notice sent to the removed client, if it is told why it was removed.
+    fn notice(&self) -> Option<&'static str> {
+        match self {
+            // a stalled client is not reading, so there is no point in telling it
+            Removal::Quit | Removal::ProtocolViolation | Removal::Stalled => None,
+            Removal::InvalidUtf8 => Some("ERR:INVALID_UTF8\n"),
+            Removal::IdleTimeout => Some("ERR:IDLE_TIMEOUT\n"),
+            Removal::TooLong => Some("ERR:TOO_LONG\n"),
+            Removal::FrameTimeout => Some("ERR:FRAME_TIMEOUT\n"),
+            Removal::Lagging => Some("ERR:TOO_SLOW\n"),
+            Removal::Kicked => Some("SERVER:KICKED\n"),
+            Removal::Reset => Some("SERVER:RESET\n"),
+            Removal::Shutdown => Some("SERVER:SHUTDOWN\n"),
+        }
+    }
+}
+
+/** Function which writes the rejection message to a refused connection before
+* dropping (and so closing) its stream, recording the rejection in the audit log and JSON log (if any).
+*/
+async fn reject(
+    mut stream: tokio::net::TcpStream,
+    socket_addr: std::net::SocketAddr,
+    reason: Rejection,
+    state: &AcceptState,
+) {
+    if let Some(audit) = &state.audit {
+        audit.record(socket_addr, Some(reason.code()));
+    }
+    if let Some(log) = &state.json_log {
+        log.log(
+            Level::Warn,
+            "reject",
+            None,
+            Some(socket_addr),
+            Some(reason.code()),
+        );
+    }
+    let message = reason.message();
+    log_println!("rejected {} {}", socket_addr.ip(), message.trim_end());
+    if let Err(e) = stream.write_all(message.as_bytes()).await {
+        log_eprintln!("Failed to send rejection to {}: {}", socket_addr, e);
+    }
+}
+
+/**
+* Function which reads in messages from each client. When a message is recieved
+* an acknowledgement is sent to the client which sent the message, and the message
+* (as well as the client_id of the sender) is sent to all currently connected clients.
+* Each message is only processed once a permit is acquired from the shared inflight semaphore,
+* and is passed through the message hook (if any), which may transform or drop it.
+* A client sending a line longer than max_line_bytes is sent `ERR:TOO_LONG` and disconnected, as is
+* a client taking longer than the frame timeout to finish a line, with `ERR:FRAME_TIMEOUT`.
+* When an idle timeout is configured, a client which sends nothing for that long is first warned
+* with `WARN:IDLE {seconds_left}` (if a warning lead time is configured) and then disconnected.
+* Once the client is to be disconnected, its removal is handed to the remover task.
+*/
+// each argument is something the connection task keeps for its whole life, handed over by spawn_connection
+#[allow(clippy::too_many_arguments)]
+async fn handle_connection(
+    stream: impl Transport,
+    connections: Arc<Mutex<Connections>>,
+    inflight: Arc<Semaphore>,
+    hook: Option<Arc<dyn MessageHook>>,
+    config: Arc<ServerConfig>,
+    removals: mpsc::UnboundedSender<(u64, Removal)>,
+    c_id: u64,
+    slot: Option<OwnedSemaphorePermit>,
+) {
+    // the client's address is kept in its record for logging
+    let addr = stream.peer_addr();
+    //split the stream into its read half and write half
+    let (read, mut write) = stream.into_split();
+    // create reader from which to read in messages (as lines) from the client
+    let mut read = LineReader::new(read, config.max_line_bytes, config.frame_timeout);
+    // commands (such as long announcements) can be given a larger limit than chat messages
+    if let Some(prefix) = config.command_prefix.as_deref()
+        && config.max_command_bytes.is_some()
+    {
+        read = read.with_command_limit(prefix, config.command_line_limit());
+    }
+    // when the handshake is enabled, the client chooses its settings before it is logged in
+    let mut handshake = if config.handshake {
+        let handshake =
+            read_handshake(&mut read, &mut write, config.legacy_fallback, config.strict);
+        // a client which does not complete the handshake in time is dropped, so it cannot hold a connection open
+        let handshake = match config.handshake_timeout {
+            Some(limit) => match tokio::time::timeout(limit, handshake).await {
+                Ok(handshake) => handshake,
+                Err(_) => {
+                    let _ = write.write_all(b"ERR:HANDSHAKE_TIMEOUT\n").await;
+                    let identity = config.identity_format.render(c_id, None, addr);
+                    log_println!("Client {} handshake timed out", identity);
+                    return;
+                }
+            },
+            None => handshake.await,
+        };
+        match handshake {
+            Some(handshake) => handshake,
+            None => {
+                let identity = config.identity_format.render(c_id, None, addr);
+                log_println!("Client {} exited", identity);
+                return;
+            }
+        }
+    } else {
+        Handshake::default()
+    };
+    // when enabled, the login acknowledgement also tells the client which server it is talking to
+    let login_line = if config.login_info {
+        let uptime = connections.lock().await.started.elapsed().as_secs();
+        format!(
+            "LOGIN:{} version={} uptime={}",
+            c_id,
+            env!("CARGO_PKG_VERSION"),
+            uptime
+        )
+    } else {
+        format!("LOGIN:{}", c_id)
+    };
+    // write login_msg to the client, in the format it chose
+    let Some(login_msg) = encode_for(
+        &Message::reply(&login_line),
+        c_id,
+        handshake.format,
+        config.newline_policy,
+    ) else {
+        return;
+    };
+    if write.write_all(login_msg.as_bytes()).await.is_err() {
+        let identity = config.identity_format.render(c_id, None, addr);
+        log_println!("Client {} exited", identity);
+        return;
+    }
+    // create the cancellation signal for this connection, the sending end is kept in the connection record
+    let (cancel, mut cancelled) = oneshot::channel();
+    //acquire lock for connections, insert this client into the default room and replay that room's history,
+    //keeping the signal that the client's writer task has ended, and the messages a reliable client has yet to acknowledge
+    let (mut writer_finished, unacked) = {
+        let mut connections = connections.lock().await;
+        let (outbox, finished) = spawn_writer(
+            c_id,
+            write,
+            Arc::clone(&config),
+            connections.writers.clone(),
+            Arc::clone(&connections.backlog),
+        );
+        // a reliable client has the chat messages it does not acknowledge in time resent by a task of its own
+        let unacked = handshake.caps.contains(Cap::Reliable).then(|| {
+            let unacked = Arc::new(Unacked::new(
+                Duration::from_millis(config.ack_timeout_ms),
+                config.ack_retries,
+            ));
+            tokio::spawn(retransmit(c_id, outbox.feed(), Arc::clone(&unacked)));
+            unacked
+        });
+        connections.cons.insert(
+            c_id,
+            Connection {
+                outbox,
+                room: DEFAULT_ROOM.to_string(),
+                rooms: vec![DEFAULT_ROOM.to_string()],
+                is_admin: false,
+                read_only: false,
+                nick: None,
+                format: handshake.format,
+                cancel,
+                reactions: TokenBucket::new(REACTION_RATE),
+                lag_count: 0,
+                caps: handshake.caps,
+                addr,
+                replay_own: handshake.replay_own,
+                recall: VecDeque::new(),
+                violated: AtomicBool::new(false),
+                subscribed: false,
+                inline_events: false,
+                dnd: false,
+                unacked: unacked.clone(),
+                replay: None,
+                reply_tag: None,
+                _slot: slot,
+            },
+        );
+        let count = connections.cons.len();
+        connections.occupancy.send_replace(Some(count));
+        connections
+            .events
+            .publish(Event::new(EventKind::Connect, c_id, None, None));
+        // a nickname chosen during the handshake is set before history is replayed, so a reconnecting
+        // client is recognised as the sender of its own messages, a refused nickname is reported but not fatal
+        if let Some(nick) = &handshake.nick {
+            let reply = connections.set_nick(c_id, nick);
+            connections.send_to_client(c_id, &reply);
+        }
+        // the arrival is announced once the handshake's nickname is set, so it names the client the way its room will see it
+        let text = format!("{} connected", connections.describe(c_id));
+        connections.send_presence(&[DEFAULT_ROOM], c_id, None, &text);
+        connections.replay_history(c_id);
+        connections.send_topic(c_id, DEFAULT_ROOM);
+        (finished, unacked)
+    };
+    // time at which the client last sent a line, and whether it has been warned about being idle since
+    let mut last_activity = Instant::now();
+    let mut warned = false;
+    // whether the client's writer task has stopped, after which the signal that it has is not awaited again
+    let mut writer_stopped = false;
+    // the first line of a legacy client which skipped the handshake is handled before anything else it sends
+    let mut first_line = handshake.first_line.take();
+    // number of lines handled in a row straight from the read buffer, without waiting for the client
+    let mut read_ahead = 0;
+    // lines from an address flagged by the tarpit are held up before they are handled, and chat messages have
+    // their signatures checked, when a key is configured
+    let (tarpit, signer) = {
+        let connections = connections.lock().await;
+        (connections.tarpit.clone(), connections.signer.clone())
+    };
+    // loop to continually read next line/message sent from this client
+    loop {
+        // the idle deadlines only apply when an idle timeout (and warning) is configured
+        let idle_at = config.idle_timeout.map(|timeout| last_activity + timeout);
+        let warn_at = idle_at
+            .zip(config.idle_warning)
+            .map(|(idle_at, warning)| idle_at - warning)
+            .filter(|_| !warned);
+        // wait until next line/message sent by client, and store message in this variable. Lines the client has
+        // already sent (ex. when pipelining) are taken straight from the read buffer, up to READ_AHEAD_LINES in a row,
+        // after which the connection yields so a bursty client cannot starve the others
+        let buffered = match first_line {
+            None if read_ahead < READ_AHEAD_LINES => read.buffered_line(),
+            _ => None,
+        };
+        let line_result = match (first_line.take(), buffered) {
+            (Some(line), _) => Ok(ReadLine::Line(line)),
+            (None, Some(line_result)) => {
+                read_ahead += 1;
+                line_result
+            }
+            (None, None) => {
+                if read_ahead == READ_AHEAD_LINES {
+                    tokio::task::yield_now().await;
+                }
+                read_ahead = 0;
+                tokio::select! {
+                line_result = read.next_line() => line_result,
+                // the connection was cancelled (or its record dropped), which has already removed this client from connections
+                _ = &mut cancelled => break,
+                // warn the client that it will soon be disconnected, reading resumes where it left off
+                // (a disabled branch's fallback deadline is never awaited)
+                _ = sleep_until(warn_at.unwrap_or(last_activity)), if warn_at.is_some() => {
+                    let seconds_left = config.idle_warning.unwrap_or_default().as_secs();
+                    let warning = format!("WARN:IDLE {}\n", seconds_left);
+                    connections.lock().await.send_notice(c_id, &warning);
+                    warned = true;
+                    continue;
+                }
+                // the client has been idle for the full timeout, tell it and disconnect it
+                _ = sleep_until(idle_at.unwrap_or(last_activity)), if idle_at.is_some() => {
+                    let _ = removals.send((c_id, Removal::IdleTimeout));
+                    break;
+                }
+                // a client which stopped reading is reaped as stalled, while a writer which stopped for any other
+                // reason leaves the client to be removed once its reads end as well
+                stopped = &mut writer_finished, if !writer_stopped => {
+                    writer_stopped = true;
+                    if let Ok(Stopped::Stalled) = stopped {
+                        let identity = connections.lock().await.identity(c_id);
+                        log_println!("Client {} removed for not reading", identity);
+                        let _ = removals.send((c_id, Removal::Stalled));
+                        break;
+                    }
+                    continue;
+                }
+                }
+            }
+        };
+        // any line from the client counts as activity, restarting both idle timers
+        last_activity = Instant::now();
+        warned = false;
+        match line_result {
+            //Got a new line/message
+            Ok(ReadLine::Line(line)) => {
+                // the address is checked for every line, as it can be flagged while the client is connected
+                if let Some(delay) = tarpit
+                    .as_ref()
+                    .zip(addr)
+                    .and_then(|(tarpit, addr)| tarpit.delay_for(addr.ip()))
+                {
+                    tokio::time::sleep(delay).await;
+                }
+                // a reliable client's acknowledgement of a chat message stops it being resent, and is not itself a message
+                if let Some(unacked) = &unacked
+                    && let Some(seq) = line.strip_prefix("ACK:RECV ")
+                {
+                    match seq.trim().parse::<u64>() {
+                        // acknowledging a message twice (ex. once it was resent) is harmless
+                        Ok(seq) => {
+                            unacked.ack(seq);
+                        }
+                        Err(_) => {
+                            let connections = connections.lock().await;
+                            connections.send_to_client(c_id, "ERR:BAD_ACK\n");
+                            if connections.violated(c_id) {
+                                let _ = removals.send((c_id, Removal::ProtocolViolation));
+                                break;
+                            }
+                        }
+                    }
+                    continue;
+                }
+                // wait for a processing permit before handling the line, the permit is released when it is dropped at the end of this iteration
+                // (the semaphore is never closed, so acquiring cannot fail)
+                let _permit = inflight.acquire().await.unwrap();
+                // a command may carry a tag (ex. `[7]/nick alice`), which is echoed in the replies to it
+                let (tag, line) = match config
+                    .command_prefix
+                    .as_deref()
+                    .and_then(|prefix| split_tag(&line, prefix))
+                {
+                    Some((tag, command)) => (Some(tag.to_string()), command.to_string()),
+                    None => (None, line),
+                };
+                //lines starting with the command prefix are commands and are handled (and answered) without being broadcast
+                let line = match config.command_prefix.as_deref() {
+                    // a doubled prefix escapes it, the rest of the line (starting with a single prefix) is a chat message
+                    Some(prefix) if line.starts_with(&prefix.repeat(2)) => {
+                        line[prefix.len()..].to_string()
+                    }
+                    Some(prefix) if line.starts_with(prefix) => {
+                        let command = &line[prefix.len()..];
+                        let mut connections = connections.lock().await;
+                        let tagged = tag.is_some();
+                        connections.set_reply_tag(c_id, tag);
+                        connections.handle_command(c_id, command).await;
+                        if tagged {
+                            connections.set_reply_tag(c_id, None);
+                        }
+                        // in strict mode a malformed command disconnects the client, once it has been told what was wrong
+                        if connections.violated(c_id) {
+                            let _ = removals.send((c_id, Removal::ProtocolViolation));
+                            break;
+                        }
+                        continue;
+                    }
+                    _ => line,
+                };
+                // a signed message is broadcast without its signature (the server signs what it broadcasts), while one
+                // with a signature which does not match is refused, as is an unsigned one when signatures are required
+                let line = match signer.as_ref().map(|signer| signer.verify(&line)) {
+                    Some(Ok(text)) => text.to_string(),
+                    Some(Err(Unsigned::Missing)) if !config.require_signatures => line,
+                    Some(Err(_)) => {
+                        let connections = connections.lock().await;
+                        connections.send_to_client(c_id, "ERR:BAD_SIGNATURE\n");
+                        if connections.violated(c_id) {
+                            let _ = removals.send((c_id, Removal::ProtocolViolation));
+                            break;
+                        }
+                        continue;
+                    }
+                    None => line,
+                };
+                //run the message through the hook before acquiring the lock, skipping it if the hook drops it
+                let line = match hook.as_ref().map(|hook| hook.on_message(c_id, &line)) {
+                    Some(HookAction::Drop) => continue,
+                    // a transformed message may be longer than any line a client could send, so it is cut to the line limit
+                    Some(HookAction::Pass(mut transformed)) => {
+                        if let Some(max) = config.max_line_bytes {
+                            text::truncate_to_char_boundary(&mut transformed, max);
+                        }
+                        transformed
+                    }
+                    None => line,
+                };
+                //if (and only if) the message is not None, broadcast it to all other clients and send sender an acknowledgement
+                //broadcast function handles both broadcasting message to all others and sending acknowldegemnt to sender,
+                //taking the connections lock itself so it is not held while waiting on the recipients (the message is
+                //logged once the lock is held, so its sender can be labelled by its nickname)
+                let unencodable = broadcast(&connections, c_id, line).await;
+                if !unencodable.is_empty() {
+                    log_eprintln!(
+                        "Message from client_id {} skipped client_ids {:?}, which it could not be encoded for",
+                        c_id,
+                        unencodable
+                    );
+                }
+            }
+            //Got a line exceeding the maximum length, tell the client and disconnect it
+            Ok(ReadLine::TooLong) => {
+                let _ = removals.send((c_id, Removal::TooLong));
+                break;
+            }
+            //Got a line which did not arrive within the frame timeout, tell the client and disconnect it
+            Ok(ReadLine::TimedOut) => {
+                let _ = removals.send((c_id, Removal::FrameTimeout));
+                break;
+            }
+            //Got end of stream while half-close is allowed, the client may only have closed its sending side, so it keeps
+            //receiving until its writer task ends (e.g. the client closed the connection entirely), it is removed, or the time runs out
+            Ok(ReadLine::Eof) if config.half_close.is_some() => {
+                let limit = config.half_close.unwrap_or_default();
+                tokio::select! {
+                    _ = &mut cancelled => {}
+                    stopped = &mut writer_finished, if !writer_stopped => {
+                        let removal = match stopped {
+                            Ok(Stopped::Stalled) => Removal::Stalled,
+                            _ => Removal::Quit,
+                        };
+                        let _ = removals.send((c_id, removal));
+                    }
+                    // the writer has already stopped, so there is nothing left to receive
+                    _ = std::future::ready(()), if writer_stopped => {
+                        let _ = removals.send((c_id, Removal::Quit));
+                    }
+                    _ = tokio::time::sleep(limit) => {
+                        let _ = removals.send((c_id, Removal::Quit));
+                    }
+                }
+                break;
+            }
+            //Got end of stream or got an error (e.g. client disconnected) remover client from map of connections
+            Ok(ReadLine::Eof) | Err(_) => {
+                if let (Err(e), Some(log)) = (&line_result, &connections.lock().await.json_log) {
+                    log.log(
+                        Level::Error,
+                        "read_error",
+                        Some(c_id),
+                        addr,
+                        Some(&e.to_string()),
+                    );
+                }
+                //hand the removal of the current client from connections to the remover task
+                let removal = match &line_result {
+                    Err(e) if config.strict && e.kind() == std::io::ErrorKind::InvalidData => {
+                        Removal::InvalidUtf8
+                    }
+                    _ => Removal::Quit,
+                };
+                let _ = removals.send((c_id, removal));
+                //break from loop to stop awating messages from disconnected client
+                break;
+            }
+        }
+    }
+}
+
+/** Function run by the remover task, which removes the clients whose connection tasks have ended.
+* It takes the connections lock once for every batch of waiting removals (of up to REMOVAL_BATCH),
+* rather than once per client, so that many clients disconnecting at once do not all contend for the lock.
+*/
+pub async fn run_remover(
+    connections: Arc<Mutex<Connections>>,
+    mut removals: mpsc::UnboundedReceiver<(u64, Removal)>,
+) {
+    let mut batch = Vec::with_capacity(REMOVAL_BATCH);
+    while removals.recv_many(&mut batch, REMOVAL_BATCH).await > 0 {
+        let mut connections = connections.lock().await;
+        for (c_id, removal) in batch.drain(..) {
+            connections.remove_client(c_id, removal);
+        }
+    }
+}
+
+/** Implementation of the connection lifecycle for the Connections struct.
+* Implements the functions removing clients, one at a time or all at once.
+*/
+impl Connections {
+    /** Function which disconnects every client (including the admin issuing the command),
+     * notifying each with `SERVER:RESET` before closing its socket. The connection map (and so
+     * all room memberships) is cleared, while the server itself keeps accepting new clients.
+     */
+    pub fn kick_all(&mut self) {
+        let c_ids: Vec<u64> = self.cons.keys().copied().collect();
+        for c_id in c_ids {
+            self.remove_client(c_id, Removal::Reset);
+        }
+        log_println!("all clients kicked");
+    }
+
+    /** Function which disconnects every client as the server shuts down, telling each with
+     * `SERVER:SHUTDOWN`, and releases the server's handle on the writer tasks so it can wait for them
+     * to write out their remaining lines. Returns the number of clients told.
+     */
+    pub fn shut_down(&mut self) -> usize {
+        let c_ids: Vec<u64> = self.cons.keys().copied().collect();
+        let mut notified = 0;
+        for c_id in c_ids {
+            if self.remove_client(c_id, Removal::Shutdown) {
+                notified += 1;
+            }
+        }
+        self.writers = None;
+        notified
+    }
+
+    /** Function which removes the client with the given c_id for the given reason, used by every
+     * removal site so that cleanup is always complete. The notice for the reason (if any) is queued ahead
+     * of any other waiting lines, then the record is removed, which frees the client's nickname and room
+     * membership, and the rest of the client's room is sent `LEAVE:{c_id} {reason}`. Dropping the record's
+     * Outbox makes the writer task write out everything still queued, flush and shut down the socket, so
+     * the last lines reliably reach the client. The client's read task is also cancelled, making it stop
+     * promptly. Returns whether the client was connected.
+     */
+    pub fn remove_client(&mut self, c_id: u64, removal: Removal) -> bool {
+        if let Some(notice) = removal.notice() {
+            self.send_notice(c_id, notice);
+        }
+        let Some(con) = self.cons.remove(&c_id) else {
+            return false;
+        };
+        self.occupancy.send_replace(Some(self.cons.len()));
+        // broadcasts already fanning out (without the connections lock) skip the client from here on
+        con.outbox.mark_removing();
+        let _ = con.cancel.send(());
+        // a client disconnected by /kick or /kickall was kicked, any other removal is a disconnect
+        let kind = match removal {
+            Removal::Kicked | Removal::Reset => EventKind::Kick,
+            _ => EventKind::Disconnect,
+        };
+        self.events
+            .publish(Event::new(kind, c_id, None, Some(removal.reason())));
+        if let Some(nick) = &con.nick {
+            self.nicks.remove(nick);
+        }
+        // only the most recently departed clients are remembered by /seen, so departures never pile up
+        if self.last_activity.contains_key(&c_id) {
+            self.departed.push_back(c_id);
+            if self.departed.len() > SEEN_DEPARTED
+                && let Some(forgotten) = self.departed.pop_front()
+            {
+                self.last_activity.remove(&forgotten);
+            }
+        }
+        self.prune_private_rooms();
+        if let Some(log) = &self.json_log {
+            log.log(
+                Level::Info,
+                "disconnect",
+                Some(c_id),
+                con.addr,
+                Some(removal.reason()),
+            );
+        }
+        // every client is removed by a reset or shutdown, so there is nobody left to tell
+        if !matches!(removal, Removal::Reset | Removal::Shutdown) {
+            let leave = Message::Leave {
+                from: c_id,
+                sender: self
+                    .config
+                    .identity_format
+                    .render(c_id, con.nick.as_deref(), con.addr),
+                reason: removal.reason().to_string(),
+            };
+            let text = format!(
+                "{} disconnected ({})",
+                describe(c_id, con.nick.as_deref()),
+                removal.reason()
+            );
+            // the departure is seen in every room the client was in
+            let rooms: Vec<&str> = con.rooms.iter().map(String::as_str).collect();
+            self.send_presence(&rooms, c_id, Some(&leave), &text);
+        }
+        true
+    }
+}
@@ -0,0 +1,86 @@
+use serde::Serialize;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+
+// number of events kept for subscribers which have not yet forwarded them, a subscriber falling further behind misses the oldest
+const EVENT_CAPACITY: usize = 256;
+
+/** Kind of lifecycle event: a client logging in, disconnecting, being kicked (by `/kickall`),
+* joining a room, or leaving one.
+*/
+#[derive(Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    Connect,
+    Disconnect,
+    Kick,
+    Join,
+    Part,
+}
+
+/** A single lifecycle event, sent to subscribers as `EVENT:{JSON}`. Like the JSON log, every event
+* has the same fields, those which do not apply to the event being null.
+*/
+#[derive(Clone, Serialize)]
+pub struct Event {
+    // seconds since the Unix epoch
+    ts: u64,
+    event: EventKind,
+    client_id: u64,
+    // room joined or left
+    room: Option<String>,
+    // reason for a disconnect or kick, as in the LEAVE line
+    reason: Option<&'static str>,
+}
+
+impl Event {
+    // Constructor. Create an event of the given kind concerning the given client, timestamped now.
+    pub fn new(
+        event: EventKind,
+        client_id: u64,
+        room: Option<&str>,
+        reason: Option<&'static str>,
+    ) -> Self {
+        Event {
+            ts: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |elapsed| elapsed.as_secs()),
+            event,
+            client_id,
+            room: room.map(str::to_string),
+            reason,
+        }
+    }
+
+    // Function which returns the line sent to subscribers for the event.
+    pub fn line(&self) -> String {
+        // serializing plain integers and strings cannot fail
+        format!("EVENT:{}\n", serde_json::to_string(self).unwrap())
+    }
+}
+
+/** Server-wide bus lifecycle events are published to, and which each subscribed client's
+* forwarding task receives them from. Publishing never waits, and events published while nobody
+* is subscribed are discarded.
+*/
+pub struct EventBus {
+    sender: broadcast::Sender<Event>,
+}
+
+impl EventBus {
+    // Constructor. Create a bus with no subscribers.
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(EVENT_CAPACITY);
+        EventBus { sender }
+    }
+
+    // Function which publishes an event to every subscriber.
+    pub fn publish(&self, event: Event) {
+        let _ = self.sender.send(event);
+    }
+
+    // Function which subscribes to the events published from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.sender.subscribe()
+    }
+}
@@ -0,0 +1,27 @@
+use futures::future::BoxFuture;
+use std::net::SocketAddr;
+
+/** Decision made by a ConnectionFilter about a new connection. A rejected connection is
+* sent `ERR:{reason}` (with a retry hint) and closed before it is logged in.
+*/
+pub enum ConnectDecision {
+    Accept,
+    Reject(String),
+}
+
+/** Trait for filters consulted by the accept loop on every new connection, before the
+* client is logged in, allowing embedders to log, veto or otherwise act on connections
+* (for example to implement ban lists or allowlists).
+*/
+pub trait ConnectionFilter: Send + Sync {
+    fn on_connect(&self, addr: SocketAddr) -> BoxFuture<'_, ConnectDecision>;
+}
+
+/** ConnectionFilter which accepts every connection, used when no filter is supplied. */
+pub struct AcceptAll;
+
+impl ConnectionFilter for AcceptAll {
+    fn on_connect(&self, _addr: SocketAddr) -> BoxFuture<'_, ConnectDecision> {
+        Box::pin(async { ConnectDecision::Accept })
+    }
+}
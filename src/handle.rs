@@ -0,0 +1,83 @@
+use crate::Connections;
+use crate::message::Message;
+use crate::routing::broadcast_except;
+use std::collections::HashSet;
+use std::sync::{Arc, OnceLock};
+use tokio::sync::Mutex;
+
+/** Handle through which an embedder reaches a running server, ex. to send lines to chosen clients.
+* It is created before the server runs and handed to it in Extensions::handle (keeping a clone), and
+* is attached to the server's connections once the server has started. Every function returns None
+* while no server is attached.
+*/
+#[derive(Clone, Default)]
+pub struct ServerHandle {
+    connections: Arc<OnceLock<Arc<Mutex<Connections>>>>,
+}
+
+/** Result of sending a line to a list of clients (or broadcasting it): the IDs of the clients it was
+* queued for, and of those it could not be queued (or encoded) for. Unknown clients, clients which do not
+* accept the line, and clients being removed, appear in neither.
+*/
+#[derive(Debug, Default, PartialEq)]
+pub struct SendReport {
+    pub delivered: Vec<u64>,
+    pub failed: Vec<u64>,
+}
+
+impl ServerHandle {
+    // Constructor. Create a handle which is not attached to any server yet.
+    pub fn new() -> Self {
+        ServerHandle::default()
+    }
+
+    // Function which attaches the handle to the connections of the server it was handed to, only the first server being kept.
+    pub(crate) fn attach(&self, connections: Arc<Mutex<Connections>>) {
+        let _ = self.connections.set(connections);
+    }
+
+    /** Function which sends the text to each of the listed clients, regardless of their room, as
+     * `ANNOUNCE:{TEXT}` (each encoded in the client's format), returning which clients it was queued for.
+     */
+    pub async fn send_to(&self, targets: &[u64], text: &str) -> Option<SendReport> {
+        let connections = self.connections.get()?.lock().await;
+        let message = Message::Announce {
+            text: text.to_string(),
+        };
+        let (delivered, failed) = connections.send_to(targets, message);
+        Some(SendReport { delivered, failed })
+    }
+
+    /** Function which broadcasts the text as a chat message from the client with the given ID to every
+     * other client in its room except those in the exclude set, as if the client had sent it (so it is
+     * numbered, kept in the room's history and subject to the same checks), but without acknowledging it
+     * to the client. Returns which clients it was queued for, or None if it was rejected (ex. the client is
+     * not connected, or is a spectator), in which case the client is told why as usual.
+     */
+    pub async fn broadcast_except(
+        &self,
+        from: u64,
+        exclude: &HashSet<u64>,
+        text: &str,
+    ) -> Option<SendReport> {
+        let connections = self.connections.get()?;
+        let (order, self_delivery) = {
+            let connections = connections.lock().await;
+            (
+                connections.order.clone(),
+                connections.config.allow_self_delivery,
+            )
+        };
+        // like any broadcast, the message waits its turn under strict ordering
+        let _ordered = match &order {
+            Some(order) => Some(order.lock().await),
+            None => None,
+        };
+        let mut exclude = exclude.clone();
+        if !self_delivery {
+            exclude.insert(from);
+        }
+        let delivery = broadcast_except(connections, from, &exclude, text.to_string()).await?;
+        Some(delivery.report())
+    }
+}
@@ -0,0 +1,129 @@
+use crate::caps::Caps;
+use crate::message::Format;
+use crate::reader::{LineReader, ReadLine};
+use crate::transport::WriteHalf;
+use tokio::io::AsyncWriteExt;
+use tokio::time::Duration;
+
+/** Settings negotiated by a client during the handshake. The default settings are used
+* for every client when the handshake is disabled.
+*/
+pub struct Handshake {
+    pub format: Format,
+    // capabilities the client understands, every capability unless it declares its own
+    pub caps: Caps,
+    // nickname the client asks to log in with, so it is known under it from the start
+    pub nick: Option<String>,
+    // whether the client's own earlier messages are included when history is replayed to it
+    pub replay_own: bool,
+    // first line of a legacy client which skipped the handshake, handled as its first line once it is logged in
+    pub first_line: Option<String>,
+}
+
+impl Default for Handshake {
+    fn default() -> Self {
+        Handshake {
+            format: Format::Text,
+            caps: Caps::assumed(),
+            nick: None,
+            replay_own: true,
+            first_line: None,
+        }
+    }
+}
+
+/** Function which reads the handshake a client sends before logging in. The server first
+* advertises its capabilities (`CAPS:nick,rooms,...`), then the client sends any number of setting
+* lines (`FORMAT text`, `FORMAT json`, `CAPS:{list}`, `NICK {name}` or `REPLAY own={true|false}`)
+* followed by a `LOGIN` line. The nickname is only checked once the client is logged in. Invalid lines are
+* answered with an error and otherwise ignored. Returns None if the client disconnects (or fails to
+* send a valid line) before finishing the handshake.
+* With a legacy fallback window, a client whose first line is not a handshake line, or which sends nothing
+* within the window, is taken to be a legacy client (such as `nc`) and logged in with the default settings,
+* its first line being kept to be handled once it is logged in. In strict mode, the first invalid line
+* ends the handshake (after its error has been sent), disconnecting the client.
+*/
+pub async fn read_handshake(
+    read: &mut LineReader,
+    write: &mut WriteHalf,
+    legacy_fallback: Option<Duration>,
+    strict: bool,
+) -> Option<Handshake> {
+    let mut handshake = Handshake::default();
+    write
+        .write_all(format!("CAPS:{}\n", Caps::all()).as_bytes())
+        .await
+        .ok()?;
+    // only the first line can show the client does not speak the handshake
+    let mut first = legacy_fallback.is_some();
+    loop {
+        let line = match legacy_fallback.filter(|_| first) {
+            // reading is cancel safe, so a partial line left by the window expiring becomes the client's first line
+            Some(window) => match tokio::time::timeout(window, read.next_line()).await {
+                Ok(line) => line,
+                Err(_) => return Some(handshake),
+            },
+            None => read.next_line().await,
+        };
+        let line = match line {
+            Ok(ReadLine::Line(line)) => line,
+            Ok(ReadLine::TooLong) | Ok(ReadLine::TimedOut) | Ok(ReadLine::Eof) | Err(_) => {
+                return None;
+            }
+        };
+        if std::mem::take(&mut first) && !is_handshake_line(&line) {
+            handshake.first_line = Some(line);
+            return Some(handshake);
+        }
+        // the client declares the capabilities it understands, replacing the default of all of them
+        if let Some(list) = line.strip_prefix("CAPS:") {
+            handshake.caps = Caps::parse(list);
+            continue;
+        }
+        let (key, value) = line.split_once(' ').unwrap_or((&line, ""));
+        let error = match key {
+            "LOGIN" => return Some(handshake),
+            "FORMAT" => match value.trim().parse() {
+                Ok(format) => {
+                    handshake.format = format;
+                    continue;
+                }
+                Err(()) => "ERR:BAD_FORMAT\n",
+            },
+            "NICK" => {
+                handshake.nick = Some(value.trim().to_string());
+                continue;
+            }
+            "REPLAY" => match parse_replay(value.trim()) {
+                Some(replay_own) => {
+                    handshake.replay_own = replay_own;
+                    continue;
+                }
+                None => "ERR:BAD_REPLAY\n",
+            },
+            _ => "ERR:BAD_HANDSHAKE\n",
+        };
+        write.write_all(error.as_bytes()).await.ok()?;
+        // only malformed lines are violations, an unsupported (but well-formed) request is not
+        if strict && error.starts_with("ERR:BAD_") {
+            return None;
+        }
+    }
+}
+
+// Function which returns whether a line is one of the lines a client may send during the handshake.
+fn is_handshake_line(line: &str) -> bool {
+    let key = line.split(' ').next().unwrap_or_default();
+    line.starts_with("CAPS:") || matches!(key, "LOGIN" | "FORMAT" | "NICK" | "REPLAY")
+}
+
+/** Function which parses the replay preference given in the handshake or with `/replay`
+* (`own=true` or `own=false`), returning whether the client's own messages are replayed.
+*/
+pub fn parse_replay(value: &str) -> Option<bool> {
+    match value {
+        "own=true" => Some(true),
+        "own=false" => Some(false),
+        _ => None,
+    }
+}
@@ -0,0 +1,81 @@
+use rhai::{AST, Dynamic, Engine, Scope};
+use std::path::Path;
+
+// most operations a single on_message call may run before it is stopped, so a runaway script cannot hold up its connection
+const MAX_OPERATIONS: u64 = 100_000;
+// longest string (in bytes) a script may build, so a script cannot exhaust memory
+const MAX_STRING_BYTES: usize = 1024 * 1024;
+// most elements a script's arrays and maps may hold, for the same reason
+const MAX_COLLECTION_SIZE: usize = 10_000;
+
+/** Outcome of running a message through a MessageHook: either the (possibly transformed)
+* text to broadcast, or a signal that the message should be dropped without broadcasting.
+*/
+pub enum HookAction {
+    Pass(String),
+    Drop,
+}
+
+/** Trait for hooks invoked on every chat message before it is broadcast, allowing the
+* message to be transformed or dropped. Hooks are shared between all connections.
+*/
+pub trait MessageHook: Send + Sync {
+    fn on_message(&self, from: u64, text: &str) -> HookAction;
+}
+
+/** MessageHook implementation backed by a Rhai script. The script must define an
+* `on_message(from, text)` function which returns the text to broadcast, or `()` to drop
+* the message. Errors raised by the script are logged and the message is passed through unchanged.
+* As the script runs on the connection's task, each call is limited to MAX_OPERATIONS operations (and
+* its strings and collections are bounded), so a script which loops forever or runs away with memory
+* fails like any other script error rather than stalling the connection.
+*/
+pub struct ScriptHook {
+    engine: Engine,
+    ast: AST,
+}
+
+impl ScriptHook {
+    // Constructor. Compile the script at the given path, returning an error if it cannot be read or compiled.
+    pub fn load(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut engine = Engine::new();
+        engine
+            .set_max_operations(MAX_OPERATIONS)
+            .set_max_string_size(MAX_STRING_BYTES)
+            .set_max_array_size(MAX_COLLECTION_SIZE)
+            .set_max_map_size(MAX_COLLECTION_SIZE);
+        let ast = engine
+            .compile_file(path.to_path_buf())
+            .map_err(|e| format!("failed to load script {}: {}", path.display(), e))?;
+        Ok(ScriptHook { engine, ast })
+    }
+}
+
+impl MessageHook for ScriptHook {
+    fn on_message(&self, from: u64, text: &str) -> HookAction {
+        let result = self.engine.call_fn::<Dynamic>(
+            &mut Scope::new(),
+            &self.ast,
+            "on_message",
+            (from as i64, text.to_string()),
+        );
+        match result {
+            // a unit return value signals that the message should be dropped
+            Ok(value) if value.is_unit() => HookAction::Drop,
+            Ok(value) => match value.into_string() {
+                Ok(transformed) => HookAction::Pass(transformed),
+                Err(type_name) => {
+                    log_eprintln!(
+                        "Script on_message returned {}, expected a string",
+                        type_name
+                    );
+                    HookAction::Pass(text.to_string())
+                }
+            },
+            Err(e) => {
+                log_eprintln!("Script on_message failed: {}", e);
+                HookAction::Pass(text.to_string())
+            }
+        }
+    }
+}
@@ -0,0 +1,113 @@
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::str::FromStr;
+
+// value of `{ip}` for clients without a peer address, such as in-memory clients
+const NO_ADDR: &str = "local";
+
+/** A piece of an identity format: literal text, or the placeholder for one of the client's details. */
+#[derive(Clone)]
+enum Part {
+    Literal(String),
+    Id,
+    Nick,
+    Ip,
+}
+
+/** Template clients are labelled by in log lines and in the sender field of chat and departure lines,
+* made of literal text and the placeholders `{id}`, `{nick}` and `{ip}` (ex. `{nick}@{ip}`). A detail
+* a client does not have falls back: `{nick}` to the client's ID, and `{ip}` to `local`. The template
+* may not contain whitespace, so the label is always a single word of the line it is written in.
+*/
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct IdentityFormat {
+    template: String,
+    parts: Vec<Part>,
+}
+
+impl IdentityFormat {
+    // Function which labels the client with the given c_id, nickname and address according to the template.
+    pub fn render(&self, c_id: u64, nick: Option<&str>, addr: Option<SocketAddr>) -> String {
+        let mut label = String::new();
+        for part in self.parts.iter() {
+            match part {
+                Part::Literal(text) => label.push_str(text),
+                Part::Id => label.push_str(&c_id.to_string()),
+                Part::Nick => match nick {
+                    Some(nick) => label.push_str(nick),
+                    None => label.push_str(&c_id.to_string()),
+                },
+                Part::Ip => match addr {
+                    Some(addr) => label.push_str(&addr.ip().to_string()),
+                    None => label.push_str(NO_ADDR),
+                },
+            }
+        }
+        label
+    }
+}
+
+impl Default for IdentityFormat {
+    // Clients are labelled by their ID alone by default.
+    fn default() -> Self {
+        IdentityFormat {
+            template: "{id}".to_string(),
+            parts: vec![Part::Id],
+        }
+    }
+}
+
+impl FromStr for IdentityFormat {
+    type Err = String;
+
+    // Parses a template, rejecting unknown or unterminated placeholders, whitespace, and empty templates.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() || s.contains(char::is_whitespace) {
+            return Err(format!("invalid identity format: {:?}", s));
+        }
+        let mut parts = Vec::new();
+        let mut rest = s;
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                parts.push(Part::Literal(rest[..start].to_string()));
+            }
+            let Some(end) = rest[start..].find('}') else {
+                return Err(format!("unterminated placeholder in identity format {}", s));
+            };
+            parts.push(match &rest[start + 1..start + end] {
+                "id" => Part::Id,
+                "nick" => Part::Nick,
+                "ip" => Part::Ip,
+                name => {
+                    return Err(format!(
+                        "unknown placeholder {{{}}} in identity format",
+                        name
+                    ));
+                }
+            });
+            rest = &rest[start + end + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Literal(rest.to_string()));
+        }
+        Ok(IdentityFormat {
+            template: s.to_string(),
+            parts,
+        })
+    }
+}
+
+impl TryFrom<String> for IdentityFormat {
+    type Error = String;
+
+    fn try_from(template: String) -> Result<Self, Self::Error> {
+        template.parse()
+    }
+}
+
+impl From<IdentityFormat> for String {
+    fn from(format: IdentityFormat) -> Self {
+        format.template
+    }
+}
@@ -0,0 +1,226 @@
+use serde::Serialize;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::fs::File;
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::sync::mpsc;
+
+// number of files rotated out by size which are kept (path.1 being the newest), older ones are deleted
+const KEEP_ROTATED: u32 = 5;
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+/** Severity of an operational log entry. */
+#[derive(Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Level {
+    Info,
+    Warn,
+    Error,
+}
+
+/** A single operational event, written to the JSON log as a line of JSON. Every entry has
+* the same fields, those which do not apply to the event being null.
+*/
+#[derive(Serialize)]
+struct LogEntry<'a> {
+    // seconds since the Unix epoch
+    ts: u64,
+    level: Level,
+    // kind of event, ex. "connect", "disconnect", "message", "read_error"
+    event: &'a str,
+    client_id: Option<u64>,
+    addr: Option<String>,
+    detail: Option<&'a str>,
+}
+
+/** Handle used to write operational events (connections, disconnections, messages and errors)
+* to the JSON log, for log aggregation. Like the audit log, entries are handed to a dedicated task
+* which appends them to the file, rotating it daily, or by size when a maximum size is given.
+*/
+#[derive(Clone)]
+pub struct JsonLog {
+    entries: mpsc::UnboundedSender<String>,
+}
+
+impl JsonLog {
+    /** Function which opens (or creates) the JSON log at the given path for appending and
+     * spawns the task writing to it. Fails if the file cannot be opened.
+     */
+    pub fn open(path: &Path, max_bytes: Option<u64>) -> std::io::Result<Self> {
+        let file = open_append(path)?;
+        let metadata = file.metadata()?;
+        // an existing file is rotated as soon as it is written to on a later day than it was last written
+        let day = metadata
+            .modified()
+            .ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map_or_else(today, |elapsed| elapsed.as_secs() / SECS_PER_DAY);
+        let rotation = Rotation {
+            path: path.to_path_buf(),
+            max_bytes,
+            size: metadata.len(),
+            day,
+        };
+        let (entries, rx) = mpsc::unbounded_channel();
+        tokio::spawn(run_json_log_writer(File::from_std(file), rotation, rx));
+        Ok(JsonLog { entries })
+    }
+
+    /** Function which records an event, along with the client and address it concerns and any
+     * further detail (such as the reason for a disconnect), where they apply.
+     */
+    pub fn log(
+        &self,
+        level: Level,
+        event: &str,
+        client_id: Option<u64>,
+        addr: Option<SocketAddr>,
+        detail: Option<&str>,
+    ) {
+        let entry = LogEntry {
+            ts: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |elapsed| elapsed.as_secs()),
+            level,
+            event,
+            client_id,
+            addr: addr.map(|addr| addr.to_string()),
+            detail,
+        };
+        // serializing plain integers and strings cannot fail
+        let line = format!("{}\n", serde_json::to_string(&entry).unwrap());
+        // the writer task only stops if a write fails, after which entries are discarded
+        let _ = self.entries.send(line);
+    }
+}
+
+/** State the JSON log writer task needs to decide when to rotate the file: the path of the
+* active file, the maximum size (rotating daily when None), and the size and day (since the
+* Unix epoch) of the file currently being written.
+*/
+struct Rotation {
+    path: PathBuf,
+    max_bytes: Option<u64>,
+    size: u64,
+    day: u64,
+}
+
+impl Rotation {
+    // Function which returns whether the active file must be rotated before the given line is written to it.
+    fn is_due(&self, line: &str) -> bool {
+        match self.max_bytes {
+            // a line longer than the maximum is still written, to a file of its own
+            Some(max) => self.size > 0 && self.size + line.len() as u64 > max,
+            None => today() != self.day,
+        }
+    }
+
+    /** Function which moves the active file aside and opens a new, empty one in its place. Files rotated
+     * by size are numbered (path.1 being the newest), while files rotated daily are named after the day
+     * they were written (ex. path.2026-10-14).
+     */
+    fn rotate(&mut self) -> std::io::Result<File> {
+        match self.max_bytes {
+            Some(_) => {
+                // shift the previously rotated files along, dropping the oldest
+                for n in (1..KEEP_ROTATED).rev() {
+                    let from = suffixed(&self.path, &n.to_string());
+                    if from.exists() {
+                        std::fs::rename(from, suffixed(&self.path, &(n + 1).to_string()))?;
+                    }
+                }
+                std::fs::rename(&self.path, suffixed(&self.path, "1"))?;
+            }
+            None => std::fs::rename(&self.path, suffixed(&self.path, &date(self.day)))?,
+        }
+        self.size = 0;
+        self.day = today();
+        Ok(File::from_std(open_append(&self.path)?))
+    }
+}
+
+/** Function run by the JSON log writer task. It appends entries to the file, buffering them and
+* flushing whenever no more entries are waiting, and rotates the file when it is due, until
+* writing (or rotating) fails.
+*/
+async fn run_json_log_writer(
+    file: File,
+    mut rotation: Rotation,
+    mut entries: mpsc::UnboundedReceiver<String>,
+) {
+    let mut file = BufWriter::new(file);
+    while let Some(line) = entries.recv().await {
+        if rotation.is_due(&line) {
+            // everything buffered belongs to the file being rotated out
+            if let Err(e) = file.flush().await {
+                log_eprintln!("Failed to write JSON log: {}", e);
+                return;
+            }
+            match rotation.rotate() {
+                Ok(new_file) => file = BufWriter::new(new_file),
+                Err(e) => {
+                    log_eprintln!("Failed to rotate JSON log: {}", e);
+                    return;
+                }
+            }
+        }
+        if let Err(e) = file.write_all(line.as_bytes()).await {
+            log_eprintln!("Failed to write JSON log: {}", e);
+            return;
+        }
+        rotation.size += line.len() as u64;
+        if entries.is_empty()
+            && let Err(e) = file.flush().await
+        {
+            log_eprintln!("Failed to write JSON log: {}", e);
+            return;
+        }
+    }
+}
+
+// Function which opens (or creates) the file at the given path for appending.
+fn open_append(path: &Path) -> std::io::Result<std::fs::File> {
+    std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+}
+
+// Function which returns the given path with a suffix appended to its file name, ex. path.1.
+fn suffixed(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(".");
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+// Function which returns the current day, as the number of days since the Unix epoch.
+fn today() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs() / SECS_PER_DAY)
+}
+
+/** Function which formats a number of days since the Unix epoch as a YYYY-MM-DD date (in UTC),
+* using the proleptic Gregorian calendar algorithm of Howard Hinnant's `civil_from_days`.
+*/
+fn date(days: u64) -> String {
+    // shift the epoch to 0000-03-01, so leap days fall at the end of each 400 year era
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let day_of_era = z % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
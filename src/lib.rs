@@ -0,0 +1,689 @@
+// declared first, so its macros can be used by every other module
+#[macro_use]
+mod logger;
+mod audit;
+mod caps;
+mod commands;
+pub mod config;
+mod connection;
+mod events;
+pub mod filter;
+pub mod handle;
+mod handshake;
+pub mod hooks;
+mod identity;
+mod jsonlog;
+mod listener;
+mod message;
+mod ratelimit;
+mod reader;
+mod reliable;
+mod replay;
+mod routing;
+pub mod signing;
+pub mod store;
+mod targets;
+mod tarpit;
+mod text;
+mod transport;
+mod writer;
+
+use audit::AuditLog;
+use caps::Caps;
+use config::{NewlinePolicy, ServerConfig};
+use connection::{AcceptState, accept_in_memory, accept_loop, run_remover};
+use events::EventBus;
+use filter::{AcceptAll, ConnectionFilter};
+use handle::ServerHandle;
+use hooks::{MessageHook, ScriptHook};
+use jsonlog::JsonLog;
+use message::{Format, Message};
+use ratelimit::TokenBucket;
+use reliable::Unacked;
+use replay::Replay;
+use routing::run_presence;
+use signing::Signer;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
+use store::{FileStore, MemoryStore, MessageStore, Record, StoredMessage};
+use tarpit::Tarpit;
+use tokio::io::DuplexStream;
+use tokio::net::TcpListener;
+use tokio::sync::{Mutex, OwnedSemaphorePermit, Semaphore, mpsc, oneshot, watch};
+use tokio::time::{Instant, sleep};
+use writer::{Backlog, Outbox};
+
+// returned by run_server when a listen address's port is already in use
+pub use identity::IdentityFormat;
+pub use listener::AddrInUse;
+
+// name of the room every client is placed in upon connecting
+const DEFAULT_ROOM: &str = "lobby";
+// number of messages delivered to each client which are kept for /recall
+const RECALL_SIZE: usize = 20;
+// number of departed clients whose last activity is kept for /seen, the earliest departed being forgotten beyond it
+const SEEN_DEPARTED: usize = 1024;
+// most messages read back from the message store at startup, each room keeping the last history_size of its own
+const RESTORE_LIMIT: usize = 10_000;
+// most changes waiting for the message store task, further ones being dropped while the store falls behind
+const STORE_QUEUE: usize = 1024;
+
+/** Extension points for embedding the server, supplied alongside the ServerConfig.
+* The default extensions accept every connection, use the message hook configured
+* by the `--script` option (if any), keep messages in the store configured by the
+* `--message-store-file` option (in memory when there is none), and only serve clients connecting over TCP.
+* Each in-memory stream received through in_memory is served as a client like any TCP
+* connection, the other end of the stream acting as the client, which lets tests drive the
+* server without sockets (see accept_in_memory). A handle, if given, is attached to the server
+* once it has started, so the embedder can reach its clients through its own clone of the handle.
+*/
+pub struct Extensions {
+    pub connection_filter: Arc<dyn ConnectionFilter>,
+    pub message_hook: Option<Arc<dyn MessageHook>>,
+    pub in_memory: Option<mpsc::UnboundedReceiver<DuplexStream>>,
+    pub message_store: Option<Arc<dyn MessageStore>>,
+    pub handle: Option<ServerHandle>,
+}
+
+impl Default for Extensions {
+    fn default() -> Self {
+        Extensions {
+            connection_filter: Arc::new(AcceptAll),
+            message_hook: None,
+            in_memory: None,
+            message_store: None,
+            handle: None,
+        }
+    }
+}
+
+/** Reason the server shut down cleanly: it was interrupted (Ctrl-C, or SIGINT from a supervisor), or
+* its last client disconnected while it was to exit when empty.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShutdownReason {
+    Signal,
+    Empty,
+}
+
+impl ShutdownReason {
+    // Function which returns the name of the reason, as shown in the summary.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ShutdownReason::Signal => "signal",
+            ShutdownReason::Empty => "empty",
+        }
+    }
+}
+
+/** Summary of a clean shutdown, returned by run_server: why the server shut down, how many clients
+* were told it was shutting down, the total number of messages broadcast, and how long it ran. A server
+* which stops because of an error returns the error instead.
+*/
+#[derive(Debug)]
+pub struct ShutdownSummary {
+    pub reason: ShutdownReason,
+    pub clients_notified: usize,
+    pub messages_broadcast: u64,
+    pub uptime: Duration,
+}
+
+impl std::fmt::Display for ShutdownSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "shutdown reason={} clients_notified={} messages_broadcast={} uptime={}",
+            self.reason.name(),
+            self.clients_notified,
+            self.messages_broadcast,
+            self.uptime.as_secs()
+        )
+    }
+}
+
+/**
+* Function to be called by main, running the server with the default extensions.
+*/
+pub async fn run_server(
+    config: ServerConfig,
+) -> Result<ShutdownSummary, Box<dyn std::error::Error>> {
+    run_server_with(config, Extensions::default()).await
+}
+
+/**
+* Function which runs the server with the given extensions. This is where connections to ther server are
+* validated and assigned their own thread. Uses tokio::spawn to create asynchronous threads.
+*/
+pub async fn run_server_with(
+    config: ServerConfig,
+    extensions: Extensions,
+) -> Result<ShutdownSummary, Box<dyn std::error::Error>> {
+    // an embedder may have built the config itself, so it is checked here as well as when parsing arguments
+    config.validate()?;
+    // log lines are written by a dedicated task from here on, so connection tasks never wait on stdout
+    logger::init();
+    // share the config with every connection task
+    let config = Arc::new(config);
+    // channel through which /rebind hands the accept loop a listener bound to a new address
+    let (rebind, rebound) = mpsc::unbounded_channel();
+    // establish Connections variable, which maintains a hashmap of client_id to OwnedWriteHalf for all currently connected clients
+    let mut connections = Connections::new(Arc::clone(&config), rebind);
+    // open the message store, which is supplied, configured or kept in memory, failing startup if its file cannot be opened
+    let store_file = config
+        .message_store_file
+        .as_ref()
+        .or(config.history_file.as_ref());
+    let message_store: Arc<dyn MessageStore> = match (extensions.message_store, store_file) {
+        (Some(store), _) => store,
+        (None, Some(path)) => Arc::new(FileStore::open(path, RESTORE_LIMIT)?),
+        (None, None) => Arc::new(MemoryStore::new(config.history_size)),
+    };
+    // client IDs continue after those of the restored messages' senders
+    let first_id = connections.restore(message_store.recent(RESTORE_LIMIT).await);
+    let (to_store, stored) = mpsc::channel(STORE_QUEUE);
+    connections.to_store = Some(to_store);
+    connections.message_store = Some(Arc::clone(&message_store));
+    tokio::spawn(run_message_store(message_store, stored));
+    // channel which closes once every writer task has ended, so shutdown can wait for farewells to be written
+    let (writers, mut writers_done) = mpsc::channel::<()>(1);
+    connections.writers = Some(writers);
+    // open the JSON log, if one is configured, failing startup if it cannot be opened
+    let json_log = match &config.json_log {
+        Some(path) => Some(JsonLog::open(path, config.json_log_max_bytes)?),
+        None => None,
+    };
+    connections.json_log = json_log.clone();
+    // addresses violating rate limits are slowed down, if a tarpit delay is configured
+    let tarpit = config.tarpit_delay.map(|delay| {
+        let window = Duration::from_millis(config.tarpit_window_ms);
+        Arc::new(Tarpit::new(delay, config.tarpit_threshold, window))
+    });
+    connections.tarpit = tarpit.clone();
+    // exiting when empty waits for the count of connected clients to drop to zero
+    let occupancy = connections.occupancy.subscribe();
+    let exit_grace = config
+        .exit_when_empty
+        .then(|| Duration::from_millis(config.exit_grace_ms));
+    let connections = Arc::new(Mutex::new(connections));
+    if let Some(handle) = &extensions.handle {
+        handle.attach(Arc::clone(&connections));
+    }
+    // channel through which connection tasks hand their removal to a single task, which removes them in batches
+    let (removals, removals_rx) = mpsc::unbounded_channel();
+    tokio::spawn(run_remover(Arc::clone(&connections), removals_rx));
+    // periodically send every client a snapshot of who is connected, if configured
+    if let Some(interval) = config.presence_interval {
+        tokio::spawn(run_presence(Arc::clone(&connections), interval));
+    }
+    // establish semaphore shared by all connections, limiting how many messages are broadcast concurrently
+    let inflight = Arc::new(Semaphore::new(config.max_inflight));
+    // load the message hook script, if one is configured and no hook was supplied, failing startup if it cannot be compiled
+    let hook: Option<Arc<dyn MessageHook>> = match (extensions.message_hook, &config.script) {
+        (Some(hook), _) => Some(hook),
+        (None, Some(path)) => Some(Arc::new(ScriptHook::load(path)?)),
+        (None, None) => None,
+    };
+    // establish a TcpListener which is bound to the configured address (localhost port 8888 by default),
+    // and one for each additional listen address, failing startup if any of them cannot be bound
+    // with auto_port, a primary port which is already in use is replaced by one chosen by the OS
+    let listener = match listener::bind(config.bind, config.reuse_port).await {
+        Err(e) if e.kind() == std::io::ErrorKind::AddrInUse && config.auto_port => {
+            log_println!(
+                "port {} already in use, falling back to an ephemeral port",
+                config.bind.port()
+            );
+            let ephemeral = std::net::SocketAddr::new(config.bind.ip(), 0);
+            listener::bind(ephemeral, config.reuse_port)
+                .await
+                .map_err(|e| listener::bind_error(e, ephemeral))?
+        }
+        listener => listener.map_err(|e| listener::bind_error(e, config.bind))?,
+    };
+    let mut extra_listeners = Vec::with_capacity(config.listen.len());
+    for addr in &config.listen {
+        let listener = listener::bind(addr, config.reuse_port).await;
+        extra_listeners.push(listener.map_err(|e| listener::bind_error(e, *addr))?);
+    }
+    // open the audit file, if one is configured, failing startup if it cannot be opened
+    let audit = match &config.audit_file {
+        Some(path) => Some(AuditLog::open(path)?),
+        None => None,
+    };
+    let state = Arc::new(AcceptState {
+        // global limit on the rate at which new connections are accepted, shared by all clients
+        accept_limit: config
+            .accept_rate
+            .map(|rate| std::sync::Mutex::new(TokenBucket::new(rate))),
+        // places under the client limit, shared by all clients
+        slots: config.max_clients.map(|max| Arc::new(Semaphore::new(max))),
+        config,
+        connections: Arc::clone(&connections),
+        inflight,
+        hook,
+        connection_filter: extensions.connection_filter,
+        audit,
+        json_log,
+        tarpit,
+        removals,
+        next_id: AtomicU64::new(first_id),
+    });
+    // in-memory clients are served alongside the TCP ones, sharing the same connections
+    if let Some(streams) = extensions.in_memory {
+        tokio::spawn(accept_in_memory(Arc::clone(&state), streams));
+    }
+    // every additional listen address has its own accept loop, all feeding the same connections
+    for listener in extra_listeners {
+        // /rebind only moves the primary listener, so the other accept loops are given a channel which never receives
+        let (_, never_rebound) = mpsc::unbounded_channel();
+        let state = Arc::clone(&state);
+        tokio::spawn(async move {
+            let Err(e) = accept_loop(state, listener, never_rebound).await;
+            log_eprintln!("Error accepting connections: {}", e);
+        });
+    }
+    let reason = tokio::select! {
+        // accepting only ever stops by failing
+        Err(e) = accept_loop(state, listener, rebound) => {
+            logger::flush().await;
+            return Err(e.into());
+        }
+        result = tokio::signal::ctrl_c() => {
+            result?;
+            ShutdownReason::Signal
+        }
+        Some(()) = emptied(occupancy, exit_grace.unwrap_or_default()), if exit_grace.is_some() => {
+            log_println!("last client disconnected");
+            ShutdownReason::Empty
+        }
+    };
+    log_println!("shutting down");
+    let summary = {
+        let mut connections = connections.lock().await;
+        let clients_notified = connections.shut_down();
+        ShutdownSummary {
+            reason,
+            clients_notified,
+            messages_broadcast: connections.next_seq - 1,
+            uptime: connections.started.elapsed(),
+        }
+    };
+    // nothing is ever sent, so this waits until every writer task has ended (each within the grace period)
+    while writers_done.recv().await.is_some() {}
+    logger::flush().await;
+    Ok(summary)
+}
+
+/** Function which completes once the server has had no clients for the given grace period, given the
+* count of connected clients. It never completes before the first client has connected, so a server
+* exiting when empty waits for its first client however long it takes to arrive, and a client (re)connecting
+* within the grace period keeps the server running. None is only returned if the count is no longer kept.
+*/
+async fn emptied(mut occupancy: watch::Receiver<Option<usize>>, grace: Duration) -> Option<()> {
+    loop {
+        occupancy.wait_for(|count| *count == Some(0)).await.ok()?;
+        sleep(grace).await;
+        // any change during the grace period restarts it, even a client which has already left again
+        if !occupancy.has_changed().ok()? {
+            return Some(());
+        }
+    }
+}
+
+/** Function run by the message store task, which applies each change handed to it to the store, one at a
+* time and in the order they happened, so a slow store never holds up a broadcast.
+*/
+async fn run_message_store(store: Arc<dyn MessageStore>, mut records: mpsc::Receiver<Record>) {
+    while let Some(record) = records.recv().await {
+        match record {
+            Record::Message(message) => store.append(&message).await,
+            Record::Edit { seq, text } => store.edit(seq, &text).await,
+            Record::Delete { seq } => store.delete(seq).await,
+        }
+    }
+}
+
+// Function which names a client in SYSTEM: lines, by its ID followed by its nickname (if it has one) in parentheses.
+fn describe(c_id: u64, nick: Option<&str>) -> String {
+    match nick {
+        Some(nick) => format!("{} ({})", c_id, nick),
+        None => c_id.to_string(),
+    }
+}
+
+/** Function which encodes the message for the client with the given c_id in the client's format, writing
+* embedded newlines according to the given policy, printing the error and returning None if it cannot be
+* encoded, so the client is skipped.
+*/
+fn encode_for(
+    message: &Message,
+    c_id: u64,
+    format: Format,
+    newlines: NewlinePolicy,
+) -> Option<String> {
+    match message.encode(format, newlines) {
+        Ok(line) => Some(line),
+        Err(e) => {
+            log_eprintln!("Failed to encode data for client_id {}: {}", c_id, e);
+            None
+        }
+    }
+}
+
+/**Struct to maintain mapping of client IDs to corresponding connection state to
+* enable message broadcasting, along with the recent message history of each room.
+* Struct is used to manage ownership via Arc and Mutex.
+*/
+struct Connections {
+    cons: HashMap<u64, Connection>,
+    // bounded ring buffer of recent messages for each room, keyed by room name
+    history: HashMap<String, VecDeque<HistoryEntry>>,
+    // topic of each room which has one, keyed by room name
+    topics: HashMap<String, String>,
+    // settings of the server, shared with the accept loop and every connection task
+    config: Arc<ServerConfig>,
+    // used by /rebind to pass a newly bound listener to the accept loop
+    rebind: mpsc::UnboundedSender<TcpListener>,
+    // time each client last sent a line, kept after the client disconnects for /seen
+    last_activity: HashMap<u64, Instant>,
+    // IDs of the departed clients in last_activity, in the order they departed, at most SEEN_DEPARTED of them
+    departed: VecDeque<u64>,
+    // time at which the server started, used to report its uptime
+    started: Instant,
+    // aggregate rate limit of each room that has had a message broadcast, keyed by room name
+    room_limits: HashMap<String, TokenBucket>,
+    // sequence number assigned to the next broadcast message, unique across all rooms
+    next_seq: u64,
+    // store the messages are kept in, asked for its health by /stats, None until it is opened
+    message_store: Option<Arc<dyn MessageStore>>,
+    // channel through which changes to the messages are handed to the message store task, None until it is started
+    to_store: Option<mpsc::Sender<Record>>,
+    // handle held by each writer task until it ends, None once the server is shutting down
+    writers: Option<mpsc::Sender<()>>,
+    // number of connected clients, updated as clients are added and removed, None until the first client has connected
+    occupancy: watch::Sender<Option<usize>>,
+    // count of the normal lines queued across every client, shared with their writer tasks
+    backlog: Arc<Backlog>,
+    // ID of the client using each nickname, so commands can address clients by nickname
+    nicks: HashMap<String, u64>,
+    // log operational events are written to, nothing is logged there when None
+    json_log: Option<JsonLog>,
+    // signs every broadcast chat message, and checks the signatures of incoming ones, None unless a key is configured
+    signer: Option<Signer>,
+    // bus lifecycle events are published to, forwarded to every client subscribed to them
+    events: EventBus,
+    // strikes against addresses which violated rate limits, and the delay imposed on flagged ones, None unless configured
+    tarpit: Option<Arc<Tarpit>>,
+    // owner and invited clients of each private room, keyed by room name, kept while the room has clients in it
+    private_rooms: HashMap<String, PrivateRoom>,
+    // lock held by a broadcast from numbering its message until it is queued for every recipient, None unless strict ordering is configured
+    order: Option<Arc<Mutex<()>>>,
+    // number of broadcast lines dropped for recipients whose queue stayed full, and of recipients disconnected for it
+    dropped: u64,
+    overflow_disconnects: u64,
+    // number of times each command has been issued, keyed by command name (or "unknown")
+    command_counts: HashMap<String, u64>,
+}
+
+/** State kept for each connected client: the Outbox used to queue lines for the
+* client's writer task, the name of the room the client is currently in (which its messages are
+* sent to) and of every room it receives messages from, whether the
+* client has authenticated as an admin, whether the client is a read-only spectator,
+* the client's nickname (if it has set one), the format the client receives lines in, the
+* handle used to cancel the client's read task, the rate limit on the client's reactions, and the
+* number of consecutive broadcasts the client has lagged on, the capabilities the client understands, the client's address,
+* and whether its own messages are replayed to it.
+*/
+struct Connection {
+    outbox: Outbox,
+    room: String,
+    // every room the client is in, including its current room
+    rooms: Vec<String>,
+    is_admin: bool,
+    read_only: bool,
+    nick: Option<String>,
+    format: Format,
+    cancel: oneshot::Sender<()>,
+    reactions: TokenBucket,
+    lag_count: u32,
+    caps: Caps,
+    addr: Option<std::net::SocketAddr>,
+    // whether the client's own earlier messages are included when history is replayed to it
+    replay_own: bool,
+    // sender and text of the last messages delivered to the client (broadcasts and private messages), oldest first
+    recall: VecDeque<(u64, String)>,
+    // whether the client has been sent a protocol error in strict mode, so it is to be disconnected
+    violated: AtomicBool,
+    // whether the client is subscribed to lifecycle events, in which case it is not sent chat messages
+    subscribed: bool,
+    // whether the client is told of other clients' presence changes with SYSTEM: lines rather than control lines
+    inline_events: bool,
+    // whether the client is in do not disturb mode, in which it is not sent chat messages broadcast to its rooms
+    dnd: bool,
+    // chat messages sent to a reliable client which it has not acknowledged yet, None for other clients
+    unacked: Option<Arc<Unacked>>,
+    // history being replayed to the client in chunks, behind which its live chat messages wait, None if it never was
+    replay: Option<Arc<Replay>>,
+    // tag of the command being handled for the client, echoed in the ACK: and ERR: lines replying to it
+    reply_tag: Option<String>,
+    // the client's place under the client limit (if any), given up when the record is dropped
+    _slot: Option<OwnedSemaphorePermit>,
+}
+
+impl Connection {
+    // Function which keeps a message delivered to the client for /recall, forgetting the oldest once RECALL_SIZE are kept.
+    fn remember(&mut self, from: u64, text: &str) {
+        if self.recall.len() == RECALL_SIZE {
+            self.recall.pop_front();
+        }
+        self.recall.push_back((from, text.to_string()));
+    }
+
+    // Function which returns whether the client is in the given room, receiving the messages broadcast there.
+    fn in_room(&self, room: &str) -> bool {
+        self.rooms.iter().any(|joined| joined == room)
+    }
+
+    // Function which returns whether the client declared the capability needed to be sent the message.
+    fn accepts(&self, message: &Message) -> bool {
+        message
+            .required_cap()
+            .is_none_or(|cap| self.caps.contains(cap))
+    }
+
+    /** Function which chooses the version of a message the client is sent: the message itself if the
+     * client has the capability it needs, otherwise the degraded fallback (if any, and if the client
+     * accepts it), or nothing.
+     */
+    fn version_of<'a>(
+        &self,
+        message: &'a Message,
+        fallback: Option<&'a Message>,
+    ) -> Option<&'a Message> {
+        if self.accepts(message) {
+            return Some(message);
+        }
+        fallback.filter(|fallback| self.accepts(fallback))
+    }
+}
+
+/** Access list of a private room: the client which created it, and the clients it has invited.
+* Only these clients can join the room.
+*/
+struct PrivateRoom {
+    owner: u64,
+    invited: HashSet<u64>,
+}
+
+/** A single message retained in a room's history, replayed to clients joining the room.
+*/
+struct HistoryEntry {
+    seq: u64,
+    c_id: u64,
+    // nickname of the sender when it sent the message, identifying the sender across reconnections
+    nick: Option<String>,
+    text: String,
+}
+
+/** Implementation for Connections struct.
+* Implements constructor function, fn new().
+* Implements the functions restoring and persisting history, and looking up clients.
+* Broadcasting is implemented in routing, commands in commands, and removing clients in connection.
+*/
+impl Connections {
+    // Constructor. Create a new, empty, instance of `Connections` using the settings of the given config.
+    fn new(config: Arc<ServerConfig>, rebind: mpsc::UnboundedSender<TcpListener>) -> Self {
+        let order = config.strict_order.then(|| Arc::new(Mutex::new(())));
+        let backlog = Arc::new(Backlog::new(config.max_buffered_lines));
+        let signer = config
+            .hmac_key
+            .as_deref()
+            .map(|key| Signer::new(key.as_bytes()));
+        Connections {
+            cons: HashMap::new(),
+            history: HashMap::new(),
+            topics: HashMap::new(),
+            config,
+            rebind,
+            last_activity: HashMap::new(),
+            departed: VecDeque::new(),
+            started: Instant::now(),
+            room_limits: HashMap::new(),
+            next_seq: 1,
+            message_store: None,
+            to_store: None,
+            writers: None,
+            occupancy: watch::Sender::new(None),
+            backlog,
+            nicks: HashMap::new(),
+            json_log: None,
+            signer,
+            events: EventBus::new(),
+            tarpit: None,
+            private_rooms: HashMap::new(),
+            order,
+            dropped: 0,
+            overflow_disconnects: 0,
+            command_counts: HashMap::new(),
+        }
+    }
+
+    // Function which records a rate limit violation against the client's address, if a tarpit is configured.
+    fn strike(&self, c_id: u64) {
+        if let (Some(tarpit), Some(addr)) =
+            (&self.tarpit, self.cons.get(&c_id).and_then(|con| con.addr))
+        {
+            tarpit.strike(addr.ip());
+        }
+    }
+
+    /** Function which restores the history of every room from the messages read back from the message
+     * store, and continues numbering messages after the last restored message. Returns the first client ID
+     * after those of every restored message's sender, so that clients connecting from now on are never taken
+     * for the sender of an earlier run's message (which would let them edit or delete it).
+     */
+    fn restore(&mut self, messages: Vec<StoredMessage>) -> u64 {
+        let mut first_id = 1;
+        for message in messages {
+            self.next_seq = self.next_seq.max(message.seq + 1);
+            first_id = first_id.max(message.from + 1);
+            let history = self.history.entry(message.room).or_default();
+            history.push_back(HistoryEntry {
+                seq: message.seq,
+                c_id: message.from,
+                nick: message.nick,
+                text: message.text,
+            });
+            while history.len() > self.config.history_size {
+                history.pop_front();
+            }
+        }
+        first_id
+    }
+
+    /** Function which hands the change to the message store task without waiting, logging and dropping it
+     * if the store has fallen so far behind that STORE_QUEUE changes are already waiting.
+     */
+    fn persist(&self, record: Record) {
+        let Some(to_store) = &self.to_store else {
+            return;
+        };
+        if let Err(mpsc::error::TrySendError::Full(record)) = to_store.try_send(record) {
+            log_eprintln!("Message store is falling behind, dropping {:?}", record);
+        }
+    }
+
+    // Function which returns whether the message store is healthy and keeping up with the changes handed to it.
+    fn persistence_healthy(&self) -> bool {
+        let keeping_up = self
+            .to_store
+            .as_ref()
+            .is_none_or(|to_store| to_store.capacity() > 0);
+        let healthy = self
+            .message_store
+            .as_ref()
+            .is_none_or(|store| store.is_healthy());
+        keeping_up && healthy
+    }
+
+    /** Function which resolves the target of a command, given either as a client ID or as the
+     * nickname of a connected client, to a client ID. An ID is returned as given, whether or not
+     * it belongs to a connected client, while an unknown nickname resolves to nothing.
+     */
+    fn resolve(&self, target: &str) -> Option<u64> {
+        match target.parse::<u64>() {
+            Ok(id) => Some(id),
+            Err(_) => self.nicks.get(target).copied(),
+        }
+    }
+
+    // Function which returns whether the client with the given c_id has been sent a protocol error in strict mode.
+    fn violated(&self, c_id: u64) -> bool {
+        self.cons
+            .get(&c_id)
+            .is_some_and(|con| con.violated.load(Ordering::Relaxed))
+    }
+
+    // Function which sets the tag of the command being handled for the client with the given c_id, None once it has been handled.
+    fn set_reply_tag(&mut self, c_id: u64, tag: Option<String>) {
+        if let Some(con) = self.cons.get_mut(&c_id) {
+            con.reply_tag = tag;
+        }
+    }
+
+    // Function which returns the IDs of every connected client, in ascending order.
+    fn client_ids(&self) -> Vec<u64> {
+        let mut c_ids: Vec<u64> = self.cons.keys().copied().collect();
+        c_ids.sort();
+        c_ids
+    }
+
+    // Function which returns whether the client with the given c_id is an admin.
+    fn is_admin(&self, c_id: u64) -> bool {
+        self.cons.get(&c_id).is_some_and(|con| con.is_admin)
+    }
+
+    // Function which forgets private rooms nobody is in any more, freeing their names (and ownership) for reuse.
+    fn prune_private_rooms(&mut self) {
+        let cons = &self.cons;
+        self.private_rooms
+            .retain(|room, _| cons.values().any(|con| con.in_room(room)));
+    }
+
+    // Function which labels the client with the given c_id in the identity format, for log lines and the sender of chat and departure lines.
+    fn identity(&self, c_id: u64) -> String {
+        let con = self.cons.get(&c_id);
+        self.config.identity_format.render(
+            c_id,
+            con.and_then(|con| con.nick.as_deref()),
+            con.and_then(|con| con.addr),
+        )
+    }
+
+    // Function which names the client with the given c_id in SYSTEM: lines (see describe).
+    fn describe(&self, c_id: u64) -> String {
+        let nick = self.cons.get(&c_id).and_then(|con| con.nick.as_deref());
+        describe(c_id, nick)
+    }
+}
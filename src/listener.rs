@@ -0,0 +1,90 @@
+use socket2::{Domain, Protocol, Socket, Type};
+use std::fmt;
+use std::net::SocketAddr;
+use tokio::net::{TcpListener, ToSocketAddrs, lookup_host};
+
+// number of connections the OS queues for a listener before they are accepted
+const BACKLOG: i32 = 1024;
+
+/** Error returned by run_server when a listen address cannot be bound because its port is already
+* in use (typically by another server), so it can be told apart from other failures to start.
+*/
+#[derive(Debug)]
+pub struct AddrInUse {
+    pub addr: SocketAddr,
+}
+
+impl fmt::Display for AddrInUse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "port {} already in use", self.addr.port())
+    }
+}
+
+impl std::error::Error for AddrInUse {}
+
+/** Function which converts a failure to bind a listener to the given address into the error returned
+* for it, which is AddrInUse when the port is already in use and the failure itself otherwise.
+*/
+pub fn bind_error(e: std::io::Error, addr: SocketAddr) -> Box<dyn std::error::Error> {
+    match e.kind() {
+        std::io::ErrorKind::AddrInUse => Box::new(AddrInUse { addr }),
+        _ => Box::new(e),
+    }
+}
+
+/** Function which binds a listener to the given address. With reuse_port, SO_REUSEPORT is set
+* on the socket before it is bound, so several server processes can listen on the same address
+* (ex. a new process starting while the old one shuts down) and the OS shares new connections
+* between them. Each address the given one resolves to is tried in turn, like TcpListener::bind.
+*/
+pub async fn bind(addr: impl ToSocketAddrs, reuse_port: bool) -> std::io::Result<TcpListener> {
+    if !reuse_port {
+        return TcpListener::bind(addr).await;
+    }
+    let mut last_error = None;
+    for addr in lookup_host(addr).await? {
+        match bind_reusable(addr) {
+            Ok(listener) => return Ok(listener),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "could not resolve to any address",
+        )
+    }))
+}
+
+// Function which binds a listener with SO_REUSEPORT (and SO_REUSEADDR) set to the given address.
+fn bind_reusable(addr: SocketAddr) -> std::io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_reuse_address(true)?;
+    set_reuse_port(&socket)?;
+    // tokio requires the socket to be non-blocking
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(BACKLOG)?;
+    TcpListener::from_std(socket.into())
+}
+
+// Function which sets SO_REUSEPORT on the socket, on the platforms which support it.
+#[cfg(all(
+    unix,
+    not(any(target_os = "solaris", target_os = "illumos", target_os = "cygwin"))
+))]
+fn set_reuse_port(socket: &Socket) -> std::io::Result<()> {
+    socket.set_reuse_port(true)
+}
+
+// Function which fails on platforms without SO_REUSEPORT, so --reuse-port is not silently ignored.
+#[cfg(not(all(
+    unix,
+    not(any(target_os = "solaris", target_os = "illumos", target_os = "cygwin"))
+)))]
+fn set_reuse_port(_socket: &Socket) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "SO_REUSEPORT is not supported on this platform",
+    ))
+}
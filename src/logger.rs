@@ -0,0 +1,127 @@
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, oneshot};
+
+// number of lines waiting for the logging task beyond which further lines are dropped
+const LOG_CAPACITY: usize = 4096;
+
+/** Stream a log line is written to, standard output for events and standard error for failures. */
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+/** Request handled by the logging task: a line to write, or a flush of every line sent before it,
+* completing the given sender once they have been written.
+*/
+enum Entry {
+    Line(Stream, String),
+    Flush(oneshot::Sender<()>),
+}
+
+// sending end of the logging task's channel, set once the task has been started
+static LOGGER: OnceLock<mpsc::Sender<Entry>> = OnceLock::new();
+// number of lines dropped because the logging task had fallen LOG_CAPACITY lines behind
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+/** Like println!, but hands the line to the logging task rather than taking the stdout lock,
+* so connection tasks never block on a slow or contended terminal.
+*/
+macro_rules! log_println {
+    ($($arg:tt)*) => {
+        $crate::logger::log($crate::logger::Stream::Stdout, format!($($arg)*))
+    };
+}
+
+/** Like eprintln!, but hands the line to the logging task rather than taking the stderr lock. */
+macro_rules! log_eprintln {
+    ($($arg:tt)*) => {
+        $crate::logger::log($crate::logger::Stream::Stderr, format!($($arg)*))
+    };
+}
+
+/** Function which starts the logging task, if it has not been started yet. Lines logged before
+* then (or outside of a runtime) are printed directly.
+*/
+pub fn init() {
+    LOGGER.get_or_init(|| {
+        let (entries, rx) = mpsc::channel(LOG_CAPACITY);
+        tokio::spawn(run_logger(rx));
+        entries
+    });
+}
+
+/** Function which queues a line for the logging task, printing it directly if the task is not running
+* (such as once the runtime it was started on has shut down). The line is dropped (and counted) if the
+* task has fallen LOG_CAPACITY lines behind, such as on a blocked terminal, as logging must never hold
+* up the task producing the line.
+*/
+pub fn log(stream: Stream, line: String) {
+    let entry = Entry::Line(stream, line);
+    let entry = match LOGGER.get() {
+        Some(entries) => match entries.try_send(entry) {
+            Ok(()) => return,
+            Err(TrySendError::Full(_)) => {
+                DROPPED.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+            Err(TrySendError::Closed(entry)) => entry,
+        },
+        None => entry,
+    };
+    match entry {
+        Entry::Line(Stream::Stdout, line) => println!("{}", line),
+        Entry::Line(Stream::Stderr, line) => eprintln!("{}", line),
+        Entry::Flush(_) => {}
+    }
+}
+
+/** Function which waits until every line logged so far has been written, so lines logged just before
+* the server exits are not lost.
+*/
+pub async fn flush() {
+    let Some(entries) = LOGGER.get() else {
+        return;
+    };
+    let (done, flushed) = oneshot::channel();
+    if entries.send(Entry::Flush(done)).await.is_ok() {
+        let _ = flushed.await;
+    }
+}
+
+// Function which returns the number of log lines dropped since the server started.
+pub fn dropped() -> u64 {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+/** Function run by the logging task, which writes each line to its stream, flushing whenever no more
+* lines are waiting. Write failures are ignored, as there is nowhere left to report them.
+*/
+async fn run_logger(mut entries: mpsc::Receiver<Entry>) {
+    let mut stdout = tokio::io::stdout();
+    let mut stderr = tokio::io::stderr();
+    while let Some(entry) = entries.recv().await {
+        match entry {
+            Entry::Line(Stream::Stdout, line) => write_line(&mut stdout, line).await,
+            Entry::Line(Stream::Stderr, line) => write_line(&mut stderr, line).await,
+            Entry::Flush(done) => {
+                let _ = stdout.flush().await;
+                let _ = stderr.flush().await;
+                let _ = done.send(());
+                continue;
+            }
+        }
+        if entries.is_empty() {
+            let _ = stdout.flush().await;
+            let _ = stderr.flush().await;
+        }
+    }
+}
+
+// Function which writes a line, followed by a newline, to the given stream.
+async fn write_line(stream: &mut (impl AsyncWrite + Unpin), mut line: String) {
+    line.push('\n');
+    let _ = stream.write_all(line.as_bytes()).await;
+}
@@ -0,0 +1,209 @@
+use crate::caps::Cap;
+use crate::config::NewlinePolicy;
+use serde::Serialize;
+use std::str::FromStr;
+
+/** Output format a client receives its lines in, negotiated during the handshake.
+* Text is the plain line protocol, while Json sends each line as a JSON object. Unencodable, which
+* only exists in the crate's own tests, fails to encode every line but replies (which it writes as
+* Text), to exercise the paths skipping recipients whose copy of a line cannot be encoded.
+*/
+#[derive(Clone, Copy, PartialEq)]
+pub enum Format {
+    Text,
+    Json,
+    #[cfg(test)]
+    Unencodable,
+}
+
+impl FromStr for Format {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            #[cfg(test)]
+            "unencodable" => Ok(Format::Unencodable),
+            _ => Err(()),
+        }
+    }
+}
+
+/** A line sent from the server to a client, independent of the client's output format.
+* Chat messages, history replay, edits, reactions, announcements, private messages, departures, presence snapshots
+* and inline system lines have their own variants, while every other
+* protocol line (acknowledgements, errors, command replies) is carried as a Reply. Chat and history
+* messages carry the server-assigned sequence number identifying the message, which is only
+* included in the Json format. Chat messages and departures also carry the sender's label in the
+* identity format, which the Text format names the sender by, while Json keeps its client ID.
+*/
+#[derive(Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Message {
+    Chat {
+        seq: u64,
+        from: u64,
+        #[serde(skip)]
+        sender: String,
+        text: String,
+        // signature of the text, when messages are signed
+        #[serde(skip_serializing_if = "Option::is_none")]
+        hmac: Option<String>,
+        // whether the Text format also shows the sequence number, for clients acknowledging receipt
+        #[serde(skip)]
+        numbered: bool,
+    },
+    History {
+        seq: u64,
+        from: u64,
+        text: String,
+    },
+    Edit {
+        seq: u64,
+        text: String,
+    },
+    Delete {
+        seq: u64,
+    },
+    React {
+        seq: u64,
+        from: u64,
+        emoji: String,
+    },
+    Announce {
+        text: String,
+    },
+    Private {
+        from: u64,
+        text: String,
+    },
+    Leave {
+        from: u64,
+        #[serde(skip)]
+        sender: String,
+        reason: String,
+    },
+    Presence {
+        clients: Vec<u64>,
+    },
+    // a change in another client's presence, rendered as text for clients which chose inline events
+    System {
+        text: String,
+    },
+    Reply {
+        line: String,
+    },
+}
+
+impl Message {
+    // Function which wraps a protocol line (with or without its trailing newline) as a Reply.
+    pub fn reply(line: &str) -> Self {
+        Message::Reply {
+            line: line.trim_end_matches('\n').to_string(),
+        }
+    }
+
+    // Function which returns a copy of the message which, if it is a chat message, shows its sequence number in the Text format.
+    pub fn numbered(&self) -> Self {
+        let mut copy = self.clone();
+        if let Message::Chat { numbered, .. } = &mut copy {
+            *numbered = true;
+        }
+        copy
+    }
+
+    // Function which returns the capability a client must have to be sent the message, if any.
+    pub fn required_cap(&self) -> Option<Cap> {
+        match self {
+            Message::History { .. } => Some(Cap::History),
+            Message::Edit { .. } | Message::Delete { .. } => Some(Cap::Edits),
+            Message::React { .. } => Some(Cap::Reactions),
+            Message::Private { .. } => Some(Cap::Dm),
+            Message::Leave { .. } | Message::Presence { .. } => Some(Cap::Presence),
+            Message::Chat { .. }
+            | Message::Announce { .. }
+            | Message::System { .. }
+            | Message::Reply { .. } => None,
+        }
+    }
+
+    /** Function which encodes the message as a newline-terminated line in the given format, failing if it
+     * cannot be serialized. In the Text format, newlines embedded in a message's text are written according
+     * to the given policy (which may turn the message into several lines), while Json escapes them itself.
+     */
+    pub fn encode(
+        &self,
+        format: Format,
+        newlines: NewlinePolicy,
+    ) -> Result<String, serde_json::Error> {
+        match format {
+            Format::Text => Ok(match self {
+                Message::Chat {
+                    seq,
+                    sender,
+                    text,
+                    hmac,
+                    numbered,
+                    ..
+                } => {
+                    let mut suffix = String::new();
+                    if let Some(hmac) = hmac {
+                        suffix.push_str(&format!(" hmac={}", hmac));
+                    }
+                    if *numbered {
+                        suffix.push_str(&format!(" seq={}", seq));
+                    }
+                    body_lines(&format!("MESSAGE:{} ", sender), text, &suffix, newlines)
+                }
+                Message::History { from, text, .. } => {
+                    body_lines(&format!("HISTORY:{} ", from), text, "", newlines)
+                }
+                Message::Edit { seq, text } => {
+                    body_lines(&format!("EDIT:{} ", seq), text, "", newlines)
+                }
+                Message::Delete { seq } => format!("DELETE:{}\n", seq),
+                Message::React { seq, from, emoji } => {
+                    format!("REACT:{} {} {}\n", seq, from, emoji)
+                }
+                Message::Announce { text } => body_lines("ANNOUNCE:", text, "", newlines),
+                Message::Private { from, text } => {
+                    body_lines(&format!("PRIVATE:{} ", from), text, "", newlines)
+                }
+                Message::Leave { sender, reason, .. } => format!("LEAVE:{} {}\n", sender, reason),
+                Message::Presence { clients } => {
+                    let clients: Vec<String> = clients.iter().map(u64::to_string).collect();
+                    format!("PRESENCE:{}\n", clients.join(","))
+                }
+                Message::System { text } => format!("SYSTEM:{}\n", text),
+                Message::Reply { line } => format!("{}\n", line),
+            }),
+            Format::Json => Ok(format!("{}\n", serde_json::to_string(self)?)),
+            #[cfg(test)]
+            Format::Unencodable => match self {
+                Message::Reply { .. } => self.encode(Format::Text, newlines),
+                _ => Err(serde::ser::Error::custom("unencodable format")),
+            },
+        }
+    }
+}
+
+/** Function which formats a message's text between the prefix and suffix of its line, writing any newlines
+* embedded in the text according to the given policy. When split, each line of the text gets its own copy
+* of the prefix, and the suffix (such as the signature, which covers the whole text) follows the last one.
+*/
+fn body_lines(prefix: &str, text: &str, suffix: &str, newlines: NewlinePolicy) -> String {
+    match newlines {
+        NewlinePolicy::Escape => format!("{}{}{}\n", prefix, text.replace('\n', "\\n"), suffix),
+        NewlinePolicy::Space => format!("{}{}{}\n", prefix, text.replace('\n', " "), suffix),
+        NewlinePolicy::Split => {
+            let mut lines: String = text
+                .split('\n')
+                .map(|part| format!("{}{}\n", prefix, part))
+                .collect();
+            // the suffix goes before the final newline
+            lines.insert_str(lines.len() - 1, suffix);
+            lines
+        }
+    }
+}
@@ -0,0 +1,53 @@
+use std::time::{Duration, Instant};
+
+/** Token bucket rate limiter. The bucket holds up to `capacity` tokens and is refilled at
+* `rate` tokens per second; each permitted event takes one token, so bursts of up to
+* `capacity` events are allowed while the long-run average is limited to `rate` per second.
+*/
+pub struct TokenBucket {
+    capacity: f64,
+    rate: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    // Constructor. Create a full bucket allowing `rate` events per second, with bursts of up to `rate` events.
+    pub fn new(rate: u32) -> Self {
+        TokenBucket {
+            capacity: f64::from(rate),
+            rate: f64::from(rate),
+            tokens: f64::from(rate),
+            last_refill: Instant::now(),
+        }
+    }
+
+    // Function which takes a token if one is available, returning whether the event is permitted.
+    pub fn try_take(&mut self) -> bool {
+        self.refill();
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    // Function which returns how long until a token is available, zero if one is available now.
+    pub fn time_until_available(&mut self) -> Duration {
+        self.refill();
+        if self.tokens >= 1.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64((1.0 - self.tokens) / self.rate)
+        }
+    }
+
+    // Function which adds the tokens accumulated since the last refill, up to the capacity.
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.last_refill = now;
+    }
+}
@@ -0,0 +1,161 @@
+use crate::transport::ReadHalf;
+use futures::FutureExt;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::time::{Duration, Instant, timeout_at};
+
+/** Outcome of reading from a client: a complete line (without its delimiter), the end of
+* the stream, a line which grew past the byte limit before its delimiter arrived, or a line
+* which was started but not finished within the frame timeout.
+*/
+pub enum ReadLine {
+    Line(String),
+    TooLong,
+    TimedOut,
+    Eof,
+}
+
+/** Reader which splits the bytes sent by a client into newline-delimited lines, like
+* `BufReader::lines`, while checking the length of the current line as each chunk arrives.
+* This allows a client which never sends a delimiter to be detected as soon as it crosses
+* the limit, rather than buffering its input forever. Likewise, a client which starts a line
+* and then trickles its bytes can be detected once the line has taken longer than the frame timeout.
+*/
+pub struct LineReader {
+    reader: BufReader<ReadHalf>,
+    // bytes of the line currently being read, kept between calls so reading is cancel safe
+    buf: Vec<u8>,
+    max_line_bytes: Option<usize>,
+    // command prefix and the maximum length of a line starting with it, None when commands share the chat limit
+    commands: Option<(Vec<u8>, Option<usize>)>,
+    // longest time a line may take to arrive once its first byte has been read, unlimited when None
+    frame_timeout: Option<Duration>,
+    // time the first byte of the line currently being read arrived, None while no line is in progress
+    line_started: Option<Instant>,
+}
+
+impl LineReader {
+    // Constructor. Create a LineReader over the given read half, with optional limits on line length and on the time a line takes to arrive.
+    pub fn new(
+        read: ReadHalf,
+        max_line_bytes: Option<usize>,
+        frame_timeout: Option<Duration>,
+    ) -> Self {
+        LineReader {
+            reader: BufReader::new(read),
+            buf: Vec::new(),
+            max_line_bytes,
+            commands: None,
+            frame_timeout,
+            line_started: None,
+        }
+    }
+
+    /** Function which gives lines starting with the command prefix their own length limit (unlimited
+     * when None), in place of the chat limit. Lines starting with a doubled prefix are escaped chat
+     * messages, so they keep the chat limit.
+     */
+    pub fn with_command_limit(mut self, prefix: &str, max_command_bytes: Option<usize>) -> Self {
+        self.commands = Some((prefix.as_bytes().to_vec(), max_command_bytes));
+        self
+    }
+
+    /** Function which reads until the next newline, returning the line with any trailing
+     * "\r" removed, or TooLong as soon as the line exceeds max_line_bytes, or TimedOut as soon
+     * as a started line has taken longer than the frame timeout. A line which is not valid UTF-8
+     * is returned as an InvalidData error.
+     */
+    pub async fn next_line(&mut self) -> std::io::Result<ReadLine> {
+        loop {
+            let available = match (self.frame_timeout, self.line_started) {
+                // the rest of the line must arrive before the frame deadline
+                (Some(limit), Some(started)) => {
+                    match timeout_at(started + limit, self.reader.fill_buf()).await {
+                        Ok(available) => available?,
+                        Err(_) => {
+                            self.buf.clear();
+                            self.line_started = None;
+                            return Ok(ReadLine::TimedOut);
+                        }
+                    }
+                }
+                _ => self.reader.fill_buf().await?,
+            };
+            // end of stream, return any partial final line before reporting Eof
+            if available.is_empty() {
+                if self.buf.is_empty() {
+                    return Ok(ReadLine::Eof);
+                }
+                return self.take_line();
+            }
+            // copy bytes up to (and including) the delimiter, if one is available
+            let (chunk, found) = match available.iter().position(|b| *b == b'\n') {
+                Some(i) => (&available[..=i], true),
+                None => (available, false),
+            };
+            if self.buf.is_empty() {
+                self.line_started = Some(Instant::now());
+            }
+            self.buf.extend_from_slice(chunk);
+            let consumed = chunk.len();
+            self.reader.consume(consumed);
+            // the delimiter does not count towards the length of the line
+            let line_len = self.buf.len() - usize::from(found);
+            if self.line_limit().is_some_and(|max| line_len > max) {
+                self.buf.clear();
+                self.line_started = None;
+                return Ok(ReadLine::TooLong);
+            }
+            if found {
+                self.buf.pop();
+                return self.take_line();
+            }
+        }
+    }
+
+    /** Function which returns the next line if the client has already sent it in full, taking it from
+     * the bytes buffered by the last read rather than waiting for more, so several pipelined lines can be
+     * handled per wake-up. Returns None when the buffered bytes hold no complete line.
+     */
+    pub fn buffered_line(&mut self) -> Option<std::io::Result<ReadLine>> {
+        if !self.reader.buffer().contains(&b'\n') {
+            return None;
+        }
+        // a delimiter is buffered, so reading the line completes without waiting on the client
+        self.next_line().now_or_never()
+    }
+
+    /** Function which returns the length limit for the line currently being read: the command limit once
+     * the line is known to be a command, the chat limit once it is known not to be, and the larger of the
+     * two while its first bytes could still begin either.
+     */
+    fn line_limit(&self) -> Option<usize> {
+        let Some((prefix, max_command_bytes)) = &self.commands else {
+            return self.max_line_bytes;
+        };
+        let escaped = prefix.repeat(2);
+        if self.buf.starts_with(&escaped) {
+            self.max_line_bytes
+        } else if escaped.starts_with(&self.buf) {
+            // None is unlimited, so it is the larger of any two limits
+            self.max_line_bytes
+                .zip(*max_command_bytes)
+                .map(|(chat, command)| chat.max(command))
+        } else if self.buf.starts_with(prefix) {
+            *max_command_bytes
+        } else {
+            self.max_line_bytes
+        }
+    }
+
+    // Function which empties the line buffer, returning its contents as a line.
+    fn take_line(&mut self) -> std::io::Result<ReadLine> {
+        self.line_started = None;
+        if self.buf.last() == Some(&b'\r') {
+            self.buf.pop();
+        }
+        let bytes = std::mem::take(&mut self.buf);
+        String::from_utf8(bytes)
+            .map(ReadLine::Line)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+    }
+}
@@ -0,0 +1,104 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use tokio::sync::Notify;
+use tokio::time::{Duration, Instant};
+
+/** A chat message sent to a reliable client which it has not acknowledged yet: the line it was sent,
+* the number of times it has been resent, and the time by which it must be acknowledged or resent.
+*/
+struct Pending {
+    line: String,
+    resends: u32,
+    due: Instant,
+}
+
+/** Outcome of an unacknowledged message falling due: the line to send the client again, or the sequence
+* number of a message which was resent as often as allowed and has been given up on.
+*/
+pub enum Overdue {
+    Resend(String),
+    Failed(u64),
+}
+
+/** Chat messages sent to a reliable client (one which declared the `reliable` capability) that it has not yet
+* acknowledged with `ACK:RECV {SEQ}`, keyed by sequence number. Each is resent once the acknowledgement timeout
+* passes, the timeout doubling after every resend, until it is acknowledged or it has been resent the maximum
+* number of times. Messages are tracked by the broadcasts queueing them, and resent by the client's
+* retransmit task, which is woken whenever a message is tracked.
+*/
+pub struct Unacked {
+    pending: Mutex<BTreeMap<u64, Pending>>,
+    timeout: Duration,
+    retries: u32,
+    tracked: Notify,
+}
+
+impl Unacked {
+    // Constructor. Create an empty set of unacknowledged messages, resent after the given timeout up to the given number of times.
+    pub fn new(timeout: Duration, retries: u32) -> Self {
+        Unacked {
+            pending: Mutex::new(BTreeMap::new()),
+            timeout,
+            retries,
+            tracked: Notify::new(),
+        }
+    }
+
+    // Function which records that the message with the given seq was sent to the client as the given line.
+    pub fn track(&self, seq: u64, line: String) {
+        let due = Instant::now() + self.timeout;
+        self.pending.lock().unwrap().insert(
+            seq,
+            Pending {
+                line,
+                resends: 0,
+                due,
+            },
+        );
+        self.tracked.notify_one();
+    }
+
+    // Function which records the client's acknowledgement of the message with the given seq, returning whether it was awaited.
+    pub fn ack(&self, seq: u64) -> bool {
+        self.pending.lock().unwrap().remove(&seq).is_some()
+    }
+
+    // Function which returns the time at which the next message falls due, None when every message has been acknowledged.
+    pub fn next_due(&self) -> Option<Instant> {
+        self.pending
+            .lock()
+            .unwrap()
+            .values()
+            .map(|pending| pending.due)
+            .min()
+    }
+
+    /** Function which returns what to do about every message which has fallen due, oldest first: each one
+     * with resends left is to be resent (and is due again after twice its last wait), while the rest are
+     * given up on and forgotten.
+     */
+    pub fn overdue(&self) -> Vec<Overdue> {
+        let now = Instant::now();
+        let mut pending = self.pending.lock().unwrap();
+        let mut overdue = Vec::new();
+        pending.retain(|seq, message| {
+            if message.due > now {
+                return true;
+            }
+            if message.resends == self.retries {
+                overdue.push(Overdue::Failed(*seq));
+                return false;
+            }
+            message.resends += 1;
+            message.due = now + self.timeout * 2u32.pow(message.resends);
+            overdue.push(Overdue::Resend(message.line.clone()));
+            true
+        });
+        overdue
+    }
+
+    // Function which waits until a message is tracked, or returns at once if one was tracked since the last wait.
+    pub async fn wait_tracked(&self) {
+        self.tracked.notified().await;
+    }
+}
@@ -0,0 +1,105 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+// number of live lines a paced replay may hold back, beyond which further live lines are dropped
+const MAX_HELD: usize = 1024;
+
+/** Lines still to be delivered to a client by a paced history replay (see pace_replay): the rest of the
+* history being replayed, followed by the chat messages broadcast to the client since the replay started,
+* which are held back so they reach the client after the history, in order. The queue is None once the
+* replay has finished, after which broadcasts are queued for the client directly again.
+*/
+pub struct Replay {
+    queue: Mutex<Option<Pending>>,
+}
+
+// Lines a replay has left to deliver, along with how many of them are held back live lines rather than history.
+struct Pending {
+    lines: VecDeque<(Kind, String)>,
+    held: usize,
+}
+
+// Whether a line waiting in a replay is history, or a live line held back behind it.
+#[derive(PartialEq)]
+enum Kind {
+    History,
+    Live,
+}
+
+/** What became of a live line offered to a replay: handed back as the replay has already finished (so
+* it is to be queued as usual), held back until the history has been delivered, or dropped.
+*/
+pub enum Hold {
+    Finished(String),
+    Held,
+    Dropped,
+}
+
+impl Replay {
+    // Constructor. Create a replay which is to deliver the given lines of history.
+    pub fn new(lines: Vec<String>) -> Self {
+        let lines = lines
+            .into_iter()
+            .map(|line| (Kind::History, line))
+            .collect();
+        Replay {
+            queue: Mutex::new(Some(Pending { lines, held: 0 })),
+        }
+    }
+
+    /** Function which adds more history to the replay (such as when the client joins another room while
+     * it is still being replayed the last one's), returning false when the replay has already finished.
+     */
+    pub fn extend(&self, lines: Vec<String>) -> bool {
+        match self.queue.lock().unwrap().as_mut() {
+            Some(pending) => {
+                let lines = lines.into_iter().map(|line| (Kind::History, line));
+                pending.lines.extend(lines);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /** Function which holds back a live line broadcast to the client until the replay is over, or hands
+     * it back when the replay has already finished (see Hold). Only the live lines count towards the limit,
+     * however much history is left: a line arriving while MAX_HELD live lines are already held back is
+     * dropped, as it would be for a client lagging behind its room.
+     */
+    pub fn hold(&self, line: String) -> Hold {
+        let mut queue = self.queue.lock().unwrap();
+        let Some(pending) = queue.as_mut() else {
+            return Hold::Finished(line);
+        };
+        if pending.held >= MAX_HELD {
+            return Hold::Dropped;
+        }
+        pending.held += 1;
+        pending.lines.push_back((Kind::Live, line));
+        Hold::Held
+    }
+
+    /** Function which takes the next chunk of at most the given number of lines to deliver, oldest first.
+     * Once there is nothing left, the replay is finished (so no line can be held back after the last chunk
+     * is taken) and an empty chunk is returned.
+     */
+    pub fn next_chunk(&self, size: usize) -> Vec<String> {
+        let mut guard = self.queue.lock().unwrap();
+        let Some(pending) = guard.as_mut() else {
+            return Vec::new();
+        };
+        if pending.lines.is_empty() {
+            *guard = None;
+            return Vec::new();
+        }
+        let size = size.min(pending.lines.len());
+        let mut chunk = Vec::with_capacity(size);
+        for (kind, line) in pending.lines.drain(..size) {
+            if kind == Kind::Live {
+                pending.held -= 1;
+            }
+            chunk.push(line);
+        }
+        chunk
+    }
+}
@@ -0,0 +1,842 @@
+use crate::commands::is_protocol_error;
+use crate::config::{NickPolicy, OverflowPolicy};
+use crate::connection::Removal;
+use crate::events::Event;
+use crate::handle::SendReport;
+use crate::jsonlog::Level;
+use crate::message::Message;
+use crate::ratelimit::TokenBucket;
+use crate::reliable::{Overdue, Unacked};
+use crate::replay::{Hold, Replay};
+use crate::store::{Record, StoredMessage};
+use crate::writer::{Enqueued, Feed, Priority};
+use crate::{Connections, HistoryEntry, encode_for};
+use futures::StreamExt;
+use std::cell::OnceCell;
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::time::{Instant, sleep, sleep_until};
+
+// how long a broadcast waits for room in a recipient's queue before skipping that recipient
+const LAG_TIMEOUT: Duration = Duration::from_millis(250);
+// number of consecutive broadcasts a recipient may be skipped on before it is removed
+const MAX_LAG: u32 = 5;
+
+/** Function run by the presence task, which sends every client (with the presence capability) the IDs
+* of every connected client once per interval, as `PRESENCE:{id},{id},...`, so a client which missed
+* departures can correct its view. Like a broadcast, the snapshot is queued without holding the
+* connections lock, and is skipped for clients whose queue stays full, as the next one replaces it.
+*/
+pub async fn run_presence(connections: Arc<Mutex<Connections>>, interval: Duration) {
+    let mut ticks = tokio::time::interval(interval);
+    // the first tick completes immediately, and there is nothing to report as the server starts
+    ticks.tick().await;
+    loop {
+        ticks.tick().await;
+        let sends = connections.lock().await.presence();
+        for (feed, line) in sends {
+            feed.enqueue(line, LAG_TIMEOUT, OverflowPolicy::DropNewest)
+                .await;
+        }
+    }
+}
+
+/** Function run by the forwarding task of a client subscribed to lifecycle events, which queues each
+* event published from then on for the client. Like presence snapshots, an event is skipped while the
+* client's queue stays full, and the events a client falls too far behind on are missed. The task ends
+* once the client is being removed, which it notices on the event for its own removal at the latest.
+*/
+pub async fn forward_events(feed: Feed, mut events: tokio::sync::broadcast::Receiver<Event>) {
+    loop {
+        match events.recv().await {
+            Ok(event) => {
+                let enqueued = feed
+                    .enqueue(event.line(), LAG_TIMEOUT, OverflowPolicy::DropNewest)
+                    .await;
+                if let Enqueued::Removed | Enqueued::Closed = enqueued {
+                    break;
+                }
+            }
+            Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+        }
+    }
+}
+
+/** Function run by the retransmit task of a reliable client, which resends each chat message the client has
+* not acknowledged in time (see Unacked), skipping a resend while the client's queue stays full like a broadcast
+* would, and logs the messages given up on. The task ends once the client is being removed.
+*/
+pub async fn retransmit(c_id: u64, feed: Feed, unacked: Arc<Unacked>) {
+    loop {
+        let next_due = unacked.next_due();
+        tokio::select! {
+            _ = feed.removed() => break,
+            // a newly tracked message may fall due before the one being waited for
+            _ = unacked.wait_tracked() => continue,
+            _ = sleep_until(next_due.unwrap_or_else(Instant::now)), if next_due.is_some() => {}
+        }
+        for overdue in unacked.overdue() {
+            match overdue {
+                Overdue::Resend(line) => {
+                    let enqueued = feed
+                        .enqueue(line, LAG_TIMEOUT, OverflowPolicy::DropNewest)
+                        .await;
+                    if let Enqueued::Removed | Enqueued::Closed = enqueued {
+                        return;
+                    }
+                }
+                Overdue::Failed(seq) => {
+                    log_println!(
+                        "Message {} to client {} failed, never acknowledged",
+                        seq,
+                        c_id
+                    );
+                }
+            }
+        }
+    }
+}
+
+/** Function run by the task pacing a history replay (see replay_history), which delivers the rest of the
+* history and then the live lines held back behind it, a chunk at a time with a pause before each, until
+* there is nothing left. Like a broadcast, a line which finds the client's queue full is dealt with by the
+* overflow policy, which is drop-newest in place of disconnect, as the task cannot remove the client itself
+* (a client which never catches up is still removed under disconnect by the broadcasts after the replay). The
+* task ends early once the client is being removed.
+*/
+async fn pace_replay(
+    feed: Feed,
+    replay: Arc<Replay>,
+    chunk: usize,
+    pause: Duration,
+    policy: OverflowPolicy,
+) {
+    loop {
+        tokio::select! {
+            _ = feed.removed() => return,
+            _ = sleep(pause) => {}
+        }
+        let lines = replay.next_chunk(chunk);
+        if lines.is_empty() {
+            return;
+        }
+        for line in lines {
+            let enqueued = feed.enqueue(line, LAG_TIMEOUT, policy).await;
+            if let Enqueued::Removed | Enqueued::Closed = enqueued {
+                return;
+            }
+        }
+    }
+}
+
+/** Function which broadcasts a message line from the client with the given c_id to the other
+* clients in its room, and acknowledges it to the sender (see broadcast_except, which this delegates to
+* with only the sender excluded, or no one when self delivery is allowed). Each sender's messages reach
+* every recipient in the order they were sent, and under strict ordering all messages reach every
+* recipient in sequence number order, at the cost of fanning out one broadcast at a time. Returns the IDs
+* of the recipients whose copy of the message could not be encoded in their format, which are skipped.
+*/
+pub async fn broadcast(connections: &Mutex<Connections>, c_id: u64, line: String) -> Vec<u64> {
+    // under strict ordering, numbering the message, queueing it for every recipient and acknowledging it happen
+    // while holding the order lock, so messages are queued for every recipient in the order of their sequence numbers
+    let (order, self_delivery) = {
+        let connections = connections.lock().await;
+        (
+            connections.order.clone(),
+            connections.config.allow_self_delivery,
+        )
+    };
+    let _ordered = match &order {
+        Some(order) => Some(order.lock().await),
+        None => None,
+    };
+    let exclude = if self_delivery {
+        HashSet::new()
+    } else {
+        HashSet::from([c_id])
+    };
+    let Some(delivery) = broadcast_except(connections, c_id, &exclude, line).await else {
+        return Vec::new();
+    };
+    // Attempt to queue message acknowledgement for the sender, if it is still connected at this point
+    // the acknowledgement carries the message's sequence number, unless the old bare format is configured
+    let connections = connections.lock().await;
+    let ack = if connections.config.bare_ack {
+        "ACK:MESSAGE\n".to_string()
+    } else {
+        format!("ACK:MESSAGE {}\n", delivery.seq)
+    };
+    connections.send_to_client(c_id, &ack);
+    delivery.unencodable
+}
+
+/** Function which broadcasts a message line from the client with the given c_id to every client in
+* its room except those in the exclude set (which may include the sender itself), without acknowledging it
+* (see Connections::prepare_broadcast). The connections lock is only held while the broadcast is prepared
+* and while its outcome is recorded, not while waiting for room in the recipients' queues, so a slow
+* recipient no longer holds up every other client's messages (and commands) server-wide, only this
+* sender's. Returns what became of the message for each recipient, or None if it was rejected (see
+* Connections::prepare_broadcast) and nothing was broadcast. Embedders reach it through
+* ServerHandle::broadcast_except.
+*/
+pub(crate) async fn broadcast_except(
+    connections: &Mutex<Connections>,
+    c_id: u64,
+    exclude: &HashSet<u64>,
+    line: String,
+) -> Option<Delivery> {
+    let fanout = connections
+        .lock()
+        .await
+        .prepare_broadcast(c_id, exclude, line)?;
+    // every other client may be excluded (or the sender alone in the room), in which case there is nothing to fan out
+    let mut outcomes = Vec::new();
+    if !fanout.sends.is_empty() {
+        // write to several recipients at once, noting what became of the message for each
+        let sends: Vec<_> = fanout
+            .sends
+            .into_iter()
+            .map(|(id, feed, line)| deliver(id, feed, line, fanout.policy))
+            .collect();
+        outcomes = futures::stream::iter(sends)
+            .buffer_unordered(fanout.concurrency)
+            .collect()
+            .await;
+    }
+    connections
+        .lock()
+        .await
+        .finish_broadcast(c_id, &fanout.text, &outcomes);
+    Some(Delivery {
+        seq: fanout.seq,
+        outcomes,
+        unencodable: fanout.unencodable,
+    })
+}
+
+/** Result of a broadcast: the message's sequence number, what became of the message for each recipient
+* it was queued for, and the recipients whose copy could not be encoded in their format, which were skipped.
+*/
+pub(crate) struct Delivery {
+    seq: u64,
+    outcomes: Vec<(u64, Enqueued)>,
+    unencodable: Vec<u64>,
+}
+
+impl Delivery {
+    /** Function which reports the recipients the message was queued for (including those for which an
+     * older line was dropped to make room), and those it was not, along with the recipients it could not be
+     * encoded for. Recipients which were being removed appear in neither, like unknown clients in send_to.
+     */
+    pub(crate) fn report(self) -> SendReport {
+        let mut report = SendReport::default();
+        for (id, enqueued) in self.outcomes {
+            match enqueued {
+                Enqueued::Queued | Enqueued::DroppedOldest => report.delivered.push(id),
+                Enqueued::DroppedNewest
+                | Enqueued::Overflowed
+                | Enqueued::Shed
+                | Enqueued::Closed => report.failed.push(id),
+                Enqueued::Removed => {}
+            }
+        }
+        report.failed.extend(self.unencodable);
+        report
+    }
+}
+
+/** A broadcast prepared under the connections lock, to be fanned out once the lock is released: the
+* message's sequence number and text, each recipient's Feed along with its copy of the message (encoded in
+* its format), the recipients whose copy could not be encoded, and the fan-out settings.
+*/
+struct Fanout {
+    seq: u64,
+    text: String,
+    sends: Vec<(u64, Feed, String)>,
+    unencodable: Vec<u64>,
+    concurrency: usize,
+    policy: OverflowPolicy,
+}
+
+/** Function which queues a broadcast line for the recipient with the given c_id, returning the
+* c_id and what became of the line. If the recipient's queue stays full for LAG_TIMEOUT, the overflow
+* policy decides what is dropped rather than holding up the broadcast. A stopped writer is printed,
+* while a recipient being removed is skipped silently.
+*/
+async fn deliver(c_id: u64, feed: Feed, line: String, policy: OverflowPolicy) -> (u64, Enqueued) {
+    let enqueued = feed.enqueue(line, LAG_TIMEOUT, policy).await;
+    if let Enqueued::Closed = enqueued {
+        log_eprintln!("Failed to send data to client_id {}: channel closed", c_id);
+    }
+    (c_id, enqueued)
+}
+
+/** Implementation of message routing for the Connections struct.
+* Implements the functions preparing broadcasts and recording their outcome, and the functions queueing
+* lines for single clients, rooms and lists of clients, including the replay of room history.
+*/
+impl Connections {
+    /** Function which takes in c_id (client ID of the sender), the clients to leave out and a message
+     * line, and prepares its broadcast. It records the message in the history of the sender's room, then
+     * collects the clients currently in that room which the message is to be queued for: every client not
+     * in the exclude set (which leaves out the sender unless self delivery is allowed). The sends themselves happen once the lock
+     * is released, up to fanout_concurrency at once, and a recipient whose queue stays full is handled
+     * by the overflow policy. Messages from spectators are rejected with `ERR:READ_ONLY` instead, messages
+     * from clients without a nickname on a server requiring one with `ERR:NICK_REQUIRED`, and
+     * messages to a room which is over its aggregate rate limit with `ERR:ROOM_BUSY`, in which case
+     * there is nothing to broadcast. When rooms are disabled, every client is in the default room,
+     * which makes this a broadcast to every client without a separate code path.
+     */
+    fn prepare_broadcast(
+        &mut self,
+        c_id: u64,
+        exclude: &HashSet<u64>,
+        line: String,
+    ) -> Option<Fanout> {
+        // the sender's room determines both the history buffer and the recipients, a sender which
+        // has been removed in the meantime (e.g. kicked) has no room, so its message is dropped
+        let room = match self.cons.get(&c_id) {
+            Some(con) if con.read_only => {
+                self.send_to_client(c_id, "ERR:READ_ONLY\n");
+                return None;
+            }
+            // a server requiring nicknames only lets named clients chat
+            Some(con) if con.nick.is_none() && self.config.nick_policy == NickPolicy::Required => {
+                self.send_to_client(c_id, "ERR:NICK_REQUIRED\n");
+                return None;
+            }
+            Some(con) => {
+                log_println!("message {} {}", self.identity(c_id), line);
+                con.room.clone()
+            }
+            None => {
+                log_eprintln!("Dropping message from disconnected client_id {}", c_id);
+                return None;
+            }
+        };
+        self.last_activity.insert(c_id, Instant::now());
+        // a room receiving more messages than its aggregate rate allows drops the message, whoever sent it
+        if let Some(rate) = self.config.room_rate
+            && !self
+                .room_limits
+                .entry(room.clone())
+                .or_insert_with(|| TokenBucket::new(rate))
+                .try_take()
+        {
+            self.strike(c_id);
+            self.send_to_client(c_id, "ERR:ROOM_BUSY\n");
+            return None;
+        }
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        if let Some(log) = &self.json_log {
+            let addr = self.cons.get(&c_id).and_then(|con| con.addr);
+            let detail = format!("room={} seq={}", room, seq);
+            log.log(Level::Info, "message", Some(c_id), addr, Some(&detail));
+        }
+        let message = Message::Chat {
+            seq,
+            from: c_id,
+            sender: self.identity(c_id),
+            text: line.clone(),
+            hmac: self.signer.as_ref().map(|signer| signer.sign(&line)),
+            numbered: false,
+        };
+        let nick = self.cons.get(&c_id).and_then(|con| con.nick.clone());
+        self.persist(Record::Message(StoredMessage {
+            seq,
+            room: room.clone(),
+            from: c_id,
+            nick: nick.clone(),
+            text: line.clone(),
+        }));
+        let text = line.clone();
+        // push message to the room's history, discarding the oldest entry once the buffer is full
+        let history = self.history.entry(room.clone()).or_default();
+        history.push_back(HistoryEntry {
+            seq,
+            c_id,
+            nick,
+            text: line,
+        });
+        while history.len() > self.config.history_size {
+            history.pop_front();
+        }
+        let mut unencodable = Vec::new();
+        // recipients being replayed history, for which the message is held back until the replay is over
+        let mut held = Vec::new();
+        // number of those recipients for which too much is held back already, so the message is dropped
+        let mut dropped = 0;
+        // the recipients are the clients in the sender's room which are not excluded, nor in do not disturb mode
+        let recipients = self.cons.iter().filter(|(id, con)| {
+            !exclude.contains(id) && !con.subscribed && !con.dnd && con.in_room(&room)
+        });
+        // each recipient receives its own copy of the message, encoded in its chosen format, and a
+        // recipient whose copy cannot be encoded is skipped without affecting the others
+        let mut sends = Vec::new();
+        // copy of the message showing its sequence number, made for the first reliable recipient
+        let numbered = OnceCell::new();
+        for (id, con) in recipients {
+            // chat messages need no capability today, but the fan-out honours the recipient's like any other send
+            let Some(message) = con.version_of(&message, None) else {
+                continue;
+            };
+            // reliable recipients are shown the sequence number they acknowledge the message with, and it is
+            // tracked from now on, so it is resent even if this copy is dropped for lagging
+            let message = match con.unacked {
+                Some(_) => numbered.get_or_init(|| message.numbered()),
+                None => message,
+            };
+            match encode_for(message, *id, con.format, self.config.newline_policy) {
+                Some(line) => {
+                    if let Some(unacked) = &con.unacked {
+                        unacked.track(seq, line.clone());
+                    }
+                    // live messages wait behind a paced replay, so they reach the client after the history
+                    let line = match &con.replay {
+                        Some(replay) => match replay.hold(line) {
+                            Hold::Finished(line) => line,
+                            Hold::Held => {
+                                held.push(*id);
+                                continue;
+                            }
+                            Hold::Dropped => {
+                                dropped += 1;
+                                continue;
+                            }
+                        },
+                        None => line,
+                    };
+                    sends.push((*id, con.outbox.feed(), line))
+                }
+                None => unencodable.push(*id),
+            }
+        }
+        self.dropped += dropped;
+        // held back messages are kept for /recall now, as they leave no outcome to record once fanned out
+        for id in held {
+            if let Some(con) = self.cons.get_mut(&id) {
+                con.remember(c_id, &text);
+            }
+        }
+        Some(Fanout {
+            seq,
+            text,
+            sends,
+            unencodable,
+            concurrency: self.config.fanout_concurrency,
+            policy: self.config.overflow_policy,
+        })
+    }
+
+    /** Function which completes a broadcast once it has been fanned out, keeping the message for /recall
+     * by each recipient it was queued for and recording which recipients lagged.
+     */
+    fn finish_broadcast(&mut self, c_id: u64, text: &str, outcomes: &[(u64, Enqueued)]) {
+        for (id, enqueued) in outcomes {
+            if let Enqueued::Queued | Enqueued::DroppedOldest = enqueued
+                && let Some(con) = self.cons.get_mut(id)
+            {
+                con.remember(c_id, text);
+            }
+        }
+        self.record_lag(outcomes);
+    }
+
+    /** Function which updates the lag count of each recipient of a broadcast, given what became of the
+     * message for it, and counts the lines dropped by the overflow policy. A successful send resets the
+     * count, while a recipient for which the message itself was dropped on MAX_LAG consecutive messages
+     * is removed, as it is not keeping up with its room, as is a recipient whose queue overflowed
+     * under the disconnect policy.
+     */
+    fn record_lag(&mut self, outcomes: &[(u64, Enqueued)]) {
+        for &(id, ref enqueued) in outcomes {
+            let Some(con) = self.cons.get_mut(&id) else {
+                continue;
+            };
+            match enqueued {
+                Enqueued::Queued => con.lag_count = 0,
+                // the recipient still gets the newest messages, so it is never removed for lagging
+                Enqueued::DroppedOldest => self.dropped += 1,
+                Enqueued::DroppedNewest
+                    if self.config.overflow_policy == OverflowPolicy::DropOldest =>
+                {
+                    self.dropped += 1
+                }
+                Enqueued::DroppedNewest => {
+                    self.dropped += 1;
+                    con.lag_count += 1;
+                    if con.lag_count >= MAX_LAG {
+                        log_println!("Client {} removed for lagging", self.identity(id));
+                        self.remove_client(id, Removal::Lagging);
+                    }
+                }
+                Enqueued::Overflowed => {
+                    self.overflow_disconnects += 1;
+                    log_println!(
+                        "Client {} removed for overflowing its queue",
+                        self.identity(id)
+                    );
+                    self.remove_client(id, Removal::Lagging);
+                }
+                // shedding protects the server as a whole, it says nothing about whether this recipient keeps up
+                Enqueued::Shed | Enqueued::Removed | Enqueued::Closed => {}
+            }
+        }
+    }
+
+    /** Function which builds the presence snapshot (the IDs of every connected client), returning it
+     * encoded for each client with the presence capability, along with the feed it is queued through.
+     */
+    fn presence(&self) -> Vec<(Feed, String)> {
+        let snapshot = Message::Presence {
+            clients: self.client_ids(),
+        };
+        self.cons
+            .iter()
+            .filter(|(_, con)| con.accepts(&snapshot))
+            .filter_map(|(id, con)| {
+                encode_for(&snapshot, *id, con.format, self.config.newline_policy)
+                    .map(|line| (con.outbox.feed(), line))
+            })
+            .collect()
+    }
+
+    /** Function which sends a server announcement to every connected client, regardless of
+     * room. Announcements are not recorded in history and are not acknowledged like messages,
+     * and are sent with high priority so they are written ahead of queued messages.
+     */
+    pub fn announce(&mut self, text: &str) {
+        let announcement = Message::Announce {
+            text: text.to_string(),
+        };
+        for con in self.cons.iter() {
+            let Some(line) = encode_for(
+                &announcement,
+                *con.0,
+                con.1.format,
+                self.config.newline_policy,
+            ) else {
+                continue;
+            };
+            if let Err(e) = con.1.outbox.try_send(Priority::High, line) {
+                log_eprintln!("Failed to send data to client_id {}: {}", con.0, e);
+            }
+        }
+    }
+
+    /** Function which writes the recent messages of the client's current room to that
+     * client, oldest first, each as a `HISTORY:{CLIENT_ID} {MESSAGE}` line. The first replay_chunk
+     * lines are queued at once (as far as the client's queue has room), and the rest are delivered by a
+     * pace_replay task in chunks of that size, so a long replay neither floods the client nor holds the
+     * connections lock while its queue is full.
+     * Chat messages broadcast to the client in the meantime are held back until the replay is over.
+     */
+    pub fn replay_history(&mut self, c_id: u64) {
+        let Some(con) = self.cons.get(&c_id) else {
+            return;
+        };
+        let Some(history) = self.history.get(&con.room) else {
+            return;
+        };
+        let mut lines = Vec::new();
+        for entry in history {
+            // a client which asked not to be replayed its own messages skips those it sent, whether
+            // during this connection or under its current nickname during an earlier one
+            let own = entry.c_id == c_id || (entry.nick.is_some() && entry.nick == con.nick);
+            if own && !con.replay_own {
+                continue;
+            }
+            let message = Message::History {
+                seq: entry.seq,
+                from: entry.c_id,
+                text: entry.text.clone(),
+            };
+            // history is only replayed to clients which understand it
+            if !con.accepts(&message) {
+                return;
+            }
+            if let Some(line) = encode_for(&message, c_id, con.format, self.config.newline_policy) {
+                lines.push(line);
+            }
+        }
+        // a replay still in progress (from the room the client was in before) delivers this one after its own lines
+        if let Some(replay) = &con.replay
+            && replay.extend(std::mem::take(&mut lines))
+        {
+            return;
+        }
+        let rest = lines.split_off(self.config.replay_chunk.min(lines.len()));
+        for line in lines {
+            if let Err(e) = con.outbox.try_send(Priority::Normal, line) {
+                log_eprintln!("Failed to send data to client_id {}: {}", c_id, e);
+                return;
+            }
+        }
+        if rest.is_empty() {
+            return;
+        }
+        let replay = Arc::new(Replay::new(rest));
+        let pause = Duration::from_millis(self.config.replay_pause_ms);
+        let policy = match self.config.overflow_policy {
+            OverflowPolicy::Disconnect => OverflowPolicy::DropNewest,
+            policy => policy,
+        };
+        tokio::spawn(pace_replay(
+            con.outbox.feed(),
+            Arc::clone(&replay),
+            self.config.replay_chunk,
+            pause,
+            policy,
+        ));
+        if let Some(con) = self.cons.get_mut(&c_id) {
+            con.replay = Some(replay);
+        }
+    }
+
+    /** Function which queues the message for each of the listed clients, regardless of their room,
+     * each encoded in the recipient's chosen format. Unknown client IDs are skipped. Returns the IDs of
+     * the clients the message was queued for, and of those for which queueing failed.
+     */
+    pub fn send_to(&self, targets: &[u64], message: Message) -> (Vec<u64>, Vec<u64>) {
+        let mut delivered = Vec::new();
+        let mut failed = Vec::new();
+        for id in targets {
+            let Some(con) = self.cons.get(id).filter(|con| con.accepts(&message)) else {
+                continue;
+            };
+            let Some(line) = encode_for(&message, *id, con.format, self.config.newline_policy)
+            else {
+                failed.push(*id);
+                continue;
+            };
+            match con.outbox.try_send(Priority::Normal, line) {
+                Ok(()) => delivered.push(*id),
+                Err(e) => {
+                    log_eprintln!("Failed to send data to client_id {}: {}", id, e);
+                    failed.push(*id);
+                }
+            }
+        }
+        (delivered, failed)
+    }
+
+    // Function which delivers a private message from the client with the given c_id to the given recipients, returning the acknowledgement.
+    pub fn send_private(&mut self, c_id: u64, mut targets: Vec<u64>, text: &str) -> String {
+        // like a broadcast, a client's own message is not delivered back to it unless self delivery is allowed
+        if !self.config.allow_self_delivery {
+            targets.retain(|target| *target != c_id);
+        }
+        let message = Message::Private {
+            from: c_id,
+            text: text.to_string(),
+        };
+        let (delivered, _) = self.send_to(&targets, message);
+        for id in delivered.iter() {
+            if let Some(con) = self.cons.get_mut(id) {
+                con.remember(c_id, text);
+            }
+        }
+        format!("ACK:MULTICAST {}\n", delivered.len())
+    }
+
+    /** Function which queues the message for every client in the given room except the client
+     * with the given c_id, each encoded in the recipient's chosen format. Clients without the
+     * capability the message needs are sent the fallback instead, or nothing when there is none.
+     */
+    pub fn send_to_room(
+        &self,
+        room: &str,
+        except: u64,
+        message: &Message,
+        fallback: Option<&Message>,
+    ) {
+        self.send_to_rooms(&[room], except, message, fallback);
+    }
+
+    /** Function which queues the message for every client in any of the given rooms except the client
+     * with the given c_id, like send_to_room, with each client sent a single copy however many of the
+     * rooms it is in.
+     */
+    fn send_to_rooms(
+        &self,
+        rooms: &[&str],
+        except: u64,
+        message: &Message,
+        fallback: Option<&Message>,
+    ) {
+        for (id, con) in self.cons.iter() {
+            if *id == except || !rooms.iter().any(|room| con.in_room(room)) {
+                continue;
+            }
+            let Some(message) = con.version_of(message, fallback) else {
+                continue;
+            };
+            let Some(line) = encode_for(message, *id, con.format, self.config.newline_policy)
+            else {
+                continue;
+            };
+            if let Err(e) = con.outbox.try_send(Priority::Normal, line) {
+                log_eprintln!("Failed to send data to client_id {}: {}", id, e);
+            }
+        }
+    }
+
+    /** Function which tells every other client in any of the given rooms of a change in the presence of the
+     * client with the given c_id, like send_to_rooms: clients which chose inline events are sent the change
+     * as `SYSTEM:{TEXT}`, and the rest the structured message for it, if there is one.
+     */
+    pub fn send_presence(
+        &self,
+        rooms: &[&str],
+        c_id: u64,
+        structured: Option<&Message>,
+        text: &str,
+    ) {
+        let system = Message::System {
+            text: text.to_string(),
+        };
+        for (id, con) in self.cons.iter() {
+            if *id == c_id || !rooms.iter().any(|room| con.in_room(room)) {
+                continue;
+            }
+            let message = match structured {
+                _ if con.inline_events => &system,
+                Some(structured) if con.accepts(structured) => structured,
+                _ => continue,
+            };
+            let Some(line) = encode_for(message, *id, con.format, self.config.newline_policy)
+            else {
+                continue;
+            };
+            if let Err(e) = con.outbox.try_send(Priority::Normal, line) {
+                log_eprintln!("Failed to send data to client_id {}: {}", id, e);
+            }
+        }
+    }
+
+    /** Function which queues a single line for the client with the given c_id, printing
+     * an error if the client's writer task has stopped or its queue is full.
+     */
+    pub fn send_to_client(&self, c_id: u64, line: &str) {
+        self.send_with_priority(c_id, Priority::Normal, line);
+    }
+
+    /** Function which queues a system notice (e.g. a warning or the reason for a disconnect)
+     * for the client with the given c_id, ahead of any messages still waiting to be written.
+     */
+    pub fn send_notice(&self, c_id: u64, line: &str) {
+        self.send_with_priority(c_id, Priority::High, line);
+    }
+
+    /** Function which queues a single line for the client with the given c_id and priority,
+     * encoded in the client's chosen format.
+     */
+    fn send_with_priority(&self, c_id: u64, priority: Priority, line: &str) {
+        // replies to a tagged command carry its tag, after the rest of the reply (ex. `ACK:NICK alice [7]`)
+        let tagged;
+        let line = match self
+            .cons
+            .get(&c_id)
+            .and_then(|con| con.reply_tag.as_deref())
+        {
+            Some(tag) if line.starts_with("ACK:") || line.starts_with("ERR:") => {
+                tagged = format!("{} [{}]\n", line.trim_end_matches('\n'), tag);
+                &tagged
+            }
+            _ => line,
+        };
+        if let Some(con) = self.cons.get(&c_id)
+            && self.config.strict
+            && is_protocol_error(line)
+        {
+            con.violated.store(true, Ordering::Relaxed);
+        }
+        if let Some(con) = self.cons.get(&c_id)
+            && let Some(line) = encode_for(
+                &Message::reply(line),
+                c_id,
+                con.format,
+                self.config.newline_policy,
+            )
+            && let Err(e) = con.outbox.try_send(priority, line)
+        {
+            log_eprintln!("Failed to send data to client_id {}: {}", c_id, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::ServerConfig;
+    use crate::{Extensions, run_server_with};
+    use std::time::Duration;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, DuplexStream};
+
+    // Function which reads the next line the server sends over the stream, without its newline.
+    async fn line(stream: &mut BufReader<DuplexStream>) -> String {
+        let mut line = String::new();
+        let read = stream.read_line(&mut line);
+        tokio::time::timeout(Duration::from_secs(5), read)
+            .await
+            .unwrap()
+            .unwrap();
+        line.trim_end_matches('\n').to_string()
+    }
+
+    #[tokio::test]
+    async fn recipient_which_cannot_be_encoded_for_is_skipped() {
+        let (streams, in_memory) = tokio::sync::mpsc::unbounded_channel();
+        let config = ServerConfig {
+            bind: "127.0.0.1:0".parse().unwrap(),
+            handshake: true,
+            disconnect_grace: None,
+            ..ServerConfig::default()
+        };
+        let extensions = Extensions {
+            in_memory: Some(in_memory),
+            ..Extensions::default()
+        };
+        tokio::spawn(async move {
+            let _ = run_server_with(config, extensions).await;
+        });
+        let mut clients = Vec::new();
+        for format in ["text", "unencodable", "json"] {
+            let (mut stream, server_end) = tokio::io::duplex(4096);
+            streams.send(server_end).unwrap();
+            let login = format!("FORMAT {}\nLOGIN\n", format);
+            stream.write_all(login.as_bytes()).await.unwrap();
+            clients.push(BufReader::new(stream));
+        }
+        let [sender, unencodable, other] = &mut clients[..] else {
+            unreachable!()
+        };
+        assert!(line(sender).await.starts_with("CAPS:"));
+        assert_eq!(line(sender).await, "LOGIN:1");
+        assert!(line(unencodable).await.starts_with("CAPS:"));
+        assert_eq!(line(unencodable).await, "LOGIN:2");
+        assert!(line(other).await.starts_with("CAPS:"));
+        assert!(
+            line(other)
+                .await
+                .contains(r#""type":"reply","line":"LOGIN:3""#)
+        );
+        // every client is logged in once the sender is listed alongside them
+        sender.get_mut().write_all(b"/roster\n").await.unwrap();
+        assert_eq!(line(sender).await, "ROSTER:1:lobby: 2:lobby: 3:lobby:");
+
+        // the client whose copy cannot be encoded is skipped, while everyone else gets the message
+        sender.get_mut().write_all(b"hello\n").await.unwrap();
+        assert_eq!(line(sender).await, "ACK:MESSAGE 1");
+        let received = line(other).await;
+        assert!(received.contains(r#""type":"chat""#), "{}", received);
+        assert!(received.contains(r#""text":"hello""#), "{}", received);
+        // which carries on receiving the lines which can be encoded for it
+        unencodable.get_mut().write_all(b"/ping\n").await.unwrap();
+        assert_eq!(line(unencodable).await, "PONG:");
+    }
+}
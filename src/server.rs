@@ -1,139 +1,36 @@
-use std::collections::HashMap;
-use std::sync::Arc;
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
-use tokio::net::tcp::OwnedWriteHalf;
-use tokio::sync::Mutex;
+use rust_broadcast_server::config::ServerConfig;
+use rust_broadcast_server::{AddrInUse, run_server};
+use std::process::ExitCode;
 
-/** Main function, it will call and await on the run_server function, and, in the
-* case of any error in running the server, it will print the error.
+/** Main function, it will parse the command line arguments into a ServerConfig, then
+* call and await on the run_server function, and, in the case of any error in parsing
+* the arguments or running the server, it will print the error. A port which is already in use is
+* reported on its own, along with how to choose another one. A clean shutdown prints its summary and
+* exits with status 0, while any error exits with status 1, so supervisors can tell them apart.
 */
 #[tokio::main]
-async fn main() {
-    if let Err(e) = run_server().await {
-        eprintln!("Error running server: {}", e);
-    }
-}
-
-/**
-* Function to be called by main. This is where connections to ther server are
-* validated and assigned their own thread. Uses tokio::spawn to create asynchronous threads.
-*/
-async fn run_server() -> Result<(), Box<dyn std::error::Error>> {
-    // establish Connections variable, which maintains a hashmap of client_id to OwnedWriteHalf for all currently connected clients
-    let connections = Arc::new(Mutex::new(Connections::new()));
-    // establish a TcpListener which is bound to localhost port 8888
-    let listener = tokio::net::TcpListener::bind("127.0.0.1:8888")
-        .await
-        .unwrap();
-    println!("listening on port 8888");
-    // loop to continually accept/connect to clients connecting to 127.0.0.1:8888
-    loop {
-        // wait until a socket connects to port
-        let (mut stream, socket_addr) = listener.accept().await.unwrap();
-        // assign client id to be the port of the socket address
-        let c_id = socket_addr.port();
-        // clone connections via Arc so you are free to pass it in as an argument to handle_connection
-        let connections = Arc::clone(&connections);
-        println!("connected {} {}", socket_addr.ip(), c_id);
-        // write login_msg to recently added connection
-        let login_msg = format!("LOGIN:{}\n", c_id);
-        if let Err(e) = stream.write(login_msg.as_bytes()).await {
-            println!("Client {} exited", c_id);
-            continue;
+async fn main() -> ExitCode {
+    let config = match ServerConfig::from_args(std::env::args().skip(1)) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Error parsing arguments: {}", e);
+            return ExitCode::FAILURE;
         }
-        // spawn a thread to asynchronously manage this socket/connection
-        tokio::spawn(async move {
-            handle_connection(stream, connections, c_id).await;
-        });
-    }
-}
-
-/**
-* Function which reads in messages from each client. When a message is recieved
-* an acknowledgement is sent to the client which sent the message, and the message
-* (as well as the client_id of the sender) is sent to all currently connected clients.
-*/
-async fn handle_connection(
-    stream: tokio::net::TcpStream,
-    connections: Arc<Mutex<Connections>>,
-    c_id: u16,
-) {
-    //split TcpStream into OwnedReadHalf and OwnedWriteHalf
-    let (read, write) = stream.into_split();
-    //acquire lock for connections, and insert mapping of client ID to OwnedWriteHalf for this client
-    connections.lock().await.cons.insert(c_id, write);
-    // create reader from which to read in messages (as lines) from the client
-    let reader = tokio::io::BufReader::new(read);
-    let mut read = reader.lines();
-    // loop to continually read next line/message sent from this client
-    loop {
-        // wait until next line/message sent by client, and store message in this variable
-        let line_result = read.next_line().await;
-        match line_result {
-            //Got a new line/message
-            Ok(Some(line)) => {
-                //if (and only if) the message is not None, broadcast it to all other clients and send sender an acknowledgement
-                println!("message {} {}", c_id, line);
-                let mut connections = connections.lock().await;
-                let msg = format!("MESSAGE:{} {}\n", c_id, line);
-                //broadcast function handles both broadcasting message to all others and sending acknowldegemnt to sender
-                connections.broadcast(c_id, msg).await;
-            }
-            //Got Poll::Ready(None) or got an error (e.g. client disconnected) remover client from map of connections
-            Ok(None) | Err(_) => {
-                //acquire lock on connections
-                let mut connections = connections.lock().await;
-                //remove current client from connections
-                connections.cons.remove(&c_id);
-                //break from loop to stop awating messages from disconnected client
-                break;
-            }
+    };
+    match run_server(config).await {
+        Ok(summary) => {
+            println!("{}", summary);
+            ExitCode::SUCCESS
         }
-    }
-}
-
-/**Struct to maintain mapping of client IDs to corresponding OwnedWriteHalf to
-* enable message broadcasting. Struct is used to manage ownership via Arc and Mutex.
-*/
-struct Connections {
-    cons: HashMap<u16, OwnedWriteHalf>,
-}
-
-/** Implementation for Connections struct.
-* Implements constructor function, fn new().
-* Implements function used to broadcast specified messages from specified clients.
-* Implements function to send login acknowledgement to specified client.
-*/
-impl Connections {
-    // Constructor. Create a new, empty, instance of `Connections`.
-    fn new() -> Self {
-        Connections {
-            cons: HashMap::new(),
-        }
-    }
-
-    /** Function which takes in c_id (client ID of the sender) and a string message.
-     * It iterates through all currently connected clients (i.e. those with mappings contained in self.cons)
-     * and writes through the corresponding OwnedWriteHalf either a message acknowledgement (to the sender)
-     * or the message (to all other clients).
-     */
-    async fn broadcast(&mut self, c_id: u16, message: String) {
-        // for each connection/mapping in the map for connections
-        for con in self.cons.iter_mut() {
-            // if the client id of the mapping is not the client id of the message sender
-            if *con.0 != c_id {
-                // Attempt to write message to this connection via corresponding OwnedWriteHalf, print error if any occur
-                if let Err(e) = con.1.write(message.as_bytes()).await {
-                    eprintln!("Failed to send data to client_id {}: {}", con.0, e);
-                }
-            }
-            // Otherwise, the client id matches and this is the client that sent the message
-            else {
-                // Attempt to write message acknowledgement via corresponding OwnedWriteHalf, print error if any occur
-                if let Err(e) = con.1.write(b"ACK:MESSAGE\n").await {
-                    eprintln!("Failed to send data to client_id {}: {}", c_id, e);
-                }
+        Err(e) => {
+            match e.downcast_ref::<AddrInUse>() {
+                Some(in_use) => eprintln!(
+                    "Error: {}, choose another port with --port (or pass --auto-port to use any free port)",
+                    in_use
+                ),
+                None => eprintln!("Error running server: {}", e),
             }
+            ExitCode::FAILURE
         }
     }
 }
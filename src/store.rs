@@ -0,0 +1,312 @@
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::fs::File;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+// number of consecutive failed writes after which the file store stops writing
+const MAX_FAILURES: u32 = 3;
+
+/** A chat message as kept by a MessageStore: its sequence number, the room it was sent to, the ID and
+* nickname (if it had one) of its sender, and its text.
+*/
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct StoredMessage {
+    pub seq: u64,
+    pub room: String,
+    pub from: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nick: Option<String>,
+    pub text: String,
+}
+
+/** A change to the stored messages: a message broadcast, or one of them edited or deleted. The server
+* hands these to the store's task in the order they happen, and the file store writes each as a line of JSON.
+*/
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum Record {
+    Message(StoredMessage),
+    Edit { seq: u64, text: String },
+    Delete { seq: u64 },
+}
+
+/** Trait for the backends the server keeps chat messages in, so the broadcast path does not depend on
+* how (or whether) they are stored. Every message broadcast is appended, and every edit and deletion
+* recorded, in the order they happen, and the most recent messages are read back when the server starts,
+* restoring each room's history. A store handles its own failures, as a message which cannot be stored is
+* never a reason to stop broadcasting it, and reports whether it is still storing them in `/stats`.
+*/
+pub trait MessageStore: Send + Sync {
+    // Stores the message, once every change made before it has been stored.
+    fn append<'a>(&'a self, message: &'a StoredMessage) -> BoxFuture<'a, ()>;
+
+    // Replaces the text of the stored message with the given sequence number, if it is still stored.
+    fn edit<'a>(&'a self, seq: u64, text: &'a str) -> BoxFuture<'a, ()>;
+
+    // Removes the stored message with the given sequence number, if it is still stored.
+    fn delete(&self, seq: u64) -> BoxFuture<'_, ()>;
+
+    // Returns up to the given number of the most recently appended messages still stored, as edited, oldest first.
+    fn recent(&self, n: usize) -> BoxFuture<'_, Vec<StoredMessage>>;
+
+    // Returns whether the store is still storing what it is handed, ex. whether its last write succeeded.
+    fn is_healthy(&self) -> bool {
+        true
+    }
+}
+
+/** MessageStore keeping the last messages in memory, up to its capacity, used when no other store is
+* configured. Its messages only outlive the server when an embedder runs the server again with the same store.
+*/
+pub struct MemoryStore {
+    messages: Mutex<VecDeque<StoredMessage>>,
+    capacity: usize,
+}
+
+impl MemoryStore {
+    // Constructor. Create an empty store keeping up to the given number of messages, dropping the oldest beyond it.
+    pub fn new(capacity: usize) -> Self {
+        MemoryStore {
+            messages: Mutex::new(VecDeque::new()),
+            capacity,
+        }
+    }
+}
+
+impl MessageStore for MemoryStore {
+    fn append<'a>(&'a self, message: &'a StoredMessage) -> BoxFuture<'a, ()> {
+        Box::pin(async move {
+            let mut messages = self.messages.lock().await;
+            apply(
+                &mut messages,
+                Record::Message(message.clone()),
+                self.capacity,
+            );
+        })
+    }
+
+    fn edit<'a>(&'a self, seq: u64, text: &'a str) -> BoxFuture<'a, ()> {
+        Box::pin(async move {
+            let edit = Record::Edit {
+                seq,
+                text: text.to_string(),
+            };
+            apply(&mut *self.messages.lock().await, edit, self.capacity);
+        })
+    }
+
+    fn delete(&self, seq: u64) -> BoxFuture<'_, ()> {
+        Box::pin(async move {
+            let delete = Record::Delete { seq };
+            apply(&mut *self.messages.lock().await, delete, self.capacity);
+        })
+    }
+
+    fn recent(&self, n: usize) -> BoxFuture<'_, Vec<StoredMessage>> {
+        Box::pin(async move { most_recent(&*self.messages.lock().await, n) })
+    }
+}
+
+/** Function which applies the change to the messages, oldest first: a message is added (dropping the
+* oldest beyond the capacity), and an edit or deletion changes the message it names, if it is still kept.
+*/
+fn apply(messages: &mut VecDeque<StoredMessage>, record: Record, capacity: usize) {
+    match record {
+        Record::Message(message) => {
+            messages.push_back(message);
+            while messages.len() > capacity {
+                messages.pop_front();
+            }
+        }
+        Record::Edit { seq, text } => {
+            if let Some(message) = messages.iter_mut().find(|message| message.seq == seq) {
+                message.text = text;
+            }
+        }
+        Record::Delete { seq } => messages.retain(|message| message.seq != seq),
+    }
+}
+
+// Function which returns up to the given number of the last of the messages, oldest first.
+fn most_recent(messages: &VecDeque<StoredMessage>, n: usize) -> Vec<StoredMessage> {
+    messages
+        .iter()
+        .skip(messages.len().saturating_sub(n))
+        .cloned()
+        .collect()
+}
+
+/** MessageStore appending every message, edit and deletion to a file as a line of JSON, so messages outlive
+* the server. Like a MemoryStore it keeps the last messages up to its capacity, which it reads back from
+* the file when opened and answers recent() from, and the file is compacted down to those messages (as
+* edited) when opened and whenever it has grown to twice the capacity, so neither the file nor startup
+* grows with the total history. Failed writes are logged and mark the store unhealthy, and after
+* MAX_FAILURES consecutive failures the file is no longer written to. Malformed lines are skipped when reading.
+*/
+pub struct FileStore {
+    path: PathBuf,
+    log: Mutex<Log>,
+    capacity: usize,
+    healthy: AtomicBool,
+}
+
+/** The file of a FileStore along with the messages it keeps, the number of lines in the file and the
+* number of consecutive writes to it which have failed.
+*/
+struct Log {
+    file: File,
+    messages: VecDeque<StoredMessage>,
+    lines: usize,
+    failures: u32,
+}
+
+impl FileStore {
+    /** Constructor. Open (or create) the file at the given path, reading back the last of the messages in it up
+     * to the given capacity, and compacting it if it holds anything more. Fails if it cannot be read or opened.
+     */
+    pub fn open(path: &Path, capacity: usize) -> std::io::Result<Self> {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e),
+        };
+        let mut messages = VecDeque::new();
+        let mut lines = 0;
+        for line in contents.lines().filter(|line| !line.is_empty()) {
+            lines += 1;
+            match parse_record(line) {
+                Ok(record) => apply(&mut messages, record, capacity),
+                Err(e) => log_eprintln!("Skipping malformed stored record: {}", e),
+            }
+        }
+        if lines > messages.len() {
+            std::fs::write(compacted_path(path), compacted(&messages))?;
+            std::fs::rename(compacted_path(path), path)?;
+            lines = messages.len();
+        }
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        let log = Log {
+            file: File::from_std(file),
+            messages,
+            lines,
+            failures: 0,
+        };
+        Ok(FileStore {
+            path: path.to_path_buf(),
+            log: Mutex::new(log),
+            capacity,
+            healthy: AtomicBool::new(true),
+        })
+    }
+
+    /** Function which applies the change to the messages kept and appends it to the file, compacting
+     * the file once it has grown to twice the capacity, tracking whether writes succeed until
+     * MAX_FAILURES consecutive writes have failed.
+     */
+    async fn write(&self, record: Record) {
+        let mut log = self.log.lock().await;
+        // serializing plain integers and strings cannot fail
+        let line = format!("{}\n", serde_json::to_string(&record).unwrap());
+        apply(&mut log.messages, record, self.capacity);
+        if log.failures == MAX_FAILURES {
+            return;
+        }
+        let written = if log.lines + 1 >= self.capacity.max(1) * 2 {
+            self.compact(&mut log).await
+        } else {
+            match log.file.write_all(line.as_bytes()).await {
+                Ok(()) => log.file.flush().await.map(|()| log.lines += 1),
+                Err(e) => Err(e),
+            }
+        };
+        match written {
+            Ok(()) => {
+                log.failures = 0;
+                self.healthy.store(true, Ordering::Relaxed);
+            }
+            Err(e) => {
+                log.failures += 1;
+                self.healthy.store(false, Ordering::Relaxed);
+                log_eprintln!("Failed to write to the message store: {}", e);
+                if log.failures == MAX_FAILURES {
+                    log_eprintln!("Message store disabled after {} failures", log.failures);
+                }
+            }
+        }
+    }
+
+    /** Function which replaces the file with one holding only the messages kept (which already include
+     * the change being written), written alongside it and renamed over it, then appends to the new file.
+     */
+    async fn compact(&self, log: &mut Log) -> std::io::Result<()> {
+        let compacted_path = compacted_path(&self.path);
+        tokio::fs::write(&compacted_path, compacted(&log.messages)).await?;
+        tokio::fs::rename(&compacted_path, &self.path).await?;
+        let file = tokio::fs::OpenOptions::new()
+            .append(true)
+            .open(&self.path)
+            .await?;
+        log.file = file;
+        log.lines = log.messages.len();
+        Ok(())
+    }
+}
+
+impl MessageStore for FileStore {
+    fn append<'a>(&'a self, message: &'a StoredMessage) -> BoxFuture<'a, ()> {
+        Box::pin(self.write(Record::Message(message.clone())))
+    }
+
+    fn edit<'a>(&'a self, seq: u64, text: &'a str) -> BoxFuture<'a, ()> {
+        Box::pin(self.write(Record::Edit {
+            seq,
+            text: text.to_string(),
+        }))
+    }
+
+    fn delete(&self, seq: u64) -> BoxFuture<'_, ()> {
+        Box::pin(self.write(Record::Delete { seq }))
+    }
+
+    fn recent(&self, n: usize) -> BoxFuture<'_, Vec<StoredMessage>> {
+        Box::pin(async move { most_recent(&self.log.lock().await.messages, n) })
+    }
+
+    fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+}
+
+// Function which parses a line of a FileStore's file, where messages stored before edits and deletions were recorded carry no type.
+fn parse_record(line: &str) -> serde_json::Result<Record> {
+    serde_json::from_str(line).or_else(|e| {
+        serde_json::from_str(line)
+            .map(Record::Message)
+            .map_err(|_| e)
+    })
+}
+
+// Function which returns the contents of a compacted FileStore file, a line for each of the messages.
+fn compacted(messages: &VecDeque<StoredMessage>) -> String {
+    messages
+        .iter()
+        .map(|message| {
+            let record = Record::Message(message.clone());
+            format!("{}\n", serde_json::to_string(&record).unwrap())
+        })
+        .collect()
+}
+
+// Function which returns the path a FileStore's file is compacted into before replacing it.
+fn compacted_path(path: &Path) -> PathBuf {
+    let mut compacted = path.as_os_str().to_owned();
+    compacted.push(".compacting");
+    PathBuf::from(compacted)
+}
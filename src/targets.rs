@@ -0,0 +1,89 @@
+/** A single recipient specifier in a multicast target list: a client (by ID or nickname, resolved
+* later), every client in a room (`@room:{ROOM}`), or every connected client (`*`).
+*/
+pub enum Target<'a> {
+    Client(&'a str),
+    Room(&'a str),
+    Everyone,
+}
+
+/** A parsed multicast target list: the specifiers whose recipients are included, and the clients
+* (by ID or nickname, from `!{ID}` entries) excluded from them, whatever the order of the entries.
+*/
+pub struct Targets<'a> {
+    pub include: Vec<Target<'a>>,
+    pub exclude: Vec<&'a str>,
+}
+
+impl Targets<'_> {
+    // Function which returns whether the list names a room or every client, rather than only single clients.
+    pub fn reaches_many(&self) -> bool {
+        self.include
+            .iter()
+            .any(|target| matches!(target, Target::Room(_) | Target::Everyone))
+    }
+}
+
+/** Reason a multicast target list was rejected: it had an empty entry, an entry which is not a
+* valid specifier (ex. `@room:` without a room, or `!*`), more entries (or recipients) than allowed,
+* or an entry naming an unknown nickname.
+*/
+pub enum TargetError {
+    Empty,
+    Malformed,
+    TooMany,
+    Unknown,
+}
+
+impl TargetError {
+    // Function which returns the reply sent to the client for the error.
+    pub fn reply(&self) -> &'static str {
+        match self {
+            TargetError::Empty => "ERR:BAD_TARGETS\n",
+            TargetError::Malformed => "ERR:BAD_TARGET\n",
+            TargetError::TooMany => "ERR:TOO_MANY_TARGETS\n",
+            TargetError::Unknown => "ERR:NO_SUCH_CLIENT\n",
+        }
+    }
+}
+
+/** Function which parses a comma-separated multicast target list, such as `@room:general,!105`,
+* into the specifiers it includes and the clients it excludes. A list with more than max entries
+* is rejected before any of them is parsed.
+*/
+pub fn parse(list: &str, max: Option<usize>) -> Result<Targets<'_>, TargetError> {
+    if max.is_some_and(|max| list.split(',').nth(max).is_some()) {
+        return Err(TargetError::TooMany);
+    }
+    let mut targets = Targets {
+        include: Vec::new(),
+        exclude: Vec::new(),
+    };
+    for entry in list.split(',') {
+        if entry.is_empty() {
+            return Err(TargetError::Empty);
+        }
+        if let Some(excluded) = entry.strip_prefix('!') {
+            // only single clients can be excluded
+            if !is_client(excluded) {
+                return Err(TargetError::Malformed);
+            }
+            targets.exclude.push(excluded);
+        } else if entry == "*" {
+            targets.include.push(Target::Everyone);
+        } else if let Some(spec) = entry.strip_prefix('@') {
+            match spec.strip_prefix("room:") {
+                Some(room) if !room.is_empty() => targets.include.push(Target::Room(room)),
+                _ => return Err(TargetError::Malformed),
+            }
+        } else {
+            targets.include.push(Target::Client(entry));
+        }
+    }
+    Ok(targets)
+}
+
+// Function which returns whether an entry can name a client, rather than being empty or another kind of specifier.
+fn is_client(entry: &str) -> bool {
+    !entry.is_empty() && !entry.starts_with(['!', '@', '*'])
+}
@@ -0,0 +1,69 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/** Tarpit for abusive addresses. Each rate limit violation from an address (a connection refused
+* by the accept rate, or a message or reaction refused by a rate limit) counts as a strike against
+* it, and once an address has the threshold number of strikes, its new connections and every line
+* its clients send are held up by the delay. An address's strikes expire once it has gone the window
+* without a violation, which also unflags it, so the addresses remembered are only the recent offenders.
+*/
+pub struct Tarpit {
+    delay: Duration,
+    threshold: u32,
+    window: Duration,
+    strikes: Mutex<Strikes>,
+}
+
+// Strikes against each address, with the time of its latest, and when expired strikes were last forgotten.
+struct Strikes {
+    by_ip: HashMap<IpAddr, (u32, Instant)>,
+    pruned: Instant,
+}
+
+impl Tarpit {
+    // Constructor. Create a Tarpit delaying addresses with at least threshold strikes within the window by the given delay.
+    pub fn new(delay: Duration, threshold: u32, window: Duration) -> Self {
+        Tarpit {
+            delay,
+            threshold,
+            window,
+            strikes: Mutex::new(Strikes {
+                by_ip: HashMap::new(),
+                pruned: Instant::now(),
+            }),
+        }
+    }
+
+    /** Function which records a rate limit violation from the given address, starting its count afresh
+     * if its previous strikes have expired. Once per window, the addresses whose strikes have expired are
+     * forgotten, so the strikes kept are bounded by the addresses violating limits within two windows.
+     */
+    pub fn strike(&self, ip: IpAddr) {
+        let now = Instant::now();
+        let mut strikes = self.strikes.lock().unwrap();
+        if now.duration_since(strikes.pruned) >= self.window {
+            strikes
+                .by_ip
+                .retain(|_, (_, last)| now.duration_since(*last) < self.window);
+            strikes.pruned = now;
+        }
+        let (count, last) = strikes.by_ip.entry(ip).or_insert((0, now));
+        if now.duration_since(*last) >= self.window {
+            *count = 0;
+        }
+        *count = count.saturating_add(1);
+        *last = now;
+    }
+
+    // Function which returns the delay to impose on the given address, None while it is not flagged.
+    pub fn delay_for(&self, ip: IpAddr) -> Option<Duration> {
+        let strikes = self.strikes.lock().unwrap();
+        strikes
+            .by_ip
+            .get(&ip)
+            .is_some_and(|(count, last)| *count >= self.threshold && last.elapsed() < self.window)
+            .then_some(self.delay)
+    }
+}
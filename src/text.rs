@@ -0,0 +1,14 @@
+/** Function which shortens the text to at most max_bytes bytes. When the limit falls inside
+* a multibyte character the cut is moved back to the start of that character, so the result
+* is always valid UTF-8 (and may be slightly shorter than max_bytes).
+*/
+pub fn truncate_to_char_boundary(text: &mut String, max_bytes: usize) {
+    if text.len() <= max_bytes {
+        return;
+    }
+    let mut end = max_bytes;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    text.truncate(end);
+}
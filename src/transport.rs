@@ -0,0 +1,43 @@
+use std::net::SocketAddr;
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream};
+use tokio::net::TcpStream;
+
+/** Receiving half of a client connection, read by the client's connection task. */
+pub type ReadHalf = Box<dyn AsyncRead + Unpin + Send>;
+
+/** Sending half of a client connection, owned by the client's writer task. */
+pub type WriteHalf = Box<dyn AsyncWrite + Unpin + Send>;
+
+/** Trait for the byte streams clients are served over. The connection task only needs to split
+* a stream into its halves (and to know the client's address, where it has one), so the protocol
+* is the same over TCP and over in-memory streams, such as those used to drive the server in tests.
+*/
+pub trait Transport: Send + 'static {
+    // Function which returns the address of the client, None for streams without one (such as in-memory streams).
+    fn peer_addr(&self) -> Option<SocketAddr>;
+
+    // Function which splits the stream into its receiving and sending halves.
+    fn into_split(self) -> (ReadHalf, WriteHalf);
+}
+
+impl Transport for TcpStream {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        TcpStream::peer_addr(self).ok()
+    }
+
+    fn into_split(self) -> (ReadHalf, WriteHalf) {
+        let (read, write) = TcpStream::into_split(self);
+        (Box::new(read), Box::new(write))
+    }
+}
+
+impl Transport for DuplexStream {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+
+    fn into_split(self) -> (ReadHalf, WriteHalf) {
+        let (read, write) = tokio::io::split(self);
+        (Box::new(read), Box::new(write))
+    }
+}
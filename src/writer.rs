@@ -0,0 +1,491 @@
+use crate::config::{OverflowPolicy, ServerConfig};
+use crate::ratelimit::TokenBucket;
+use crate::transport::WriteHalf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::sync::mpsc::error::{SendTimeoutError, TrySendError};
+use tokio::sync::{Mutex, Notify, mpsc, oneshot};
+use tokio::time::{Instant, sleep, sleep_until};
+
+// number of lines each of a client's queues can hold before senders have to wait
+const QUEUE_CAPACITY: usize = 1024;
+
+/** Priority of a line queued for a client. High priority lines (system notices and admin
+* announcements) are written ahead of any normal lines still waiting in the client's queue.
+*/
+#[derive(Clone, Copy)]
+pub enum Priority {
+    High,
+    Normal,
+}
+
+/** Outcome of offering a line to a client's normal queue under an overflow policy: the line was
+* queued, the line itself was dropped because the queue stayed full, the oldest queued line was
+* dropped to make room for it, the queue overflowed (so the client should be disconnected), the line
+* was shed because every client's queues together hold the global maximum, the client is being removed,
+* or the writer task has stopped.
+*/
+pub enum Enqueued {
+    Queued,
+    DroppedNewest,
+    DroppedOldest,
+    Overflowed,
+    Shed,
+    Removed,
+    Closed,
+}
+
+/** Flag set once a client is being removed (or its writer task has stopped), shared by its Outbox, its
+* writer task and every Feed, so broadcasts still in flight stop queueing lines for it, including those
+* waiting for room in its queue.
+*/
+#[derive(Default)]
+struct Removing {
+    flag: AtomicBool,
+    notify: Notify,
+}
+
+impl Removing {
+    // Function which sets the flag, waking any broadcast waiting for room in the client's queue.
+    fn mark(&self) {
+        self.flag.store(true, Ordering::SeqCst);
+        self.notify.notify_waiters();
+    }
+}
+
+/** Count of the normal lines queued across every client's writer task, shared by all of them, with
+* the global maximum beyond which broadcasts shed lines rather than queueing more, and the number of
+* lines shed. This bounds the memory held by queued lines however many clients are slow at once.
+*/
+pub struct Backlog {
+    queued: AtomicUsize,
+    // normal lines which may be queued across every client before lines are shed, unlimited when None
+    limit: Option<usize>,
+    shed: AtomicU64,
+}
+
+impl Backlog {
+    // Constructor. Create an empty Backlog with the given global maximum.
+    pub fn new(limit: Option<usize>) -> Self {
+        Backlog {
+            queued: AtomicUsize::new(0),
+            limit,
+            shed: AtomicU64::new(0),
+        }
+    }
+
+    // Function which returns the number of normal lines currently queued across every client.
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    // Function which returns the number of lines shed since the server started.
+    pub fn shed(&self) -> u64 {
+        self.shed.load(Ordering::Relaxed)
+    }
+
+    // Function which returns whether the queued lines have reached the global maximum.
+    fn is_full(&self) -> bool {
+        self.limit.is_some_and(|limit| self.queued() >= limit)
+    }
+
+    // Function which counts a line about to be queued, which is counted before it is sent so the writer never takes an uncounted line.
+    fn add(&self) {
+        self.queued.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Function which stops counting a line, once the writer has taken it (or it was dropped, or never queued).
+    fn remove(&self) {
+        self.queued.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/** Handle used to queue lines for a client's writer task, which owns the client's
+* WriteHalf. Dropping the Outbox lets the writer task write out any lines still
+* queued and then close the connection, within the disconnect grace period.
+*/
+pub struct Outbox {
+    high: mpsc::Sender<String>,
+    normal: Feed,
+    removing: Arc<Removing>,
+    // dropped along with the Outbox, which starts the writer task's disconnect grace period
+    _closing: oneshot::Sender<()>,
+}
+
+impl Outbox {
+    /** Function which queues a line for the client with the given priority without waiting, so it can
+     * be called while holding the connections lock. Once the client is marked as being removed the line
+     * is discarded, as the connection is closing. Otherwise fails if that queue is full (the client has
+     * stopped reading, and the line is dropped rather than holding up every other client) or the writer
+     * task has stopped (e.g. the client disconnected).
+     */
+    pub fn try_send(&self, priority: Priority, line: String) -> Result<(), TrySendError<String>> {
+        if self.removing.flag.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        let sent = match priority {
+            Priority::High => self.high.try_send(line),
+            Priority::Normal => {
+                self.normal.backlog.add();
+                let sent = self.normal.normal.try_send(line);
+                if sent.is_err() {
+                    self.normal.backlog.remove();
+                }
+                sent
+            }
+        };
+        // the writer may have stopped (marking the client) since the flag was checked
+        match sent {
+            Err(TrySendError::Closed(_)) if self.removing.flag.load(Ordering::SeqCst) => Ok(()),
+            sent => sent,
+        }
+    }
+
+    /** Function which marks the client as being removed, so broadcasts in flight skip it rather than
+     * queueing lines (or waiting for room) for a connection which is closing. Lines queued before
+     * then, such as the notice for the removal, are still written.
+     */
+    pub fn mark_removing(&self) {
+        self.removing.mark();
+    }
+
+    /** Function which returns a handle on the client's normal queue, which broadcasts can keep
+     * using after the connections lock (and so the Outbox) has been released.
+     */
+    pub fn feed(&self) -> Feed {
+        self.normal.clone()
+    }
+}
+
+/** Cloneable handle on a client's normal queue, through which broadcasts are queued for the client
+* without holding the connections lock. The connection only closes once every Feed as well as the
+* Outbox has been dropped, so a Feed is only kept for the duration of a broadcast.
+*/
+#[derive(Clone)]
+pub struct Feed {
+    normal: mpsc::Sender<String>,
+    // receiving end of the normal queue, shared with the writer task so the oldest line can be dropped to make room
+    normal_rx: Arc<Mutex<mpsc::Receiver<String>>>,
+    backlog: Arc<Backlog>,
+    removing: Arc<Removing>,
+}
+
+impl Feed {
+    /** Function which queues a line with normal priority, like send, but applies the overflow policy
+     * when the queue is full. Under drop-oldest the oldest queued line is dropped at once to make room,
+     * as stale lines are not worth waiting for. Otherwise the line waits for room up to the given timeout
+     * (after which the client is lagging), and is then dropped, or the overflow is reported so the
+     * client can be disconnected. Once the global backlog is full, the line is shed instead (see shed).
+     */
+    pub async fn enqueue(
+        &self,
+        line: String,
+        timeout: Duration,
+        policy: OverflowPolicy,
+    ) -> Enqueued {
+        if self.removing.flag.load(Ordering::SeqCst) {
+            return Enqueued::Removed;
+        }
+        if self.backlog.is_full() {
+            return self.shed(line);
+        }
+        self.backlog.add();
+        let enqueued = self.offer(line, timeout, policy).await;
+        if !matches!(enqueued, Enqueued::Queued | Enqueued::DroppedOldest) {
+            self.backlog.remove();
+        }
+        match enqueued {
+            // the queue is closed once the writer has stopped, which also marks the client as removed
+            Enqueued::Closed if self.removing.flag.load(Ordering::SeqCst) => Enqueued::Removed,
+            enqueued => enqueued,
+        }
+    }
+
+    /** Function which sheds load while the global backlog is full: the oldest line queued for this client
+     * is dropped to make room for the new one, keeping the total unchanged, or the new line is dropped
+     * when this client has nothing queued (the backlog being held by other clients). The writer holds the
+     * receiver while it waits for a line, so a receiver which is in use means the queue is empty, rather
+     * than waiting for an idle client's next line (which may never come) to take it.
+     */
+    fn shed(&self, line: String) -> Enqueued {
+        self.backlog.shed.fetch_add(1, Ordering::Relaxed);
+        let dropped = self
+            .normal_rx
+            .try_lock()
+            .is_ok_and(|mut normal_rx| normal_rx.try_recv().is_ok());
+        if !dropped {
+            return Enqueued::Shed;
+        }
+        // the dropped line's place in the count is taken by the new one, unless it cannot be queued after all
+        match self.normal.try_send(line) {
+            Ok(()) => Enqueued::Queued,
+            Err(TrySendError::Full(_)) => {
+                self.backlog.remove();
+                Enqueued::Shed
+            }
+            Err(TrySendError::Closed(_)) => {
+                self.backlog.remove();
+                Enqueued::Closed
+            }
+        }
+    }
+
+    // Function which offers a (counted) line to the queue under the overflow policy, see enqueue.
+    async fn offer(&self, line: String, timeout: Duration, policy: OverflowPolicy) -> Enqueued {
+        if policy == OverflowPolicy::DropOldest {
+            let line = match self.normal.try_send(line) {
+                Ok(()) => return Enqueued::Queued,
+                Err(TrySendError::Full(line)) => line,
+                Err(TrySendError::Closed(_)) => return Enqueued::Closed,
+            };
+            // the writer only holds the receiver while taking a line, which is immediate while the queue is full
+            if self.normal_rx.lock().await.try_recv().is_ok() {
+                self.backlog.remove();
+            }
+            return match self.normal.try_send(line) {
+                Ok(()) => Enqueued::DroppedOldest,
+                // another sender took the freed slot first, so this line is the one dropped after all
+                Err(TrySendError::Full(_)) => Enqueued::DroppedNewest,
+                Err(TrySendError::Closed(_)) => Enqueued::Closed,
+            };
+        }
+        // a client removed while the line waits for room is given up on at once
+        let sent = tokio::select! {
+            sent = self.normal.send_timeout(line, timeout) => sent,
+            _ = self.removed() => return Enqueued::Removed,
+        };
+        match sent {
+            Ok(()) => Enqueued::Queued,
+            Err(SendTimeoutError::Timeout(_)) if policy == OverflowPolicy::Disconnect => {
+                Enqueued::Overflowed
+            }
+            Err(SendTimeoutError::Timeout(_)) => Enqueued::DroppedNewest,
+            Err(SendTimeoutError::Closed(_)) => Enqueued::Closed,
+        }
+    }
+
+    // Function which completes once the client is marked as being removed, so tasks holding a Feed can end with it.
+    pub async fn removed(&self) {
+        let notified = self.removing.notify.notified();
+        tokio::pin!(notified);
+        // registering before checking the flag means a removal in between is never missed
+        notified.as_mut().enable();
+        if self.removing.flag.load(Ordering::SeqCst) {
+            return;
+        }
+        notified.await;
+    }
+}
+
+/** Reason a writer task stopped: its connection was closed (the client disconnected, a write failed,
+* or the client was removed), or the client stopped reading, so that no write made progress for the
+* stall timeout.
+*/
+pub enum Stopped {
+    Closed,
+    Stalled,
+}
+
+/** Function which spawns the writer task for a client, returning the Outbox used to
+* queue lines for it. Lines are flushed to the client at most flush_interval after they are
+* written (batching any lines written in the meantime), or immediately when it is None. Once the
+* Outbox is dropped, the task has the disconnect grace period to write out the remaining lines and close the
+* connection before it is dropped regardless (it waits as long as it takes when None). The running
+* handle, if given, is held until the task ends, so the server can wait for every writer to finish.
+* The returned receiver completes once the task has ended, such as when a write to the client fails,
+* with the reason it stopped (see run_writer). Normal lines are counted in the shared backlog from
+* being queued until the task takes them.
+*/
+pub fn spawn_writer(
+    c_id: u64,
+    write: WriteHalf,
+    config: Arc<ServerConfig>,
+    running: Option<mpsc::Sender<()>>,
+    backlog: Arc<Backlog>,
+) -> (Outbox, oneshot::Receiver<Stopped>) {
+    let (high, mut high_rx) = mpsc::channel(QUEUE_CAPACITY);
+    let (normal, normal_rx) = mpsc::channel(QUEUE_CAPACITY);
+    let normal_rx = Arc::new(Mutex::new(normal_rx));
+    let writer_rx = Arc::clone(&normal_rx);
+    let leftover_rx = Arc::clone(&normal_rx);
+    let writer_backlog = Arc::clone(&backlog);
+    let (closing, closing_rx) = oneshot::channel();
+    let (finished, finished_rx) = oneshot::channel();
+    let removing = Arc::new(Removing::default());
+    let writer_removing = Arc::clone(&removing);
+    tokio::spawn(async move {
+        let mut write = BufWriter::new(write);
+        let stopped = tokio::select! {
+            stopped = run_writer(c_id, &mut write, &config, &mut high_rx, writer_rx, &writer_backlog) => stopped,
+            _ = grace_expired(closing_rx, config.disconnect_grace) => {
+                log_eprintln!("Dropping connection to client_id {} after the disconnect grace period", c_id);
+                Stopped::Closed
+            }
+        };
+        // a writer which stopped (such as when the client closed its end) is as good as removed for broadcasts,
+        // which is marked before the queues close so senders see the mark rather than a closed queue
+        writer_removing.mark();
+        drop(high_rx);
+        // lines never written to the client no longer count towards the backlog, and no more can be queued
+        let mut leftover = leftover_rx.lock().await;
+        leftover.close();
+        while leftover.try_recv().is_ok() {
+            writer_backlog.remove();
+        }
+        drop(leftover);
+        drop(running);
+        let _ = finished.send(stopped);
+    });
+    let outbox = Outbox {
+        high,
+        normal: Feed {
+            normal,
+            normal_rx,
+            backlog,
+            removing: Arc::clone(&removing),
+        },
+        removing,
+        _closing: closing,
+    };
+    (outbox, finished_rx)
+}
+
+// Function which completes once the grace period has passed since the Outbox was dropped, never when there is no grace period.
+async fn grace_expired(closing: oneshot::Receiver<()>, grace: Option<Duration>) {
+    // nothing is ever sent, the receiver completes when the Outbox (and so the sender) is dropped
+    let _ = closing.await;
+    match grace {
+        Some(grace) => sleep(grace).await,
+        None => std::future::pending().await,
+    }
+}
+
+/** Function run by each writer task. It writes queued lines to the client through a buffer,
+* always draining the high priority queue before taking the next normal line, until both queues
+* are closed and empty (or a write fails), then flushes and closes the connection so every
+* written line (such as a final notice) reaches the client before the socket is closed. With a
+* flush interval, buffered lines are flushed once the oldest of them has waited the full interval,
+* even if no further lines are queued. With an egress limit, normal lines are only taken from their
+* queue as fast as the limit allows, so lines the client cannot be sent in time pile up in the queue,
+* where the overflow policy deals with them. High priority lines are never held back. With a stall
+* timeout, a write (or flush) which makes no progress for that long means the client has stopped
+* reading, and the task stops at once, as Stopped::Stalled, rather than waiting on it forever.
+*/
+async fn run_writer(
+    c_id: u64,
+    write: &mut BufWriter<WriteHalf>,
+    config: &ServerConfig,
+    high: &mut mpsc::Receiver<String>,
+    normal: Arc<Mutex<mpsc::Receiver<String>>>,
+    backlog: &Backlog,
+) -> Stopped {
+    let flush_interval = config.flush_interval;
+    let stall = config.stall_timeout;
+    let mut egress = config.egress_rate.map(TokenBucket::new);
+    // time at which the buffered lines must be flushed, nothing is waiting to be flushed when None
+    let mut flush_at: Option<Instant> = None;
+    loop {
+        // time until the egress limit allows the next normal line, which may be taken at once when zero
+        let paced = egress
+            .as_mut()
+            .map_or(Duration::ZERO, TokenBucket::time_until_available);
+        let line = tokio::select! {
+            biased;
+            // flushing first keeps the latency bounded even while lines keep arriving
+            _ = sleep_until(flush_at.unwrap_or_else(Instant::now)), if flush_at.is_some() => {
+                match progress(stall, write.flush()).await {
+                    Some(Ok(())) => {}
+                    Some(Err(e)) => {
+                        report_failure(c_id, "flush", &e);
+                        return Stopped::Closed;
+                    }
+                    None => return Stopped::Stalled,
+                }
+                flush_at = None;
+                continue;
+            }
+            Some(line) = high.recv() => line,
+            Some(line) = async { normal.lock().await.recv().await }, if paced.is_zero() => {
+                backlog.remove();
+                // a token is known to be available once the wait is over
+                if let Some(egress) = egress.as_mut() {
+                    egress.try_take();
+                }
+                line
+            }
+            // the next normal line is held back until the egress limit allows it, waiting for the queues again after
+            _ = sleep(paced), if !paced.is_zero() => continue,
+            else => break,
+        };
+        match progress(stall, write.write_all(line.as_bytes())).await {
+            Some(Ok(())) => {}
+            Some(Err(e)) => {
+                report_failure(c_id, "send", &e);
+                return Stopped::Closed;
+            }
+            None => return Stopped::Stalled,
+        }
+        match flush_interval {
+            Some(interval) => {
+                flush_at.get_or_insert_with(|| Instant::now() + interval);
+            }
+            None => match progress(stall, write.flush()).await {
+                Some(Ok(())) => {}
+                Some(Err(e)) => {
+                    report_failure(c_id, "flush", &e);
+                    return Stopped::Closed;
+                }
+                None => return Stopped::Stalled,
+            },
+        }
+    }
+    flush_and_close(c_id, write).await;
+    Stopped::Closed
+}
+
+// Function which awaits a write to the client, returning None if it makes no progress for the stall timeout (if any).
+async fn progress<T>(
+    stall: Option<Duration>,
+    write: impl Future<Output = std::io::Result<T>>,
+) -> Option<std::io::Result<T>> {
+    match stall {
+        Some(stall) => tokio::time::timeout(stall, write).await.ok(),
+        None => Some(write.await),
+    }
+}
+
+// Function which flushes any buffered lines to the client and then shuts down the write half, closing the connection.
+async fn flush_and_close(c_id: u64, write: &mut BufWriter<WriteHalf>) {
+    if let Err(e) = write.flush().await {
+        report_failure(c_id, "flush", &e);
+        return;
+    }
+    if let Err(e) = write.shutdown().await
+        && !client_gone(&e)
+    {
+        log_eprintln!("Failed to close connection to client_id {}: {}", c_id, e);
+    }
+}
+
+/** Function which prints a failed write (a send or flush) to the client with the given c_id, unless the
+* failure only means the client has already gone, such as when it closed or reset its connection while a
+* broadcast was being written to it. Its read task then reports the departure, so the failure is not an error.
+*/
+fn report_failure(c_id: u64, action: &str, e: &std::io::Error) {
+    if !client_gone(e) {
+        log_eprintln!("Failed to {} data to client_id {}: {}", action, c_id, e);
+    }
+}
+
+// Function which returns whether a failed write means the client has closed (or reset) its end of the connection.
+fn client_gone(e: &std::io::Error) -> bool {
+    matches!(
+        e.kind(),
+        std::io::ErrorKind::BrokenPipe
+            | std::io::ErrorKind::ConnectionReset
+            | std::io::ErrorKind::ConnectionAborted
+            | std::io::ErrorKind::NotConnected
+    )
+}
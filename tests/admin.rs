@@ -0,0 +1,196 @@
+mod common;
+
+use common::*;
+use rust_broadcast_server::Extensions;
+use rust_broadcast_server::config::ServerConfig;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::AsyncBufReadExt;
+use tokio::net::TcpStream;
+
+#[tokio::test]
+async fn admin_announcement_reaches_every_client_in_every_room() {
+    let addr = start_server(admin_config(), Extensions::default());
+    let mut admin = Client::login(addr, 1).await;
+    admin.become_admin().await;
+    let mut lobby = Client::login(addr, 2).await;
+    let mut elsewhere = Client::login(addr, 3).await;
+    elsewhere.send(b"/join elsewhere\n").await;
+    assert_eq!(elsewhere.line().await, b"ACK:JOIN elsewhere\n");
+
+    admin.send(b"/announce maintenance at noon\n").await;
+    for client in [&mut admin, &mut lobby, &mut elsewhere] {
+        assert_eq!(client.line().await, b"ANNOUNCE:maintenance at noon\n");
+    }
+    // an announcement is neither acknowledged like a message nor kept in history
+    admin.expect_silence().await;
+    let mut late = Client::login(addr, 4).await;
+    late.expect_silence().await;
+}
+
+#[tokio::test]
+async fn announcement_from_a_non_admin_is_forbidden() {
+    let addr = start_server(admin_config(), Extensions::default());
+    let mut client = Client::login(addr, 1).await;
+    let mut other = Client::login(addr, 2).await;
+
+    client.send(b"/announce listen to me\n").await;
+    assert_eq!(client.line().await, b"ERR:FORBIDDEN\n");
+    // a wrong password grants nothing either
+    client.send(b"/admin guess\n").await;
+    assert_eq!(client.line().await, b"ERR:FORBIDDEN\n");
+    client.send(b"/announce listen to me\n").await;
+    assert_eq!(client.line().await, b"ERR:FORBIDDEN\n");
+    other.expect_silence().await;
+}
+
+#[tokio::test]
+async fn kickall_disconnects_everyone_and_keeps_accepting() {
+    let addr = start_server(admin_config(), Extensions::default());
+    let mut admin = Client::login(addr, 1).await;
+    admin.become_admin().await;
+    let mut clients = Vec::new();
+    for c_id in 2..=4 {
+        clients.push(Client::login(addr, c_id).await);
+    }
+    clients[0].send(b"/join elsewhere\n").await;
+    assert_eq!(clients[0].line().await, b"ACK:JOIN elsewhere\n");
+
+    admin.send(b"/kickall\n").await;
+    // the admin issuing the reset is disconnected too, and nobody is told of anyone else leaving
+    for client in std::iter::once(&mut admin).chain(clients.iter_mut()) {
+        assert_eq!(client.line().await, b"SERVER:RESET\n");
+        client.expect_closed().await;
+    }
+
+    let mut fresh = Client::login(addr, 5).await;
+    fresh.send(b"/roster\n").await;
+    assert_eq!(fresh.line().await, b"ROSTER:5:lobby:\n");
+}
+
+#[tokio::test]
+async fn kick_disconnects_a_client_named_by_nickname_or_id() {
+    let addr = start_server(admin_config(), Extensions::default());
+    let mut admin = Client::login(addr, 1).await;
+    admin.become_admin().await;
+    let mut alice = Client::login(addr, 2).await;
+    let mut other = Client::login(addr, 3).await;
+    alice.send(b"/nick alice\n").await;
+    assert_eq!(alice.line().await, b"ACK:NICK alice\n");
+
+    other.send(b"/kick alice\n").await;
+    assert_eq!(other.line().await, b"ERR:FORBIDDEN\n");
+    admin.send(b"/kick alice\n").await;
+    assert_eq!(admin.line().await, b"ACK:KICK 2\n");
+    assert_eq!(alice.line().await, b"SERVER:KICKED\n");
+    alice.expect_closed().await;
+    for client in [&mut admin, &mut other] {
+        assert_eq!(client.line().await, b"LEAVE:2 kicked\n");
+    }
+    // the nickname left with its client
+    admin.send(b"/kick alice\n").await;
+    assert_eq!(admin.line().await, b"ERR:NO_SUCH_CLIENT\n");
+    admin.send(b"/kick 3\n").await;
+    assert_eq!(admin.line().await, b"ACK:KICK 3\n");
+    assert_eq!(other.line().await, b"SERVER:KICKED\n");
+    other.expect_closed().await;
+}
+
+#[tokio::test]
+async fn rebind_moves_the_listener_and_keeps_history() {
+    let addr = start_server(admin_config(), Extensions::default());
+    let mut admin = Client::login(addr, 1).await;
+    admin.become_admin().await;
+    admin.send(b"said before\n").await;
+    assert_eq!(admin.line().await, b"ACK:MESSAGE 1\n");
+
+    admin.send(b"/rebind 127.0.0.1:0\n").await;
+    let reply = admin.text().await;
+    let new_addr: SocketAddr = reply.strip_prefix("ACK:REBIND ").unwrap().parse().unwrap();
+    assert_ne!(new_addr, addr);
+    // clients arriving on the new address are numbered on from the old ones and replayed the same history
+    let mut late = Client::login(new_addr, 2).await;
+    assert_eq!(late.line().await, b"HISTORY:1 said before\n");
+    // the admin is still connected over its old socket
+    late.send(b"hello\n").await;
+    assert_eq!(late.line().await, b"ACK:MESSAGE 2\n");
+    assert_eq!(admin.line().await, b"MESSAGE:2 hello\n");
+    // nothing is accepted on the old address any more
+    let deadline = tokio::time::Instant::now() + LINE_TIMEOUT;
+    while TcpStream::connect(addr).await.is_ok() {
+        assert!(
+            tokio::time::Instant::now() < deadline,
+            "still listening on {}",
+            addr
+        );
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+}
+
+#[tokio::test]
+async fn removed_client_receives_its_backlog_and_notice_before_the_close() {
+    let addr = start_server(admin_config(), Extensions::default());
+    let mut admin = Client::login(addr, 1).await;
+    admin.become_admin().await;
+    let mut removed = Client::login(addr, 2).await;
+
+    // the removed client has lines waiting for it when it is removed, which are written out rather than discarded
+    let padding = "x".repeat(1000);
+    for n in 1..=200 {
+        admin.send(format!("{} {}\n", n, padding).as_bytes()).await;
+        assert_eq!(admin.text().await, format!("ACK:MESSAGE {}", n));
+    }
+    admin.send(b"/kickall\n").await;
+
+    let mut messages = 0;
+    let mut notified = false;
+    loop {
+        let mut line = Vec::new();
+        let read = removed.stream.read_until(b'\n', &mut line);
+        match tokio::time::timeout(LINE_TIMEOUT, read).await.unwrap() {
+            Ok(0) => break,
+            Ok(_) if line == b"SERVER:RESET\n" => notified = true,
+            Ok(_) => {
+                messages += 1;
+                assert_eq!(
+                    line,
+                    format!("MESSAGE:1 {} {}\n", messages, padding).as_bytes()
+                );
+            }
+            Err(e) => panic!("the connection was reset rather than closed: {}", e),
+        }
+    }
+    assert!(notified, "the removal notice never arrived");
+    assert_eq!(messages, 200);
+}
+
+#[tokio::test]
+async fn config_shows_settings_with_secrets_redacted() {
+    let config = ServerConfig {
+        max_clients: Some(3),
+        ..admin_config()
+    };
+    let addr = start_server(config, Extensions::default());
+    let mut client = Client::login(addr, 1).await;
+    client.send(b"/config\n").await;
+    assert_eq!(client.line().await, b"ERR:FORBIDDEN\n");
+
+    client.become_admin().await;
+    // the ping's reply marks the end of the settings
+    client.send(b"/config\n/ping end\n").await;
+    let mut settings = Vec::new();
+    loop {
+        let line = client.text().await;
+        if line == "PONG:end" {
+            break;
+        }
+        settings.push(line);
+    }
+    assert!(settings.contains(&"CONFIG:max_clients 3".to_string()));
+    assert!(settings.contains(&"CONFIG:admin_password <redacted>".to_string()));
+    assert!(
+        settings.iter().all(|line| !line.contains(ADMIN_PASSWORD)),
+        "the password was shown: {:?}",
+        settings
+    );
+}
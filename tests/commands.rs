@@ -0,0 +1,657 @@
+mod common;
+
+use common::*;
+use rust_broadcast_server::Extensions;
+use rust_broadcast_server::config::ServerConfig;
+
+#[tokio::test]
+async fn ping_is_answered_to_the_sender_only() {
+    let addr = start_server(test_config(), Extensions::default());
+    let mut client = Client::login(addr, 1).await;
+    let mut other = Client::login(addr, 2).await;
+
+    client.send(b"/ping abc\n").await;
+    assert_eq!(client.line().await, b"PONG:abc\n");
+    other.expect_silence().await;
+    // pings in quick succession are all answered, in order
+    for nonce in 0..20 {
+        client.send(format!("/ping {}\n", nonce).as_bytes()).await;
+    }
+    for nonce in 0..20 {
+        assert_eq!(client.text().await, format!("PONG:{}", nonce));
+    }
+    other.expect_silence().await;
+}
+
+#[tokio::test]
+async fn spectator_receives_messages_but_cannot_send() {
+    let addr = start_server(test_config(), Extensions::default());
+    let mut spectator = Client::login(addr, 1).await;
+    let mut sender = Client::login(addr, 2).await;
+    spectator.send(b"/spectate\n").await;
+    assert_eq!(spectator.line().await, b"ACK:SPECTATE\n");
+
+    sender.send(b"hello\n").await;
+    assert_eq!(sender.line().await, b"ACK:MESSAGE 1\n");
+    assert_eq!(spectator.line().await, b"MESSAGE:2 hello\n");
+    spectator.send(b"let me talk\n").await;
+    assert_eq!(spectator.line().await, b"ERR:READ_ONLY\n");
+    sender.expect_silence().await;
+    // a spectator is still connected, and still listed
+    spectator.send(b"/roster\n").await;
+    assert_eq!(spectator.line().await, b"ROSTER:1:lobby: 2:lobby:\n");
+}
+
+#[tokio::test]
+async fn seen_reports_connected_departed_and_unknown_clients() {
+    let addr = start_server(test_config(), Extensions::default());
+    let mut client = Client::login(addr, 1).await;
+    let _online = Client::login(addr, 2).await;
+    let mut departed = Client::login(addr, 3).await;
+    departed.send(b"bye\n").await;
+    assert_eq!(departed.line().await, b"ACK:MESSAGE 1\n");
+    drop(departed);
+    assert_eq!(client.line().await, b"MESSAGE:3 bye\n");
+    assert_eq!(client.line().await, b"LEAVE:3 quit\n");
+
+    client.send(b"/seen 2\n").await;
+    assert_eq!(client.line().await, b"SEEN:2 online\n");
+    client.send(b"/seen 3\n").await;
+    assert_eq!(client.line().await, b"SEEN:3 0\n");
+    client.send(b"/seen 99\n").await;
+    assert_eq!(client.line().await, b"ERR:NO_SUCH_CLIENT\n");
+}
+
+#[tokio::test]
+async fn seen_forgets_the_earliest_departed_clients_beyond_its_limit() {
+    let addr = start_server(test_config(), Extensions::default());
+    let mut observer = Client::login(addr, 1).await;
+    // one more client departs than /seen remembers, each having sent a line
+    for c_id in 2..=1026 {
+        let mut departing = Client::login(addr, c_id).await;
+        departing.send(b"/ping bye\n").await;
+        assert_eq!(departing.line().await, b"PONG:bye\n");
+        drop(departing);
+        assert_eq!(observer.text().await, format!("LEAVE:{} quit", c_id));
+    }
+
+    observer.send(b"/seen 2\n").await;
+    assert_eq!(observer.line().await, b"ERR:NO_SUCH_CLIENT\n");
+    observer.send(b"/seen 3\n").await;
+    assert_eq!(observer.line().await, b"SEEN:3 0\n");
+    observer.send(b"/seen 1026\n").await;
+    assert_eq!(observer.line().await, b"SEEN:1026 0\n");
+}
+
+#[tokio::test]
+async fn overlong_and_malformed_nicknames_are_rejected() {
+    let addr = start_server(test_config(), Extensions::default());
+    let mut client = Client::login(addr, 1).await;
+
+    let longest = "n".repeat(32);
+    client
+        .send(format!("/nick {}x\n", longest).as_bytes())
+        .await;
+    assert_eq!(client.line().await, b"ERR:NICK_TOO_LONG\n");
+    client.send(b"/nick two words\n").await;
+    assert_eq!(client.line().await, b"ERR:BAD_NICK\n");
+    client.send(b"/nick tab\tbed\n").await;
+    assert_eq!(client.line().await, b"ERR:BAD_NICK\n");
+    // a carriage return inside the line is kept as part of it, and is a control character
+    client.send(b"/nick split\rline\n").await;
+    assert_eq!(client.line().await, b"ERR:BAD_NICK\n");
+    client.send(format!("/nick {}\n", longest).as_bytes()).await;
+    assert_eq!(client.text().await, format!("ACK:NICK {}", longest));
+}
+
+#[tokio::test]
+async fn roster_refresh_matches_the_connected_clients() {
+    let addr = start_server(test_config(), Extensions::default());
+    let mut client = Client::login(addr, 1).await;
+    let mut named = Client::login(addr, 2).await;
+    let leaving = Client::login(addr, 3).await;
+    let mut elsewhere = Client::login(addr, 4).await;
+    named.send(b"/nick alice\n").await;
+    assert_eq!(named.line().await, b"ACK:NICK alice\n");
+    elsewhere.send(b"/join den\n").await;
+    assert_eq!(elsewhere.line().await, b"ACK:JOIN den\n");
+    drop(leaving);
+    assert_eq!(client.line().await, b"LEAVE:3 quit\n");
+    assert_eq!(named.line().await, b"LEAVE:3 quit\n");
+
+    client.send(b"/roster\n").await;
+    assert_eq!(
+        client.line().await,
+        b"ROSTER:1:lobby: 2:lobby:alice 4:den:\n"
+    );
+    // the roster is a reply to the requester alone
+    named.expect_silence().await;
+}
+
+#[tokio::test]
+async fn custom_command_prefix_marks_commands_and_doubles_as_an_escape() {
+    let config = ServerConfig {
+        command_prefix: Some("!".to_string()),
+        ..test_config()
+    };
+    let addr = start_server(config, Extensions::default());
+    let mut client = Client::login(addr, 1).await;
+    let mut other = Client::login(addr, 2).await;
+
+    client.send(b"!ping abc\n").await;
+    assert_eq!(client.line().await, b"PONG:abc\n");
+    // the default prefix is now ordinary chat
+    client.send(b"/ping abc\n").await;
+    assert_eq!(client.line().await, b"ACK:MESSAGE 1\n");
+    assert_eq!(other.line().await, b"MESSAGE:1 /ping abc\n");
+    // a doubled prefix sends the rest of the line, prefix included, as chat
+    client.send(b"!!ping abc\n").await;
+    assert_eq!(client.line().await, b"ACK:MESSAGE 2\n");
+    assert_eq!(other.line().await, b"MESSAGE:1 !ping abc\n");
+}
+
+#[tokio::test]
+async fn without_a_command_prefix_every_line_is_chat() {
+    let config = ServerConfig {
+        command_prefix: None,
+        ..test_config()
+    };
+    let addr = start_server(config, Extensions::default());
+    let mut client = Client::login(addr, 1).await;
+    let mut other = Client::login(addr, 2).await;
+
+    client.send(b"/ping abc\n").await;
+    assert_eq!(client.line().await, b"ACK:MESSAGE 1\n");
+    assert_eq!(other.line().await, b"MESSAGE:1 /ping abc\n");
+}
+
+#[tokio::test]
+async fn clear_is_answered_to_the_sender_only() {
+    let addr = start_server(test_config(), Extensions::default());
+    let mut client = Client::login(addr, 1).await;
+    let mut other = Client::login(addr, 2).await;
+    client.send(b"kept\n").await;
+    assert_eq!(client.line().await, b"ACK:MESSAGE 1\n");
+    assert_eq!(other.line().await, b"MESSAGE:1 kept\n");
+
+    client.send(b"/clear\n").await;
+    assert_eq!(client.line().await, b"CLEAR\n");
+    other.expect_silence().await;
+    // the server's history is untouched
+    let mut late = Client::login(addr, 3).await;
+    assert_eq!(late.line().await, b"HISTORY:1 kept\n");
+}
+
+#[tokio::test]
+async fn sender_can_edit_its_message() {
+    let addr = start_server(test_config(), Extensions::default());
+    let mut sender = Client::login(addr, 1).await;
+    let mut other = Client::login(addr, 2).await;
+    sender.send(b"helo\n").await;
+    assert_eq!(sender.line().await, b"ACK:MESSAGE 1\n");
+    assert_eq!(other.line().await, b"MESSAGE:1 helo\n");
+
+    sender.send(b"/edit 1 hello\n").await;
+    assert_eq!(sender.line().await, b"ACK:EDIT 1\n");
+    assert_eq!(other.line().await, b"EDIT:1 hello\n");
+    // the edit is applied to the history as well
+    let mut late = Client::login(addr, 3).await;
+    assert_eq!(late.line().await, b"HISTORY:1 hello\n");
+}
+
+#[tokio::test]
+async fn only_the_sender_can_edit_or_delete_a_message() {
+    let addr = start_server(test_config(), Extensions::default());
+    let mut sender = Client::login(addr, 1).await;
+    let mut other = Client::login(addr, 2).await;
+    sender.send(b"mine\n").await;
+    assert_eq!(sender.line().await, b"ACK:MESSAGE 1\n");
+    assert_eq!(other.line().await, b"MESSAGE:1 mine\n");
+
+    other.send(b"/edit 1 yours now\n").await;
+    assert_eq!(other.line().await, b"ERR:FORBIDDEN\n");
+    other.send(b"/delete 1\n").await;
+    assert_eq!(other.line().await, b"ERR:FORBIDDEN\n");
+    other.send(b"/edit 99 nothing\n").await;
+    assert_eq!(other.line().await, b"ERR:NO_SUCH_MESSAGE\n");
+    sender.expect_silence().await;
+    let mut late = Client::login(addr, 3).await;
+    assert_eq!(late.line().await, b"HISTORY:1 mine\n");
+}
+
+#[tokio::test]
+async fn sender_can_delete_its_message() {
+    let addr = start_server(test_config(), Extensions::default());
+    let mut sender = Client::login(addr, 1).await;
+    let mut other = Client::login(addr, 2).await;
+    sender.send(b"oops\nkept\n").await;
+    assert_eq!(sender.line().await, b"ACK:MESSAGE 1\n");
+    assert_eq!(sender.line().await, b"ACK:MESSAGE 2\n");
+    assert_eq!(other.line().await, b"MESSAGE:1 oops\n");
+    assert_eq!(other.line().await, b"MESSAGE:1 kept\n");
+
+    sender.send(b"/delete 1\n").await;
+    assert_eq!(sender.line().await, b"ACK:DELETE 1\n");
+    assert_eq!(other.line().await, b"DELETE:1\n");
+    // a deleted message is gone from the history, and cannot be deleted again
+    let mut late = Client::login(addr, 3).await;
+    assert_eq!(late.line().await, b"HISTORY:1 kept\n");
+    late.expect_silence().await;
+    sender.send(b"/delete 1\n").await;
+    assert_eq!(sender.line().await, b"ERR:NO_SUCH_MESSAGE\n");
+}
+
+#[tokio::test]
+async fn reaction_to_a_message_reaches_the_others() {
+    let addr = start_server(test_config(), Extensions::default());
+    let mut sender = Client::login(addr, 1).await;
+    let mut reactor = Client::login(addr, 2).await;
+    let mut other = Client::login(addr, 3).await;
+    sender.send(b"good news\n").await;
+    assert_eq!(sender.line().await, b"ACK:MESSAGE 1\n");
+    assert_eq!(reactor.line().await, b"MESSAGE:1 good news\n");
+    assert_eq!(other.line().await, b"MESSAGE:1 good news\n");
+
+    reactor.send("/react 1 👍\n".as_bytes()).await;
+    assert_eq!(reactor.line().await, b"ACK:REACT 1\n");
+    assert_eq!(sender.text().await, "REACT:1 2 👍");
+    assert_eq!(other.text().await, "REACT:1 2 👍");
+    reactor.send("/react 99 👍\n".as_bytes()).await;
+    assert_eq!(reactor.line().await, b"ERR:NO_SUCH_MESSAGE\n");
+    // reactions are rate limited like any other burst
+    for _ in 0..10 {
+        reactor.send("/react 1 🎉\n".as_bytes()).await;
+    }
+    let mut limited = false;
+    for _ in 0..10 {
+        let line = reactor.text().await;
+        limited |= line == "ERR:SLOW_DOWN";
+        if limited {
+            break;
+        }
+    }
+    assert!(limited, "reactions were never rate limited");
+}
+
+#[tokio::test]
+async fn multicast_reaches_only_the_listed_clients() {
+    let addr = start_server(test_config(), Extensions::default());
+    let mut sender = Client::login(addr, 1).await;
+    let mut first = Client::login(addr, 2).await;
+    let mut left_out = Client::login(addr, 3).await;
+    let mut second = Client::login(addr, 4).await;
+
+    sender.send(b"/multicast 2,4 just you two\n").await;
+    assert_eq!(sender.line().await, b"ACK:MULTICAST 2\n");
+    assert_eq!(first.line().await, b"PRIVATE:1 just you two\n");
+    assert_eq!(second.line().await, b"PRIVATE:1 just you two\n");
+    left_out.expect_silence().await;
+}
+
+#[tokio::test]
+async fn multicast_skips_unknown_client_ids() {
+    let addr = start_server(test_config(), Extensions::default());
+    let mut sender = Client::login(addr, 1).await;
+    let mut known = Client::login(addr, 2).await;
+
+    // the count only includes the clients the message reached
+    sender.send(b"/multicast 2,99 hello\n").await;
+    assert_eq!(sender.line().await, b"ACK:MULTICAST 1\n");
+    assert_eq!(known.line().await, b"PRIVATE:1 hello\n");
+    sender.send(b"/multicast nobody hello\n").await;
+    assert_eq!(sender.line().await, b"ERR:NO_SUCH_CLIENT\n");
+    known.expect_silence().await;
+}
+
+#[tokio::test]
+async fn version_and_uptime_are_answered_to_the_requester_only() {
+    let addr = start_server(test_config(), Extensions::default());
+    let mut client = Client::login(addr, 1).await;
+    let mut other = Client::login(addr, 2).await;
+
+    client.send(b"/version\n").await;
+    assert_eq!(
+        client.text().await,
+        format!("VERSION:{}", env!("CARGO_PKG_VERSION"))
+    );
+    client.send(b"/uptime\n").await;
+    let uptime = client.text().await;
+    let secs: u64 = uptime.strip_prefix("UPTIME:").unwrap().parse().unwrap();
+    assert!(secs < 60);
+    other.expect_silence().await;
+}
+
+#[tokio::test]
+async fn clients_can_be_addressed_by_nickname() {
+    let addr = start_server(test_config(), Extensions::default());
+    let mut sender = Client::login(addr, 1).await;
+    let mut alice = Client::login(addr, 2).await;
+    let mut other = Client::login(addr, 3).await;
+    alice.send(b"/nick alice\n").await;
+    assert_eq!(alice.line().await, b"ACK:NICK alice\n");
+
+    sender.send(b"/multicast alice hi alice\n").await;
+    assert_eq!(sender.line().await, b"ACK:MULTICAST 1\n");
+    assert_eq!(alice.line().await, b"PRIVATE:1 hi alice\n");
+    sender.send(b"/seen alice\n").await;
+    assert_eq!(sender.line().await, b"SEEN:2 online\n");
+    // an unknown nickname names nobody
+    sender.send(b"/multicast bob hi bob\n").await;
+    assert_eq!(sender.line().await, b"ERR:NO_SUCH_CLIENT\n");
+    sender.send(b"/seen bob\n").await;
+    assert_eq!(sender.line().await, b"ERR:NO_SUCH_CLIENT\n");
+    other.expect_silence().await;
+}
+
+#[tokio::test]
+async fn msg_reaches_a_client_named_by_nickname_or_id() {
+    let addr = start_server(test_config(), Extensions::default());
+    let mut sender = Client::login(addr, 1).await;
+    let mut alice = Client::login(addr, 2).await;
+    let mut other = Client::login(addr, 3).await;
+    alice.send(b"/nick alice\n").await;
+    assert_eq!(alice.line().await, b"ACK:NICK alice\n");
+
+    sender.send(b"/msg alice hi alice\n").await;
+    assert_eq!(sender.line().await, b"ACK:MULTICAST 1\n");
+    assert_eq!(alice.line().await, b"PRIVATE:1 hi alice\n");
+    sender.send(b"/msg 2 hi again\n").await;
+    assert_eq!(sender.line().await, b"ACK:MULTICAST 1\n");
+    assert_eq!(alice.line().await, b"PRIVATE:1 hi again\n");
+    // unknown nicknames and IDs of clients which are not connected name nobody
+    sender.send(b"/msg bob hi bob\n").await;
+    assert_eq!(sender.line().await, b"ERR:NO_SUCH_CLIENT\n");
+    sender.send(b"/msg 99 hi\n").await;
+    assert_eq!(sender.line().await, b"ERR:NO_SUCH_CLIENT\n");
+    other.expect_silence().await;
+}
+
+#[tokio::test]
+async fn self_addressed_private_message_is_skipped_by_default() {
+    let addr = start_server(test_config(), Extensions::default());
+    let mut client = Client::login(addr, 1).await;
+    let mut other = Client::login(addr, 2).await;
+
+    client.send(b"/multicast 1 note to self\n").await;
+    assert_eq!(client.line().await, b"ACK:MULTICAST 0\n");
+    client.send(b"/multicast 1,2 to us both\n").await;
+    assert_eq!(client.line().await, b"ACK:MULTICAST 1\n");
+    assert_eq!(other.line().await, b"PRIVATE:1 to us both\n");
+    client.expect_silence().await;
+}
+
+#[tokio::test]
+async fn self_delivery_loops_private_messages_and_broadcasts_back() {
+    let config = ServerConfig {
+        allow_self_delivery: true,
+        ..test_config()
+    };
+    let addr = start_server(config, Extensions::default());
+    let mut client = Client::login(addr, 1).await;
+
+    client.send(b"/multicast 1 note to self\n").await;
+    assert_eq!(client.line().await, b"PRIVATE:1 note to self\n");
+    assert_eq!(client.line().await, b"ACK:MULTICAST 1\n");
+    client.send(b"echo\n").await;
+    assert_eq!(client.line().await, b"MESSAGE:1 echo\n");
+    assert_eq!(client.line().await, b"ACK:MESSAGE 1\n");
+}
+
+#[tokio::test]
+async fn resumed_client_can_skip_its_own_messages_in_replay() {
+    let config = ServerConfig {
+        handshake: true,
+        ..test_config()
+    };
+    let addr = start_server(config, Extensions::default());
+    let mut alice = Client::connect(addr).await;
+    assert!(alice.text().await.starts_with("CAPS:"));
+    alice.send(b"NICK alice\nLOGIN\n").await;
+    assert_eq!(alice.line().await, b"LOGIN:1\n");
+    assert_eq!(alice.line().await, b"ACK:NICK alice\n");
+    let mut other = Client::connect(addr).await;
+    handshake(&mut other, "").await;
+    assert_eq!(other.line().await, b"LOGIN:2\n");
+    alice.send(b"mine\n").await;
+    assert_eq!(alice.line().await, b"ACK:MESSAGE 1\n");
+    assert_eq!(other.line().await, b"MESSAGE:1 mine\n");
+    other.send(b"theirs\n").await;
+    assert_eq!(other.line().await, b"ACK:MESSAGE 2\n");
+    assert_eq!(alice.line().await, b"MESSAGE:2 theirs\n");
+    drop(alice);
+
+    // resuming under the same nickname, the message sent during the earlier connection is skipped
+    let mut resumed = Client::connect(addr).await;
+    assert!(resumed.text().await.starts_with("CAPS:"));
+    resumed.send(b"NICK alice\nREPLAY own=false\nLOGIN\n").await;
+    assert_eq!(resumed.line().await, b"LOGIN:3\n");
+    assert_eq!(resumed.line().await, b"ACK:NICK alice\n");
+    assert_eq!(resumed.line().await, b"HISTORY:2 theirs\n");
+    resumed.expect_silence().await;
+    // by default a client is replayed its own messages too
+    resumed.send(b"/replay own=true\n/join lobby\n").await;
+    assert_eq!(resumed.line().await, b"ACK:REPLAY own=true\n");
+    assert_eq!(resumed.line().await, b"ACK:JOIN lobby\n");
+    assert_eq!(resumed.line().await, b"HISTORY:1 mine\n");
+    assert_eq!(resumed.line().await, b"HISTORY:2 theirs\n");
+}
+
+#[tokio::test]
+async fn stats_count_each_command_issued() {
+    let addr = start_server(test_config(), Extensions::default());
+    let mut client = Client::login(addr, 1).await;
+    let mut other = Client::login(addr, 2).await;
+
+    client
+        .send(b"/join den\n/join lobby\n/ping 1\n/bogus\n/unknown\n")
+        .await;
+    assert_eq!(client.line().await, b"ACK:JOIN den\n");
+    assert_eq!(client.line().await, b"ACK:JOIN lobby\n");
+    assert_eq!(client.line().await, b"PONG:1\n");
+    // a command named "unknown" is counted with the other unknown commands
+    assert_eq!(client.line().await, b"ERR:UNKNOWN_COMMAND\n");
+    assert_eq!(client.line().await, b"ERR:UNKNOWN_COMMAND\n");
+    // every client's commands are counted, chat messages are not
+    other.send(b"/nick bob\nhello\n").await;
+    assert_eq!(other.line().await, b"ACK:NICK bob\n");
+    assert_eq!(other.line().await, b"ACK:MESSAGE 1\n");
+    assert_eq!(client.line().await, b"MESSAGE:2 hello\n");
+    client.send(b"/stats\n").await;
+    let stats = client.text().await;
+    assert!(
+        stats.ends_with(" commands=join:2,nick:1,ping:1,stats:1,unknown:2"),
+        "{}",
+        stats
+    );
+}
+
+#[tokio::test]
+async fn recall_only_returns_the_messages_the_client_received() {
+    let addr = start_server(test_config(), Extensions::default());
+    let mut client = Client::login(addr, 1).await;
+    let mut neighbour = Client::login(addr, 2).await;
+    let mut elsewhere = Client::login(addr, 3).await;
+    elsewhere.send(b"/join den\n").await;
+    assert_eq!(elsewhere.line().await, b"ACK:JOIN den\n");
+
+    neighbour.send(b"for the lobby\n").await;
+    assert_eq!(neighbour.line().await, b"ACK:MESSAGE 1\n");
+    assert_eq!(client.line().await, b"MESSAGE:2 for the lobby\n");
+    // a message to another room is kept in its history, but never reached the client
+    elsewhere.send(b"for the den\n").await;
+    assert_eq!(elsewhere.line().await, b"ACK:MESSAGE 2\n");
+    elsewhere.send(b"/multicast 1 just for you\n").await;
+    assert_eq!(elsewhere.line().await, b"ACK:MULTICAST 1\n");
+    assert_eq!(client.line().await, b"PRIVATE:3 just for you\n");
+
+    client.send(b"/recall\n").await;
+    assert_eq!(client.line().await, b"RECALL:2 for the lobby\n");
+    assert_eq!(client.line().await, b"RECALL:3 just for you\n");
+    assert_eq!(client.line().await, b"ACK:RECALL 2\n");
+    client.send(b"/recall 1\n").await;
+    assert_eq!(client.line().await, b"RECALL:3 just for you\n");
+    assert_eq!(client.line().await, b"ACK:RECALL 1\n");
+    client.send(b"/recall many\n").await;
+    assert_eq!(client.line().await, b"ERR:BAD_COUNT\n");
+    client.send(b"/join den\n").await;
+    assert_eq!(client.line().await, b"ACK:JOIN den\n");
+    assert_eq!(client.line().await, b"HISTORY:3 for the den\n");
+}
+
+#[tokio::test]
+async fn multicast_can_target_rooms_everyone_and_exclusions() {
+    let addr = start_server(admin_config(), Extensions::default());
+    let mut sender = Client::login(addr, 1).await;
+    sender.become_admin().await;
+    let mut general = Vec::new();
+    for c_id in 2..=4 {
+        let mut client = Client::login(addr, c_id).await;
+        client.send(b"/join general\n").await;
+        assert_eq!(client.line().await, b"ACK:JOIN general\n");
+        general.push(client);
+    }
+    let mut lobby = Client::login(addr, 5).await;
+
+    sender
+        .send(b"/multicast @room:general,!3 to the room\n")
+        .await;
+    assert_eq!(sender.line().await, b"ACK:MULTICAST 2\n");
+    assert_eq!(general[0].line().await, b"PRIVATE:1 to the room\n");
+    assert_eq!(general[2].line().await, b"PRIVATE:1 to the room\n");
+    general[1].expect_silence().await;
+    lobby.expect_silence().await;
+
+    // the exclusion applies wherever it appears, and a client named twice receives the message once
+    sender.send(b"/multicast !4,*,5 to all\n").await;
+    assert_eq!(sender.line().await, b"ACK:MULTICAST 3\n");
+    for client in general[..2].iter_mut().chain(std::iter::once(&mut lobby)) {
+        assert_eq!(client.line().await, b"PRIVATE:1 to all\n");
+        client.expect_silence().await;
+    }
+    general[2].expect_silence().await;
+
+    sender.send(b"/multicast @room: nobody\n").await;
+    assert_eq!(sender.line().await, b"ERR:BAD_TARGET\n");
+    sender.send(b"/multicast 2,!* nobody\n").await;
+    assert_eq!(sender.line().await, b"ERR:BAD_TARGET\n");
+    lobby.expect_silence().await;
+
+    // other clients may only name single clients
+    lobby
+        .send(b"/multicast @room:general from the lobby\n")
+        .await;
+    assert_eq!(lobby.line().await, b"ERR:FORBIDDEN\n");
+    lobby.send(b"/multicast 2,* from the lobby\n").await;
+    assert_eq!(lobby.line().await, b"ERR:FORBIDDEN\n");
+    lobby.send(b"/multicast 2,!3 from the lobby\n").await;
+    assert_eq!(lobby.line().await, b"ACK:MULTICAST 1\n");
+    assert_eq!(general[0].line().await, b"PRIVATE:5 from the lobby\n");
+    for client in general.iter_mut() {
+        client.expect_silence().await;
+    }
+}
+
+#[tokio::test]
+async fn event_subscriber_sees_other_clients_come_and_go() {
+    let addr = start_server(test_config(), Extensions::default());
+    let mut subscriber = Client::login(addr, 1).await;
+    subscriber.send(b"/subscribe events\n").await;
+    assert_eq!(subscriber.line().await, b"ACK:SUBSCRIBE events\n");
+    subscriber.send(b"/subscribe chat\n").await;
+    assert_eq!(subscriber.line().await, b"ERR:BAD_SUBSCRIPTION\n");
+
+    let event = |line: String| -> serde_json::Value {
+        serde_json::from_str(line.strip_prefix("EVENT:").expect(&line)).unwrap()
+    };
+    let mut visitor = Client::login(addr, 2).await;
+    let connected = event(subscriber.text().await);
+    assert_eq!(connected["event"], "connect");
+    assert_eq!(connected["client_id"], 2);
+    assert!(connected["ts"].as_u64().unwrap() > 0);
+    // chat is not part of the stream
+    visitor.send(b"anyone here?\n").await;
+    assert_eq!(visitor.line().await, b"ACK:MESSAGE 1\n");
+    drop(visitor);
+    // the subscriber is still in its room, so it is told of the departure as usual too, in either order
+    let mut lines = vec![subscriber.text().await, subscriber.text().await];
+    lines.sort();
+    assert_eq!(lines[1], "LEAVE:2 quit");
+    let disconnected = event(lines.remove(0));
+    assert_eq!(disconnected["event"], "disconnect");
+    assert_eq!(disconnected["client_id"], 2);
+    assert_eq!(disconnected["reason"], "quit");
+    subscriber.expect_silence().await;
+}
+
+#[tokio::test]
+async fn multicast_beyond_the_target_limit_reaches_no_one() {
+    let config = ServerConfig {
+        max_multicast_targets: Some(2),
+        ..admin_config()
+    };
+    let addr = start_server(config, Extensions::default());
+    let mut sender = Client::login(addr, 1).await;
+    sender.become_admin().await;
+    let mut others = Vec::new();
+    for c_id in 2..=4 {
+        others.push(Client::login(addr, c_id).await);
+    }
+
+    sender.send(b"/multicast 2,3 within the limit\n").await;
+    assert_eq!(sender.line().await, b"ACK:MULTICAST 2\n");
+    assert_eq!(others[0].line().await, b"PRIVATE:1 within the limit\n");
+    assert_eq!(others[1].line().await, b"PRIVATE:1 within the limit\n");
+    // too long a list, and a short one expanding to too many clients, are both refused outright
+    sender.send(b"/multicast 2,3,4 too many\n").await;
+    assert_eq!(sender.line().await, b"ERR:TOO_MANY_TARGETS\n");
+    sender.send(b"/multicast @room:lobby too many\n").await;
+    assert_eq!(sender.line().await, b"ERR:TOO_MANY_TARGETS\n");
+    sender.send(b"/multicast * too many\n").await;
+    assert_eq!(sender.line().await, b"ERR:TOO_MANY_TARGETS\n");
+    for client in others.iter_mut() {
+        client.expect_silence().await;
+    }
+    // excluded clients, and the sender itself, do not count towards the limit
+    sender
+        .send(b"/multicast *,!3 within the limit again\n")
+        .await;
+    assert_eq!(sender.line().await, b"ACK:MULTICAST 2\n");
+    assert_eq!(
+        others[0].line().await,
+        b"PRIVATE:1 within the limit again\n"
+    );
+    assert_eq!(
+        others[2].line().await,
+        b"PRIVATE:1 within the limit again\n"
+    );
+    others[1].expect_silence().await;
+}
+
+#[tokio::test]
+async fn do_not_disturb_keeps_private_messages_and_announcements() {
+    let addr = start_server(admin_config(), Extensions::default());
+    let mut admin = Client::login(addr, 1).await;
+    admin.become_admin().await;
+    let mut away = Client::login(addr, 2).await;
+    away.send(b"/dnd on\n").await;
+    assert_eq!(away.line().await, b"ACK:DND on\n");
+    away.send(b"/dnd maybe\n").await;
+    assert_eq!(away.line().await, b"ERR:BAD_DND\n");
+
+    admin.send(b"room chatter\n").await;
+    assert_eq!(admin.line().await, b"ACK:MESSAGE 1\n");
+    admin.send(b"/multicast 2 ping\n").await;
+    assert_eq!(admin.line().await, b"ACK:MULTICAST 1\n");
+    admin.send(b"/announce restarting soon\n").await;
+    assert_eq!(admin.line().await, b"ANNOUNCE:restarting soon\n");
+    // the broadcast never arrives, the private message and announcement do
+    assert_eq!(away.line().await, b"PRIVATE:1 ping\n");
+    assert_eq!(away.line().await, b"ANNOUNCE:restarting soon\n");
+    away.expect_silence().await;
+
+    away.send(b"/dnd off\n").await;
+    assert_eq!(away.line().await, b"ACK:DND off\n");
+    admin.send(b"welcome back\n").await;
+    assert_eq!(admin.line().await, b"ACK:MESSAGE 2\n");
+    assert_eq!(away.line().await, b"MESSAGE:1 welcome back\n");
+}
@@ -0,0 +1,199 @@
+// shared by every test file, each of which only uses some of it
+#![allow(dead_code)]
+
+use rust_broadcast_server::config::ServerConfig;
+use rust_broadcast_server::{Extensions, ShutdownSummary, run_server_with};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+// longest time a test waits for a line the server is expected to send
+pub const LINE_TIMEOUT: Duration = Duration::from_secs(5);
+// time a test waits to be sure the server sends nothing more
+pub const QUIET_PERIOD: Duration = Duration::from_millis(300);
+// password the admin tests authenticate with
+pub const ADMIN_PASSWORD: &str = "secret";
+
+/** Function which starts a server with the given settings (listening on a free port instead of the one
+* configured) on a thread of its own, as run_server runs until the process is stopped, and returns the
+* address it listens on. The server may not be accepting connections yet, see Client::connect.
+*/
+pub fn start_server(config: ServerConfig, extensions: Extensions) -> SocketAddr {
+    spawn_server(config, extensions).0
+}
+
+// Function which starts a server like start_server, also returning the thread it runs on, which ends with its shutdown summary.
+pub fn spawn_server(
+    config: ServerConfig,
+    extensions: Extensions,
+) -> (SocketAddr, std::thread::JoinHandle<ShutdownSummary>) {
+    let addr = free_addr();
+    (addr, spawn_server_at(addr, config, extensions))
+}
+
+// Function which starts a server like spawn_server, listening on the given address, returning the thread it runs on.
+pub fn spawn_server_at(
+    addr: SocketAddr,
+    config: ServerConfig,
+    extensions: Extensions,
+) -> std::thread::JoinHandle<ShutdownSummary> {
+    let config = ServerConfig {
+        bind: addr,
+        ..config
+    };
+    std::thread::spawn(move || {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime
+            .block_on(run_server_with(config, extensions))
+            .unwrap()
+    })
+}
+
+// Function which finds a local address with a free port, by binding to port 0 and releasing the port again.
+pub fn free_addr() -> SocketAddr {
+    let probe = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    probe.local_addr().unwrap()
+}
+
+// Function which returns the settings the tests run the server with unless they need others.
+pub fn test_config() -> ServerConfig {
+    ServerConfig {
+        // no disconnect grace, so closed connections are released at once
+        disconnect_grace: None,
+        ..ServerConfig::default()
+    }
+}
+
+// Function which returns the test settings with an admin password, for the tests of admin commands.
+pub fn admin_config() -> ServerConfig {
+    ServerConfig {
+        admin_password: Some(ADMIN_PASSWORD.to_string()),
+        ..test_config()
+    }
+}
+
+// Function which returns the path of a file in the temporary directory which no other test uses, removing any left behind.
+pub fn temp_path(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    path
+}
+
+/** Function which waits until the condition holds, checking it every 20ms, failing the test with the
+* given description if it still does not hold after LINE_TIMEOUT.
+*/
+pub async fn eventually(what: &str, mut condition: impl FnMut() -> bool) {
+    let deadline = tokio::time::Instant::now() + LINE_TIMEOUT;
+    while !condition() {
+        assert!(tokio::time::Instant::now() < deadline, "{}", what);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+}
+
+/** A client of the server under test, sending lines and reading the lines the server sends it,
+* over TCP or an in-memory stream.
+*/
+pub struct Client<S> {
+    pub stream: BufReader<S>,
+}
+
+impl Client<TcpStream> {
+    /** Constructor. Connect to the server at the given address, retrying while it is still starting
+     * (refused connections never reach the server, so they take no client ID).
+     */
+    pub async fn connect(addr: SocketAddr) -> Self {
+        let deadline = tokio::time::Instant::now() + LINE_TIMEOUT;
+        loop {
+            match TcpStream::connect(addr).await {
+                Ok(stream) => return Client::new(stream),
+                Err(_) if tokio::time::Instant::now() < deadline => {
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                }
+                Err(e) => panic!("could not connect to {}: {}", addr, e),
+            }
+        }
+    }
+
+    // Constructor. Connect to the server like connect, checking that the client is logged in with the given ID.
+    pub async fn login(addr: SocketAddr, c_id: u64) -> Self {
+        let mut client = Client::connect(addr).await;
+        let login = format!("LOGIN:{}\n", c_id);
+        assert_eq!(client.line().await, login.as_bytes());
+        client
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Client<S> {
+    // Constructor. Create a client over an established stream.
+    pub fn new(stream: S) -> Self {
+        Client {
+            stream: BufReader::new(stream),
+        }
+    }
+
+    // Function which sends the given bytes to the server as they are.
+    pub async fn send(&mut self, bytes: &[u8]) {
+        self.stream.get_mut().write_all(bytes).await.unwrap();
+    }
+
+    // Function which reads the next line from the server, including its newline, failing the test if none arrives in time.
+    pub async fn line(&mut self) -> Vec<u8> {
+        let mut line = Vec::new();
+        let read = tokio::time::timeout(LINE_TIMEOUT, self.stream.read_until(b'\n', &mut line));
+        match read.await {
+            Ok(Ok(n)) if n > 0 => line,
+            Ok(Ok(_)) => panic!("connection closed while waiting for a line"),
+            Ok(Err(e)) => panic!("read failed while waiting for a line: {}", e),
+            Err(_) => panic!("no line arrived, only {:?}", String::from_utf8_lossy(&line)),
+        }
+    }
+
+    // Function which reads the next line like line, as text without its newline, for lines only part of which is checked.
+    pub async fn text(&mut self) -> String {
+        let line = self.line().await;
+        String::from_utf8(line)
+            .unwrap()
+            .trim_end_matches('\n')
+            .to_string()
+    }
+
+    // Function which asserts that the server sends nothing more for the quiet period.
+    pub async fn expect_silence(&mut self) {
+        let mut line = Vec::new();
+        let read = tokio::time::timeout(QUIET_PERIOD, self.stream.read_until(b'\n', &mut line));
+        if let Ok(Ok(n)) = read.await {
+            assert_eq!(n, 0, "unexpected line {:?}", String::from_utf8_lossy(&line));
+        }
+    }
+
+    // Function which asserts that the server closes the connection, after sending any remaining lines.
+    pub async fn expect_closed(&mut self) {
+        let mut rest = Vec::new();
+        let read = tokio::time::timeout(LINE_TIMEOUT, self.stream.read_until(b'\n', &mut rest));
+        match read.await {
+            Ok(Ok(0)) => {}
+            Ok(Ok(_)) => panic!("unexpected line {:?}", String::from_utf8_lossy(&rest)),
+            // a reset after the last line is as good as a close
+            Ok(Err(_)) => {}
+            Err(_) => panic!("connection was not closed"),
+        }
+    }
+
+    // Function which authenticates the client as an admin with the test password.
+    pub async fn become_admin(&mut self) {
+        self.send(format!("/admin {}\n", ADMIN_PASSWORD).as_bytes())
+            .await;
+        assert_eq!(self.line().await, b"ACK:ADMIN\n");
+    }
+}
+
+// Function which completes the handshake for a client, declaring the given capabilities.
+pub async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(client: &mut Client<S>, caps: &str) {
+    let advertised = client.line().await;
+    assert!(advertised.starts_with(b"CAPS:"));
+    client
+        .send(format!("CAPS:{}\nLOGIN\n", caps).as_bytes())
+        .await;
+}
@@ -0,0 +1,146 @@
+mod common;
+
+use common::*;
+use rust_broadcast_server::Extensions;
+use rust_broadcast_server::config::ServerConfig;
+use std::time::Duration;
+
+// Function which writes a config file of its own with the given contents, returning its path.
+fn write_config(name: &str, contents: &str) -> std::path::PathBuf {
+    let path = temp_path(&format!("{}.json", name));
+    std::fs::write(&path, contents).unwrap();
+    path
+}
+
+// Function which builds a ServerConfig from the given arguments, as the server binary would.
+fn from_args(args: &[&str]) -> Result<ServerConfig, String> {
+    ServerConfig::from_args(args.iter().map(|arg| arg.to_string()))
+}
+
+// Function which returns the error the settings were refused with, failing the test if they were accepted.
+fn refused(result: Result<ServerConfig, String>) -> String {
+    match result {
+        Ok(_) => panic!("the settings were accepted"),
+        Err(e) => e,
+    }
+}
+
+#[test]
+fn config_file_sets_the_settings_it_names_and_leaves_the_rest() {
+    let path = write_config(
+        "parsed",
+        r#"{"bind": "127.0.0.1:9000", "max_clients": 10, "idle_timeout": 30, "banner": "hello"}"#,
+    );
+
+    let config = ServerConfig::from_file(&path).unwrap();
+    assert_eq!(config.bind.to_string(), "127.0.0.1:9000");
+    assert_eq!(config.max_clients, Some(10));
+    assert_eq!(config.idle_timeout, Some(Duration::from_secs(30)));
+    assert_eq!(config.banner.as_deref(), Some("hello"));
+    // anything the file does not mention keeps its default
+    let defaults = ServerConfig::default();
+    assert_eq!(config.history_size, defaults.history_size);
+    assert_eq!(config.max_line_bytes, defaults.max_line_bytes);
+}
+
+#[test]
+fn flags_override_the_config_file_wherever_they_appear() {
+    let path = write_config("precedence", r#"{"max_clients": 10, "history_size": 5}"#);
+    let path = path.to_str().unwrap();
+
+    let config = from_args(&["--max-clients", "3", "--config", path]).unwrap();
+    assert_eq!(config.max_clients, Some(3));
+    assert_eq!(config.history_size, 5);
+    let config = from_args(&["--config", path, "--history-size", "7"]).unwrap();
+    assert_eq!(config.max_clients, Some(10));
+    assert_eq!(config.history_size, 7);
+}
+
+#[test]
+fn invalid_config_files_are_reported() {
+    let missing = refused(from_args(&["--config", "/nonexistent/config.json"]));
+    assert!(
+        missing.starts_with("cannot read config file"),
+        "{}",
+        missing
+    );
+    let path = write_config("malformed", r#"{"max_clients": "#);
+    let malformed = refused(ServerConfig::from_file(&path));
+    assert!(
+        malformed.starts_with("invalid config file"),
+        "{}",
+        malformed
+    );
+    let path = write_config("unknown", r#"{"max_client": 10}"#);
+    let unknown = refused(ServerConfig::from_file(&path));
+    assert!(unknown.contains("max_client"), "{}", unknown);
+    // a file is held to the same rules as the flags
+    let path = write_config("invalid", r#"{"max_clients": 0}"#);
+    let invalid = refused(from_args(&["--config", path.to_str().unwrap()]));
+    assert_eq!(invalid, "--max-clients must be at least 1");
+}
+
+#[tokio::test]
+async fn server_honours_a_custom_config() {
+    let config = ServerConfig {
+        history_size: 2,
+        ..test_config()
+    };
+    let addr = start_server(config, Extensions::default());
+    let mut sender = Client::login(addr, 1).await;
+    for n in 1..=3 {
+        sender.send(format!("message {}\n", n).as_bytes()).await;
+        assert_eq!(sender.text().await, format!("ACK:MESSAGE {}", n));
+    }
+
+    // only the last history_size messages are kept for clients arriving later
+    let mut late = Client::login(addr, 2).await;
+    assert_eq!(late.line().await, b"HISTORY:1 message 2\n");
+    assert_eq!(late.line().await, b"HISTORY:1 message 3\n");
+    late.expect_silence().await;
+}
+
+#[test]
+fn inconsistent_settings_are_refused_with_a_description() {
+    let unsigned = refused(from_args(&["--require-signatures"]));
+    assert_eq!(unsigned, "--require-signatures requires --hmac-key");
+    let warning = refused(from_args(&["--idle-timeout", "5", "--idle-warning", "5"]));
+    assert_eq!(warning, "--idle-warning must be less than --idle-timeout");
+    let no_timeout = refused(from_args(&["--idle-warning", "5"]));
+    assert_eq!(no_timeout, "--idle-warning requires --idle-timeout");
+    let unreachable = refused(from_args(&[
+        "--admin-password",
+        "secret",
+        "--command-prefix",
+        "",
+    ]));
+    assert_eq!(
+        unreachable,
+        "--admin-password requires commands to be enabled (see --command-prefix)"
+    );
+    let rooms = refused(from_args(&[
+        "--disable-rooms",
+        "--max-rooms-per-client",
+        "2",
+    ]));
+    assert_eq!(
+        rooms,
+        "--max-rooms-per-client cannot be used with --disable-rooms"
+    );
+}
+
+#[tokio::test]
+async fn server_refuses_to_start_with_inconsistent_settings() {
+    let config = ServerConfig {
+        require_signatures: true,
+        ..test_config()
+    };
+    let error = rust_broadcast_server::run_server_with(config, Extensions::default())
+        .await
+        .map(|_| ())
+        .unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "--require-signatures requires --hmac-key"
+    );
+}
@@ -0,0 +1,578 @@
+mod common;
+
+use common::*;
+use rust_broadcast_server::config::ServerConfig;
+use rust_broadcast_server::{AddrInUse, Extensions, run_server_with};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+use tokio::sync::mpsc;
+
+#[tokio::test]
+async fn full_server_rejection_carries_a_retry_hint() {
+    let config = ServerConfig {
+        max_clients: Some(1),
+        ..test_config()
+    };
+    let addr = start_server(config, Extensions::default());
+    let _first = Client::login(addr, 1).await;
+
+    let mut refused = Client::connect(addr).await;
+    assert_eq!(refused.line().await, b"ERR:SERVER_FULL retry=5\n");
+    refused.expect_closed().await;
+}
+
+#[tokio::test]
+async fn cancelled_connection_stops_reading() {
+    let (streams, in_memory) = mpsc::unbounded_channel();
+    let extensions = Extensions {
+        in_memory: Some(in_memory),
+        ..Extensions::default()
+    };
+    let addr = start_server(admin_config(), extensions);
+    let mut admin = Client::login(addr, 1).await;
+    admin.become_admin().await;
+    let (mut stream, server_end) = tokio::io::duplex(4096);
+    streams.send(server_end).unwrap();
+    let mut client = Client::new(&mut stream);
+    assert_eq!(client.line().await, b"LOGIN:2\n");
+
+    // the reset cancels every connection, the client's read task included
+    admin.send(b"/kickall\n").await;
+    assert_eq!(admin.line().await, b"SERVER:RESET\n");
+    assert_eq!(client.line().await, b"SERVER:RESET\n");
+    client.expect_closed().await;
+    // once the read task has ended the server's end of the stream is dropped, so writing to it fails
+    let deadline = tokio::time::Instant::now() + LINE_TIMEOUT;
+    while stream.write_all(b"anyone?\n").await.is_ok() {
+        assert!(
+            tokio::time::Instant::now() < deadline,
+            "the read task kept reading"
+        );
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+}
+
+#[tokio::test]
+async fn banner_is_the_first_line_a_client_receives() {
+    let config = ServerConfig {
+        banner: Some("RustBroadcast 1.0".to_string()),
+        ..test_config()
+    };
+    let addr = start_server(config, Extensions::default());
+
+    let mut client = Client::connect(addr).await;
+    assert_eq!(client.line().await, b"RustBroadcast 1.0\n");
+    assert_eq!(client.line().await, b"LOGIN:1\n");
+}
+
+#[tokio::test]
+async fn login_info_carries_the_crate_version_and_uptime() {
+    let config = ServerConfig {
+        login_info: true,
+        ..test_config()
+    };
+    let addr = start_server(config, Extensions::default());
+
+    let mut client = Client::connect(addr).await;
+    let login = client.text().await;
+    let fields: Vec<&str> = login.split(' ').collect();
+    assert_eq!(fields.len(), 3, "unexpected login line {:?}", login);
+    assert_eq!(fields[0], "LOGIN:1");
+    assert_eq!(fields[1], format!("version={}", env!("CARGO_PKG_VERSION")));
+    let uptime: u64 = fields[2].strip_prefix("uptime=").unwrap().parse().unwrap();
+    assert!(uptime < 60);
+}
+
+#[tokio::test]
+async fn removal_cleans_up_whatever_its_cause() {
+    let config = ServerConfig {
+        max_line_bytes: Some(64),
+        // only a strict server disconnects a client for invalid UTF-8 with a notice
+        strict: true,
+        ..test_config()
+    };
+    let addr = start_server(config, Extensions::default());
+    let mut observer = Client::login(addr, 1).await;
+    observer.send(b"/join den\n").await;
+    assert_eq!(observer.line().await, b"ACK:JOIN den\n");
+    let mut leaving = Vec::new();
+    for (c_id, nick) in [(2, "quitter"), (3, "rambler"), (4, "garbler")] {
+        let mut client = Client::login(addr, c_id).await;
+        client
+            .send(format!("/join den\n/nick {}\n", nick).as_bytes())
+            .await;
+        assert_eq!(client.text().await, "ACK:JOIN den");
+        assert_eq!(client.text().await, format!("ACK:NICK {}", nick));
+        leaving.push(client);
+    }
+    let mut garbler = leaving.pop().unwrap();
+    let mut rambler = leaving.pop().unwrap();
+
+    // the rest of the room is told of every departure, with its cause
+    drop(leaving);
+    for client in [&mut observer, &mut rambler, &mut garbler] {
+        assert_eq!(client.line().await, b"LEAVE:2 quit\n");
+    }
+    rambler.send(&[b'x'; 100]).await;
+    assert_eq!(rambler.line().await, b"ERR:TOO_LONG\n");
+    for client in [&mut observer, &mut garbler] {
+        assert_eq!(client.line().await, b"LEAVE:3 too_long\n");
+    }
+    garbler.send(b"\xff\xfe\n").await;
+    assert_eq!(garbler.line().await, b"ERR:INVALID_UTF8\n");
+    assert_eq!(observer.line().await, b"LEAVE:4 invalid_utf8\n");
+
+    // none of them is listed any more, and their nicknames are free again
+    observer.send(b"/roster\n").await;
+    assert_eq!(observer.line().await, b"ROSTER:1:den:\n");
+    let mut successor = Client::login(addr, 5).await;
+    for nick in ["quitter", "rambler", "garbler"] {
+        successor.send(format!("/nick {}\n", nick).as_bytes()).await;
+        assert_eq!(successor.text().await, format!("ACK:NICK {}", nick));
+    }
+}
+
+#[tokio::test]
+async fn client_still_logging_in_counts_towards_the_client_limit() {
+    let config = ServerConfig {
+        max_clients: Some(1),
+        handshake: true,
+        ..test_config()
+    };
+    let addr = start_server(config, Extensions::default());
+    let mut first = Client::connect(addr).await;
+    assert!(first.text().await.starts_with("CAPS:"));
+
+    // the first client has not finished its handshake, but has its place already
+    let mut refused = Client::connect(addr).await;
+    assert_eq!(refused.line().await, b"ERR:SERVER_FULL retry=5\n");
+    refused.expect_closed().await;
+    first.send(b"LOGIN\n").await;
+    assert_eq!(first.line().await, b"LOGIN:1\n");
+}
+
+#[tokio::test]
+async fn mass_disconnect_leaves_no_client_behind() {
+    let addr = start_server(test_config(), Extensions::default());
+    let mut observer = Client::login(addr, 1).await;
+    let mut clients = Vec::new();
+    for c_id in 2..=201 {
+        clients.push(Client::login(addr, c_id).await);
+    }
+
+    drop(clients);
+    let mut departed = std::collections::HashSet::new();
+    while departed.len() < 200 {
+        let line = observer.text().await;
+        let c_id: u64 = line
+            .strip_prefix("LEAVE:")
+            .and_then(|rest| rest.strip_suffix(" quit"))
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!(departed.insert(c_id), "{} left twice", c_id);
+    }
+    observer.send(b"/roster\n").await;
+    assert_eq!(observer.line().await, b"ROSTER:1:lobby:\n");
+}
+
+#[tokio::test]
+async fn clients_on_different_listen_addresses_share_the_server() {
+    let second = free_addr();
+    let config = ServerConfig {
+        listen: vec![second],
+        ..test_config()
+    };
+    let addr = start_server(config, Extensions::default());
+    let mut first = Client::login(addr, 1).await;
+    let mut other = Client::login(second, 2).await;
+
+    first.send(b"from the first\n").await;
+    assert_eq!(first.line().await, b"ACK:MESSAGE 1\n");
+    assert_eq!(other.line().await, b"MESSAGE:1 from the first\n");
+    other.send(b"from the second\n").await;
+    assert_eq!(other.line().await, b"ACK:MESSAGE 2\n");
+    assert_eq!(first.line().await, b"MESSAGE:2 from the second\n");
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn shutdown_farewell_reaches_clients_within_the_grace_period() {
+    let addr = free_addr();
+    let mut server = tokio::process::Command::new(env!("CARGO_BIN_EXE_server"))
+        .args([
+            "--port",
+            &addr.port().to_string(),
+            "--disconnect-grace",
+            "1",
+        ])
+        .stdout(std::process::Stdio::null())
+        .spawn()
+        .unwrap();
+    let mut reader = Client::login(addr, 1).await;
+    reader.send(b"/join elsewhere\n").await;
+    assert_eq!(reader.line().await, b"ACK:JOIN elsewhere\n");
+    // a client which reads nothing, with far more queued for it than its socket buffers hold
+    let socket = tokio::net::TcpSocket::new_v4().unwrap();
+    socket.set_recv_buffer_size(4096).unwrap();
+    let _stuck = socket.connect(addr).await.unwrap();
+    let mut sender = Client::login(addr, 3).await;
+    let padding = "x".repeat(60_000);
+    for n in 1..=200 {
+        sender.send(format!("{}\n", padding).as_bytes()).await;
+        assert_eq!(sender.text().await, format!("ACK:MESSAGE {}", n));
+    }
+
+    let interrupted = std::process::Command::new("kill")
+        .args(["-INT", &server.id().unwrap().to_string()])
+        .status()
+        .unwrap();
+    assert!(interrupted.success());
+    assert_eq!(reader.line().await, b"SERVER:SHUTDOWN\n");
+    reader.expect_closed().await;
+    // the stuck client holds up the exit for no longer than the grace period
+    let started = tokio::time::Instant::now();
+    let status = tokio::time::timeout(LINE_TIMEOUT, server.wait())
+        .await
+        .expect("the server did not exit")
+        .unwrap();
+    assert!(status.success());
+    assert!(started.elapsed() < Duration::from_secs(3));
+}
+
+#[tokio::test]
+async fn half_closed_client_keeps_receiving_broadcasts() {
+    let config = ServerConfig {
+        half_close: Some(Duration::from_secs(1)),
+        ..test_config()
+    };
+    let addr = start_server(config, Extensions::default());
+    let mut sender = Client::login(addr, 1).await;
+    let mut listener = Client::login(addr, 2).await;
+
+    // the listener closes its sending side only, and is not taken to have left
+    listener.stream.get_mut().shutdown().await.unwrap();
+    sender.expect_silence().await;
+    sender.send(b"still there?\n").await;
+    assert_eq!(sender.line().await, b"ACK:MESSAGE 1\n");
+    assert_eq!(listener.line().await, b"MESSAGE:1 still there?\n");
+    // until the time runs out
+    assert_eq!(sender.line().await, b"LEAVE:2 quit\n");
+    listener.expect_closed().await;
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn two_servers_share_a_port_with_reuse_port() {
+    let config = || ServerConfig {
+        reuse_port: true,
+        ..test_config()
+    };
+    let addr = free_addr();
+    let first = spawn_server_at(addr, config(), Extensions::default());
+    let _client = Client::login(addr, 1).await;
+    let second = spawn_server_at(addr, config(), Extensions::default());
+
+    // each server numbers its own clients, so both serving shows as a second client with the first ID
+    let deadline = tokio::time::Instant::now() + LINE_TIMEOUT;
+    let mut clients = Vec::new();
+    loop {
+        assert!(
+            tokio::time::Instant::now() < deadline,
+            "the second server never accepted a client"
+        );
+        let mut client = Client::connect(addr).await;
+        if client.line().await == b"LOGIN:1\n" {
+            break;
+        }
+        clients.push(client);
+    }
+    assert!(!first.is_finished() && !second.is_finished());
+}
+
+#[tokio::test]
+async fn dumb_client_skipping_the_handshake_still_works() {
+    let config = ServerConfig {
+        handshake: true,
+        legacy_fallback: Some(Duration::from_millis(200)),
+        ..test_config()
+    };
+    let addr = start_server(config, Extensions::default());
+    let mut modern = Client::connect(addr).await;
+    handshake(&mut modern, "").await;
+    assert_eq!(modern.line().await, b"LOGIN:1\n");
+
+    // the dumb client's first line is a chat message, which is broadcast once it is logged in
+    let mut dumb = Client::connect(addr).await;
+    assert!(dumb.text().await.starts_with("CAPS:"));
+    dumb.send(b"hello from nc\n").await;
+    assert_eq!(dumb.line().await, b"LOGIN:2\n");
+    assert_eq!(dumb.line().await, b"ACK:MESSAGE 1\n");
+    assert_eq!(modern.line().await, b"MESSAGE:2 hello from nc\n");
+    // one which sends nothing at all is logged in once the window has passed
+    let mut silent = Client::connect(addr).await;
+    assert!(silent.text().await.starts_with("CAPS:"));
+    assert_eq!(silent.line().await, b"LOGIN:3\n");
+    assert_eq!(silent.line().await, b"HISTORY:2 hello from nc\n");
+    modern.send(b"hi both\n").await;
+    assert_eq!(modern.line().await, b"ACK:MESSAGE 2\n");
+    assert_eq!(dumb.line().await, b"MESSAGE:1 hi both\n");
+    assert_eq!(silent.line().await, b"MESSAGE:1 hi both\n");
+}
+
+/** Function which starts the server binary listening on the given address with the given further arguments,
+* returning the process along with a task which ends with every line it printed to standard error.
+*/
+fn spawn_collecting_errors(
+    addr: std::net::SocketAddr,
+    args: &[&str],
+) -> (tokio::process::Child, tokio::task::JoinHandle<Vec<String>>) {
+    let mut server = tokio::process::Command::new(env!("CARGO_BIN_EXE_server"))
+        .args(["--port", &addr.port().to_string()])
+        .args(args)
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .unwrap();
+    let mut stderr = tokio::io::BufReader::new(server.stderr.take().unwrap()).lines();
+    let errors = tokio::spawn(async move {
+        let mut errors = Vec::new();
+        while let Ok(Some(line)) = stderr.next_line().await {
+            errors.push(line);
+        }
+        errors
+    });
+    (server, errors)
+}
+
+#[tokio::test]
+async fn kicking_clients_while_they_broadcast_logs_no_failed_writes() {
+    let addr = free_addr();
+    let (mut server, errors) = spawn_collecting_errors(addr, &["--admin-password", ADMIN_PASSWORD]);
+    let mut admin = Client::login(addr, 1).await;
+    admin.become_admin().await;
+
+    // two clients pipeline messages to each other (and the admin), reading whatever arrives until they are closed
+    let mut broadcasters = Vec::new();
+    for c_id in 2..=3 {
+        let client = Client::login(addr, c_id).await;
+        let (mut reader, mut writer) = client.stream.into_inner().into_split();
+        broadcasters.push(tokio::spawn(async move {
+            let burst = "busy\n".repeat(2000);
+            let _ = writer.write_all(burst.as_bytes()).await;
+        }));
+        broadcasters.push(tokio::spawn(async move {
+            let _ = tokio::io::copy(&mut reader, &mut tokio::io::sink()).await;
+        }));
+    }
+    // the admin kicks everyone once the broadcasts are well under way
+    for _ in 0..50 {
+        assert!(admin.text().await.starts_with("MESSAGE:"));
+    }
+    admin.send(b"/kickall\n").await;
+    // the notice may overtake broadcasts queued for the admin before it, which are still written out
+    let mut notified = false;
+    loop {
+        let mut line = String::new();
+        let read = admin.stream.read_line(&mut line);
+        match tokio::time::timeout(LINE_TIMEOUT, read).await.unwrap() {
+            Ok(0) | Err(_) => break,
+            Ok(_) if line == "SERVER:RESET\n" => notified = true,
+            Ok(_) => assert!(line.starts_with("MESSAGE:"), "{}", line),
+        }
+    }
+    assert!(notified, "the removal notice never arrived");
+    for task in broadcasters {
+        tokio::time::timeout(LINE_TIMEOUT, task)
+            .await
+            .expect("a kicked client was never closed")
+            .unwrap();
+    }
+
+    let mut fresh = Client::connect(addr).await;
+    assert_eq!(fresh.line().await, b"LOGIN:4\n");
+    fresh.send(b"/ping after\n").await;
+    while fresh.text().await != "PONG:after" {}
+    server.kill().await.unwrap();
+    let errors = errors.await.unwrap();
+    assert!(
+        errors.iter().all(|line| !line.starts_with("Failed")),
+        "{:?}",
+        errors
+    );
+}
+
+#[tokio::test]
+async fn malformed_commands_only_disconnect_in_strict_mode() {
+    for input in [&b"/bogus\n"[..], b"/recall many\n"] {
+        let lenient = start_server(test_config(), Extensions::default());
+        let mut client = Client::login(lenient, 1).await;
+        client.send(input).await;
+        assert!(client.text().await.starts_with("ERR:"));
+        client.send(b"/ping still here\n").await;
+        assert_eq!(client.line().await, b"PONG:still here\n");
+
+        let config = ServerConfig {
+            strict: true,
+            ..test_config()
+        };
+        let strict = start_server(config, Extensions::default());
+        let mut observer = Client::login(strict, 1).await;
+        let mut client = Client::login(strict, 2).await;
+        client.send(input).await;
+        assert!(client.text().await.starts_with("ERR:"));
+        client.expect_closed().await;
+        assert_eq!(observer.line().await, b"LEAVE:2 protocol_violation\n");
+        // a refused request is not malformed
+        observer.send(b"/announce hello\n").await;
+        assert_eq!(observer.line().await, b"ERR:FORBIDDEN\n");
+        observer.send(b"/ping still here\n").await;
+        assert_eq!(observer.line().await, b"PONG:still here\n");
+    }
+}
+
+#[tokio::test]
+async fn port_already_in_use_is_reported_as_such() {
+    let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = taken.local_addr().unwrap();
+    let config = ServerConfig {
+        bind: addr,
+        ..test_config()
+    };
+    let Err(error) = run_server_with(config, Extensions::default()).await else {
+        panic!("the server started on a port in use");
+    };
+    let Some(in_use) = error.downcast_ref::<AddrInUse>() else {
+        panic!("{}", error);
+    };
+    assert_eq!(in_use.addr, addr);
+
+    let output = tokio::process::Command::new(env!("CARGO_BIN_EXE_server"))
+        .args(["--port", &addr.port().to_string()])
+        .output()
+        .await
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.starts_with(&format!(
+            "Error: port {} already in use, choose another port with --port",
+            addr.port()
+        )),
+        "{}",
+        stderr
+    );
+}
+
+#[tokio::test]
+async fn auto_port_falls_back_to_a_free_port() {
+    let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = taken.local_addr().unwrap().port();
+    let mut server = tokio::process::Command::new(env!("CARGO_BIN_EXE_server"))
+        .args(["--port", &port.to_string(), "--auto-port"])
+        .stdout(std::process::Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .unwrap();
+    let mut stdout = tokio::io::BufReader::new(server.stdout.take().unwrap()).lines();
+    let chosen = loop {
+        let line = tokio::time::timeout(LINE_TIMEOUT, stdout.next_line())
+            .await
+            .unwrap()
+            .unwrap()
+            .expect("the server exited");
+        if let Some(chosen) = line.strip_prefix("listening on port ") {
+            break chosen.parse::<u16>().unwrap();
+        }
+    };
+    assert_ne!(chosen, port);
+    Client::login(([127, 0, 0, 1], chosen).into(), 1).await;
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn interrupted_server_prints_its_summary_and_exits_cleanly() {
+    let addr = free_addr();
+    let server = tokio::process::Command::new(env!("CARGO_BIN_EXE_server"))
+        .args(["--port", &addr.port().to_string()])
+        .stdout(std::process::Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .unwrap();
+    let mut first = Client::login(addr, 1).await;
+    let mut second = Client::login(addr, 2).await;
+    for (seq, text) in [(1, "one"), (2, "two")] {
+        first.send(format!("{}\n", text).as_bytes()).await;
+        assert_eq!(first.text().await, format!("ACK:MESSAGE {}", seq));
+        assert_eq!(second.text().await, format!("MESSAGE:1 {}", text));
+    }
+
+    let interrupted = std::process::Command::new("kill")
+        .args(["-INT", &server.id().unwrap().to_string()])
+        .status()
+        .unwrap();
+    assert!(interrupted.success());
+    for client in [&mut first, &mut second] {
+        assert_eq!(client.line().await, b"SERVER:SHUTDOWN\n");
+    }
+    let output = tokio::time::timeout(LINE_TIMEOUT, server.wait_with_output())
+        .await
+        .expect("the server did not exit")
+        .unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let summary = stdout
+        .lines()
+        .find(|line| line.starts_with("shutdown reason="))
+        .expect(&stdout);
+    assert!(
+        summary
+            .starts_with("shutdown reason=signal clients_notified=2 messages_broadcast=2 uptime="),
+        "{}",
+        summary
+    );
+}
+
+#[tokio::test]
+async fn clients_dropping_mid_broadcast_log_no_failed_writes() {
+    let addr = free_addr();
+    let (mut server, errors) = spawn_collecting_errors(addr, &[]);
+    let mut sender = Client::login(addr, 1).await;
+    let padding = "x".repeat(1000);
+
+    // clients keep arriving and disconnecting (some with lines still unread) while the sender broadcasts
+    let mut leavers = Vec::new();
+    for round in 0..20 {
+        for _ in 0..5 {
+            leavers.push(Client::connect(addr).await);
+        }
+        sender
+            .send(format!("{} {}\n{} {}\n", round, padding, round, padding).as_bytes())
+            .await;
+        leavers.drain(..3);
+        tokio::task::yield_now().await;
+        leavers.clear();
+    }
+    // the sender is also told of every departure
+    let mut acks = 0;
+    while acks < 40 {
+        let line = sender.text().await;
+        if line.starts_with("ACK:MESSAGE ") {
+            acks += 1;
+        } else {
+            assert!(line.starts_with("LEAVE:"), "{}", line);
+        }
+    }
+    let mut fresh = Client::connect(addr).await;
+    fresh.send(b"/ping after\n").await;
+    while fresh.text().await != "PONG:after" {}
+
+    server.kill().await.unwrap();
+    let errors = errors.await.unwrap();
+    assert!(
+        errors.iter().all(|line| !line.starts_with("Failed")),
+        "{:?}",
+        errors
+    );
+}
@@ -0,0 +1,593 @@
+mod common;
+
+use common::*;
+use rust_broadcast_server::config::ServerConfig;
+use rust_broadcast_server::{Extensions, IdentityFormat};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+
+#[tokio::test]
+async fn concurrent_fanout_reaches_every_client() {
+    let config = ServerConfig {
+        fanout_concurrency: 16,
+        ..test_config()
+    };
+    let addr = start_server(config, Extensions::default());
+    let mut sender = Client::login(addr, 1).await;
+    let mut receivers = Vec::new();
+    for c_id in 2..=101 {
+        receivers.push(Client::login(addr, c_id).await);
+    }
+
+    sender.send(b"to everyone\n").await;
+    assert_eq!(sender.line().await, b"ACK:MESSAGE 1\n");
+    for receiver in receivers.iter_mut() {
+        assert_eq!(receiver.line().await, b"MESSAGE:1 to everyone\n");
+    }
+}
+
+#[tokio::test]
+async fn high_priority_notice_overtakes_queued_messages() {
+    let (streams, in_memory) = mpsc::unbounded_channel();
+    let extensions = Extensions {
+        in_memory: Some(in_memory),
+        ..Extensions::default()
+    };
+    let addr = start_server(admin_config(), extensions);
+    let mut admin = Client::login(addr, 1).await;
+    admin.become_admin().await;
+    let mut sender = Client::login(addr, 2).await;
+    // the receiver's stream only buffers a few lines, so while it is not read the rest wait in its queue
+    let (stream, server_end) = tokio::io::duplex(64);
+    streams.send(server_end).unwrap();
+    let mut receiver = Client::new(stream);
+    assert_eq!(receiver.line().await, b"LOGIN:3\n");
+
+    for n in 1..=200 {
+        sender.send(format!("chat {}\n", n).as_bytes()).await;
+    }
+    for n in 1..=200 {
+        assert_eq!(sender.text().await, format!("ACK:MESSAGE {}", n));
+        assert_eq!(admin.text().await, format!("MESSAGE:2 chat {}", n));
+    }
+    admin.send(b"/announce urgent\n").await;
+    assert_eq!(admin.line().await, b"ANNOUNCE:urgent\n");
+
+    let mut lines = Vec::new();
+    for _ in 0..201 {
+        lines.push(receiver.text().await);
+    }
+    let announced = lines
+        .iter()
+        .position(|line| line == "ANNOUNCE:urgent")
+        .unwrap();
+    assert!(
+        announced < 10,
+        "announcement only arrived as line {}",
+        announced
+    );
+    // the messages it overtook still arrive, in order
+    lines.remove(announced);
+    for (n, line) in (1..=200).zip(&lines) {
+        assert_eq!(line, &format!("MESSAGE:2 chat {}", n));
+    }
+}
+
+#[tokio::test]
+async fn json_and_text_clients_receive_the_same_message_in_their_formats() {
+    let config = ServerConfig {
+        handshake: true,
+        ..test_config()
+    };
+    let addr = start_server(config, Extensions::default());
+    let mut sender = Client::connect(addr).await;
+    handshake(&mut sender, "").await;
+    assert_eq!(sender.line().await, b"LOGIN:1\n");
+    let mut text = Client::connect(addr).await;
+    handshake(&mut text, "").await;
+    assert_eq!(text.line().await, b"LOGIN:2\n");
+    let mut json = Client::connect(addr).await;
+    assert!(json.text().await.starts_with("CAPS:"));
+    json.send(b"FORMAT json\nLOGIN\n").await;
+    let login: serde_json::Value = serde_json::from_str(&json.text().await).unwrap();
+    assert_eq!(
+        login,
+        serde_json::json!({"type": "reply", "line": "LOGIN:3"})
+    );
+
+    sender.send(b"same message\n").await;
+    assert_eq!(sender.line().await, b"ACK:MESSAGE 1\n");
+    assert_eq!(text.line().await, b"MESSAGE:1 same message\n");
+    let chat: serde_json::Value = serde_json::from_str(&json.text().await).unwrap();
+    assert_eq!(
+        chat,
+        serde_json::json!({"type": "chat", "seq": 1, "from": 1, "text": "same message"})
+    );
+}
+
+#[tokio::test]
+async fn message_without_other_recipients_is_still_acknowledged() {
+    let addr = start_server(test_config(), Extensions::default());
+    let mut alone = Client::login(addr, 1).await;
+
+    alone.send(b"anyone?\n").await;
+    assert_eq!(alone.line().await, b"ACK:MESSAGE 1\n");
+    alone.expect_silence().await;
+}
+
+#[tokio::test]
+async fn message_from_a_sender_which_has_just_left_is_handled() {
+    let addr = start_server(test_config(), Extensions::default());
+    let mut receiver = Client::login(addr, 1).await;
+    let mut sender = Client::login(addr, 2).await;
+
+    sender.send(b"last words\n").await;
+    drop(sender);
+    // the message may be broadcast before the sender's removal or dropped after it, but the sender always leaves
+    let mut line = receiver.line().await;
+    let broadcast = line == b"MESSAGE:2 last words\n";
+    if broadcast {
+        line = receiver.line().await;
+    }
+    assert_eq!(line, b"LEAVE:2 quit\n");
+    // the server carries on, with a broadcast message kept in history like any other
+    let mut next = Client::login(addr, 3).await;
+    if broadcast {
+        assert_eq!(next.line().await, b"HISTORY:2 last words\n");
+    }
+    next.send(b"still up\n").await;
+    assert!(next.text().await.starts_with("ACK:MESSAGE "));
+    assert_eq!(receiver.line().await, b"MESSAGE:3 still up\n");
+}
+
+#[tokio::test]
+async fn acknowledgement_carries_the_broadcast_sequence_number() {
+    let config = ServerConfig {
+        handshake: true,
+        ..test_config()
+    };
+    let addr = start_server(config, Extensions::default());
+    let mut senders = Vec::new();
+    for c_id in 1..=2 {
+        let mut sender = Client::connect(addr).await;
+        handshake(&mut sender, "").await;
+        assert_eq!(sender.text().await, format!("LOGIN:{}", c_id));
+        senders.push(sender);
+    }
+    // the JSON format shows each broadcast's sequence number
+    let mut receiver = Client::connect(addr).await;
+    assert!(receiver.text().await.starts_with("CAPS:"));
+    receiver.send(b"FORMAT json\nLOGIN\n").await;
+    receiver.line().await;
+
+    for (i, sender) in senders.iter_mut().enumerate() {
+        for n in 0..10 {
+            sender.send(format!("{} {}\n", i, n).as_bytes()).await;
+        }
+    }
+    let mut acked = std::collections::HashMap::new();
+    for (i, sender) in senders.iter_mut().enumerate() {
+        let mut n = 0;
+        while n < 10 {
+            let line = sender.text().await;
+            if let Some(seq) = line.strip_prefix("ACK:MESSAGE ") {
+                acked.insert(format!("{} {}", i, n), seq.parse::<u64>().unwrap());
+                n += 1;
+            }
+        }
+    }
+    for _ in 0..20 {
+        let chat: serde_json::Value = serde_json::from_str(&receiver.text().await).unwrap();
+        let text = chat["text"].as_str().unwrap();
+        assert_eq!(chat["seq"].as_u64(), acked.get(text).copied(), "{}", text);
+    }
+}
+
+#[tokio::test]
+async fn bare_acknowledgement_is_kept_for_old_clients() {
+    let config = ServerConfig {
+        bare_ack: true,
+        ..test_config()
+    };
+    let addr = start_server(config, Extensions::default());
+    let mut sender = Client::login(addr, 1).await;
+
+    sender.send(b"hello\n").await;
+    assert_eq!(sender.line().await, b"ACK:MESSAGE\n");
+}
+
+#[tokio::test]
+async fn awkward_text_is_encoded_for_every_recipient() {
+    let config = ServerConfig {
+        handshake: true,
+        ..test_config()
+    };
+    let addr = start_server(config, Extensions::default());
+    let mut sender = Client::connect(addr).await;
+    handshake(&mut sender, "").await;
+    assert_eq!(sender.line().await, b"LOGIN:1\n");
+    let mut json = Client::connect(addr).await;
+    assert!(json.text().await.starts_with("CAPS:"));
+    json.send(b"FORMAT json\nLOGIN\n").await;
+    json.line().await;
+    let mut text = Client::connect(addr).await;
+    handshake(&mut text, "").await;
+    assert_eq!(text.line().await, b"LOGIN:3\n");
+
+    // quotes, backslashes and control characters need escaping in JSON but are sent as they are in text
+    let awkward = "say \"hi\" \\ \t\u{1} 😀 }{";
+    sender.send(format!("{}\n", awkward).as_bytes()).await;
+    assert_eq!(sender.line().await, b"ACK:MESSAGE 1\n");
+    let chat: serde_json::Value = serde_json::from_str(&json.text().await).unwrap();
+    assert_eq!(chat["text"], awkward);
+    assert_eq!(text.text().await, format!("MESSAGE:1 {}", awkward));
+}
+
+#[tokio::test]
+async fn minimal_client_only_receives_the_events_it_declared() {
+    let config = ServerConfig {
+        handshake: true,
+        ..test_config()
+    };
+    let addr = start_server(config, Extensions::default());
+    let mut clients = Vec::new();
+    for (c_id, caps) in [(1, "reactions"), (2, "dm"), (3, "dm,reactions,presence")] {
+        let mut client = Client::connect(addr).await;
+        handshake(&mut client, caps).await;
+        assert_eq!(client.text().await, format!("LOGIN:{}", c_id));
+        clients.push(client);
+    }
+    let mut full = clients.pop().unwrap();
+    let mut minimal = clients.pop().unwrap();
+    let mut sender = clients.pop().unwrap();
+
+    // chat messages need no capability
+    sender.send(b"news\n").await;
+    assert_eq!(sender.line().await, b"ACK:MESSAGE 1\n");
+    assert_eq!(minimal.line().await, b"MESSAGE:1 news\n");
+    assert_eq!(full.line().await, b"MESSAGE:1 news\n");
+    // the minimal client did not declare reactions, so is told of them in a chat message instead
+    full.send("/react 1 👍\n".as_bytes()).await;
+    assert_eq!(full.line().await, b"ACK:REACT 1\n");
+    assert_eq!(sender.text().await, "REACT:1 3 👍");
+    assert_eq!(minimal.text().await, "MESSAGE:3 reacted 👍 to message 1");
+    // but did declare private messages, which the sender did not
+    full.send(b"/multicast 1,2 psst\n").await;
+    assert!(full.text().await.starts_with("ACK:MULTICAST "));
+    assert_eq!(minimal.line().await, b"PRIVATE:3 psst\n");
+    // and neither of them declared presence
+    drop(full);
+    minimal.expect_silence().await;
+    sender.expect_silence().await;
+}
+
+#[tokio::test]
+async fn buffered_message_is_flushed_without_further_activity() {
+    let config = ServerConfig {
+        flush_interval: Some(std::time::Duration::from_millis(200)),
+        ..test_config()
+    };
+    let addr = start_server(config, Extensions::default());
+    let mut sender = Client::login(addr, 1).await;
+    let mut receiver = Client::login(addr, 2).await;
+
+    // a single message, with nothing sent after it to push it out
+    let started = tokio::time::Instant::now();
+    sender.send(b"lone message\n").await;
+    assert_eq!(receiver.line().await, b"MESSAGE:1 lone message\n");
+    assert!(
+        started.elapsed() < std::time::Duration::from_secs(1),
+        "the message took {:?} to arrive",
+        started.elapsed()
+    );
+    assert_eq!(sender.line().await, b"ACK:MESSAGE 1\n");
+}
+
+#[tokio::test]
+async fn edit_reaches_capable_clients_and_a_fallback_the_rest() {
+    let config = ServerConfig {
+        handshake: true,
+        ..test_config()
+    };
+    let addr = start_server(config, Extensions::default());
+    let mut clients = Vec::new();
+    for (c_id, caps) in [(1, ""), (2, "edits"), (3, "")] {
+        let mut client = Client::connect(addr).await;
+        handshake(&mut client, caps).await;
+        assert_eq!(client.text().await, format!("LOGIN:{}", c_id));
+        clients.push(client);
+    }
+    let mut incapable = clients.pop().unwrap();
+    let mut capable = clients.pop().unwrap();
+    let mut sender = clients.pop().unwrap();
+    sender.send(b"typo\n").await;
+    assert_eq!(sender.line().await, b"ACK:MESSAGE 1\n");
+    assert_eq!(capable.line().await, b"MESSAGE:1 typo\n");
+    assert_eq!(incapable.line().await, b"MESSAGE:1 typo\n");
+
+    // the same edit, in the form each recipient can handle
+    sender.send(b"/edit 1 fixed\n").await;
+    assert_eq!(sender.line().await, b"ACK:EDIT 1\n");
+    assert_eq!(capable.line().await, b"EDIT:1 fixed\n");
+    assert_eq!(
+        incapable.line().await,
+        b"MESSAGE:1 edited message 1: fixed\n"
+    );
+}
+
+#[tokio::test]
+async fn client_which_never_reads_does_not_freeze_the_server() {
+    let (streams, in_memory) = mpsc::unbounded_channel();
+    let extensions = Extensions {
+        in_memory: Some(in_memory),
+        ..Extensions::default()
+    };
+    let addr = start_server(admin_config(), extensions);
+    let mut admin = Client::login(addr, 1).await;
+    admin.become_admin().await;
+    let mut other = Client::login(addr, 2).await;
+    // the stuck client's stream only buffers a few lines, and it reads nothing after logging in
+    let (stream, server_end) = tokio::io::duplex(64);
+    streams.send(server_end).unwrap();
+    let mut stuck = Client::new(stream);
+    assert_eq!(stuck.line().await, b"LOGIN:3\n");
+
+    // far more private messages and announcements than its queues hold are each answered at once
+    for _ in 0..1500 {
+        admin.send(b"/multicast 3 psst\n").await;
+        assert!(admin.text().await.starts_with("ACK:MULTICAST "));
+    }
+    for _ in 0..1500 {
+        admin.send(b"/announce hear ye\n").await;
+        assert_eq!(admin.line().await, b"ANNOUNCE:hear ye\n");
+        assert_eq!(other.line().await, b"ANNOUNCE:hear ye\n");
+    }
+    // as are its own commands, whose replies it never reads
+    for _ in 0..1500 {
+        stuck.send(b"/ping unread\n").await;
+    }
+    other.send(b"still served\n").await;
+    assert_eq!(other.line().await, b"ACK:MESSAGE 1\n");
+    assert_eq!(admin.line().await, b"MESSAGE:2 still served\n");
+}
+
+#[tokio::test]
+async fn broadcasts_in_other_rooms_are_not_held_up_by_a_slow_one() {
+    let (streams, in_memory) = mpsc::unbounded_channel();
+    let extensions = Extensions {
+        in_memory: Some(in_memory),
+        ..Extensions::default()
+    };
+    let addr = start_server(test_config(), extensions);
+    let mut slow_sender = Client::login(addr, 1).await;
+    let mut sender = Client::login(addr, 2).await;
+    let mut receiver = Client::login(addr, 3).await;
+    for client in [&mut sender, &mut receiver] {
+        client.send(b"/join den\n").await;
+        assert_eq!(client.line().await, b"ACK:JOIN den\n");
+    }
+    // a lobby client which never reads, whose stream only buffers a few lines
+    let (stream, server_end) = tokio::io::duplex(64);
+    streams.send(server_end).unwrap();
+    let mut stuck = Client::new(stream);
+    assert_eq!(stuck.line().await, b"LOGIN:4\n");
+
+    // fill its queue, until a broadcast to it has to wait for room
+    let mut n = 0;
+    loop {
+        n += 1;
+        assert!(n < 2000, "the stuck client's queue never filled");
+        let sent = tokio::time::Instant::now();
+        slow_sender.send(b"filler\n").await;
+        slow_sender.line().await;
+        if sent.elapsed() > std::time::Duration::from_millis(200) {
+            break;
+        }
+    }
+    // while the next lobby broadcast waits, a broadcast in the den goes straight through
+    let sent = tokio::time::Instant::now();
+    slow_sender.send(b"waits\n").await;
+    tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    sender.send(b"goes through\n").await;
+    assert_eq!(receiver.text().await, "MESSAGE:2 goes through");
+    assert!(sender.text().await.starts_with("ACK:MESSAGE "));
+    let fast = sent.elapsed();
+    assert!(slow_sender.text().await.starts_with("ACK:MESSAGE "));
+    let slow = sent.elapsed();
+    // the lobby's broadcast waits for the stuck client for the lag timeout, which the den's is well within
+    let waited = std::time::Duration::from_millis(200);
+    assert!(
+        fast < waited && slow > waited,
+        "the den waited {:?}, the lobby {:?}",
+        fast,
+        slow
+    );
+}
+
+#[tokio::test]
+async fn strict_order_shows_every_recipient_the_same_sequence() {
+    let config = ServerConfig {
+        handshake: true,
+        strict_order: true,
+        ..test_config()
+    };
+    let addr = start_server(config, Extensions::default());
+    let mut senders = Vec::new();
+    for c_id in 1..=3 {
+        let mut sender = Client::connect(addr).await;
+        handshake(&mut sender, "").await;
+        assert_eq!(sender.text().await, format!("LOGIN:{}", c_id));
+        senders.push(sender);
+    }
+    let mut receivers = Vec::new();
+    for _ in 0..2 {
+        let mut receiver = Client::connect(addr).await;
+        assert!(receiver.text().await.starts_with("CAPS:"));
+        receiver.send(b"FORMAT json\nLOGIN\n").await;
+        receiver.line().await;
+        receivers.push(receiver);
+    }
+
+    // the senders' messages interleave, each sending its next before the others' are through
+    for n in 0..20 {
+        for (i, sender) in senders.iter_mut().enumerate() {
+            sender.send(format!("{} {}\n", i, n).as_bytes()).await;
+        }
+    }
+    let mut seen = Vec::new();
+    for receiver in receivers.iter_mut() {
+        let mut order = Vec::new();
+        let mut next = [0; 3];
+        for _ in 0..60 {
+            let chat: serde_json::Value = serde_json::from_str(&receiver.text().await).unwrap();
+            let seq = chat["seq"].as_u64().unwrap();
+            // in sequence number order, and each sender's messages in the order it sent them
+            assert_eq!(seq, order.len() as u64 + 1);
+            let from = chat["from"].as_u64().unwrap() as usize - 1;
+            assert_eq!(chat["text"], format!("{} {}", from, next[from]));
+            next[from] += 1;
+            order.push(chat["text"].clone());
+        }
+        seen.push(order);
+    }
+    assert_eq!(seen[0], seen[1]);
+}
+
+#[tokio::test]
+async fn periodic_presence_reflects_the_connected_clients() {
+    let config = ServerConfig {
+        presence_interval: Some(std::time::Duration::from_secs(1)),
+        ..test_config()
+    };
+    let addr = start_server(config, Extensions::default());
+    let mut first = Client::login(addr, 1).await;
+    let mut second = Client::login(addr, 2).await;
+
+    for client in [&mut first, &mut second] {
+        assert_eq!(client.line().await, b"PRESENCE:1,2\n");
+    }
+    drop(second);
+    assert_eq!(first.line().await, b"LEAVE:2 quit\n");
+    // the next snapshot no longer lists the client which left
+    assert_eq!(first.line().await, b"PRESENCE:1\n");
+}
+
+#[tokio::test]
+async fn broadcast_can_leave_out_no_one_one_or_several_clients() {
+    // no one is left out when self delivery is allowed
+    let config = ServerConfig {
+        allow_self_delivery: true,
+        ..test_config()
+    };
+    let addr = start_server(config, Extensions::default());
+    let mut sender = Client::login(addr, 1).await;
+    let mut other = Client::login(addr, 2).await;
+    sender.send(b"to all of us\n").await;
+    assert_eq!(sender.line().await, b"MESSAGE:1 to all of us\n");
+    assert_eq!(sender.line().await, b"ACK:MESSAGE 1\n");
+    assert_eq!(other.line().await, b"MESSAGE:1 to all of us\n");
+
+    // by default only the sender is
+    let addr = start_server(test_config(), Extensions::default());
+    let mut sender = Client::login(addr, 1).await;
+    let mut others = Vec::new();
+    for c_id in 2..=5 {
+        others.push(Client::login(addr, c_id).await);
+    }
+    sender.send(b"to the others\n").await;
+    assert_eq!(sender.line().await, b"ACK:MESSAGE 1\n");
+    for client in others.iter_mut() {
+        assert_eq!(client.line().await, b"MESSAGE:1 to the others\n");
+    }
+    sender.expect_silence().await;
+
+    // several are, alongside the sender, once they stop taking chat messages
+    for client in &mut others[..2] {
+        client.send(b"/dnd on\n").await;
+        assert_eq!(client.line().await, b"ACK:DND on\n");
+    }
+    others[2].send(b"/subscribe events\n").await;
+    assert_eq!(others[2].line().await, b"ACK:SUBSCRIBE events\n");
+    sender.send(b"to the few\n").await;
+    assert_eq!(sender.line().await, b"ACK:MESSAGE 2\n");
+    assert_eq!(others[3].line().await, b"MESSAGE:1 to the few\n");
+    for client in others.iter_mut() {
+        client.expect_silence().await;
+    }
+}
+
+#[tokio::test]
+async fn pipelined_burst_is_handled_quickly_without_starving_others() {
+    const LINES: usize = 20_000;
+    let addr = start_server(test_config(), Extensions::default());
+    let burster = Client::login(addr, 1).await;
+    let mut other = Client::login(addr, 2).await;
+    other.send(b"/dnd on\n").await;
+    assert_eq!(other.line().await, b"ACK:DND on\n");
+
+    // the whole burst is written at once, while the acknowledgements are read as they come
+    let (read, mut write) = burster.stream.into_inner().into_split();
+    let started = tokio::time::Instant::now();
+    tokio::spawn(async move {
+        write
+            .write_all("burst\n".repeat(LINES).as_bytes())
+            .await
+            .unwrap();
+        write
+    });
+    let mut burster = Client::new(tokio::io::join(read, tokio::io::sink()));
+    assert_eq!(burster.line().await, b"ACK:MESSAGE 1\n");
+    // another client is answered while the burst is still being handled
+    other.send(b"/ping meanwhile\n").await;
+    assert_eq!(other.line().await, b"PONG:meanwhile\n");
+    let answered = started.elapsed();
+    for seq in 2..=LINES {
+        assert_eq!(burster.text().await, format!("ACK:MESSAGE {}", seq));
+    }
+    let elapsed = started.elapsed();
+    let rate = LINES as f64 / elapsed.as_secs_f64();
+    println!(
+        "{} pipelined lines in {:?} ({:.0} lines/s)",
+        LINES, elapsed, rate
+    );
+    assert!(elapsed < LINE_TIMEOUT, "{:?}", elapsed);
+    assert!(
+        answered < elapsed / 2,
+        "answered after {:?} of {:?}",
+        answered,
+        elapsed
+    );
+}
+
+#[tokio::test]
+async fn identity_format_labels_the_same_client_differently() {
+    let cases = [
+        ("{id}", "2", "3"),
+        ("{nick}@{ip}", "alice@127.0.0.1", "3@127.0.0.1"),
+        ("user-{nick}", "user-alice", "user-3"),
+    ];
+    for (template, named, anonymous) in cases {
+        let config = ServerConfig {
+            identity_format: template.parse::<IdentityFormat>().unwrap(),
+            ..test_config()
+        };
+        let addr = start_server(config, Extensions::default());
+        let mut observer = Client::login(addr, 1).await;
+        let mut alice = Client::login(addr, 2).await;
+        alice.send(b"/nick alice\nhi\n").await;
+        assert_eq!(alice.line().await, b"ACK:NICK alice\n");
+        assert_eq!(alice.line().await, b"ACK:MESSAGE 1\n");
+        // a client without a nickname falls back to its ID
+        let mut nameless = Client::login(addr, 3).await;
+        assert!(nameless.text().await.starts_with("HISTORY:"));
+        nameless.send(b"hey\n").await;
+        assert_eq!(nameless.line().await, b"ACK:MESSAGE 2\n");
+
+        assert_eq!(observer.text().await, format!("MESSAGE:{} hi", named));
+        assert_eq!(observer.text().await, format!("MESSAGE:{} hey", anonymous));
+        drop(alice);
+        assert_eq!(observer.text().await, format!("LEAVE:{} quit", named));
+    }
+    assert!("{bogus}".parse::<IdentityFormat>().is_err());
+    assert!("{nick} {ip}".parse::<IdentityFormat>().is_err());
+}
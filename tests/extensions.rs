@@ -0,0 +1,232 @@
+mod common;
+
+use common::*;
+use futures::future::BoxFuture;
+use rust_broadcast_server::Extensions;
+use rust_broadcast_server::filter::{ConnectDecision, ConnectionFilter};
+use rust_broadcast_server::handle::{SendReport, ServerHandle};
+use rust_broadcast_server::store::{MessageStore, StoredMessage};
+use std::collections::HashSet;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use tokio::net::TcpSocket;
+
+/** ConnectionFilter which rejects connections from one address, and records the address of every
+* connection it is consulted on.
+*/
+struct BanOne {
+    banned: IpAddr,
+    seen: Mutex<Vec<IpAddr>>,
+}
+
+impl ConnectionFilter for BanOne {
+    fn on_connect(&self, addr: SocketAddr) -> BoxFuture<'_, ConnectDecision> {
+        self.seen.lock().unwrap().push(addr.ip());
+        let decision = if addr.ip() == self.banned {
+            ConnectDecision::Reject("BANNED".to_string())
+        } else {
+            ConnectDecision::Accept
+        };
+        Box::pin(async move { decision })
+    }
+}
+
+/** MessageStore standing in for a backend which has stopped responding: appending a message (or
+* recording an edit or deletion) never completes, and it holds no messages.
+*/
+struct StuckStore;
+
+impl MessageStore for StuckStore {
+    fn append<'a>(&'a self, _message: &'a StoredMessage) -> BoxFuture<'a, ()> {
+        Box::pin(futures::future::pending())
+    }
+
+    fn edit<'a>(&'a self, _seq: u64, _text: &'a str) -> BoxFuture<'a, ()> {
+        Box::pin(futures::future::pending())
+    }
+
+    fn delete(&self, _seq: u64) -> BoxFuture<'_, ()> {
+        Box::pin(futures::future::pending())
+    }
+
+    fn recent(&self, _n: usize) -> BoxFuture<'_, Vec<StoredMessage>> {
+        Box::pin(async { Vec::new() })
+    }
+}
+
+// Function which connects to the server from the given local address, as a client on another host would.
+async fn connect_from(local: IpAddr, addr: SocketAddr) -> Client<tokio::net::TcpStream> {
+    let socket = TcpSocket::new_v4().unwrap();
+    socket.bind(SocketAddr::new(local, 0)).unwrap();
+    Client::new(socket.connect(addr).await.unwrap())
+}
+
+#[tokio::test]
+async fn filter_rejects_connections_from_a_banned_address() {
+    let banned = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2));
+    let filter = Arc::new(BanOne {
+        banned,
+        seen: Mutex::new(Vec::new()),
+    });
+    let extensions = Extensions {
+        connection_filter: filter.clone(),
+        ..Extensions::default()
+    };
+    let addr = start_server(test_config(), extensions);
+    // connect normally first, so the server is known to be listening
+    let mut allowed = Client::login(addr, 1).await;
+
+    let mut rejected = connect_from(banned, addr).await;
+    assert_eq!(rejected.line().await, b"ERR:BANNED retry=60\n");
+    rejected.expect_closed().await;
+    // the rejected connection took no client ID, and the others are unaffected
+    let mut next = Client::login(addr, 2).await;
+    next.send(b"hello\n").await;
+    assert_eq!(next.line().await, b"ACK:MESSAGE 1\n");
+    assert_eq!(allowed.line().await, b"MESSAGE:2 hello\n");
+    let localhost = IpAddr::V4(Ipv4Addr::LOCALHOST);
+    assert_eq!(
+        *filter.seen.lock().unwrap(),
+        vec![localhost, banned, localhost]
+    );
+}
+
+#[tokio::test]
+async fn broadcasts_carry_on_while_the_message_store_is_stuck() {
+    let extensions = Extensions {
+        message_store: Some(Arc::new(StuckStore)),
+        ..Extensions::default()
+    };
+    let addr = start_server(test_config(), extensions);
+    let mut sender = Client::login(addr, 1).await;
+    let mut receiver = Client::login(addr, 2).await;
+
+    for n in 1..=20 {
+        sender.send(format!("message {}\n", n).as_bytes()).await;
+        assert_eq!(sender.text().await, format!("ACK:MESSAGE {}", n));
+        assert_eq!(receiver.text().await, format!("MESSAGE:1 message {}", n));
+    }
+    // history is still served from memory
+    let mut late = Client::login(addr, 3).await;
+    assert_eq!(late.line().await, b"HISTORY:1 message 1\n");
+    late.send(b"/stats\n").await;
+    let mut stats = late.text().await;
+    while stats.starts_with("HISTORY:") {
+        stats = late.text().await;
+    }
+    assert!(stats.contains(" persistence_healthy=true "), "{}", stats);
+
+    // once the messages waiting for the store fill its queue, further ones are dropped rather than waited on
+    drop(late);
+    for client in [&mut sender, &mut receiver] {
+        assert_eq!(client.line().await, b"LEAVE:3 quit\n");
+    }
+    for n in 21..=1100 {
+        sender.send(format!("message {}\n", n).as_bytes()).await;
+        assert_eq!(sender.text().await, format!("ACK:MESSAGE {}", n));
+        assert_eq!(receiver.text().await, format!("MESSAGE:1 message {}", n));
+    }
+    sender.send(b"/stats\n").await;
+    let stats = sender.text().await;
+    assert!(stats.contains(" persistence_healthy=false "), "{}", stats);
+}
+
+#[tokio::test]
+async fn clients_can_log_in_and_chat_entirely_in_memory() {
+    let (streams, in_memory) = tokio::sync::mpsc::unbounded_channel();
+    let extensions = Extensions {
+        in_memory: Some(in_memory),
+        ..Extensions::default()
+    };
+    start_server(test_config(), extensions);
+    // no socket is involved on either side, so every byte the server writes is checked as it is
+    let mut clients = Vec::new();
+    for _ in 0..3 {
+        let (stream, server_end) = tokio::io::duplex(4096);
+        streams.send(server_end).unwrap();
+        clients.push(Client::new(stream));
+    }
+    for (c_id, client) in (1..).zip(clients.iter_mut()) {
+        assert_eq!(client.line().await, format!("LOGIN:{}\n", c_id).as_bytes());
+    }
+
+    clients[0].send(b"hello, memory\n").await;
+    assert_eq!(clients[0].line().await, b"ACK:MESSAGE 1\n");
+    assert_eq!(clients[1].line().await, b"MESSAGE:1 hello, memory\n");
+    assert_eq!(clients[2].line().await, b"MESSAGE:1 hello, memory\n");
+    // a message to a room nobody else is in reaches no one
+    clients[2].send(b"/join den\nalone here\n").await;
+    assert_eq!(clients[2].line().await, b"ACK:JOIN den\n");
+    assert_eq!(clients[2].line().await, b"ACK:MESSAGE 2\n");
+    for client in clients.iter_mut() {
+        client.expect_silence().await;
+    }
+}
+
+#[tokio::test]
+async fn handle_sends_to_the_listed_clients_only() {
+    let handle = ServerHandle::new();
+    assert_eq!(handle.send_to(&[1], "too early").await, None);
+    let extensions = Extensions {
+        handle: Some(handle.clone()),
+        ..Extensions::default()
+    };
+    let addr = start_server(test_config(), extensions);
+    let mut first = Client::login(addr, 1).await;
+    let mut second = Client::login(addr, 2).await;
+    let mut elsewhere = Client::login(addr, 3).await;
+    elsewhere.send(b"/join elsewhere\n").await;
+    assert_eq!(elsewhere.line().await, b"ACK:JOIN elsewhere\n");
+
+    // the unknown client 9 is skipped, and the listed clients are reached whatever their room
+    let report = handle.send_to(&[3, 9, 1], "maintenance soon").await;
+    let expected = SendReport {
+        delivered: vec![3, 1],
+        failed: vec![],
+    };
+    assert_eq!(report, Some(expected));
+    assert_eq!(first.line().await, b"ANNOUNCE:maintenance soon\n");
+    assert_eq!(elsewhere.line().await, b"ANNOUNCE:maintenance soon\n");
+    second.expect_silence().await;
+}
+
+#[tokio::test]
+async fn handle_broadcasts_to_the_room_except_the_excluded_clients() {
+    let handle = ServerHandle::new();
+    let extensions = Extensions {
+        handle: Some(handle.clone()),
+        ..Extensions::default()
+    };
+    let addr = start_server(test_config(), extensions);
+    let mut clients = Vec::new();
+    for c_id in 1..=5 {
+        clients.push(Client::login(addr, c_id).await);
+    }
+    // fanned out concurrently, so the clients reached are reported in no particular order
+    let broadcast = async |exclude: &[u64], text: &str| {
+        let exclude: HashSet<u64> = exclude.iter().copied().collect();
+        let mut report = handle.broadcast_except(1, &exclude, text).await.unwrap();
+        report.delivered.sort();
+        assert_eq!(report.failed, Vec::<u64>::new());
+        report.delivered
+    };
+
+    assert_eq!(broadcast(&[], "to everyone").await, vec![2, 3, 4, 5]);
+    for client in clients[1..].iter_mut() {
+        assert_eq!(client.line().await, b"MESSAGE:1 to everyone\n");
+    }
+    assert_eq!(broadcast(&[3], "all but one").await, vec![2, 4, 5]);
+    assert_eq!(broadcast(&[2, 4, 5], "all but three").await, vec![3]);
+    for c_id in [2, 4, 5] {
+        assert_eq!(clients[c_id - 1].line().await, b"MESSAGE:1 all but one\n");
+    }
+    assert_eq!(clients[2].line().await, b"MESSAGE:1 all but three\n");
+    // the message is not acknowledged to the client it is sent as
+    for client in clients.iter_mut() {
+        client.expect_silence().await;
+    }
+    let nobody = handle
+        .broadcast_except(9, &HashSet::new(), "from nobody")
+        .await;
+    assert_eq!(nobody, None);
+}
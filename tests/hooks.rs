@@ -0,0 +1,122 @@
+mod common;
+
+use common::*;
+use rust_broadcast_server::Extensions;
+use rust_broadcast_server::config::{NewlinePolicy, ServerConfig};
+use std::net::SocketAddr;
+
+// Function which writes the given Rhai script to a file of its own and starts a server running it on every message.
+fn start_with_script(name: &str, script: &str) -> SocketAddr {
+    start_with_script_config(name, script, test_config())
+}
+
+// Function which starts a server running the given Rhai script like start_with_script, with the given settings.
+fn start_with_script_config(name: &str, script: &str, config: ServerConfig) -> SocketAddr {
+    let path = temp_path(&format!("{}.rhai", name));
+    std::fs::write(&path, script).unwrap();
+    let config = ServerConfig {
+        script: Some(path),
+        ..config
+    };
+    start_server(config, Extensions::default())
+}
+
+#[tokio::test]
+async fn script_can_transform_messages() {
+    let addr = start_with_script("uppercase", "fn on_message(from, text) { text.to_upper() }");
+    let mut sender = Client::login(addr, 1).await;
+    let mut receiver = Client::login(addr, 2).await;
+
+    sender.send(b"quiet please\n").await;
+    assert_eq!(sender.line().await, b"ACK:MESSAGE 1\n");
+    assert_eq!(receiver.line().await, b"MESSAGE:1 QUIET PLEASE\n");
+}
+
+#[tokio::test]
+async fn script_can_drop_messages() {
+    let addr = start_with_script(
+        "drop-bad",
+        r#"fn on_message(from, text) { if text.contains("bad") { () } else { text } }"#,
+    );
+    let mut sender = Client::login(addr, 1).await;
+    let mut receiver = Client::login(addr, 2).await;
+
+    sender.send(b"a bad word\n").await;
+    sender.send(b"a good word\n").await;
+    // a dropped message is neither broadcast nor acknowledged, nor does it take a sequence number
+    assert_eq!(sender.line().await, b"ACK:MESSAGE 1\n");
+    assert_eq!(receiver.line().await, b"MESSAGE:1 a good word\n");
+    receiver.expect_silence().await;
+    sender.expect_silence().await;
+}
+
+#[tokio::test]
+async fn runaway_script_falls_back_to_the_original_message() {
+    let addr = start_with_script("runaway", "fn on_message(from, text) { loop { } }");
+    let mut sender = Client::login(addr, 1).await;
+    let mut receiver = Client::login(addr, 2).await;
+
+    sender.send(b"first\n").await;
+    assert_eq!(sender.line().await, b"ACK:MESSAGE 1\n");
+    assert_eq!(receiver.line().await, b"MESSAGE:1 first\n");
+    // the stopped call has not left the connection (or its processing permit) stuck
+    receiver.send(b"second\n").await;
+    assert_eq!(receiver.line().await, b"ACK:MESSAGE 2\n");
+    assert_eq!(sender.line().await, b"MESSAGE:2 second\n");
+}
+
+#[tokio::test]
+async fn lengthened_message_is_cut_at_a_character_boundary() {
+    let config = ServerConfig {
+        max_line_bytes: Some(7),
+        ..test_config()
+    };
+    let addr = start_with_script_config(
+        "triple",
+        "fn on_message(from, text) { text + text + text }",
+        config,
+    );
+    let mut sender = Client::login(addr, 1).await;
+    let mut receiver = Client::login(addr, 2).await;
+
+    // accented letters are two bytes each, so the seventh byte would split the fourth of them
+    sender.send("ééé\n".as_bytes()).await;
+    assert_eq!(sender.line().await, b"ACK:MESSAGE 1\n");
+    assert_eq!(receiver.text().await, "MESSAGE:1 ééé");
+    // an emoji is four bytes, of which only two would fit
+    sender.send("😀a\n".as_bytes()).await;
+    assert_eq!(sender.line().await, b"ACK:MESSAGE 2\n");
+    assert_eq!(receiver.text().await, "MESSAGE:1 😀a");
+}
+
+#[tokio::test]
+async fn embedded_newline_is_written_per_the_newline_policy() {
+    let cases = [
+        (NewlinePolicy::Escape, &["MESSAGE:1 first\\nsecond"][..]),
+        (NewlinePolicy::Space, &["MESSAGE:1 first second"]),
+        (
+            NewlinePolicy::Split,
+            &["MESSAGE:1 first", "MESSAGE:1 second"],
+        ),
+    ];
+    for (policy, expected) in cases {
+        let config = ServerConfig {
+            newline_policy: policy,
+            ..test_config()
+        };
+        let addr = start_with_script_config(
+            "newline",
+            r#"fn on_message(from, text) { text + "\nsecond" }"#,
+            config,
+        );
+        let mut sender = Client::login(addr, 1).await;
+        let mut receiver = Client::login(addr, 2).await;
+
+        sender.send(b"first\n").await;
+        assert_eq!(sender.line().await, b"ACK:MESSAGE 1\n");
+        for line in expected {
+            assert_eq!(receiver.text().await, *line);
+        }
+        receiver.expect_silence().await;
+    }
+}
@@ -0,0 +1,549 @@
+mod common;
+
+use common::*;
+use rust_broadcast_server::config::{Feature, NickPolicy, OverflowPolicy, ServerConfig};
+use rust_broadcast_server::store::{MemoryStore, MessageStore};
+use rust_broadcast_server::{Extensions, ShutdownReason};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+#[tokio::test]
+async fn login_acknowledges_each_client_with_its_own_id() {
+    let addr = start_server(test_config(), Extensions::default());
+    let mut first = Client::connect(addr).await;
+    assert_eq!(first.line().await, b"LOGIN:1\n");
+    let mut second = Client::connect(addr).await;
+    assert_eq!(second.line().await, b"LOGIN:2\n");
+    // a client is told nothing about the other clients logging in
+    first.expect_silence().await;
+}
+
+#[tokio::test]
+async fn message_is_broadcast_to_others_and_acknowledged_to_sender() {
+    let addr = start_server(test_config(), Extensions::default());
+    let mut sender = Client::connect(addr).await;
+    assert_eq!(sender.line().await, b"LOGIN:1\n");
+    let mut receiver = Client::connect(addr).await;
+    assert_eq!(receiver.line().await, b"LOGIN:2\n");
+    let mut bystander = Client::connect(addr).await;
+    assert_eq!(bystander.line().await, b"LOGIN:3\n");
+
+    sender.send(b"hello world\n").await;
+    assert_eq!(sender.line().await, b"ACK:MESSAGE 1\n");
+    assert_eq!(receiver.line().await, b"MESSAGE:1 hello world\n");
+    assert_eq!(bystander.line().await, b"MESSAGE:1 hello world\n");
+    // the sender is not sent its own message
+    sender.expect_silence().await;
+}
+
+#[tokio::test]
+async fn messages_arrive_in_the_order_they_were_sent() {
+    let addr = start_server(test_config(), Extensions::default());
+    let mut sender = Client::connect(addr).await;
+    assert_eq!(sender.line().await, b"LOGIN:1\n");
+    let mut receiver = Client::connect(addr).await;
+    assert_eq!(receiver.line().await, b"LOGIN:2\n");
+
+    // pipelined in a single write, with a carriage return the server strips
+    sender.send(b"one\ntwo\r\nthree\n").await;
+    for (seq, text) in ["one", "two", "three"].iter().enumerate() {
+        let ack = format!("ACK:MESSAGE {}\n", seq + 1);
+        assert_eq!(sender.line().await, ack.as_bytes());
+        let message = format!("MESSAGE:1 {}\n", text);
+        assert_eq!(receiver.line().await, message.as_bytes());
+    }
+}
+
+#[tokio::test]
+async fn disconnected_client_is_removed_and_announced() {
+    let addr = start_server(test_config(), Extensions::default());
+    let mut staying = Client::connect(addr).await;
+    assert_eq!(staying.line().await, b"LOGIN:1\n");
+    let mut leaving = Client::connect(addr).await;
+    assert_eq!(leaving.line().await, b"LOGIN:2\n");
+
+    drop(leaving);
+    assert_eq!(staying.line().await, b"LEAVE:2 quit\n");
+    // the departed client is no longer a recipient, and rosters no longer list it
+    staying.send(b"anyone there?\n").await;
+    assert_eq!(staying.line().await, b"ACK:MESSAGE 1\n");
+    staying.send(b"/roster\n").await;
+    assert_eq!(staying.line().await, b"ROSTER:1:lobby:\n");
+}
+
+#[tokio::test]
+async fn commands_are_answered_only_to_their_sender() {
+    let addr = start_server(test_config(), Extensions::default());
+    let mut client = Client::connect(addr).await;
+    assert_eq!(client.line().await, b"LOGIN:1\n");
+    let mut other = Client::connect(addr).await;
+    assert_eq!(other.line().await, b"LOGIN:2\n");
+
+    client.send(b"/ping 42\n").await;
+    assert_eq!(client.line().await, b"PONG:42\n");
+    client.send(b"/nick alice\n").await;
+    assert_eq!(client.line().await, b"ACK:NICK alice\n");
+    client.send(b"/nosuchcommand\n").await;
+    assert_eq!(client.line().await, b"ERR:UNKNOWN_COMMAND\n");
+    // a doubled prefix escapes a chat message starting with the prefix
+    client.send(b"//not a command\n").await;
+    assert_eq!(client.line().await, b"ACK:MESSAGE 1\n");
+    assert_eq!(other.line().await, b"MESSAGE:1 /not a command\n");
+    other.expect_silence().await;
+}
+
+#[tokio::test]
+async fn joining_client_is_replayed_room_history() {
+    let addr = start_server(test_config(), Extensions::default());
+    let mut sender = Client::connect(addr).await;
+    assert_eq!(sender.line().await, b"LOGIN:1\n");
+    sender.send(b"first\nsecond\n").await;
+    assert_eq!(sender.line().await, b"ACK:MESSAGE 1\n");
+    assert_eq!(sender.line().await, b"ACK:MESSAGE 2\n");
+
+    let mut late = Client::connect(addr).await;
+    assert_eq!(late.line().await, b"LOGIN:2\n");
+    assert_eq!(late.line().await, b"HISTORY:1 first\n");
+    assert_eq!(late.line().await, b"HISTORY:1 second\n");
+    late.expect_silence().await;
+}
+
+#[tokio::test]
+async fn overlong_line_disconnects_the_client() {
+    let config = ServerConfig {
+        max_line_bytes: Some(8),
+        ..test_config()
+    };
+    let addr = start_server(config, Extensions::default());
+    let mut client = Client::connect(addr).await;
+    assert_eq!(client.line().await, b"LOGIN:1\n");
+    let mut other = Client::connect(addr).await;
+    assert_eq!(other.line().await, b"LOGIN:2\n");
+
+    client.send(b"this line is too long\n").await;
+    assert_eq!(client.line().await, b"ERR:TOO_LONG\n");
+    client.expect_closed().await;
+    assert_eq!(other.line().await, b"LEAVE:1 too_long\n");
+}
+
+#[tokio::test]
+async fn in_memory_clients_share_rooms_with_tcp_clients() {
+    let (streams, in_memory) = mpsc::unbounded_channel();
+    let extensions = Extensions {
+        in_memory: Some(in_memory),
+        ..Extensions::default()
+    };
+    let addr = start_server(test_config(), extensions);
+    let mut tcp = Client::connect(addr).await;
+    assert_eq!(tcp.line().await, b"LOGIN:1\n");
+    let (stream, server_end) = tokio::io::duplex(4096);
+    streams.send(server_end).unwrap();
+    let mut memory = Client::new(stream);
+    assert_eq!(memory.line().await, b"LOGIN:2\n");
+
+    memory.send(b"from memory\n").await;
+    assert_eq!(memory.line().await, b"ACK:MESSAGE 1\n");
+    assert_eq!(tcp.line().await, b"MESSAGE:2 from memory\n");
+    tcp.send(b"from tcp\n").await;
+    assert_eq!(tcp.line().await, b"ACK:MESSAGE 2\n");
+    assert_eq!(memory.line().await, b"MESSAGE:1 from tcp\n");
+}
+
+#[tokio::test]
+async fn clients_without_an_address_get_distinct_ids() {
+    let (streams, in_memory) = mpsc::unbounded_channel();
+    let extensions = Extensions {
+        in_memory: Some(in_memory),
+        ..Extensions::default()
+    };
+    let addr = start_server(test_config(), extensions);
+    let mut tcp = Client::connect(addr).await;
+    assert_eq!(tcp.line().await, b"LOGIN:1\n");
+    // in-memory clients have no address, let alone a port, to tell them apart
+    let mut memory = Vec::new();
+    for _ in 0..2 {
+        let (stream, server_end) = tokio::io::duplex(4096);
+        streams.send(server_end).unwrap();
+        memory.push(Client::new(stream));
+    }
+    assert_eq!(memory[0].line().await, b"LOGIN:2\n");
+    assert_eq!(memory[1].line().await, b"LOGIN:3\n");
+    tcp.send(b"/roster\n").await;
+    assert_eq!(tcp.line().await, b"ROSTER:1:lobby: 2:lobby: 3:lobby:\n");
+}
+
+// Function which returns settings with the handshake on and short acknowledgement timeouts, for reliable clients.
+fn reliable_config(ack_retries: u32) -> ServerConfig {
+    ServerConfig {
+        handshake: true,
+        ack_timeout_ms: 100,
+        ack_retries,
+        ..test_config()
+    }
+}
+
+#[tokio::test]
+async fn acknowledged_message_is_not_resent() {
+    let addr = start_server(reliable_config(2), Extensions::default());
+    let mut sender = Client::connect(addr).await;
+    handshake(&mut sender, "").await;
+    assert_eq!(sender.line().await, b"LOGIN:1\n");
+    let mut receiver = Client::connect(addr).await;
+    handshake(&mut receiver, "reliable").await;
+    assert_eq!(receiver.line().await, b"LOGIN:2\n");
+
+    sender.send(b"hello\n").await;
+    assert_eq!(sender.line().await, b"ACK:MESSAGE 1\n");
+    // only the reliable receiver is sent the sequence number to acknowledge
+    assert_eq!(receiver.line().await, b"MESSAGE:1 hello seq=1\n");
+    receiver.send(b"ACK:RECV 1\n").await;
+    receiver.expect_silence().await;
+    // acknowledgements are not chat messages
+    sender.expect_silence().await;
+}
+
+#[tokio::test]
+async fn unacknowledged_message_is_resent_until_retries_run_out() {
+    let addr = start_server(reliable_config(2), Extensions::default());
+    let mut sender = Client::connect(addr).await;
+    handshake(&mut sender, "").await;
+    assert_eq!(sender.line().await, b"LOGIN:1\n");
+    let mut receiver = Client::connect(addr).await;
+    handshake(&mut receiver, "reliable").await;
+    assert_eq!(receiver.line().await, b"LOGIN:2\n");
+
+    sender.send(b"hello\n").await;
+    assert_eq!(sender.line().await, b"ACK:MESSAGE 1\n");
+    // sent once, then resent once per retry
+    for _ in 0..3 {
+        assert_eq!(receiver.line().await, b"MESSAGE:1 hello seq=1\n");
+    }
+    // the last resend is waited on for 400ms before the message is given up on
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    receiver.expect_silence().await;
+    receiver.send(b"ACK:RECV x\n").await;
+    assert_eq!(receiver.line().await, b"ERR:BAD_ACK\n");
+}
+
+#[tokio::test]
+async fn required_nickname_must_be_set_before_chatting() {
+    let config = ServerConfig {
+        nick_policy: NickPolicy::Required,
+        ..test_config()
+    };
+    let addr = start_server(config, Extensions::default());
+    let mut sender = Client::connect(addr).await;
+    assert_eq!(sender.line().await, b"LOGIN:1\n");
+    let mut receiver = Client::connect(addr).await;
+    assert_eq!(receiver.line().await, b"LOGIN:2\n");
+
+    sender.send(b"too soon\n").await;
+    assert_eq!(sender.line().await, b"ERR:NICK_REQUIRED\n");
+    receiver.expect_silence().await;
+    sender.send(b"/nick alice\n").await;
+    assert_eq!(sender.line().await, b"ACK:NICK alice\n");
+    sender.send(b"hello\n").await;
+    // the refused message took no sequence number
+    assert_eq!(sender.line().await, b"ACK:MESSAGE 1\n");
+    assert_eq!(receiver.line().await, b"MESSAGE:1 hello\n");
+}
+
+#[tokio::test]
+async fn forbidden_nickname_is_refused() {
+    let config = ServerConfig {
+        nick_policy: NickPolicy::Forbidden,
+        ..test_config()
+    };
+    let addr = start_server(config, Extensions::default());
+    let mut client = Client::connect(addr).await;
+    assert_eq!(client.line().await, b"LOGIN:1\n");
+
+    client.send(b"/nick alice\n").await;
+    assert_eq!(client.line().await, b"ERR:NICK_FORBIDDEN\n");
+    client.send(b"/roster\n").await;
+    assert_eq!(client.line().await, b"ROSTER:1:lobby:\n");
+    // anonymous clients chat as usual
+    client.send(b"hello\n").await;
+    assert_eq!(client.line().await, b"ACK:MESSAGE 1\n");
+}
+
+#[tokio::test]
+async fn paced_replay_delivers_live_messages_after_the_history() {
+    let config = ServerConfig {
+        history_size: 300,
+        replay_chunk: 50,
+        replay_pause_ms: 50,
+        ..test_config()
+    };
+    let addr = start_server(config, Extensions::default());
+    let mut sender = Client::connect(addr).await;
+    assert_eq!(sender.line().await, b"LOGIN:1\n");
+    let history: String = (1..=300).map(|n| format!("old {}\n", n)).collect();
+    sender.send(history.as_bytes()).await;
+    for seq in 1..=300 {
+        let ack = format!("ACK:MESSAGE {}\n", seq);
+        assert_eq!(sender.line().await, ack.as_bytes());
+    }
+
+    let mut late = Client::connect(addr).await;
+    assert_eq!(late.line().await, b"LOGIN:2\n");
+    // sent while the rest of the history is still being paced out
+    sender.send(b"live 1\nlive 2\nlive 3\n").await;
+    for seq in 301..=303 {
+        let ack = format!("ACK:MESSAGE {}\n", seq);
+        assert_eq!(sender.line().await, ack.as_bytes());
+    }
+    // a slow client, which only starts reading once everything is queued for it
+    tokio::time::sleep(Duration::from_millis(400)).await;
+    for n in 1..=300 {
+        let line = format!("HISTORY:1 old {}\n", n);
+        assert_eq!(late.line().await, line.as_bytes());
+    }
+    for n in 1..=3 {
+        let line = format!("MESSAGE:1 live {}\n", n);
+        assert_eq!(late.line().await, line.as_bytes());
+    }
+    late.expect_silence().await;
+}
+
+#[tokio::test]
+async fn paced_replay_follows_the_overflow_policy() {
+    let (streams, in_memory) = mpsc::unbounded_channel();
+    let extensions = Extensions {
+        in_memory: Some(in_memory),
+        ..Extensions::default()
+    };
+    let config = ServerConfig {
+        history_size: 3000,
+        replay_chunk: 100,
+        replay_pause_ms: 10,
+        overflow_policy: OverflowPolicy::DropOldest,
+        ..test_config()
+    };
+    let addr = start_server(config, extensions);
+    let mut sender = Client::connect(addr).await;
+    assert_eq!(sender.line().await, b"LOGIN:1\n");
+    for batch in (1..=3000).collect::<Vec<_>>().chunks(100) {
+        let lines: String = batch.iter().map(|n| format!("old {}\n", n)).collect();
+        sender.send(lines.as_bytes()).await;
+        for _ in batch {
+            assert!(sender.text().await.starts_with("ACK:MESSAGE "));
+        }
+    }
+
+    // a client which reads nothing until the replay has overflowed its queue
+    let (stream, server_end) = tokio::io::duplex(64);
+    streams.send(server_end).unwrap();
+    let mut late = Client::new(stream);
+    assert_eq!(late.line().await, b"LOGIN:2\n");
+    tokio::time::sleep(Duration::from_millis(1000)).await;
+    // the oldest lines made way for the newest rather than the other way round
+    let mut read = 0;
+    loop {
+        read += 1;
+        if late.text().await == "HISTORY:1 old 3000" {
+            break;
+        }
+    }
+    assert!(read < 2500, "{}", read);
+}
+
+#[tokio::test]
+async fn replay_longer_than_the_hold_limit_keeps_live_messages() {
+    let config = ServerConfig {
+        history_size: 1500,
+        replay_pause_ms: 50,
+        ..test_config()
+    };
+    let addr = start_server(config, Extensions::default());
+    let mut sender = Client::connect(addr).await;
+    assert_eq!(sender.line().await, b"LOGIN:1\n");
+    // sent in batches, reading the acknowledgements in between, so none is lost to a full queue
+    let mut seq = 0;
+    let mut send = async |sender: &mut Client<_>, prefix: &str, count: usize| {
+        for batch in (1..=count).collect::<Vec<_>>().chunks(100) {
+            let lines: String = batch
+                .iter()
+                .map(|n| format!("{} {}\n", prefix, n))
+                .collect();
+            sender.send(lines.as_bytes()).await;
+            for _ in batch {
+                seq += 1;
+                assert_eq!(sender.text().await, format!("ACK:MESSAGE {}", seq));
+            }
+        }
+    };
+    send(&mut sender, "old", 1500).await;
+
+    // far more history than the replay may hold live lines, which the live lines do not wait behind
+    let mut late = Client::connect(addr).await;
+    assert_eq!(late.line().await, b"LOGIN:2\n");
+    send(&mut sender, "live", 1030).await;
+    for n in 1..=1500 {
+        assert_eq!(late.text().await, format!("HISTORY:1 old {}", n));
+    }
+    // only the live lines beyond the limit are dropped, and counted
+    for n in 1..=1024 {
+        assert_eq!(late.text().await, format!("MESSAGE:1 live {}", n));
+    }
+    late.send(b"/stats\n").await;
+    let stats = late.text().await;
+    assert!(stats.contains(" dropped=6 "), "{}", stats);
+}
+
+#[tokio::test]
+async fn disabled_feature_commands_are_rejected() {
+    let config = ServerConfig {
+        disabled_features: vec![Feature::Dm, Feature::Reactions],
+        ..test_config()
+    };
+    let addr = start_server(config, Extensions::default());
+    let mut client = Client::connect(addr).await;
+    assert_eq!(client.line().await, b"LOGIN:1\n");
+    let mut other = Client::connect(addr).await;
+    assert_eq!(other.line().await, b"LOGIN:2\n");
+
+    client.send(b"/multicast 2 psst\n").await;
+    assert_eq!(client.line().await, b"ERR:FEATURE_DISABLED\n");
+    client.send(b"hello\n").await;
+    assert_eq!(client.line().await, b"ACK:MESSAGE 1\n");
+    assert_eq!(other.line().await, b"MESSAGE:1 hello\n");
+    other.send(b"/react 1 +1\n").await;
+    assert_eq!(other.line().await, b"ERR:FEATURE_DISABLED\n");
+    // the commands of features left enabled work as usual
+    other.send(b"/ping 7\n").await;
+    assert_eq!(other.line().await, b"PONG:7\n");
+    client.expect_silence().await;
+}
+
+#[tokio::test]
+async fn command_tag_is_echoed_only_in_its_replies() {
+    let addr = start_server(test_config(), Extensions::default());
+    let mut client = Client::connect(addr).await;
+    assert_eq!(client.line().await, b"LOGIN:1\n");
+    let mut other = Client::connect(addr).await;
+    assert_eq!(other.line().await, b"LOGIN:2\n");
+
+    client.send(b"[a1]/multicast 2 hi\n").await;
+    assert_eq!(client.line().await, b"ACK:MULTICAST 1 [a1]\n");
+    assert_eq!(other.line().await, b"PRIVATE:1 hi\n");
+    client.send(b"[b2]/nosuchcommand\n").await;
+    assert_eq!(client.line().await, b"ERR:UNKNOWN_COMMAND [b2]\n");
+    // untagged commands are answered as usual, and brackets only tag commands
+    client.send(b"/nick alice\n").await;
+    assert_eq!(client.line().await, b"ACK:NICK alice\n");
+    client.send(b"[c3] not a command\n").await;
+    assert_eq!(client.line().await, b"ACK:MESSAGE 1\n");
+    assert_eq!(other.line().await, b"MESSAGE:1 [c3] not a command\n");
+    other.expect_silence().await;
+}
+
+#[tokio::test]
+async fn server_exits_once_its_last_client_has_gone() {
+    let config = ServerConfig {
+        exit_when_empty: true,
+        exit_grace_ms: 200,
+        ..test_config()
+    };
+    let (addr, server) = spawn_server(config, Extensions::default());
+    // a server which never had a client waits for its first one beyond the grace period
+    tokio::time::sleep(Duration::from_millis(400)).await;
+    let mut client = Client::connect(addr).await;
+    assert_eq!(client.line().await, b"LOGIN:1\n");
+    client.send(b"last words\n").await;
+    assert_eq!(client.line().await, b"ACK:MESSAGE 1\n");
+    assert!(!server.is_finished());
+    drop(client);
+    let left = std::time::Instant::now();
+
+    // polled rather than joined, so a server which never exits fails the test instead of hanging it
+    while !server.is_finished() {
+        assert!(left.elapsed() < LINE_TIMEOUT, "server did not exit");
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert!(left.elapsed() >= Duration::from_millis(200));
+    let summary = server.join().unwrap();
+    assert_eq!(summary.reason, ShutdownReason::Empty);
+    // nobody was left to be told of the shutdown
+    assert_eq!(summary.clients_notified, 0);
+    assert_eq!(summary.messages_broadcast, 1);
+}
+
+#[tokio::test]
+async fn supplied_message_store_restores_history_on_the_next_run() {
+    let store = Arc::new(MemoryStore::new(10));
+    let extensions = Extensions {
+        message_store: Some(Arc::clone(&store) as Arc<dyn MessageStore>),
+        ..Extensions::default()
+    };
+    let addr = start_server(test_config(), extensions);
+    let mut sender = Client::connect(addr).await;
+    assert_eq!(sender.line().await, b"LOGIN:1\n");
+    sender.send(b"kept\n").await;
+    assert_eq!(sender.line().await, b"ACK:MESSAGE 1\n");
+    // the store is appended to by a task of its own, so the message may take a moment to arrive
+    let deadline = tokio::time::Instant::now() + LINE_TIMEOUT;
+    while store.recent(10).await.is_empty() {
+        assert!(
+            tokio::time::Instant::now() < deadline,
+            "message was not stored"
+        );
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    // a second server given the same store starts with the history the first one left
+    let extensions = Extensions {
+        message_store: Some(store),
+        ..Extensions::default()
+    };
+    let addr = start_server(test_config(), extensions);
+    let mut late = Client::connect(addr).await;
+    // client IDs continue after the restored message's sender, which is someone else
+    assert_eq!(late.line().await, b"LOGIN:2\n");
+    assert_eq!(late.line().await, b"HISTORY:1 kept\n");
+    late.send(b"next\n").await;
+    // numbering continues after the restored message
+    assert_eq!(late.line().await, b"ACK:MESSAGE 2\n");
+}
+
+#[tokio::test]
+async fn edits_and_deletions_survive_a_restart_with_the_file_store() {
+    let path = temp_path("edit-restart.jsonl");
+    let config = || ServerConfig {
+        message_store_file: Some(path.clone()),
+        ..test_config()
+    };
+    let addr = start_server(config(), Extensions::default());
+    let mut sender = Client::login(addr, 1).await;
+    for (n, text) in (1..).zip(["first", "second", "third"]) {
+        sender.send(format!("{}\n", text).as_bytes()).await;
+        assert_eq!(sender.text().await, format!("ACK:MESSAGE {}", n));
+    }
+    sender.send(b"/edit 1 first, edited\n").await;
+    assert_eq!(sender.line().await, b"ACK:EDIT 1\n");
+    sender.send(b"/delete 2\n").await;
+    assert_eq!(sender.line().await, b"ACK:DELETE 2\n");
+    // the store is written by a task of its own, so the deletion may take a moment to reach the file
+    eventually("deletion was not stored", || {
+        std::fs::read_to_string(&path).is_ok_and(|file| file.contains(r#""type":"delete""#))
+    })
+    .await;
+
+    // a second server using the same file starts with the history as edited
+    let addr = start_server(config(), Extensions::default());
+    let mut late = Client::login(addr, 2).await;
+    assert_eq!(late.line().await, b"HISTORY:1 first, edited\n");
+    assert_eq!(late.line().await, b"HISTORY:1 third\n");
+    late.expect_silence().await;
+    // a new client is not the sender of the earlier run's messages, so it may not change them
+    late.send(b"/edit 1 first, hijacked\n").await;
+    assert_eq!(late.line().await, b"ERR:FORBIDDEN\n");
+    late.send(b"/delete 3\n").await;
+    assert_eq!(late.line().await, b"ERR:FORBIDDEN\n");
+    late.send(b"next\n").await;
+    assert_eq!(late.line().await, b"ACK:MESSAGE 4\n");
+    late.send(b"/edit 4 next, edited\n").await;
+    assert_eq!(late.line().await, b"ACK:EDIT 4\n");
+    let _ = std::fs::remove_file(&path);
+}
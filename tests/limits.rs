@@ -0,0 +1,558 @@
+mod common;
+
+use common::*;
+use rust_broadcast_server::Extensions;
+use rust_broadcast_server::config::{OverflowPolicy, ServerConfig};
+use std::time::Duration;
+use tokio::io::AsyncBufReadExt;
+use tokio::sync::mpsc;
+
+/** Function which starts a server with the given overflow policy and connects a sender, an observer and a
+* client which never reads, whose in-memory stream only buffers a few lines, then has the sender send it
+* more messages than its queue holds, returning (sender, observer, unresponsive) once every one is acknowledged.
+*/
+async fn saturate(
+    policy: OverflowPolicy,
+) -> (
+    Client<tokio::net::TcpStream>,
+    Client<tokio::net::TcpStream>,
+    Client<tokio::io::DuplexStream>,
+) {
+    let (streams, in_memory) = mpsc::unbounded_channel();
+    let extensions = Extensions {
+        in_memory: Some(in_memory),
+        ..Extensions::default()
+    };
+    let config = ServerConfig {
+        overflow_policy: policy,
+        ..test_config()
+    };
+    let addr = start_server(config, extensions);
+    let mut sender = Client::login(addr, 1).await;
+    let observer = Client::login(addr, 2).await;
+    let (stream, server_end) = tokio::io::duplex(64);
+    streams.send(server_end).unwrap();
+    let mut unresponsive = Client::new(stream);
+    assert_eq!(unresponsive.line().await, b"LOGIN:3\n");
+
+    for n in 1..=1100 {
+        sender.send(format!("{}\n", n).as_bytes()).await;
+    }
+    let mut n = 0;
+    while n < 1100 {
+        if sender.text().await.starts_with("ACK:MESSAGE ") {
+            n += 1;
+        }
+    }
+    (sender, observer, unresponsive)
+}
+
+// Function which returns the value of the given field of a STATS: line, ex. `dropped`.
+fn stat(stats: &str, field: &str) -> u64 {
+    let prefix = format!("{}=", field);
+    stats
+        .trim_start_matches("STATS:")
+        .split(' ')
+        .find_map(|pair| pair.strip_prefix(&prefix))
+        .unwrap()
+        .parse()
+        .unwrap()
+}
+
+#[tokio::test]
+async fn every_message_is_delivered_under_the_inflight_cap() {
+    let config = ServerConfig {
+        max_inflight: 1,
+        ..test_config()
+    };
+    let addr = start_server(config, Extensions::default());
+    let mut receiver = Client::login(addr, 1).await;
+    let mut senders = Vec::new();
+    for c_id in 2..=4 {
+        senders.push(Client::login(addr, c_id).await);
+    }
+
+    // every sender pipelines its messages at once, so they contend for the single permit
+    for (i, sender) in senders.iter_mut().enumerate() {
+        let burst: String = (1..=20).map(|n| format!("{} says {}\n", i, n)).collect();
+        sender.send(burst.as_bytes()).await;
+    }
+    let mut received = Vec::new();
+    for _ in 0..60 {
+        received.push(receiver.text().await);
+    }
+    for (i, c_id) in (2..=4).enumerate() {
+        // each sender's messages still arrive in the order they were sent
+        let from_sender: Vec<&String> = received
+            .iter()
+            .filter(|line| line.starts_with(&format!("MESSAGE:{} ", c_id)))
+            .collect();
+        let expected: Vec<String> = (1..=20)
+            .map(|n| format!("MESSAGE:{} {} says {}", c_id, i, n))
+            .collect();
+        assert_eq!(from_sender, expected.iter().collect::<Vec<_>>());
+    }
+    // each sender is acknowledged its own messages, among the others' messages
+    for sender in senders.iter_mut() {
+        let mut acks = 0;
+        for _ in 0..60 {
+            if sender.text().await.starts_with("ACK:MESSAGE ") {
+                acks += 1;
+            }
+        }
+        assert_eq!(acks, 20);
+    }
+    receiver.expect_silence().await;
+}
+
+#[tokio::test]
+async fn endless_line_is_cut_off_mid_line() {
+    let config = ServerConfig {
+        max_line_bytes: Some(1024),
+        ..test_config()
+    };
+    let addr = start_server(config, Extensions::default());
+    let mut client = Client::login(addr, 1).await;
+    let mut other = Client::login(addr, 2).await;
+
+    // the line never ends, so the limit has to be enforced while it is still being read
+    client.send(&[b'x'; 4096]).await;
+    assert_eq!(client.line().await, b"ERR:TOO_LONG\n");
+    client.expect_closed().await;
+    assert_eq!(other.line().await, b"LEAVE:1 too_long\n");
+}
+
+#[tokio::test]
+async fn idle_warning_comes_first_and_activity_resets_it() {
+    let config = ServerConfig {
+        idle_timeout: Some(Duration::from_secs(2)),
+        idle_warning: Some(Duration::from_secs(1)),
+        ..test_config()
+    };
+    let addr = start_server(config, Extensions::default());
+    let mut client = Client::login(addr, 1).await;
+
+    assert_eq!(client.line().await, b"WARN:IDLE 1\n");
+    // answering the warning restarts both timers, so the client outlives its original deadline
+    client.send(b"/ping still here\n").await;
+    assert_eq!(client.line().await, b"PONG:still here\n");
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+    assert_eq!(client.line().await, b"WARN:IDLE 1\n");
+    // ignoring the warning gets the client disconnected
+    assert_eq!(client.line().await, b"ERR:IDLE_TIMEOUT\n");
+    client.expect_closed().await;
+}
+
+#[tokio::test]
+async fn connections_beyond_the_accept_rate_are_throttled() {
+    let config = ServerConfig {
+        accept_rate: Some(2),
+        ..test_config()
+    };
+    let addr = start_server(config, Extensions::default());
+
+    let mut clients = Vec::new();
+    for _ in 0..10 {
+        clients.push(Client::connect(addr).await);
+    }
+    let mut accepted = 0;
+    let mut throttled = 0;
+    for client in clients.iter_mut() {
+        let line = client.text().await;
+        if line.starts_with("LOGIN:") {
+            accepted += 1;
+        } else {
+            assert_eq!(line, "ERR:SLOW_DOWN retry=1");
+            client.expect_closed().await;
+            throttled += 1;
+        }
+    }
+    // the burst allowance lets the first connections through, the rest of a rapid burst is turned away
+    assert!(accepted >= 1, "no connection was accepted");
+    assert!(throttled >= 1, "no connection was throttled");
+}
+
+#[tokio::test]
+async fn unresponsive_client_is_removed_after_repeated_lag() {
+    let (streams, in_memory) = mpsc::unbounded_channel();
+    let extensions = Extensions {
+        in_memory: Some(in_memory),
+        ..Extensions::default()
+    };
+    let addr = start_server(test_config(), extensions);
+    let mut sender = Client::login(addr, 1).await;
+    let mut observer = Client::login(addr, 2).await;
+    // the unresponsive client never reads, and its stream only buffers a few lines
+    let (stream, server_end) = tokio::io::duplex(64);
+    streams.send(server_end).unwrap();
+    let mut unresponsive = Client::new(stream);
+    assert_eq!(unresponsive.line().await, b"LOGIN:3\n");
+
+    // enough messages to fill its queue, and then some which it keeps lagging on
+    for n in 1..=1100 {
+        sender.send(format!("{}\n", n).as_bytes()).await;
+    }
+    let mut n = 0;
+    loop {
+        let line = observer.text().await;
+        if line == "LEAVE:3 lagging" {
+            break;
+        }
+        n += 1;
+        assert_eq!(line, format!("MESSAGE:1 {}", n));
+    }
+    // the others are not held up by it any longer, and are told why it left
+    let mut n = 0;
+    while n < 1100 {
+        let line = sender.text().await;
+        if line != "LEAVE:3 lagging" {
+            n += 1;
+            assert_eq!(line, format!("ACK:MESSAGE {}", n));
+        }
+    }
+}
+
+#[tokio::test]
+async fn silent_client_is_dropped_after_the_handshake_timeout() {
+    let config = ServerConfig {
+        handshake: true,
+        handshake_timeout: Some(Duration::from_millis(200)),
+        ..test_config()
+    };
+    let addr = start_server(config, Extensions::default());
+
+    let mut silent = Client::connect(addr).await;
+    assert!(silent.text().await.starts_with("CAPS:"));
+    let started = tokio::time::Instant::now();
+    assert_eq!(silent.line().await, b"ERR:HANDSHAKE_TIMEOUT\n");
+    assert!(started.elapsed() < Duration::from_secs(2));
+    silent.expect_closed().await;
+    // a client completing the handshake in time is logged in as usual
+    let mut prompt = Client::connect(addr).await;
+    handshake(&mut prompt, "").await;
+    assert_eq!(prompt.line().await, b"LOGIN:2\n");
+}
+
+#[tokio::test]
+async fn stalled_partial_line_is_dropped_after_the_frame_timeout() {
+    let config = ServerConfig {
+        frame_timeout: Some(Duration::from_millis(300)),
+        ..test_config()
+    };
+    let addr = start_server(config, Extensions::default());
+    let mut observer = Client::login(addr, 1).await;
+    let mut trickler = Client::login(addr, 2).await;
+
+    // pausing between complete lines is not a stalled frame
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    trickler.send(b"complete\n").await;
+    assert_eq!(trickler.line().await, b"ACK:MESSAGE 1\n");
+    assert_eq!(observer.line().await, b"MESSAGE:2 complete\n");
+    // a line trickled a byte at a time is cut off even though bytes keep arriving
+    let started = tokio::time::Instant::now();
+    let mut dropped = false;
+    while !dropped && started.elapsed() < LINE_TIMEOUT {
+        trickler.send(b"x").await;
+        let mut line = Vec::new();
+        let read = trickler.stream.read_until(b'\n', &mut line);
+        if let Ok(Ok(_)) = tokio::time::timeout(Duration::from_millis(50), read).await {
+            assert_eq!(line, b"ERR:FRAME_TIMEOUT\n");
+            dropped = true;
+        }
+    }
+    assert!(dropped, "the stalled line was never cut off");
+    assert_eq!(observer.line().await, b"LEAVE:2 frame_timeout\n");
+}
+
+#[tokio::test]
+async fn drop_oldest_keeps_the_slow_client_with_the_newest_messages() {
+    let (_sender, mut observer, mut unresponsive) = saturate(OverflowPolicy::DropOldest).await;
+    for n in 1..=1100 {
+        assert_eq!(observer.text().await, format!("MESSAGE:1 {}", n));
+    }
+    observer.send(b"/stats\n").await;
+    let stats = observer.text().await;
+    assert!(
+        stat(&stats, "dropped") > 0,
+        "nothing was dropped: {}",
+        stats
+    );
+    assert_eq!(stat(&stats, "overflow_disconnects"), 0);
+    assert_eq!(stat(&stats, "clients"), 3);
+
+    // the slow client is missing messages from the middle, but has the rest in order, ending with the newest
+    let mut last = 0;
+    while last < 1100 {
+        let n: u64 = unresponsive
+            .text()
+            .await
+            .strip_prefix("MESSAGE:1 ")
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!(n > last, "{} arrived after {}", n, last);
+        last = n;
+    }
+}
+
+#[tokio::test]
+async fn disconnect_policy_removes_the_slow_client_at_once() {
+    let (_sender, mut observer, _unresponsive) = saturate(OverflowPolicy::Disconnect).await;
+    let mut n = 0;
+    loop {
+        let line = observer.text().await;
+        if line == "LEAVE:3 lagging" {
+            break;
+        }
+        n += 1;
+        assert_eq!(line, format!("MESSAGE:1 {}", n));
+    }
+    // the messages sent after its removal follow, then the reply to /stats
+    observer.send(b"/stats\n").await;
+    let mut stats = observer.text().await;
+    while stats.starts_with("MESSAGE:") {
+        stats = observer.text().await;
+    }
+    assert_eq!(stat(&stats, "overflow_disconnects"), 1);
+    assert_eq!(stat(&stats, "clients"), 2);
+}
+
+#[tokio::test]
+async fn commands_and_chat_messages_have_separate_limits() {
+    let config = ServerConfig {
+        max_line_bytes: Some(32),
+        max_command_bytes: Some(128),
+        ..admin_config()
+    };
+    let addr = start_server(config, Extensions::default());
+    let mut admin = Client::login(addr, 1).await;
+    admin.become_admin().await;
+    let mut chatter = Client::login(addr, 2).await;
+
+    // a command past the chat limit is fine within the command limit
+    let long = "y".repeat(100);
+    admin.send(format!("/announce {}\n", long).as_bytes()).await;
+    assert_eq!(admin.text().await, format!("ANNOUNCE:{}", long));
+    assert_eq!(chatter.text().await, format!("ANNOUNCE:{}", long));
+    // a chat message of the same length is not
+    chatter.send(format!("{}\n", long).as_bytes()).await;
+    assert_eq!(chatter.line().await, b"ERR:TOO_LONG\n");
+    chatter.expect_closed().await;
+    assert_eq!(admin.line().await, b"LEAVE:2 too_long\n");
+    // nor is a command past the command limit
+    admin
+        .send(format!("/announce {}\n", "z".repeat(200)).as_bytes())
+        .await;
+    assert_eq!(admin.line().await, b"ERR:TOO_LONG\n");
+    admin.expect_closed().await;
+}
+
+#[tokio::test]
+async fn overload_is_shed_to_keep_the_global_backlog_bounded() {
+    let (streams, in_memory) = mpsc::unbounded_channel();
+    let extensions = Extensions {
+        in_memory: Some(in_memory),
+        ..Extensions::default()
+    };
+    let config = ServerConfig {
+        max_buffered_lines: Some(200),
+        ..test_config()
+    };
+    let addr = start_server(config, extensions);
+    let mut sender = Client::login(addr, 1).await;
+    // several clients which never read, each of whose queues alone could hold more than the global maximum
+    let mut stuck = Vec::new();
+    for c_id in 2..=6 {
+        let (stream, server_end) = tokio::io::duplex(64);
+        streams.send(server_end).unwrap();
+        let mut client = Client::new(stream);
+        assert_eq!(client.text().await, format!("LOGIN:{}", c_id));
+        stuck.push(client);
+    }
+
+    for n in 1..=500 {
+        sender.send(format!("burst {}\n", n).as_bytes()).await;
+    }
+    let mut n = 0;
+    while n < 500 {
+        if sender.text().await.starts_with("ACK:MESSAGE ") {
+            n += 1;
+        }
+    }
+    sender.send(b"/stats\n").await;
+    let stats = sender.text().await;
+    assert!(stat(&stats, "queued") <= 200, "{}", stats);
+    assert!(stat(&stats, "global_shed_total") > 0, "{}", stats);
+    // shedding is not lagging, so nobody was removed
+    assert_eq!(stat(&stats, "clients"), 6);
+}
+
+#[tokio::test]
+async fn shedding_for_an_idle_client_does_not_hold_up_the_broadcast() {
+    let (streams, in_memory) = mpsc::unbounded_channel();
+    let extensions = Extensions {
+        in_memory: Some(in_memory),
+        ..Extensions::default()
+    };
+    let config = ServerConfig {
+        max_buffered_lines: Some(20),
+        ..test_config()
+    };
+    let addr = start_server(config, extensions);
+    let mut sender = Client::login(addr, 1).await;
+    // a client which reads everything, but has nothing queued when the backlog fills up
+    let mut idle = Client::login(addr, 2).await;
+    let mut stuck = Vec::new();
+    for c_id in 3..=4 {
+        let (stream, server_end) = tokio::io::duplex(64);
+        streams.send(server_end).unwrap();
+        let mut client = Client::new(stream);
+        assert_eq!(client.text().await, format!("LOGIN:{}", c_id));
+        stuck.push(client);
+    }
+
+    for n in 1..=100 {
+        sender.send(format!("burst {}\n", n).as_bytes()).await;
+    }
+    let mut n = 0;
+    while n < 100 {
+        if sender.text().await.starts_with("ACK:MESSAGE ") {
+            n += 1;
+        }
+    }
+    sender.send(b"/stats\n").await;
+    let stats = sender.text().await;
+    assert!(stat(&stats, "global_shed_total") > 0, "{}", stats);
+    assert_eq!(stat(&stats, "clients"), 4);
+    assert!(idle.text().await.starts_with("MESSAGE:1 burst "));
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn flagged_address_is_slowed_while_a_clean_one_is_not() {
+    const DELAY: Duration = Duration::from_millis(500);
+    let config = ServerConfig {
+        tarpit_delay: Some(DELAY),
+        tarpit_threshold: 1,
+        ..test_config()
+    };
+    let addr = start_server(config, Extensions::default());
+    // every 127.x.x.x address is local on Linux, so the clean client connects from an address of its own
+    let connect_from = |ip: [u8; 4]| async move {
+        let socket = tokio::net::TcpSocket::new_v4().unwrap();
+        socket.bind((ip, 0).into()).unwrap();
+        Client::new(socket.connect(addr).await.unwrap())
+    };
+    let mut abuser = Client::login(addr, 1).await;
+    abuser.send(b"react to me\n").await;
+    assert_eq!(abuser.line().await, b"ACK:MESSAGE 1\n");
+    // reacting too fast is a strike, which flags the address at once
+    loop {
+        abuser.send("/react 1 👍\n".as_bytes()).await;
+        if abuser.text().await == "ERR:SLOW_DOWN" {
+            break;
+        }
+    }
+
+    let started = tokio::time::Instant::now();
+    abuser.send(b"/ping slow\n").await;
+    assert_eq!(abuser.line().await, b"PONG:slow\n");
+    assert!(started.elapsed() >= DELAY);
+    let started = tokio::time::Instant::now();
+    let mut again = connect_from([127, 0, 0, 1]).await;
+    assert_eq!(again.line().await, b"LOGIN:2\n");
+    assert!(started.elapsed() >= DELAY);
+
+    let started = tokio::time::Instant::now();
+    let mut clean = connect_from([127, 0, 0, 2]).await;
+    assert_eq!(clean.line().await, b"LOGIN:3\n");
+    assert_eq!(clean.line().await, b"HISTORY:1 react to me\n");
+    clean.send(b"/ping fast\n").await;
+    assert_eq!(clean.line().await, b"PONG:fast\n");
+    assert!(started.elapsed() < DELAY);
+}
+
+#[tokio::test]
+async fn flagged_address_is_let_off_once_its_strikes_expire() {
+    const DELAY: Duration = Duration::from_millis(500);
+    let config = ServerConfig {
+        tarpit_delay: Some(DELAY),
+        tarpit_threshold: 1,
+        tarpit_window_ms: 300,
+        ..test_config()
+    };
+    let addr = start_server(config, Extensions::default());
+    let mut abuser = Client::login(addr, 1).await;
+    abuser.send(b"react to me\n").await;
+    assert_eq!(abuser.line().await, b"ACK:MESSAGE 1\n");
+    loop {
+        abuser.send("/react 1 👍\n".as_bytes()).await;
+        if abuser.text().await == "ERR:SLOW_DOWN" {
+            break;
+        }
+    }
+
+    // the line is held up for longer than the window, which the address then goes without a strike
+    let started = tokio::time::Instant::now();
+    abuser.send(b"/ping slow\n").await;
+    assert_eq!(abuser.line().await, b"PONG:slow\n");
+    assert!(started.elapsed() >= DELAY);
+    let started = tokio::time::Instant::now();
+    abuser.send(b"/ping fast\n").await;
+    assert_eq!(abuser.line().await, b"PONG:fast\n");
+    assert!(started.elapsed() < DELAY);
+}
+
+#[tokio::test]
+async fn egress_rate_paces_the_lines_written_to_a_client() {
+    let config = ServerConfig {
+        egress_rate: Some(5),
+        ..test_config()
+    };
+    let addr = start_server(config, Extensions::default());
+    let mut sender = Client::login(addr, 1).await;
+    let mut receiver = Client::login(addr, 2).await;
+
+    let started = tokio::time::Instant::now();
+    sender.send("paced\n".repeat(15).as_bytes()).await;
+    // a burst of 5 lines is written at once, the other 10 at 5 per second
+    for _ in 0..5 {
+        assert_eq!(receiver.line().await, b"MESSAGE:1 paced\n");
+    }
+    assert!(started.elapsed() < Duration::from_millis(500));
+    for _ in 0..10 {
+        assert_eq!(receiver.line().await, b"MESSAGE:1 paced\n");
+    }
+    let elapsed = started.elapsed();
+    assert!(elapsed >= Duration::from_millis(1800), "{:?}", elapsed);
+    assert!(elapsed < Duration::from_secs(4), "{:?}", elapsed);
+}
+
+#[tokio::test]
+async fn client_which_never_reads_is_removed_as_stalled() {
+    let config = ServerConfig {
+        stall_timeout: Some(Duration::from_secs(1)),
+        ..test_config()
+    };
+    let addr = start_server(config, Extensions::default());
+    let mut sender = Client::login(addr, 1).await;
+    // a client which connects but never reads, whose socket buffers soon fill up
+    let socket = tokio::net::TcpSocket::new_v4().unwrap();
+    socket.set_recv_buffer_size(4096).unwrap();
+    let _stuck = socket.connect(addr).await.unwrap();
+    let mut observer = Client::login(addr, 3).await;
+
+    let padding = "x".repeat(60_000);
+    for n in 1..=100 {
+        sender.send(format!("{}\n", padding).as_bytes()).await;
+        assert_eq!(sender.text().await, format!("ACK:MESSAGE {}", n));
+        assert!(observer.text().await.starts_with("MESSAGE:1 "));
+    }
+    let started = tokio::time::Instant::now();
+    assert_eq!(observer.line().await, b"LEAVE:2 stalled\n");
+    assert_eq!(sender.line().await, b"LEAVE:2 stalled\n");
+    assert!(started.elapsed() < Duration::from_secs(2));
+    // the connection is gone as a whole, not merely left out of broadcasts
+    observer.send(b"/roster\n").await;
+    assert_eq!(observer.line().await, b"ROSTER:1:lobby: 3:lobby:\n");
+}
@@ -0,0 +1,137 @@
+mod common;
+
+use common::*;
+use rust_broadcast_server::Extensions;
+use rust_broadcast_server::config::ServerConfig;
+
+#[tokio::test]
+async fn audit_file_records_accepted_and_rejected_connections() {
+    let path = temp_path("audit.jsonl");
+    let config = ServerConfig {
+        audit_file: Some(path.clone()),
+        max_clients: Some(1),
+        ..test_config()
+    };
+    let addr = start_server(config, Extensions::default());
+    let accepted = Client::login(addr, 1).await;
+    let mut refused = Client::connect(addr).await;
+    assert_eq!(refused.line().await, b"ERR:SERVER_FULL retry=5\n");
+
+    let read_entries = || -> Vec<serde_json::Value> {
+        std::fs::read_to_string(&path)
+            .unwrap_or_default()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    };
+    eventually("both connections are audited", || read_entries().len() == 2).await;
+    let entries = read_entries();
+    assert_eq!(entries[0]["outcome"], "connected");
+    assert_eq!(entries[0]["ip"], "127.0.0.1");
+    assert_eq!(
+        entries[0]["port"],
+        accepted.stream.get_ref().local_addr().unwrap().port()
+    );
+    assert!(entries[0].get("reason").is_none());
+    assert_eq!(entries[1]["outcome"], "rejected");
+    assert_eq!(entries[1]["reason"], "SERVER_FULL");
+    assert!(entries[1]["time"].as_u64().unwrap() > 0);
+}
+
+#[tokio::test]
+async fn connect_event_is_logged_as_json() {
+    let path = temp_path("events.jsonl");
+    let config = ServerConfig {
+        json_log: Some(path.clone()),
+        ..test_config()
+    };
+    let addr = start_server(config, Extensions::default());
+    let client = Client::login(addr, 1).await;
+
+    let connect = || -> Option<serde_json::Value> {
+        std::fs::read_to_string(&path)
+            .unwrap_or_default()
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .find(|entry| entry["event"] == "connect")
+    };
+    eventually("the connection is logged", || connect().is_some()).await;
+    let entry = connect().unwrap();
+    assert_eq!(entry["level"], "info");
+    assert_eq!(entry["client_id"], 1);
+    assert_eq!(
+        entry["addr"],
+        client.stream.get_ref().local_addr().unwrap().to_string()
+    );
+    assert!(entry["ts"].as_u64().unwrap() > 0);
+    assert!(entry["detail"].is_null());
+}
+
+#[tokio::test]
+async fn json_log_is_rotated_by_size() {
+    let path = temp_path("rotated.jsonl");
+    let rotated = std::path::PathBuf::from(format!("{}.1", path.display()));
+    let _ = std::fs::remove_file(&rotated);
+    let config = ServerConfig {
+        json_log: Some(path.clone()),
+        json_log_max_bytes: Some(300),
+        ..test_config()
+    };
+    let addr = start_server(config, Extensions::default());
+    let mut clients = Vec::new();
+    for c_id in 1..=5 {
+        clients.push(Client::login(addr, c_id).await);
+    }
+
+    eventually("the log is rotated", || rotated.exists()).await;
+    // neither file grows past the limit, and every entry is still whole
+    for file in [&path, &rotated] {
+        let contents = std::fs::read_to_string(file).unwrap();
+        assert!(
+            contents.len() <= 300,
+            "{} is {} bytes",
+            file.display(),
+            contents.len()
+        );
+        for line in contents.lines() {
+            serde_json::from_str::<serde_json::Value>(line).unwrap();
+        }
+    }
+}
+
+#[tokio::test]
+async fn server_keeps_serving_under_a_log_flood() {
+    let addr = free_addr();
+    // the server's output is never read, so the logging task is soon stuck on a full pipe
+    let _server = tokio::process::Command::new(env!("CARGO_BIN_EXE_server"))
+        .args(["--port", &addr.port().to_string()])
+        .stdout(std::process::Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .unwrap();
+    let mut sender = Client::login(addr, 1).await;
+    let padding = "x".repeat(1000);
+    for n in 1..=6000 {
+        sender.send(format!("{} {}\n", n, padding).as_bytes()).await;
+        assert_eq!(sender.text().await, format!("ACK:MESSAGE {}", n));
+    }
+
+    let mut late = Client::connect(addr).await;
+    assert_eq!(late.line().await, b"LOGIN:2\n");
+    late.send(b"/stats\n").await;
+    let stats = loop {
+        let line = late.text().await;
+        if let Some(stats) = line.strip_prefix("STATS:") {
+            break stats.to_string();
+        }
+    };
+    let dropped: u64 = stats
+        .split(' ')
+        .find_map(|field| field.strip_prefix("log_dropped="))
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!(dropped > 0, "{}", stats);
+    late.send(b"still here\n").await;
+    assert_eq!(sender.line().await, b"MESSAGE:2 still here\n");
+}
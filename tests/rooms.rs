@@ -0,0 +1,265 @@
+mod common;
+
+use common::*;
+use rust_broadcast_server::Extensions;
+use rust_broadcast_server::config::ServerConfig;
+use tokio::io::AsyncBufReadExt;
+
+#[tokio::test]
+async fn room_history_is_only_replayed_to_joiners_of_that_room() {
+    let addr = start_server(test_config(), Extensions::default());
+    let mut sender = Client::login(addr, 1).await;
+    sender.send(b"/join a\n").await;
+    assert_eq!(sender.line().await, b"ACK:JOIN a\n");
+    sender.send(b"said in a\n").await;
+    assert_eq!(sender.line().await, b"ACK:MESSAGE 1\n");
+
+    let mut other_room = Client::login(addr, 2).await;
+    other_room.send(b"/join b\n").await;
+    assert_eq!(other_room.line().await, b"ACK:JOIN b\n");
+    other_room.expect_silence().await;
+
+    let mut same_room = Client::login(addr, 3).await;
+    // the default room has no history of its own yet
+    same_room.expect_silence().await;
+    same_room.send(b"/join a\n").await;
+    assert_eq!(same_room.line().await, b"ACK:JOIN a\n");
+    assert_eq!(same_room.line().await, b"HISTORY:1 said in a\n");
+    same_room.expect_silence().await;
+}
+
+#[tokio::test]
+async fn room_rate_limits_the_room_as_a_whole() {
+    let config = ServerConfig {
+        room_rate: Some(5),
+        ..test_config()
+    };
+    let addr = start_server(config, Extensions::default());
+    let mut senders = Vec::new();
+    for c_id in 1..=3 {
+        senders.push(Client::login(addr, c_id).await);
+    }
+    let mut elsewhere = Client::login(addr, 4).await;
+    elsewhere.send(b"/join quiet\n").await;
+    assert_eq!(elsewhere.line().await, b"ACK:JOIN quiet\n");
+
+    // each sender stays well within any per-client limit, but together they exceed the room's
+    for sender in senders.iter_mut() {
+        sender.send(b"one\ntwo\nthree\nfour\n").await;
+    }
+    let mut accepted = 0;
+    let mut busy = 0;
+    for sender in senders.iter_mut() {
+        let mut acks = 0;
+        // every sender also receives the messages of the others which got through
+        while acks < 4 {
+            let line = sender.text().await;
+            if line.starts_with("ACK:MESSAGE") {
+                accepted += 1;
+                acks += 1;
+            } else if line == "ERR:ROOM_BUSY" {
+                busy += 1;
+                acks += 1;
+            }
+        }
+    }
+    assert!(busy > 0, "the room limit never engaged");
+    assert!(accepted <= 6, "{} messages got through", accepted);
+    // another room has a limit of its own
+    elsewhere.send(b"unaffected\n").await;
+    assert_eq!(
+        elsewhere.text().await,
+        format!("ACK:MESSAGE {}", accepted + 1)
+    );
+}
+
+#[tokio::test]
+async fn joins_are_capped_per_client() {
+    let config = ServerConfig {
+        max_rooms_per_client: Some(3),
+        ..test_config()
+    };
+    let addr = start_server(config, Extensions::default());
+    let mut client = Client::login(addr, 1).await;
+    let mut in_a = Client::login(addr, 2).await;
+    in_a.send(b"/join a\n").await;
+    assert_eq!(in_a.line().await, b"ACK:JOIN a\n");
+
+    // the lobby counts as one of the three
+    for room in ["a", "b"] {
+        client.send(format!("/join {}\n", room).as_bytes()).await;
+        assert_eq!(client.text().await, format!("ACK:JOIN {}", room));
+    }
+    client.send(b"/join c\n").await;
+    assert_eq!(client.line().await, b"ERR:TOO_MANY_JOINS\n");
+    // the client is still in every room it joined
+    in_a.send(b"to a\n").await;
+    assert_eq!(in_a.line().await, b"ACK:MESSAGE 1\n");
+    assert_eq!(client.line().await, b"MESSAGE:2 to a\n");
+    // leaving a room makes space for another
+    client.send(b"/part a\n/join c\n").await;
+    assert_eq!(client.line().await, b"ACK:PART a\n");
+    assert_eq!(client.line().await, b"ACK:JOIN c\n");
+}
+
+#[tokio::test]
+async fn private_room_admits_only_invited_clients() {
+    let addr = start_server(test_config(), Extensions::default());
+    let mut owner = Client::login(addr, 1).await;
+    let mut guest = Client::login(addr, 2).await;
+    let mut outsider = Client::login(addr, 3).await;
+    owner.send(b"/join secret --private\n").await;
+    assert_eq!(owner.line().await, b"ACK:JOIN secret\n");
+
+    guest.send(b"/join secret\n").await;
+    assert_eq!(guest.line().await, b"ERR:INVITE_REQUIRED\n");
+    // only the owner can invite
+    outsider.send(b"/invite 3 secret\n").await;
+    assert_eq!(outsider.line().await, b"ERR:FORBIDDEN\n");
+    owner.send(b"/invite 2 secret\n").await;
+    assert_eq!(owner.line().await, b"ACK:INVITE 2 secret\n");
+    assert_eq!(guest.line().await, b"INVITE:secret from 1\n");
+    guest.send(b"/join secret\n").await;
+    assert_eq!(guest.line().await, b"ACK:JOIN secret\n");
+    owner.send(b"welcome\n").await;
+    assert_eq!(owner.line().await, b"ACK:MESSAGE 1\n");
+    assert_eq!(guest.line().await, b"MESSAGE:1 welcome\n");
+    // the invite was for the guest alone
+    outsider.send(b"/join secret\n").await;
+    assert_eq!(outsider.line().await, b"ERR:INVITE_REQUIRED\n");
+}
+
+// Function which runs the same exchange between three clients against a server with the given settings, returning the lines each received.
+async fn transcript(config: ServerConfig) -> Vec<Vec<String>> {
+    let addr = start_server(config, Extensions::default());
+    let mut clients = Vec::new();
+    for c_id in 1..=3 {
+        clients.push(Client::login(addr, c_id).await);
+    }
+    // each step waits for the server's answer, so the transcript does not depend on timing
+    clients[0].send(b"first\n").await;
+    assert_eq!(clients[0].line().await, b"ACK:MESSAGE 1\n");
+    clients[1].send(b"/roster\n").await;
+    assert!(clients[1].text().await.starts_with("MESSAGE:1 "));
+    assert!(clients[1].text().await.starts_with("ROSTER:"));
+    clients[2].send(b"second\n").await;
+    assert!(clients[2].text().await.starts_with("MESSAGE:1 "));
+    assert_eq!(clients[2].line().await, b"ACK:MESSAGE 2\n");
+    drop(clients.remove(1));
+    let mut late = Client::login(addr, 4).await;
+    assert!(late.text().await.starts_with("HISTORY:"));
+    assert!(late.text().await.starts_with("HISTORY:"));
+    clients.push(late);
+    clients[1].send(b"third\n").await;
+    let mut lines = Vec::new();
+    for client in clients.iter_mut() {
+        let mut received = Vec::new();
+        loop {
+            let mut line = Vec::new();
+            let read = client.stream.read_until(b'\n', &mut line);
+            match tokio::time::timeout(QUIET_PERIOD, read).await {
+                Ok(Ok(n)) if n > 0 => received.push(String::from_utf8(line).unwrap()),
+                _ => break,
+            }
+        }
+        lines.push(received);
+    }
+    lines
+}
+
+#[tokio::test]
+async fn broadcasting_with_rooms_disabled_matches_a_single_room() {
+    let with_rooms = transcript(test_config()).await;
+    let without_rooms = transcript(ServerConfig {
+        disable_rooms: true,
+        ..test_config()
+    })
+    .await;
+    assert_eq!(with_rooms, without_rooms);
+    // and the exchange went as expected
+    assert!(with_rooms[0].contains(&"MESSAGE:3 second\n".to_string()));
+    assert!(with_rooms[2].contains(&"MESSAGE:3 third\n".to_string()));
+}
+
+#[tokio::test]
+async fn topic_reaches_the_room_and_later_joiners() {
+    let addr = start_server(test_config(), Extensions::default());
+    let mut setter = Client::login(addr, 1).await;
+    let mut member = Client::login(addr, 2).await;
+    let mut outsider = Client::login(addr, 3).await;
+    for client in [&mut setter, &mut member] {
+        client.send(b"/join den\n").await;
+        assert_eq!(client.line().await, b"ACK:JOIN den\n");
+    }
+
+    setter.send(b"/topic den plans for friday\n").await;
+    assert_eq!(setter.line().await, b"ACK:TOPIC den\n");
+    assert_eq!(member.line().await, b"TOPIC:den plans for friday\n");
+    // a room the client is not in is off limits
+    outsider.send(b"/topic den hijacked\n").await;
+    assert_eq!(outsider.line().await, b"ERR:NOT_IN_ROOM\n");
+    // a client joining later is told the current topic
+    outsider.send(b"/join den\n").await;
+    assert_eq!(outsider.line().await, b"ACK:JOIN den\n");
+    assert_eq!(outsider.line().await, b"TOPIC:den plans for friday\n");
+    member.expect_silence().await;
+}
+
+#[tokio::test]
+async fn rooms_listing_shows_members_privacy_and_topics() {
+    let addr = start_server(test_config(), Extensions::default());
+    let mut greeter = Client::login(addr, 1).await;
+    greeter.send(b"/topic lobby Welcome\n").await;
+    assert_eq!(greeter.line().await, b"ACK:TOPIC lobby\n");
+    let mut hermit = Client::login(addr, 2).await;
+    assert_eq!(hermit.line().await, b"TOPIC:lobby Welcome\n");
+    hermit
+        .send(b"/join dev --private\n/topic dev Deep work\n")
+        .await;
+    assert_eq!(hermit.line().await, b"ACK:JOIN dev\n");
+    assert_eq!(hermit.line().await, b"ACK:TOPIC dev\n");
+    // a room without a topic is listed without one
+    let mut members = Vec::new();
+    for c_id in 3..=4 {
+        let mut member = Client::login(addr, c_id).await;
+        assert_eq!(member.line().await, b"TOPIC:lobby Welcome\n");
+        member.send(b"/join general\n").await;
+        assert_eq!(member.line().await, b"ACK:JOIN general\n");
+        members.push(member);
+    }
+
+    greeter.send(b"/rooms\n").await;
+    assert_eq!(
+        greeter.line().await,
+        b"ROOMS:dev(1,private):Deep work|general(2,public)|lobby(1,public):Welcome\n"
+    );
+}
+
+#[tokio::test]
+async fn inline_events_replace_control_lines_with_system_lines() {
+    let addr = start_server(test_config(), Extensions::default());
+    let mut structured = Client::login(addr, 1).await;
+    let mut inline = Client::login(addr, 2).await;
+    inline.send(b"/events inline\n").await;
+    assert_eq!(inline.line().await, b"ACK:EVENTS inline\n");
+
+    let mut carol = Client::login(addr, 3).await;
+    carol.send(b"/nick carol\nhello\n").await;
+    assert_eq!(carol.line().await, b"ACK:NICK carol\n");
+    assert_eq!(carol.line().await, b"ACK:MESSAGE 1\n");
+    drop(carol);
+
+    // the changes are interleaved with the chat, in the order they happened
+    assert_eq!(inline.line().await, b"SYSTEM:3 connected\n");
+    assert_eq!(inline.line().await, b"SYSTEM:3 is now known as carol\n");
+    assert_eq!(inline.line().await, b"MESSAGE:3 hello\n");
+    assert_eq!(
+        inline.line().await,
+        b"SYSTEM:3 (carol) disconnected (quit)\n"
+    );
+    inline.expect_silence().await;
+    // by default only the departure is told, as a control line
+    assert_eq!(structured.line().await, b"MESSAGE:3 hello\n");
+    assert_eq!(structured.line().await, b"LEAVE:3 quit\n");
+    structured.expect_silence().await;
+}
@@ -0,0 +1,110 @@
+mod common;
+
+use common::*;
+use rust_broadcast_server::Extensions;
+use rust_broadcast_server::config::ServerConfig;
+use rust_broadcast_server::signing::{Signer, Unsigned};
+
+// key the tests share with the server
+const KEY: &str = "shared secret";
+
+// Function which returns the test settings with the shared key, optionally requiring every message to be signed.
+fn signing_config(require_signatures: bool) -> ServerConfig {
+    ServerConfig {
+        hmac_key: Some(KEY.to_string()),
+        require_signatures,
+        ..test_config()
+    }
+}
+
+#[test]
+fn signatures_match_the_rfc_4231_test_vectors() {
+    let long_key = [0xaa; 131];
+    // test cases 1, 2, 6 and 7, cases 3 and 4 signing data which is not text (see the unit tests in signing.rs)
+    let cases: [(&[u8], &str, &str); 4] = [
+        (
+            &[0x0b; 20],
+            "Hi There",
+            "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7",
+        ),
+        (
+            b"Jefe",
+            "what do ya want for nothing?",
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843",
+        ),
+        (
+            &long_key,
+            "Test Using Larger Than Block-Size Key - Hash Key First",
+            "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54",
+        ),
+        (
+            &long_key,
+            "This is a test using a larger than block-size key and a larger than block-size data. \
+             The key needs to be hashed before being used by the HMAC algorithm.",
+            "9b09ffa71b942fcb27635fbcd5b0e944bfdc63644f0713938a7f51535c3a35e2",
+        ),
+    ];
+    for (key, data, mac) in cases {
+        assert_eq!(Signer::new(key).sign(data), mac, "{}", data);
+    }
+    // test case 5 only gives the first 128 bits of the signature
+    let truncated = Signer::new(&[0x0c; 20]).sign("Test With Truncation");
+    assert_eq!(&truncated[..32], "a3b6167473100ee06e0c796c2955552b");
+}
+
+#[test]
+fn verify_accepts_only_a_matching_signature() {
+    let signer = Signer::new(KEY.as_bytes());
+    let signed = format!("hello hmac={}", signer.sign("hello"));
+    assert_eq!(signer.verify(&signed).ok(), Some("hello"));
+    // upper case hex is as good as lower case
+    let upper = format!("hello hmac={}", signer.sign("hello").to_uppercase());
+    assert_eq!(signer.verify(&upper).ok(), Some("hello"));
+    let tampered = signed.replacen("hello", "jello", 1);
+    assert!(matches!(signer.verify(&tampered), Err(Unsigned::Invalid)));
+    let other_key = Signer::new(b"another secret");
+    assert!(matches!(other_key.verify(&signed), Err(Unsigned::Invalid)));
+    assert!(matches!(signer.verify("hello"), Err(Unsigned::Missing)));
+}
+
+#[tokio::test]
+async fn signed_message_is_accepted_and_a_tampered_one_rejected() {
+    let addr = start_server(signing_config(true), Extensions::default());
+    let mut sender = Client::login(addr, 1).await;
+    let mut receiver = Client::login(addr, 2).await;
+    let signer = Signer::new(KEY.as_bytes());
+
+    let signature = signer.sign("pay bob 10");
+    sender
+        .send(format!("pay bob 10 hmac={}\n", signature).as_bytes())
+        .await;
+    assert_eq!(sender.line().await, b"ACK:MESSAGE 1\n");
+    assert_eq!(
+        receiver.text().await,
+        format!("MESSAGE:1 pay bob 10 hmac={}", signature)
+    );
+    // the same signature does not cover altered text, and nothing unsigned gets through either
+    sender
+        .send(format!("pay bob 1000 hmac={}\n", signature).as_bytes())
+        .await;
+    assert_eq!(sender.line().await, b"ERR:BAD_SIGNATURE\n");
+    sender.send(b"pay bob 1000\n").await;
+    assert_eq!(sender.line().await, b"ERR:BAD_SIGNATURE\n");
+    receiver.expect_silence().await;
+}
+
+#[tokio::test]
+async fn broadcast_signatures_are_verifiable_by_clients() {
+    let addr = start_server(signing_config(false), Extensions::default());
+    let mut sender = Client::login(addr, 1).await;
+    let mut receiver = Client::login(addr, 2).await;
+    let signer = Signer::new(KEY.as_bytes());
+
+    // unsigned messages are accepted unless signatures are required, and are signed by the server
+    sender.send(b"meet at noon\n").await;
+    assert_eq!(sender.line().await, b"ACK:MESSAGE 1\n");
+    let line = receiver.text().await;
+    let signed = line.strip_prefix("MESSAGE:1 ").unwrap();
+    assert_eq!(signer.verify(signed).ok(), Some("meet at noon"));
+    assert!(Signer::new(b"wrong key").verify(signed).is_err());
+}
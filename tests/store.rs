@@ -0,0 +1,142 @@
+use rust_broadcast_server::store::{FileStore, MemoryStore, MessageStore, StoredMessage};
+use std::path::PathBuf;
+
+// Function which returns a message as the server would store it, with the given sequence number.
+fn message(seq: u64) -> StoredMessage {
+    StoredMessage {
+        seq,
+        room: "lobby".to_string(),
+        from: seq % 3 + 1,
+        nick: seq.is_multiple_of(2).then(|| "alice".to_string()),
+        text: format!("message {}", seq),
+    }
+}
+
+// Function which returns the path of a file in the temporary directory which no other test uses, removing any left behind.
+fn temp_path(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("{}-{}.jsonl", name, std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    path
+}
+
+/** Function which checks the behaviour every MessageStore shares, given an empty store holding at
+* least five messages: it starts empty, then returns the most recent messages appended, oldest first,
+* as edited and without those deleted.
+*/
+async fn check_contract(store: &dyn MessageStore) {
+    assert!(store.recent(10).await.is_empty());
+    for seq in 1..=5 {
+        store.append(&message(seq)).await;
+    }
+    assert_eq!(
+        store.recent(10).await,
+        (1..=5).map(message).collect::<Vec<_>>()
+    );
+    assert_eq!(store.recent(2).await, vec![message(4), message(5)]);
+    assert!(store.recent(0).await.is_empty());
+
+    store.edit(2, "edited").await;
+    store.delete(4).await;
+    // changes to messages the store does not hold are ignored
+    store.edit(9, "missing").await;
+    store.delete(9).await;
+    let edited = StoredMessage {
+        text: "edited".to_string(),
+        ..message(2)
+    };
+    assert_eq!(
+        store.recent(10).await,
+        vec![message(1), edited, message(3), message(5)]
+    );
+    assert_eq!(store.recent(2).await, vec![message(3), message(5)]);
+    assert!(store.is_healthy());
+}
+
+#[tokio::test]
+async fn memory_store_keeps_the_contract() {
+    check_contract(&MemoryStore::new(5)).await;
+}
+
+#[tokio::test]
+async fn file_store_keeps_the_contract() {
+    let path = temp_path("file-store-contract");
+    check_contract(&FileStore::open(&path, 5).unwrap()).await;
+    let _ = std::fs::remove_file(&path);
+}
+
+#[tokio::test]
+async fn memory_store_drops_the_oldest_beyond_its_capacity() {
+    let store = MemoryStore::new(3);
+    for seq in 1..=5 {
+        store.append(&message(seq)).await;
+    }
+    assert_eq!(
+        store.recent(10).await,
+        (3..=5).map(message).collect::<Vec<_>>()
+    );
+}
+
+#[tokio::test]
+async fn file_store_keeps_messages_across_reopening() {
+    let path = temp_path("file-store-reopen");
+    let store = FileStore::open(&path, 10).unwrap();
+    store.append(&message(1)).await;
+    store.append(&message(2)).await;
+    drop(store);
+
+    let store = FileStore::open(&path, 10).unwrap();
+    store.append(&message(3)).await;
+    assert_eq!(
+        store.recent(10).await,
+        (1..=3).map(message).collect::<Vec<_>>()
+    );
+    let _ = std::fs::remove_file(&path);
+}
+
+#[tokio::test]
+async fn file_store_reads_messages_stored_without_a_type() {
+    let path = temp_path("file-store-untyped");
+    let old = serde_json::to_string(&message(1)).unwrap();
+    std::fs::write(&path, format!("{}\nnot json\n", old)).unwrap();
+
+    let store = FileStore::open(&path, 10).unwrap();
+    store.append(&message(2)).await;
+    store.edit(1, "edited").await;
+    let edited = StoredMessage {
+        text: "edited".to_string(),
+        ..message(1)
+    };
+    assert_eq!(store.recent(10).await, vec![edited, message(2)]);
+    let _ = std::fs::remove_file(&path);
+}
+
+// Function which returns the number of lines in the file at the given path.
+fn line_count(path: &PathBuf) -> usize {
+    std::fs::read_to_string(path).unwrap().lines().count()
+}
+
+#[tokio::test]
+async fn file_store_compacts_its_file_to_the_messages_it_keeps() {
+    let path = temp_path("file-store-compact");
+    let store = FileStore::open(&path, 3).unwrap();
+    for seq in 1..=5 {
+        store.append(&message(seq)).await;
+    }
+    assert_eq!(line_count(&path), 5);
+    // the file reaches twice the capacity, so it is rewritten with the three messages kept, as edited
+    store.edit(5, "edited").await;
+    assert_eq!(line_count(&path), 3);
+    let edited = StoredMessage {
+        text: "edited".to_string(),
+        ..message(5)
+    };
+    store.append(&message(6)).await;
+    assert_eq!(line_count(&path), 4);
+    drop(store);
+
+    // opened with a smaller capacity, the file is compacted straight away
+    let store = FileStore::open(&path, 2).unwrap();
+    assert_eq!(line_count(&path), 2);
+    assert_eq!(store.recent(10).await, vec![edited, message(6)]);
+    let _ = std::fs::remove_file(&path);
+}